@@ -4,11 +4,14 @@
 // =============================================================
 use chess::{Board, BoardStatus, ChessMove, Color as ChessColor, MoveGen, Piece, Square};
 use macroquad::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
 use single_instance::SingleInstance;          // en‑instans‑lås
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io::{BufRead, BufReader, Write};
-use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, Stdio};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -22,6 +25,128 @@ const CREATE_NO_WINDOW: u32 = 0x0800_0000;    // döljer Stockfish‑konsolen
 // DEL 0: ANALYS-STRUKTURER
 // =============================================================
 
+// En evaluering sett från vits perspektiv
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eval {
+    Pawns(f32),
+    Mate { moves: u32, winner: ChessColor },
+}
+
+impl Eval {
+    // Evaluering i bönder; forcerad matt räknas som ett mycket stort värde där
+    // kortare matt ger större belopp, så att matt i 2 sorteras före matt i 5
+    fn as_pawns(self) -> f32 {
+        match self {
+            Eval::Pawns(p) => p,
+            Eval::Mate { moves, winner } => {
+                let magnitude = 100.0 - moves.min(1000) as f32 * 0.01;
+                if winner == ChessColor::White { magnitude } else { -magnitude }
+            }
+        }
+    }
+
+    // Samma evaluering sedd från svarts perspektiv
+    fn flipped(self) -> Self {
+        match self {
+            Eval::Pawns(p) => Eval::Pawns(-p),
+            Eval::Mate { moves, winner } => Eval::Mate { moves, winner: !winner },
+        }
+    }
+}
+
+// Formatera en evaluering som "+1.23", "-0.50" eller "M4" med valfritt antal decimaler
+fn format_eval(eval: Eval, precision: usize) -> String {
+    match eval {
+        Eval::Pawns(p) => {
+            let rounded = format!("{:.*}", precision, p.abs());
+            if rounded.chars().all(|c| c == '0' || c == '.') {
+                rounded
+            } else if p > 0.0 {
+                format!("+{rounded}")
+            } else {
+                format!("-{rounded}")
+            }
+        }
+        Eval::Mate { moves: 0, winner } => {
+            if winner == ChessColor::White { "#".to_string() } else { "-#".to_string() }
+        }
+        Eval::Mate { moves, winner } => {
+            if winner == ChessColor::White { format!("M{moves}") } else { format!("-M{moves}") }
+        }
+    }
+}
+
+// Resultatet av partianalysens enda sökning i en ställning: evalueringen (vits
+// perspektiv), bästa variant (tom om motorn inte gav något drag) och näst bästa
+// dragets evaluering, som visar om bästa draget var det enda som höll
+#[derive(Debug, Clone)]
+struct PositionResult {
+    evaluation: f32,
+    best_line: Vec<ChessMove>,
+    second_evaluation: Option<f32>,
+}
+
+impl PositionResult {
+    fn material_only(board: &Board) -> Self {
+        Self { evaluation: ChessGame::simple_material_evaluation(board), best_line: Vec::new(), second_evaluation: None }
+    }
+}
+
+// Kandidatvarianter per ställning i partianalysen: bästa och näst bästa
+const GAME_ANALYSIS_LINES: u8 = 2;
+
+// Gränser för positiva markeringar, i procentenheter vinstchans för den som drog
+const GREAT_MOVE_MARGIN: f32 = 15.0; // näst bästa drag förlorar minst så mycket
+const HOLDING_WIN_PERCENT: f32 = 45.0; // ställningen är minst ungefär jämn efter draget
+const BRILLIANT_MAX_DROP: f32 = 2.0; // offret får kosta högst så mycket
+const SACRIFICE_PAWNS: f32 = 2.0; // minsta materialförlust som räknas som offer
+
+// Meddelanden från partianalysen som körs i bakgrunden
+enum GameAnalysisMessage {
+    Progress { done: usize, total: usize },
+    Done(GameAnalysis),
+}
+
+// Vinstchans i procent (0–100) för vit vid given evaluering i bönder, enligt
+// Lichess modell. Matt och enorma fördelar kapas vid ±10 bönder.
+fn win_percent(pawns: f32) -> f32 {
+    let centipawns = (pawns * 100.0).clamp(-1000.0, 1000.0);
+    50.0 + 50.0 * (2.0 / (1.0 + (-0.003_682_08 * centipawns).exp()) - 1.0)
+}
+
+// Vinstchansen före och efter ett drag, sett från den som gjorde draget
+fn mover_win_percents(eval_before: f32, eval_after: f32, side_that_moved: ChessColor) -> (f32, f32) {
+    let (before, after) = (win_percent(eval_before), win_percent(eval_after));
+    if side_that_moved == ChessColor::White { (before, after) } else { (100.0 - before, 100.0 - after) }
+}
+
+// Dragets noggrannhet (0–100) utifrån hur mycket vinstchansen sjönk för den som drog
+fn move_accuracy(win_before: f32, win_after: f32) -> f32 {
+    let drop = (win_before - win_after).max(0.0);
+    (103.1668 * (-0.04354 * drop).exp() - 3.1669).clamp(0.0, 100.0)
+}
+
+// Höll bästa draget ställningen medan näst bästa förlorade klart? Sett från den som drar.
+fn is_only_good_move(best_eval: f32, second_eval: Option<f32>, side_to_move: ChessColor) -> bool {
+    let Some(second_eval) = second_eval else { return false };
+    let (best, second) = mover_win_percents(best_eval, second_eval, side_to_move);
+    best >= HOLDING_WIN_PERCENT && best - second >= GREAT_MOVE_MARGIN
+}
+
+// Material (bönder) som den som drog har gett upp när motståndaren tagit för sig:
+// jämför före draget med två halvdrag in i motorns variant efter draget
+fn sacrificed_material(material_before: f32, board_after: &Board, pv_after: &[ChessMove], side_that_moved: ChessColor) -> f32 {
+    let mut board = *board_after;
+    for &m in pv_after.iter().take(2) {
+        if !board.legal(m) {
+            break;
+        }
+        board = board.make_move_new(m);
+    }
+    let lost = material_before - ChessGame::simple_material_evaluation(&board);
+    if side_that_moved == ChessColor::White { lost } else { -lost }
+}
+
 // Struktur för att lagra analysinformation per drag
 #[derive(Debug, Clone)]
 struct MoveAnalysis {
@@ -33,8 +158,109 @@ struct MoveAnalysis {
     is_blunder: bool,
     is_mistake: bool,
     is_inaccuracy: bool,
+    is_great: bool,     // enda draget som höll ställningen ("!")
+    is_brilliant: bool, // korrekt offer ("!!")
     best_move: Option<ChessMove>,
     best_move_notation: Option<String>,
+    best_line: Vec<ChessMove>, // motorns huvudvariant från ställningen före draget
+    material_after: f32, // materialbalans efter draget, från vits perspektiv
+    skipped: bool,       // draget låg utanför den valda partifasen och analyserades inte
+    side: ChessColor,    // sidan som gjorde draget
+}
+
+// Färger för positiva markeringar i draglistorna
+const BRILLIANT_COLOR: Color = Color::new(0.0, 0.65, 0.75, 1.0);
+const GREAT_COLOR: Color = Color::new(0.1, 0.7, 0.2, 1.0);
+
+impl MoveAnalysis {
+    // Förändring i evaluering sett från den som gjorde draget (positivt = förbättring)
+    fn eval_delta(&self, side_that_moved: ChessColor) -> f32 {
+        let delta = self.evaluation_after - self.evaluation_before;
+        if side_that_moved == ChessColor::White { delta } else { -delta }
+    }
+
+    fn accuracy(&self) -> f32 {
+        let (before, after) = mover_win_percents(self.evaluation_before, self.evaluation_after, self.side);
+        move_accuracy(before, after)
+    }
+
+    // Svensk benämning och färg för dragets klassificering
+    fn classification(&self) -> (&'static str, Color) {
+        if self.is_brilliant {
+            ("briljant drag", BRILLIANT_COLOR)
+        } else if self.is_great {
+            ("starkt drag", GREAT_COLOR)
+        } else if self.is_blunder {
+            ("blunder", RED)
+        } else if self.is_mistake {
+            ("misstag", ORANGE)
+        } else if self.is_inaccuracy {
+            ("inexakthet", Color::new(0.8, 0.8, 0.0, 1.0))
+        } else {
+            ("bra drag", DARKGREEN)
+        }
+    }
+
+    // "!!" eller "!" efter draget, tomt för övriga drag
+    fn positive_suffix(&self) -> &'static str {
+        if self.is_brilliant {
+            "!!"
+        } else if self.is_great {
+            "!"
+        } else {
+            ""
+        }
+    }
+}
+
+// Vilken del av partiet som ska analyseras
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnalysisPhase {
+    All,
+    Opening,
+    Middlegame,
+    Endgame,
+}
+
+// Öppningen räknas som de första tio dragen; slutspel när lite pjäsmaterial återstår
+const OPENING_PLIES: usize = 20;
+const ENDGAME_MAX_PIECE_MATERIAL: u32 = 26;
+
+impl AnalysisPhase {
+    fn next(self) -> Self {
+        match self {
+            AnalysisPhase::All => AnalysisPhase::Opening,
+            AnalysisPhase::Opening => AnalysisPhase::Middlegame,
+            AnalysisPhase::Middlegame => AnalysisPhase::Endgame,
+            AnalysisPhase::Endgame => AnalysisPhase::All,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            AnalysisPhase::All => "Hela",
+            AnalysisPhase::Opening => "Öppn",
+            AnalysisPhase::Middlegame => "Mitt",
+            AnalysisPhase::Endgame => "Slut",
+        }
+    }
+
+    // Avgör om halvdraget med givet index, spelat i ställningen `board`, hör till fasen
+    fn contains(self, ply: usize, board: &Board) -> bool {
+        let endgame = Self::piece_material(board) <= ENDGAME_MAX_PIECE_MATERIAL;
+        match self {
+            AnalysisPhase::All => true,
+            AnalysisPhase::Opening => ply < OPENING_PLIES,
+            AnalysisPhase::Middlegame => ply >= OPENING_PLIES && !endgame,
+            AnalysisPhase::Endgame => endgame,
+        }
+    }
+
+    // Sammanlagt pjäsmaterial (utan bönder och kungar) för båda sidor
+    fn piece_material(board: &Board) -> u32 {
+        let value = |piece: Piece, worth: u32| board.pieces(piece).popcnt() * worth;
+        value(Piece::Knight, 3) + value(Piece::Bishop, 3) + value(Piece::Rook, 5) + value(Piece::Queen, 9)
+    }
 }
 
 // Struktur för att lagra hela partianalysen
@@ -46,33 +272,477 @@ struct GameAnalysis {
     total_blunders: usize,
     total_mistakes: usize,
     total_inaccuracies: usize,
+    fortresses: Vec<(usize, usize)>, // möjliga fästningar som (första, sista) dragindex
+    depth: u8,
+}
+
+impl GameAnalysis {
+    // Sammanställ övergripande statistik ur de analyserade dragen
+    fn from_moves(moves: Vec<MoveAnalysis>, depth: u8) -> Self {
+        let (white_accuracy, black_accuracy) = ChessGame::calculate_accuracy(&moves);
+        Self {
+            white_accuracy,
+            black_accuracy,
+            total_blunders: moves.iter().filter(|m| m.is_blunder).count(),
+            total_mistakes: moves.iter().filter(|m| m.is_mistake).count(),
+            total_inaccuracies: moves.iter().filter(|m| m.is_inaccuracy).count(),
+            fortresses: detect_fortresses(&moves),
+            moves,
+            depth,
+        }
+    }
+
+    fn side_moves(&self, side: ChessColor) -> impl Iterator<Item = &MoveAnalysis> {
+        self.moves.iter().filter(move |m| !m.skipped && m.side == side)
+    }
+
+    // Genomsnittlig centipawn-förlust (ACPL) för en sida
+    fn average_centipawn_loss(&self, side: ChessColor) -> f32 {
+        let losses: Vec<i32> = self.side_moves(side).map(|m| m.centipawn_loss.max(0)).collect();
+        if losses.is_empty() {
+            return 0.0;
+        }
+        losses.iter().sum::<i32>() as f32 / losses.len() as f32
+    }
+
+    // (blunders, misstag, inexaktheter) för en sida
+    fn error_counts(&self, side: ChessColor) -> (usize, usize, usize) {
+        self.side_moves(side).fold((0, 0, 0), |(b, m, i), mv| {
+            (b + mv.is_blunder as usize, m + mv.is_mistake as usize, i + mv.is_inaccuracy as usize)
+        })
+    }
+
+    // De dyraste felen i partiet som (dragindex, analys), störst förlust först
+    fn key_moments(&self, count: usize) -> Vec<(usize, &MoveAnalysis)> {
+        let mut errors: Vec<(usize, &MoveAnalysis)> = self.moves.iter()
+            .enumerate()
+            .filter(|(_, m)| !m.skipped && (m.is_blunder || m.is_mistake || m.is_inaccuracy))
+            .collect();
+        errors.sort_by_key(|(_, m)| std::cmp::Reverse(m.centipawn_loss));
+        errors.truncate(count);
+        errors
+    }
+}
+
+// Hur många tidigare analyser som sparas för jämförelse
+const ANALYSIS_HISTORY_LEN: usize = 5;
+
+// Beskriv hur en ny analys skiljer sig från en tidigare av samma parti
+fn analysis_diff_lines(old: &GameAnalysis, new: &GameAnalysis) -> Vec<String> {
+    let mut changed = Vec::new();
+    let mut total_shift = 0.0;
+    let mut compared = 0;
+
+    for (i, (before, after)) in old.moves.iter().zip(&new.moves).enumerate() {
+        if before.move_notation != after.move_notation {
+            break; // Partierna skiljer sig åt härifrån
+        }
+        if before.skipped || after.skipped {
+            continue;
+        }
+
+        compared += 1;
+        total_shift += (after.evaluation_after - before.evaluation_after).abs();
+        let (old_label, _) = before.classification();
+        let (new_label, _) = after.classification();
+        if old_label != new_label {
+            changed.push(format!("{}. {}: {} → {}", i + 1, after.move_notation, old_label, new_label));
+        }
+    }
+
+    if compared == 0 {
+        return Vec::new();
+    }
+
+    let mut lines = vec![
+        format!("Jämfört med förra analysen (djup {} → {}):", old.depth, new.depth),
+        format!("{} drag bytte klassificering, evalueringen flyttade i snitt {:.2}", changed.len(), total_shift / compared as f32),
+    ];
+    lines.extend(changed.into_iter().take(3));
+    lines
+}
+
+// Minsta antal halvdrag och materialövertag för att en sträcka ska räknas som möjlig fästning.
+// Gränserna är medvetet snäva för att undvika falsklarm.
+const FORTRESS_MIN_PLIES: usize = 12;
+const FORTRESS_MIN_MATERIAL: f32 = 3.0;
+const FORTRESS_MAX_EVAL: f32 = 0.5;
+
+// Hitta långa sträckor där en sida har klart mer material men evalueringen ändå ligger nära noll
+fn detect_fortresses(moves: &[MoveAnalysis]) -> Vec<(usize, usize)> {
+    let mut stretches = Vec::new();
+    let mut start: Option<usize> = None;
+
+    for (i, m) in moves.iter().enumerate() {
+        let material_sign = m.material_after.signum();
+        let same_side = start.is_none_or(|s| moves[s].material_after.signum() == material_sign);
+        let candidate = !m.skipped
+            && m.material_after.abs() >= FORTRESS_MIN_MATERIAL
+            && m.evaluation_after.abs() <= FORTRESS_MAX_EVAL;
+
+        match (candidate && same_side, start) {
+            (true, None) => start = Some(i),
+            (true, Some(_)) => {}
+            (false, Some(s)) => {
+                if i - s >= FORTRESS_MIN_PLIES {
+                    stretches.push((s, i - 1));
+                }
+                start = if candidate { Some(i) } else { None };
+            }
+            (false, None) => {}
+        }
+    }
+
+    if let Some(s) = start {
+        if moves.len() - s >= FORTRESS_MIN_PLIES {
+            stretches.push((s, moves.len() - 1));
+        }
+    }
+    stretches
 }
 
 // =============================================================
 // DEL 1: STOCKFISH‑UCI‑KONTROLLER
 // =============================================================
 
+// En motorinställning som motorn annonserar under handskakningen
+#[derive(Debug, Clone)]
+pub struct EngineOption {
+    pub name: String,
+    pub kind: String,
+    pub default: Option<String>,
+    pub min: Option<String>,
+    pub max: Option<String>,
+}
+
+impl EngineOption {
+    // Tolka en rad av typen "option name Hash type spin default 16 min 1 max 33554432"
+    fn parse(line: &str) -> Option<Self> {
+        let rest = line.trim().strip_prefix("option name ")?;
+        let type_pos = rest.find(" type ")?;
+        let name = rest[..type_pos].trim().to_string();
+        let mut toks = rest[type_pos + 6..].split_whitespace();
+        let kind = toks.next()?.to_string();
+
+        let mut option = Self { name, kind, default: None, min: None, max: None };
+        while let Some(key) = toks.next() {
+            let value = toks.next().map(|v| v.to_string());
+            match key {
+                "default" => option.default = value,
+                "min" => option.min = value,
+                "max" => option.max = value,
+                _ => {}
+            }
+        }
+        Some(option)
+    }
+}
+
+// Information som motorn skickar innan "uciok"
+#[derive(Debug, Clone, Default)]
+pub struct EngineInfo {
+    pub path: String, // sökvägen som motorn startades från
+    pub name: String,
+    pub author: String,
+    pub options: Vec<EngineOption>,
+}
+
+impl EngineInfo {
+    pub fn supports_option(&self, name: &str) -> bool {
+        self.options.iter().any(|o| o.name.eq_ignore_ascii_case(name))
+    }
+}
+
+// Resultat av en sökning: bästa drag, motorns sista evaluering och huvudvariant
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub best_move: ChessMove,
+    pub eval: Option<Eval>,
+    pub pv: Vec<ChessMove>,
+}
+
+// Hur långt en pågående sökning kommit, ur motorns info-rader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchProgress {
+    pub depth: u8,
+    pub nodes: u64,
+    pub nps: u64,
+}
+
+// En uppdatering från den löpande analysen: djup, evaluering och huvudvariant
+#[derive(Debug, Clone)]
+pub struct AnalysisInfo {
+    pub depth: u8,
+    pub eval: Eval,
+    pub pv: Vec<ChessMove>,
+}
+
+// Hur länge en sökning får pågå: till ett fast djup, en fast tid i millisekunder eller
+// efter partiets klocka, där motorn själv fördelar tiden
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchLimit {
+    Depth(u8),
+    MoveTime(u32),
+    Clock { wtime: u32, btime: u32, winc: u32, binc: u32 },
+}
+
+impl SearchLimit {
+    fn go_command(self) -> String {
+        match self {
+            SearchLimit::Depth(depth) => format!("go depth {depth}"),
+            SearchLimit::MoveTime(millis) => format!("go movetime {millis}"),
+            SearchLimit::Clock { wtime, btime, winc, binc } => {
+                format!("go wtime {wtime} btime {btime} winc {winc} binc {binc}")
+            }
+        }
+    }
+}
+
+// Sammanfattning av Stockfish-kommandot "bench"
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BenchResult {
+    pub total_time_ms: u64,
+    pub nodes: u64,
+    pub nps: u64,
+}
+
+impl BenchResult {
+    // Tolka sammanfattningsraderna ("Total time (ms) : 1234" osv.)
+    fn parse<'a>(lines: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        let mut result = BenchResult::default();
+        let mut found_nps = false;
+
+        for line in lines {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let Ok(value) = value.trim().parse::<u64>() else {
+                continue;
+            };
+            match key.trim() {
+                "Total time (ms)" => result.total_time_ms = value,
+                "Nodes searched" => result.nodes = value,
+                "Nodes/second" => {
+                    result.nps = value;
+                    found_nps = true;
+                }
+                _ => {}
+            }
+        }
+
+        found_nps.then_some(result)
+    }
+}
+
+// Standardstorlek för motorns hashtabell i MB
+const DEFAULT_HASH_MB: u32 = 256;
+
+// Alla kärnor utom en, så att fönstret fortsätter vara följsamt under sökningen
+fn default_engine_threads() -> u32 {
+    thread::available_parallelism()
+        .map(|n| n.get().saturating_sub(1).max(1) as u32)
+        .unwrap_or(1)
+}
+
+// Värdet efter en kommandoradsflagga, t.ex. "--hash 512"
+fn cli_value(flag: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != flag);
+    args.next()?;
+    args.next()
+}
+
 pub struct StockfishController {
     process:       Child,
     stdin:         ChildStdin,
     stdout_reader: BufReader<ChildStdout>,
+    info:          EngineInfo,
+    stderr_tail:   Arc<Mutex<VecDeque<String>>>,
+    stop_requested: Arc<AtomicBool>, // sätts utifrån för att avbryta en pågående sökning
+    applied_options: Vec<(String, String)>, // satta optioner, skickas igen efter omstart
+}
+
+// Marginal runt brädet; rymmer koordinater, evalueringsstapeln och knappraden
+const BOARD_MARGIN: f32 = 100.0;
+// Plats till höger om brädet: mellanrum + kontrollpanel + kant
+const PANEL_SPACE: f32 = 260.0;
+const PANEL_GAP: f32 = 40.0;
+// Rad ovanför och under brädet (utanför koordinaterna) för varje sidas slagna pjäser
+const PLAYER_BAR_HEIGHT: f32 = 28.0;
+// Navigeringsknapparna under brädet
+const NAV_BUTTON_WIDTH: f32 = 50.0;
+
+// Brädets och panelens placering, räknas om från fönsterstorleken varje bildruta
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Layout {
+    board_x: f32,
+    board_y: f32,
+    square_size: f32,
+    panel_x: f32,
+}
+
+impl Layout {
+    fn from_screen(width: f32, height: f32) -> Self {
+        // Rutstorleken begränsas av den axel som har minst plats
+        let by_width = (width - BOARD_MARGIN - PANEL_SPACE) / 8.0;
+        let by_height = (height - 2.0 * (BOARD_MARGIN + PLAYER_BAR_HEIGHT)) / 8.0;
+        let square_size = by_width.min(by_height).max(20.0);
+        let board_size = 8.0 * square_size;
+
+        // Centrera bräde + panel som en enhet i fönstret
+        let board_x = BOARD_MARGIN + ((width - BOARD_MARGIN - board_size - PANEL_SPACE) / 2.0).max(0.0);
+        let board_y = ((height - board_size) / 2.0).max(BOARD_MARGIN / 2.0);
+
+        Self {
+            board_x,
+            board_y,
+            square_size,
+            panel_x: board_x + board_size + PANEL_GAP,
+        }
+    }
+
+    fn current() -> Self {
+        Self::from_screen(screen_width(), screen_height())
+    }
+
+    // Bara brädet i en kvadratisk bild, med marginal runt om för ram och koordinater
+    fn for_image(size: f32, margin: f32) -> Self {
+        Self {
+            board_x: margin,
+            board_y: margin,
+            square_size: (size - 2.0 * margin) / 8.0,
+            panel_x: size,
+        }
+    }
+
+    fn board_size(&self) -> f32 {
+        8.0 * self.square_size
+    }
+
+    // Övre vänstra hörnet av rutan (x, y) i skärmkoordinater
+    fn square_origin(&self, x: i32, y: i32) -> (f32, f32) {
+        (self.board_x + x as f32 * self.square_size, self.board_y + y as f32 * self.square_size)
+    }
+
+    // Vilken ruta (x, y) som ligger under en skärmpunkt, om någon
+    fn square_at(&self, (px, py): (f32, f32)) -> Option<(i32, i32)> {
+        let x = ((px - self.board_x) / self.square_size).floor() as i32;
+        let y = ((py - self.board_y) / self.square_size).floor() as i32;
+        ((0..8).contains(&x) && (0..8).contains(&y)).then_some((x, y))
+    }
+
+    fn board_center(&self) -> (f32, f32) {
+        let half = self.board_size() / 2.0;
+        (self.board_x + half, self.board_y + half)
+    }
+}
+
+// Övre vänstra hörnet för en overlay som centreras i fönstret; hålls kvar på skärmen
+// när fönstret är mindre än overlayen
+fn overlay_origin(width: f32, height: f32) -> (f32, f32) {
+    (((screen_width() - width) / 2.0).max(0.0), ((screen_height() - height) / 2.0).max(0.0))
+}
+
+// Inställningsfönstret: bredd, radhöjd och utrymmet för rubriken ovanför och tipsen under raderna
+const SETTINGS_WINDOW_WIDTH: f32 = 420.0;
+const SETTINGS_ROW_HEIGHT: f32 = 30.0;
+const SETTINGS_HEADER: f32 = 60.0;
+const SETTINGS_FOOTER: f32 = 45.0;
+
+// Inställningsraderna som visas när `height` pixlar finns för dem: (första, antal).
+// Minst en rad visas, och scrollningen hålls inom listan även om fönstret växt.
+fn settings_page(rows: usize, height: f32, scroll: usize) -> (usize, usize) {
+    let visible = ((height / SETTINGS_ROW_HEIGHT).floor().max(1.0) as usize).min(rows);
+    (scroll.min(rows - visible), visible)
+}
+
+// Storlek (bredd, höjd) på dialogrutorna
+const FEN_DIALOG_SIZE: (f32, f32) = (520.0, 170.0);
+const NAME_DIALOG_SIZE: (f32, f32) = (380.0, 170.0);
+const RESUME_DIALOG_SIZE: (f32, f32) = (420.0, 140.0);
+const PUZZLE_DIALOG_SIZE: (f32, f32) = (420.0, 140.0);
+const ENGINE_NOTICE_SIZE: (f32, f32) = (600.0, 290.0);
+const IMPORT_DIALOG_SIZE: (f32, f32) = (420.0, 150.0);
+const ANALYSIS_WINDOW_SIZE: (f32, f32) = (600.0, 700.0);
+
+// Pjäspaletten i ställningsredigeraren: en rad per färg överst i panelen
+const EDITOR_PALETTE_Y: f32 = 105.0;
+const EDITOR_CELL: f32 = 30.0;
+const EDITOR_PIECES: [Piece; 6] = [Piece::King, Piece::Queen, Piece::Rook, Piece::Bishop, Piece::Knight, Piece::Pawn];
+
+// Toast som visas medan en släppt fil läses in
+const LOADING_TOAST: &str = "Laddar fil…";
+
+// Djup för engångsdjupsökningen (D eller Shift när sökningen startar)
+const DEEP_SEARCH_DEPTH: u8 = 22;
+
+// Minsta tid i sekunder mellan dragen i motormatchen, så att den går att följa
+const AUTOPLAY_MOVE_DELAY: f64 = 0.8;
+
+// Paus i sekunder före motståndarens svar och nästa uppgift i taktikläget
+const PUZZLE_STEP_DELAY: f64 = 0.6;
+
+// Antal kandidatdrag (MultiPV) som visas i positionsanalysen
+const POSITION_ANALYSIS_LINES: u8 = 3;
+
+// Antal halvdrag av huvudvarianten som visas som text och pilar
+const PV_DISPLAY_PLIES: usize = 6;
+
+// Djupgräns för den oändliga analysen; i praktiken obegränsad men ser till att
+// motorn alltid blir klar även i ställningar där den inte skriver fler info-rader
+const INFINITE_ANALYSIS_DEPTH: u8 = 40;
+
+// Antal synliga rader i kontrollpanelens draglista
+const PANEL_MOVE_LIST_ROWS: usize = 12;
+
+// Fil där pågående parti autosparas som PGN
+const AUTOSAVE_PATH: &str = "senaste_parti.pgn";
+
+// Bild av brädet från "Exportera bild"
+const BOARD_IMAGE_PATH: &str = "schack_bild.png";
+
+// Sparfil för "Spara spel"/"Ladda spel". Versionen höjs när formatet ändras så att
+// äldre program kan säga ifrån i stället för att läsa fel.
+const SAVE_PATH: &str = "sparat_parti.json";
+const SAVE_FORMAT: &str = "daniels-schack";
+const SAVE_VERSION: u32 = 1;
+
+// Inställningar som sparas mellan körningarna: allt i GameSettings utom partiets
+// egna val, plus reglagen, spelarens färg och motorns sökväg
+const SETTINGS_PATH: &str = "installningar.json";
+
+// Motorsökvägen i inställningsfilen, om filen finns och går att läsa
+fn saved_engine_path() -> Option<String> {
+    let text = std::fs::read_to_string(SETTINGS_PATH).ok()?;
+    let file: SettingsFile = serde_json::from_str(&text).ok()?;
+    file.engine_path.filter(|path| !path.trim().is_empty())
 }
 
+// Hur många av motorns senaste stderr-rader som sparas för felmeddelanden
+const STDERR_TAIL_LINES: usize = 5;
+
 impl StockfishController {
     pub fn new() -> Result<Self, String> {
         println!("[StockfishController] Startar Stockfish …");
 
-        // Prova olika sökvägar för Stockfish
-        let stockfish_paths = vec![
-            "stockfish.exe",
-            "stockfish",
-            ".\\stockfish.exe",
-            "C:\\stockfish\\stockfish.exe",
-        ];
+        // En uttryckligen angiven motor provas ensam; annars provas de vanliga sökvägarna,
+        // med den som fungerade förra gången (enligt inställningsfilen) först
+        let configured = Self::configured_path();
+        let stockfish_paths: Vec<String> = match &configured {
+            Some((path, _)) => vec![path.clone()],
+            None => {
+                let mut paths: Vec<String> = saved_engine_path().into_iter().collect();
+                for default in ["stockfish.exe", "stockfish", ".\\stockfish.exe", "C:\\stockfish\\stockfish.exe"] {
+                    if !paths.iter().any(|p| p == default) {
+                        paths.push(default.to_string());
+                    }
+                }
+                paths
+            }
+        };
 
         let mut last_error = String::new();
         
-        for path in stockfish_paths {
+        for path in &stockfish_paths {
             println!("[StockfishController] Provar sökväg: {}", path);
             
             // Bygg kommandot
@@ -94,8 +764,20 @@ impl StockfishController {
                     let stdin = process.stdin.take().ok_or("Kunde inte fånga stdin")?;
                     let stdout = process.stdout.take().ok_or("Kunde inte fånga stdout")?;
                     let stdout_reader = BufReader::new(stdout);
+                    let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+                    if let Some(stderr) = process.stderr.take() {
+                        Self::spawn_stderr_drain(stderr, Arc::clone(&stderr_tail));
+                    }
 
-                    let mut controller = Self { process, stdin, stdout_reader };
+                    let mut controller = Self {
+                        process,
+                        stdin,
+                        stdout_reader,
+                        info: EngineInfo::default(),
+                        stderr_tail,
+                        stop_requested: Arc::new(AtomicBool::new(false)),
+                        applied_options: Vec::new(),
+                    };
 
                     // Initiera UCI‑protokollet
                     println!("[StockfishController] Skickar 'uci' kommando...");
@@ -105,7 +787,7 @@ impl StockfishController {
                     }
                     
                     println!("[StockfishController] Väntar på 'uciok'...");
-                    if let Err(e) = controller.wait_for("uciok") {
+                    if let Err(e) = controller.read_handshake() {
                         last_error = format!("Fick inte uciok: {e}");
                         continue;
                     }
@@ -120,7 +802,11 @@ impl StockfishController {
                         continue;
                     }
 
-                    println!("[StockfishController] Stockfish redo!");
+                    // Hash och Threads måste skickas före första "go" för att sökningen ska dra nytta av dem
+                    controller.apply_startup_options();
+
+                    println!("[StockfishController] Stockfish redo! ({} av {})", controller.info.name, controller.info.author);
+                    controller.info.path = path.clone();
                     return Ok(controller);
                 }
                 Err(e) => {
@@ -130,94 +816,506 @@ impl StockfishController {
             }
         }
         
+        if let Some((path, source)) = configured {
+            return Err(format!("Kunde inte starta motorn från {source} ('{path}'): {last_error}"));
+        }
         Err(format!("Kunde inte starta Stockfish med någon sökväg. Senaste fel: {}", last_error))
     }
 
-    fn send_command(&mut self, cmd: &str) -> Result<(), String> {
-        writeln!(self.stdin, "{cmd}").map_err(|e| format!("Kunde inte skicka kommando: {e}"))
+    // Motorsökväg från "--engine <sökväg>" eller miljövariabeln STOCKFISH_PATH,
+    // tillsammans med var den kom ifrån (för felmeddelandet)
+    fn configured_path() -> Option<(String, &'static str)> {
+        if let Some(path) = cli_value("--engine") {
+            return Some((path, "--engine"));
+        }
+        std::env::var("STOCKFISH_PATH")
+            .ok()
+            .filter(|path| !path.trim().is_empty())
+            .map(|path| (path, "STOCKFISH_PATH"))
     }
 
-    fn wait_for(&mut self, expected: &str) -> Result<(), String> {
-        let mut line = String::new();
-        let start_time = std::time::Instant::now();
-        let timeout = Duration::from_secs(5); // 5 sekunder timeout
-        
-        loop {
-            line.clear();
-            
-            // Kontrollera timeout
-            if start_time.elapsed() > timeout {
-                return Err(format!("Timeout när vi väntade på '{expected}' från Stockfish"));
-            }
-            
-            match self.stdout_reader.read_line(&mut line) {
-                Ok(0) => return Err("Stockfish stängde stdout".into()),
-                Ok(_) => {
-                    if line.contains(expected) {
-                        return Ok(());
+    // Läs motorns stderr i en egen tråd så att felmeddelanden hamnar i loggen
+    fn spawn_stderr_drain(stderr: ChildStderr, tail: Arc<Mutex<VecDeque<String>>>) {
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines() {
+                let Ok(line) = line else { break };
+                eprintln!("[Stockfish stderr] {line}");
+                if let Ok(mut tail) = tail.lock() {
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
                     }
+                    tail.push_back(line);
                 }
-                Err(e) => return Err(format!("Kunde inte läsa från Stockfish: {e}")),
             }
-            
-            // Kort paus för att inte spamma CPU
-            thread::sleep(Duration::from_millis(10));
+        });
+    }
+
+    // Lägg till motorns senaste stderr-rader till ett felmeddelande
+    fn with_stderr(&self, message: String) -> String {
+        let tail = match self.stderr_tail.lock() {
+            Ok(tail) => tail.iter().cloned().collect::<Vec<_>>().join(" | "),
+            Err(_) => String::new(),
+        };
+
+        if tail.is_empty() {
+            message
+        } else {
+            format!("{message} (stderr: {tail})")
         }
     }
 
-    pub fn get_best_move(&mut self, board: &Board, depth: u8) -> Result<ChessMove, String> {
-        self.send_command(&format!("position fen {}", board))?;
-        self.send_command(&format!("go depth {depth}"))?;
+    // Sätt en UCI-option, men bara om motorn annonserat den i handskakningen
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        if !self.info.supports_option(name) {
+            return Err(format!("Motorn saknar optionen '{name}'"));
+        }
+        self.send_command(&format!("setoption name {name} value {value}"))?;
+        self.applied_options.retain(|(n, _)| n != name);
+        self.applied_options.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
 
-        let mut line = String::new();
-        loop {
-            line.clear();
-            if self.stdout_reader.read_line(&mut line).is_err() {
-                return Err("Kunde inte läsa från Stockfish".into());
-            }
-            if line.starts_with("bestmove") {
-                let toks: Vec<&str> = line.split_whitespace().collect();
-                if toks.len() >= 2 {
-                    let uci_move = toks[1];
-                    return ChessMove::from_str(uci_move)
-                        .map_err(|_| format!("Ogiltigt drag mottaget från Stockfish: {uci_move}"));
-                }
-                return Err("Ofullständigt 'bestmove'-svar".into());
-            }
+    // Hash och Threads från "--hash <MB>" / "--threads <n>", annars standardvärden.
+    // Motorer som saknar optionerna hoppas över.
+    fn apply_startup_options(&mut self) {
+        let hash_mb = cli_value("--hash").and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_HASH_MB);
+        let threads = cli_value("--threads").and_then(|v| v.parse().ok()).unwrap_or_else(default_engine_threads);
+
+        if let Err(e) = self.set_hash_mb(hash_mb) {
+            println!("[StockfishController] Hash ej satt: {e}");
+        }
+        if let Err(e) = self.set_threads(threads) {
+            println!("[StockfishController] Threads ej satt: {e}");
+        }
+        // Vänta in motorn så att hashtabellen är allokerad innan första sökningen
+        if self.send_command("isready").is_ok() {
+            let _ = self.wait_for("readyok");
         }
     }
 
-    // Ny funktion för att få evaluering
-    pub fn get_evaluation(&mut self, board: &Board, depth: u8) -> Result<f32, String> {
-        self.send_command(&format!("position fen {}", board))?;
-        self.send_command(&format!("go depth {depth}"))?;
+    pub fn set_hash_mb(&mut self, mb: u32) -> Result<(), String> {
+        self.set_option("Hash", &mb.max(1).to_string())
+    }
 
-        let mut line = String::new();
-        let mut evaluation = 0.0;
+    pub fn set_threads(&mut self, n: u32) -> Result<(), String> {
+        self.set_option("Threads", &n.max(1).to_string())
+    }
+
+    // Lever motorprocessen fortfarande?
+    pub fn is_alive(&mut self) -> bool {
+        matches!(self.process.try_wait(), Ok(None))
+    }
+
+    // Starta en ny motorprocess med samma handskakning och skicka tidigare satta optioner igen.
+    // Avbrottsflaggan behålls eftersom den delas med ThreadSafeAiController.
+    pub fn restart(&mut self) -> Result<(), String> {
+        println!("[StockfishController] Startar om motorn …");
+        let _ = self.process.kill();
+
+        let mut fresh = Self::new()?;
+        fresh.stop_requested = Arc::clone(&self.stop_requested);
+        let options = std::mem::take(&mut self.applied_options);
+        *self = fresh;
+
+        for (name, value) in &options {
+            self.set_option(name, value)?;
+        }
+        println!("[StockfishController] Motorn omstartad, {} optioner återställda", options.len());
+        Ok(())
+    }
+
+    // Sätt motorstyrkan (0–20). "isready" först så att motorn inte är mitt i något
+    // när optionen kommer; anropet sker under mutexen och väntar därför in en pågående sökning.
+    pub fn set_skill_level(&mut self, level: u8) -> Result<(), String> {
+        self.send_command("isready")?;
+        self.wait_for("readyok")?;
+        self.set_option("Skill Level", &level.min(20).to_string())
+    }
+
+    // Begränsa motorn till en viss Elo (1320–3190). Saknar motorn optionerna görs ingenting.
+    pub fn set_elo(&mut self, elo: u16) -> Result<(), String> {
+        if !self.info.supports_option("UCI_LimitStrength") || !self.info.supports_option("UCI_Elo") {
+            println!("[set_elo] Motorn saknar UCI_LimitStrength/UCI_Elo, ignorerar");
+            return Ok(());
+        }
+        self.set_option("UCI_LimitStrength", "true")?;
+        self.set_option("UCI_Elo", &elo.clamp(1320, 3190).to_string())
+    }
+
+    // Slå av Elo-begränsningen och spela med full styrka igen
+    pub fn clear_elo_limit(&mut self) -> Result<(), String> {
+        if !self.info.supports_option("UCI_LimitStrength") {
+            return Ok(());
+        }
+        self.set_option("UCI_LimitStrength", "false")
+    }
+
+    // Be motorn avbryta pågående sökning; den svarar med "bestmove" direkt
+    pub fn stop(&mut self) -> Result<(), String> {
+        self.send_command("stop")
+    }
+
+    // Flagga som en annan tråd kan sätta för att avbryta sökningen utan att låsa motorn
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stop_requested)
+    }
+
+    fn send_command(&mut self, cmd: &str) -> Result<(), String> {
+        if !self.is_alive() {
+            return Err(self.with_stderr("Stockfish har avslutats".into()));
+        }
+        writeln!(self.stdin, "{cmd}").map_err(|e| self.with_stderr(format!("Kunde inte skicka kommando: {e}")))
+    }
+
+    // Läs en rad från motorn; stängd stdout eller läsfel blir ett felmeddelande med stderr-utdrag
+    fn read_engine_line(&mut self, line: &mut String) -> Result<(), String> {
+        line.clear();
+        match self.stdout_reader.read_line(line) {
+            Ok(0) => {
+                // Ge stderr-tråden en chans att hinna läsa motorns sista rader
+                thread::sleep(Duration::from_millis(50));
+                Err(self.with_stderr("Stockfish stängde stdout".into()))
+            }
+            Ok(_) => Ok(()),
+            Err(e) => Err(self.with_stderr(format!("Kunde inte läsa från Stockfish: {e}"))),
+        }
+    }
+
+    // Läs id- och option-raderna fram till "uciok"
+    fn read_handshake(&mut self) -> Result<(), String> {
+        let mut line = String::new();
+        loop {
+            self.read_engine_line(&mut line)?;
+
+            let trimmed = line.trim();
+            if trimmed == "uciok" {
+                return Ok(());
+            } else if let Some(name) = trimmed.strip_prefix("id name ") {
+                self.info.name = name.to_string();
+            } else if let Some(author) = trimmed.strip_prefix("id author ") {
+                self.info.author = author.to_string();
+            } else if let Some(option) = EngineOption::parse(trimmed) {
+                self.info.options.push(option);
+            }
+        }
+    }
+
+    pub fn info(&self) -> &EngineInfo {
+        &self.info
+    }
+
+    fn wait_for(&mut self, expected: &str) -> Result<(), String> {
+        let mut line = String::new();
+        let start_time = std::time::Instant::now();
+        let timeout = Duration::from_secs(5); // 5 sekunder timeout
         
         loop {
-            line.clear();
-            if self.stdout_reader.read_line(&mut line).is_err() {
-                return Err("Kunde inte läsa från Stockfish".into());
+            // Kontrollera timeout
+            if start_time.elapsed() > timeout {
+                return Err(self.with_stderr(format!("Timeout när vi väntade på '{expected}' från Stockfish")));
             }
             
-            // Leta efter info-rader med score
-            if line.starts_with("info") && line.contains("score") {
-                if let Some(cp_pos) = line.find("cp ") {
-                    if let Some(end) = line[cp_pos + 3..].find(' ') {
-                        if let Ok(cp_value) = line[cp_pos + 3..cp_pos + 3 + end].parse::<i32>() {
-                            evaluation = cp_value as f32 / 100.0; // Konvertera centipawns till pawns
-                        }
+            self.read_engine_line(&mut line)?;
+            if line.contains(expected) {
+                return Ok(());
+            }
+            
+            // Kort paus för att inte spamma CPU
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // De `count` bästa varianterna med evaluering (bönder, vits perspektiv), bäst först.
+    // MultiPV återställs till 1 efteråt så att vanligt spel inte blir långsammare.
+    pub fn get_top_lines(&mut self, board: &Board, depth: u8, count: u8) -> Result<Vec<(Vec<ChessMove>, f32)>, String> {
+        // Utan lagliga drag (matt eller patt) blir listan tom, precis som med MultiPV
+        if !self.info.supports_option("MultiPV") {
+            let result = self.search(board, SearchLimit::Depth(depth))?;
+            return Ok(result.into_iter().map(|result| (result.pv, result.eval.map_or(0.0, Eval::as_pawns))).collect());
+        }
+
+        let count = count.max(1);
+        self.stop_requested.store(false, Ordering::SeqCst);
+        self.set_option("MultiPV", &count.to_string())?;
+        self.send_command(&format!("position fen {}", board))?;
+        self.send_command(&format!("go depth {depth}"))?;
+
+        let mut lines: Vec<Option<(Vec<ChessMove>, f32)>> = vec![None; count as usize];
+        let mut line = String::new();
+        let mut stopped = false;
+        let read_result = loop {
+            if let Err(e) = self.read_engine_line(&mut line) {
+                break Err(e);
+            }
+            // Avbrott hanteras som i search: motorn stoppas och dess "bestmove" slängs
+            if !stopped && self.stop_requested.swap(false, Ordering::SeqCst) {
+                if let Err(e) = self.stop() {
+                    break Err(e);
+                }
+                stopped = true;
+            }
+            if line.starts_with("bestmove") {
+                break if stopped { Err("Sökningen avbröts".to_string()) } else { Ok(()) };
+            }
+            if stopped || !line.starts_with("info") {
+                continue;
+            }
+
+            // Senaste raden för varje "multipv K" skriver över den föregående
+            let rank = line.split_whitespace().skip_while(|t| *t != "multipv").nth(1).and_then(|k| k.parse::<usize>().ok());
+            let pv = Self::parse_pv(&line);
+            let score = Self::parse_score(&line, board.side_to_move());
+            if let (Some(rank), Some(pv), Some(score)) = (rank, pv, score) {
+                if let Some(slot) = lines.get_mut(rank.wrapping_sub(1)) {
+                    *slot = Some((pv, score.as_pawns()));
+                }
+            }
+        };
+
+        self.set_option("MultiPV", "1")?;
+        read_result?;
+        Ok(lines.into_iter().flatten().collect())
+    }
+
+    // None när ställningen saknar lagliga drag (matt eller patt)
+    pub fn get_best_move(&mut self, board: &Board, depth: u8) -> Result<Option<ChessMove>, String> {
+        self.search(board, SearchLimit::Depth(depth)).map(|result| result.map(|r| r.best_move))
+    }
+
+    // Sök till givet djup eller i given tid och returnera både bästa drag och sista evalueringen.
+    // Ok(None) betyder att ställningen saknar lagliga drag; motorn svarar då "bestmove (none)".
+    pub fn search(&mut self, board: &Board, limit: SearchLimit) -> Result<Option<SearchResult>, String> {
+        self.search_with_progress(board, limit, |_| {})
+    }
+
+    // Som search, men varje info-rad med djup, noder och nps skickas till on_progress
+    pub fn search_with_progress(
+        &mut self,
+        board: &Board,
+        limit: SearchLimit,
+        mut on_progress: impl FnMut(SearchProgress),
+    ) -> Result<Option<SearchResult>, String> {
+        // En gammal begäran om avbrott gäller inte den här sökningen
+        self.stop_requested.store(false, Ordering::SeqCst);
+        self.send_command(&format!("position fen {}", board))?;
+        self.send_command(&limit.go_command())?;
+
+        let mut line = String::new();
+        let mut eval = None;
+        let mut pv = Vec::new();
+        let mut stopped = false;
+        loop {
+            self.read_engine_line(&mut line)?;
+            // Flaggan kollas mellan raderna; motorn svarar på "stop" med ett "bestmove" som slängs
+            if !stopped && self.stop_requested.swap(false, Ordering::SeqCst) {
+                self.stop()?;
+                stopped = true;
+            }
+            if stopped {
+                if line.starts_with("bestmove") {
+                    return Err("Sökningen avbröts".into());
+                }
+                continue;
+            }
+            if line.starts_with("info") {
+                if let Some(score) = Self::parse_score(&line, board.side_to_move()) {
+                    eval = Some(score);
+                }
+                if let Some(line_pv) = Self::parse_pv(&line) {
+                    pv = line_pv;
+                }
+                if let (Some(depth), Some(nodes), Some(nps)) =
+                    (Self::parse_depth(&line), Self::parse_info_number(&line, "nodes"), Self::parse_info_number(&line, "nps"))
+                {
+                    on_progress(SearchProgress { depth, nodes, nps });
+                }
+            }
+            if line.starts_with("bestmove") {
+                let toks: Vec<&str> = line.split_whitespace().collect();
+                if toks.get(1) == Some(&"(none)") {
+                    return Ok(None);
+                }
+                if toks.len() >= 2 {
+                    let uci_move = toks[1];
+                    let best_move = ChessMove::from_str(uci_move)
+                        .map_err(|_| format!("Ogiltigt drag mottaget från Stockfish: {uci_move}"))?;
+                    // Huvudvarianten ska börja med det drag som faktiskt spelas
+                    if pv.first() != Some(&best_move) {
+                        pv = vec![best_move];
                     }
+                    return Ok(Some(SearchResult { best_move, eval, pv }));
                 }
+                return Err("Ofullständigt 'bestmove'-svar".into());
             }
-            
+        }
+    }
+
+    // Analysera en ställning upp till max_depth och skicka varje ny huvudvariant till
+    // on_info. Sökningen avbryts när cancel sätts eller on_info returnerar false.
+    pub fn analyze(
+        &mut self,
+        board: &Board,
+        max_depth: u8,
+        cancel: &AtomicBool,
+        mut on_info: impl FnMut(AnalysisInfo) -> bool,
+    ) -> Result<(), String> {
+        if cancel.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.send_command(&format!("position fen {}", board))?;
+        self.send_command(&format!("go depth {max_depth}"))?;
+
+        let mut line = String::new();
+        let mut stopped = false;
+        loop {
+            self.read_engine_line(&mut line)?;
+            if line.starts_with("bestmove") {
+                return Ok(());
+            }
+            if stopped {
+                continue;
+            }
+            if cancel.load(Ordering::SeqCst) {
+                self.stop()?;
+                stopped = true;
+                continue;
+            }
+            if !line.starts_with("info") {
+                continue;
+            }
+            let (Some(depth), Some(eval), Some(pv)) =
+                (Self::parse_depth(&line), Self::parse_score(&line, board.side_to_move()), Self::parse_pv(&line))
+            else {
+                continue;
+            };
+            if !on_info(AnalysisInfo { depth, eval, pv }) {
+                self.stop()?;
+                stopped = true;
+            }
+        }
+    }
+
+    // Sökdjupet ur "depth N" i en info-rad
+    fn parse_depth(line: &str) -> Option<u8> {
+        let mut toks = line.split_whitespace().skip_while(|t| *t != "depth").skip(1);
+        toks.next()?.parse().ok()
+    }
+
+    // Talet efter ett nyckelord i en info-rad, t.ex. "nodes 123456"
+    fn parse_info_number(line: &str, key: &str) -> Option<u64> {
+        let mut toks = line.split_whitespace().skip_while(|t| *t != key).skip(1);
+        toks.next()?.parse().ok()
+    }
+
+    // Tolka "score cp N" / "score mate N" ur en info-rad. Motorn räknar från
+    // sidan vid draget, så värdet vänds till vits perspektiv. Poäng märkta
+    // lowerbound/upperbound kommer från misslyckade aspirationsfönster och
+    // är inte exakta, så de ignoreras.
+    fn parse_score(line: &str, side_to_move: ChessColor) -> Option<Eval> {
+        let mut toks = line.split_whitespace().skip_while(|t| *t != "score").skip(1);
+        let kind = toks.next()?;
+        let value = toks.next()?.parse::<i32>().ok()?;
+        if matches!(toks.next(), Some("lowerbound" | "upperbound")) {
+            return None;
+        }
+
+        let eval = match kind {
+            "cp" => {
+                let pawns = value as f32 / 100.0; // Konvertera centipawns till pawns
+                Eval::Pawns(if side_to_move == ChessColor::White { pawns } else { -pawns })
+            }
+            "mate" => {
+                // "mate 0" och negativa värden betyder att sidan vid draget blir mattad
+                let winner = if value > 0 { side_to_move } else { !side_to_move };
+                Eval::Mate { moves: value.unsigned_abs(), winner }
+            }
+            _ => return None,
+        };
+        Some(eval)
+    }
+
+    // Dragen efter "pv" i en info-rad. "pv" är sista fältet, men läsningen
+    // stannar ändå vid första token som inte är ett drag.
+    fn parse_pv(line: &str) -> Option<Vec<ChessMove>> {
+        let mut toks = line.split_whitespace().skip_while(|t| *t != "pv");
+        toks.next()?;
+        let pv: Vec<ChessMove> = toks.map_while(|t| ChessMove::from_str(t).ok()).collect();
+        (!pv.is_empty()).then_some(pv)
+    }
+
+    // Ny funktion för att få evaluering
+    pub fn get_evaluation(&mut self, board: &Board, depth: u8) -> Result<Eval, String> {
+        self.send_command(&format!("position fen {}", board))?;
+        self.send_command(&format!("go depth {depth}"))?;
+
+        let mut line = String::new();
+        let mut lines = Vec::new();
+        
+        loop {
+            self.read_engine_line(&mut line)?;
+            lines.push(line.clone());
             if line.starts_with("bestmove") {
                 break;
             }
         }
         
-        Ok(evaluation)
+        Ok(Self::final_score(lines.iter().map(String::as_str), board.side_to_move()).unwrap_or(Eval::Pawns(0.0)))
+    }
+
+    // Sista exakta evalueringen i motorns utdata fram till "bestmove"; gränsvärden hoppas över
+    fn final_score<'a>(lines: impl IntoIterator<Item = &'a str>, side_to_move: ChessColor) -> Option<Eval> {
+        let mut evaluation = None;
+        for line in lines {
+            if line.starts_with("bestmove") {
+                break;
+            }
+            if line.starts_with("info") {
+                if let Some(score) = Self::parse_score(line, side_to_move) {
+                    evaluation = Some(score);
+                }
+            }
+        }
+        evaluation
+    }
+
+    // Kör Stockfish inbyggda benchmark. Sammanfattningen skrivs på stderr i nyare
+    // versioner och på stdout i äldre, så båda kontrolleras.
+    pub fn bench(&mut self, depth: u8) -> Result<BenchResult, String> {
+        const HASH_MB: u32 = 16;
+        const THREADS: u32 = 1;
+
+        self.send_command(&format!("bench {HASH_MB} {THREADS} {depth} default depth"))?;
+        // "bench" körs synkront, så "readyok" kommer först när den är klar
+        self.send_command("isready")?;
+
+        let mut stdout_lines = Vec::new();
+        let mut line = String::new();
+        loop {
+            self.read_engine_line(&mut line)?;
+            let trimmed = line.trim();
+            if trimmed == "readyok" {
+                break;
+            }
+            if !trimmed.starts_with("info") {
+                stdout_lines.push(trimmed.to_string());
+            }
+        }
+
+        if let Some(result) = BenchResult::parse(stdout_lines.iter().map(String::as_str)) {
+            return Ok(result);
+        }
+
+        // stderr läses i en egen tråd – vänta en kort stund på att sammanfattningen hinner in
+        for _ in 0..20 {
+            if let Ok(tail) = self.stderr_tail.lock() {
+                if let Some(result) = BenchResult::parse(tail.iter().map(String::as_str)) {
+                    return Ok(result);
+                }
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        Err(self.with_stderr("Hittade ingen bench-sammanfattning från Stockfish".into()))
     }
 }
 
@@ -233,69 +1331,355 @@ impl Drop for StockfishController {
 // DEL 2: TRÅDSÄKER AI‑WRAPPER
 // =============================================================
 
+// Standardstorlek för evalueringscachen (antal ställningar)
+const EVAL_CACHE_CAPACITY: usize = 4096;
+
+// FEN+djup → evaluering (eller partianalysens sökresultat), med LRU-utrensning så att
+// minnet hålls begränsat även under långa analyssessioner
+struct EvalCache<V = Eval> {
+    capacity: usize,
+    entries: HashMap<(String, u8), (V, u64)>, // värde och senaste användning
+    recency: BTreeMap<u64, (String, u8)>,     // användningsstämpel → nyckel, äldst först
+    clock: u64,
+}
+
+impl<V: Clone> EvalCache<V> {
+    fn new(capacity: usize) -> Self {
+        Self { capacity: capacity.max(1), entries: HashMap::new(), recency: BTreeMap::new(), clock: 0 }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn touch(&mut self, key: &(String, u8)) -> Option<V> {
+        self.clock += 1;
+        let clock = self.clock;
+        let (eval, stamp) = self.entries.get_mut(key)?;
+        self.recency.remove(stamp);
+        *stamp = clock;
+        self.recency.insert(clock, key.clone());
+        Some(eval.clone())
+    }
+
+    fn get(&mut self, fen: &str, depth: u8) -> Option<V> {
+        self.touch(&(fen.to_string(), depth))
+    }
+
+    fn insert(&mut self, fen: &str, depth: u8, eval: V) {
+        let key = (fen.to_string(), depth);
+        if self.touch(&key).is_some() {
+            if let Some(entry) = self.entries.get_mut(&key) {
+                entry.0 = eval;
+            }
+            return;
+        }
+
+        // Släng den minst nyligen använda ställningen när cachen är full
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.entries.insert(key, (eval, self.clock));
+    }
+}
+
 #[derive(Clone)]
 pub struct ThreadSafeAiController {
     inner: Arc<Mutex<StockfishController>>,
+    cache: Arc<Mutex<EvalCache>>,
+    positions: Arc<Mutex<EvalCache<PositionResult>>>, // partianalysens sökresultat
+    stop_requested: Arc<AtomicBool>, // delas med motorn; mutexen hålls av sökningen
+    restarting: Arc<AtomicBool>,     // motorn håller på att startas om efter en krasch
 }
 
 impl ThreadSafeAiController {
     pub fn new() -> Result<Self, String> {
-        Ok(Self { inner: Arc::new(Mutex::new(StockfishController::new()?)) })
+        Self::with_cache_capacity(EVAL_CACHE_CAPACITY)
     }
 
-    pub fn get_best_move_async(&self, board: Board, depth: u8) -> mpsc::Receiver<ChessMove> {
-        let (tx, rx) = mpsc::channel();
+    pub fn with_cache_capacity(capacity: usize) -> Result<Self, String> {
+        let controller = StockfishController::new()?;
+        let stop_requested = controller.stop_handle();
+        Ok(Self {
+            inner: Arc::new(Mutex::new(controller)),
+            cache: Arc::new(Mutex::new(EvalCache::new(capacity))),
+            positions: Arc::new(Mutex::new(EvalCache::new(capacity))),
+            stop_requested,
+            restarting: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn is_restarting(&self) -> bool {
+        self.restarting.load(Ordering::SeqCst)
+    }
+
+    // Kör ett motoranrop. Har motorn dött startas den om en gång och anropet görs om.
+    fn with_engine<T>(&self, mut op: impl FnMut(&mut StockfishController) -> Result<T, String>) -> Result<T, String> {
+        let mut sf = self.inner.lock().map_err(|e| format!("Kunde inte låsa Stockfish‑mutex: {e}"))?;
+        match op(&mut sf) {
+            Err(e) if !sf.is_alive() => {
+                eprintln!("[AI‑tråd] Motorn har avslutats ({e}), startar om …");
+                self.restarting.store(true, Ordering::SeqCst);
+                let restarted = sf.restart();
+                self.restarting.store(false, Ordering::SeqCst);
+                restarted?;
+                op(&mut sf)
+            }
+            result => result,
+        }
+    }
+
+    // Flagga för att avbryta en pågående sökning utan att vänta på mutexen
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stop_requested)
+    }
+
+    // (antal poster, kapacitet) för diagnostik
+    pub fn cache_stats(&self) -> (usize, usize) {
+        match self.cache.lock() {
+            Ok(cache) => (cache.len(), cache.capacity()),
+            Err(_) => (0, 0),
+        }
+    }
+
+    // Evaluera en ställning, från cachen om den redan sökts på samma djup
+    pub fn evaluate(&self, board: &Board, depth: u8) -> Result<Eval, String> {
+        let fen = board.to_string();
+        if let Ok(mut cache) = self.cache.lock() {
+            if let Some(eval) = cache.get(&fen, depth) {
+                return Ok(eval);
+            }
+        }
+
+        let eval = self.with_engine(|sf| sf.get_evaluation(board, depth))?;
+
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.insert(&fen, depth, eval);
+        }
+        Ok(eval)
+    }
+
+    // Evaluering, bästa variant och näst bästa dragets evaluering ur en enda sökning. Ett
+    // drags "efter" är nästa drags "före", och en ny analys av samma parti söker inte om
+    // ställningarna, så resultaten cachas per FEN och djup. En avbruten sökning cachas inte.
+    fn analyze_position(&self, board: &Board, depth: u8, cancel: &AtomicBool) -> Result<PositionResult, String> {
+        let fen = board.to_string();
+        if let Ok(mut positions) = self.positions.lock() {
+            if let Some(position) = positions.get(&fen, depth) {
+                return Ok(position);
+            }
+        }
+
+        // Två varianter i samma sökning: den andra visar om bästa draget var det enda.
+        // Matt och patt ger inga varianter ("bestmove (none)"), bara poängen, som hämtas separat.
+        let lines = self.with_engine(|sf| sf.get_top_lines(board, depth, GAME_ANALYSIS_LINES))?;
+        let mut lines = lines.into_iter();
+        let position = match lines.next() {
+            Some((best_line, evaluation)) => {
+                PositionResult { evaluation, best_line, second_evaluation: lines.next().map(|(_, eval)| eval) }
+            }
+            None => PositionResult { evaluation: self.evaluate(board, depth)?.as_pawns(), best_line: Vec::new(), second_evaluation: None },
+        };
+
+        if !cancel.load(Ordering::SeqCst) {
+            if let Ok(mut positions) = self.positions.lock() {
+                positions.insert(&fen, depth, position.clone());
+            }
+        }
+        Ok(position)
+    }
+
+    pub fn engine_info(&self) -> EngineInfo {
+        match self.inner.lock() {
+            Ok(sf) => sf.info().clone(),
+            Err(_) => EngineInfo::default(),
+        }
+    }
+
+    pub fn bench(&self, depth: u8) -> Result<BenchResult, String> {
+        match self.inner.lock() {
+            Ok(mut sf) => sf.bench(depth),
+            Err(e) => Err(format!("Kunde inte låsa Stockfish‑mutex: {e}")),
+        }
+    }
+
+    // Sätt en option i bakgrunden så att UI:t inte väntar på en pågående sökning
+    pub fn set_option_async(&self, name: &str, value: String) {
         let controller = self.clone();
+        let name = name.to_string();
         thread::spawn(move || {
             match controller.inner.lock() {
-                Ok(mut sf) => match sf.get_best_move(&board, depth) {
-                    Ok(best) => {
-                        println!("[AI‑tråd] Bästa drag: {best}");
-                        let _ = tx.send(best);
-                    }
-                    Err(e) => eprintln!("[AI‑tråd] Fel: {e}"),
+                Ok(mut sf) => match sf.set_option(&name, &value) {
+                    Ok(()) => println!("[AI‑tråd] {name} = {value}"),
+                    Err(e) => eprintln!("[AI‑tråd] {e}"),
                 },
                 Err(e) => eprintln!("[AI‑tråd] Kunde inte låsa Stockfish‑mutex: {e}"),
             }
         });
-        rx
     }
 
-    pub fn get_evaluation_async(&self, board: Board, depth: u8) -> mpsc::Receiver<f32> {
-        let (tx, rx) = mpsc::channel();
+    pub fn set_skill_level_async(&self, level: u8) {
         let controller = self.clone();
         thread::spawn(move || {
             match controller.inner.lock() {
-                Ok(mut sf) => match sf.get_evaluation(&board, depth) {
-                    Ok(eval) => {
-                        let _ = tx.send(eval);
-                    }
-                    Err(e) => eprintln!("[AI‑evalueringstråd] Fel: {e}"),
+                Ok(mut sf) => match sf.set_skill_level(level) {
+                    Ok(()) => println!("[AI‑tråd] Skill Level = {level}"),
+                    Err(e) => eprintln!("[AI‑tråd] {e}"),
                 },
-                Err(e) => eprintln!("[AI‑evalueringstråd] Kunde inte låsa Stockfish‑mutex: {e}"),
+                Err(e) => eprintln!("[AI‑tråd] Kunde inte låsa Stockfish‑mutex: {e}"),
             }
         });
-        rx
     }
-}
 
-// =============================================================
-// DEL 3: UI‑KOMPONENTER
-// =============================================================
+    pub fn set_elo_async(&self, elo: Option<u16>) {
+        let controller = self.clone();
+        thread::spawn(move || {
+            match controller.inner.lock() {
+                Ok(mut sf) => {
+                    let result = match elo {
+                        Some(elo) => sf.set_elo(elo),
+                        None => sf.clear_elo_limit(),
+                    };
+                    match result {
+                        Ok(()) => println!("[AI‑tråd] Elo-begränsning: {elo:?}"),
+                        Err(e) => eprintln!("[AI‑tråd] {e}"),
+                    }
+                }
+                Err(e) => eprintln!("[AI‑tråd] Kunde inte låsa Stockfish‑mutex: {e}"),
+            }
+        });
+    }
 
-struct Slider {
-    x: f32,
-    y: f32,
-    width: f32,
-    height: f32,
-    min_value: f32,
-    max_value: f32,
-    current_value: f32,
-    dragging: bool,
-}
+    pub fn get_top_lines_async(&self, board: Board, depth: u8, count: u8) -> mpsc::Receiver<Vec<(Vec<ChessMove>, f32)>> {
+        let (tx, rx) = mpsc::channel();
+        let controller = self.clone();
+        thread::spawn(move || {
+            match controller.with_engine(|sf| sf.get_top_lines(&board, depth, count)) {
+                Ok(top_lines) => {
+                    println!("[AI‑tråd] {} kandidatvarianter", top_lines.len());
+                    let _ = tx.send(top_lines);
+                }
+                Err(e) => eprintln!("[AI‑tråd] Fel: {e}"),
+            }
+        });
+        rx
+    }
 
-impl Slider {
-    fn new(x: f32, y: f32, width: f32, height: f32, min_value: f32, max_value: f32, initial_value: f32) -> Self {
+    // Löpande analys i bakgrunden. Varje ny huvudvariant skickas i kanalen; sätt
+    // den returnerade flaggan (eller släpp mottagaren) för att avbryta.
+    pub fn analyze_async(&self, board: Board, max_depth: u8) -> (Arc<AtomicBool>, mpsc::Receiver<AnalysisInfo>) {
+        let (tx, rx) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let controller = self.clone();
+        let thread_cancel = Arc::clone(&cancel);
+        thread::spawn(move || {
+            let result = controller.with_engine(|sf| sf.analyze(&board, max_depth, &thread_cancel, |info| tx.send(info).is_ok()));
+            if let Err(e) = result {
+                eprintln!("[AI‑analystråd] Fel: {e}");
+            }
+        });
+        (cancel, rx)
+    }
+
+    // Kanalen får None när ställningen saknar lagliga drag. Sökningens förlopp skickas
+    // på en egen kanal med plats för en uppdatering; är den full hoppas raden över,
+    // så UI-tråden aldrig får en kö av gamla värden.
+    pub fn get_best_move_async(
+        &self,
+        board: Board,
+        limit: SearchLimit,
+    ) -> (mpsc::Receiver<Option<SearchResult>>, mpsc::Receiver<SearchProgress>) {
+        self.spawn_search(board, limit, None)
+    }
+
+    // Som get_best_move_async, men Elo-begränsningen sätts i samma lås som sökningen.
+    // Motormatchen låter på så vis varje sida spela med sin egen styrka.
+    pub fn get_best_move_at_elo_async(
+        &self,
+        board: Board,
+        limit: SearchLimit,
+        elo: Option<u16>,
+    ) -> (mpsc::Receiver<Option<SearchResult>>, mpsc::Receiver<SearchProgress>) {
+        self.spawn_search(board, limit, Some(elo))
+    }
+
+    // elo: None = lämna motorns Elo-inställning orörd, Some(None) = full styrka
+    fn spawn_search(
+        &self,
+        board: Board,
+        limit: SearchLimit,
+        elo: Option<Option<u16>>,
+    ) -> (mpsc::Receiver<Option<SearchResult>>, mpsc::Receiver<SearchProgress>) {
+        let (tx, rx) = mpsc::channel();
+        let (progress_tx, progress_rx) = mpsc::sync_channel(1);
+        let controller = self.clone();
+        thread::spawn(move || {
+            let on_progress = |progress| {
+                let _ = progress_tx.try_send(progress);
+            };
+            let search = |sf: &mut StockfishController| {
+                match elo {
+                    Some(Some(elo)) => sf.set_elo(elo)?,
+                    Some(None) => sf.clear_elo_limit()?,
+                    None => {}
+                }
+                sf.search_with_progress(&board, limit, &on_progress)
+            };
+            match controller.with_engine(search) {
+                Ok(result) => {
+                    match &result {
+                        Some(result) => println!("[AI‑tråd] Bästa drag: {}", result.best_move),
+                        None => println!("[AI‑tråd] Inga lagliga drag"),
+                    }
+                    let _ = tx.send(result);
+                }
+                Err(e) => eprintln!("[AI‑tråd] Fel: {e}"),
+            }
+        });
+        (rx, progress_rx)
+    }
+
+    pub fn get_evaluation_async(&self, board: Board, depth: u8) -> mpsc::Receiver<Eval> {
+        let (tx, rx) = mpsc::channel();
+        let controller = self.clone();
+        thread::spawn(move || {
+            match controller.evaluate(&board, depth) {
+                Ok(eval) => {
+                    let _ = tx.send(eval);
+                }
+                Err(e) => eprintln!("[AI‑evalueringstråd] Fel: {e}"),
+            }
+        });
+        rx
+    }
+}
+
+// =============================================================
+// DEL 3: UI‑KOMPONENTER
+// =============================================================
+
+struct Slider {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    min_value: f32,
+    max_value: f32,
+    current_value: f32,
+    dragging: bool,
+}
+
+impl Slider {
+    fn new(x: f32, y: f32, width: f32, height: f32, min_value: f32, max_value: f32, initial_value: f32) -> Self {
         Self {
             x,
             y,
@@ -348,6 +1732,67 @@ impl Slider {
     fn get_value(&self) -> u8 {
         self.current_value.round() as u8
     }
+
+    fn set_value(&mut self, value: f32) {
+        self.current_value = value.clamp(self.min_value, self.max_value);
+    }
+
+    fn shift_x(&mut self, dx: f32) {
+        self.x += dx;
+    }
+}
+
+// Kortvarigt meddelande som tonar ut när tiden går ut
+struct Toast {
+    message: String,
+    expires: f64,
+}
+
+// Kö av toast-meddelanden som ritas staplade ovanpå brädet
+struct Toasts {
+    queue: VecDeque<Toast>,
+}
+
+impl Toasts {
+    const DURATION: f64 = 3.0;
+    const FADE: f64 = 0.5;
+    const MAX_VISIBLE: usize = 4;
+
+    fn new() -> Self {
+        Self { queue: VecDeque::new() }
+    }
+
+    fn push(&mut self, message: String, duration: f64) {
+        println!("[toast] {}", message);
+        self.queue.push_back(Toast { message, expires: get_time() + duration });
+        while self.queue.len() > Self::MAX_VISIBLE {
+            self.queue.pop_front();
+        }
+    }
+
+    // Ta bort ett meddelande i förtid, t.ex. en laddningsindikator
+    fn dismiss(&mut self, message: &str) {
+        self.queue.retain(|t| t.message != message);
+    }
+
+    fn update(&mut self) {
+        let now = get_time();
+        self.queue.retain(|t| t.expires > now);
+    }
+
+    // Staplas nedåt strax ovanför given mittpunkt (brädets mitt)
+    fn draw(&self, (center_x, center_y): (f32, f32)) {
+        let now = get_time();
+        let mut y = center_y - 35.0;
+        for toast in &self.queue {
+            let alpha = ((toast.expires - now) / Self::FADE).clamp(0.0, 1.0) as f32;
+            let text_width = measure_text(&toast.message, None, 18, 1.0).width;
+            let x = center_x - text_width / 2.0;
+            draw_rectangle(x - 12.0, y, text_width + 24.0, 40.0, Color::new(0.0, 0.0, 0.0, 0.75 * alpha));
+            draw_text(&toast.message, x, y + 26.0, 18.0, Color::new(1.0, 1.0, 1.0, alpha));
+            y += 46.0;
+        }
+    }
 }
 
 struct Button {
@@ -368,6 +1813,10 @@ impl Button {
         }
     }
 
+    fn shift_x(&mut self, dx: f32) {
+        self.x += dx;
+    }
+
     fn is_clicked(&self) -> bool {
         if !self.active {
             return false;
@@ -390,12 +1839,17 @@ impl Button {
         draw_rectangle(self.x, self.y, self.width, self.height, bg_color);
         draw_rectangle_lines(self.x, self.y, self.width, self.height, 2.0, DARKGRAY);
         
-        // Centrera texten
-        let text_width = measure_text(&self.text, None, 16, 1.0).width;
+        // Centrera texten; en text som är för bred för knappen krymps tills den ryms
+        let mut font_size = 16;
+        let mut text_width = measure_text(&self.text, None, font_size, 1.0).width;
+        while text_width > self.width - 6.0 && font_size > 10 {
+            font_size -= 1;
+            text_width = measure_text(&self.text, None, font_size, 1.0).width;
+        }
         let text_x = self.x + (self.width - text_width) / 2.0;
         let text_y = self.y + self.height / 2.0 + 6.0;
         
-        draw_text(&self.text, text_x, text_y, 16.0, text_color);
+        draw_text(&self.text, text_x, text_y, font_size as f32, text_color);
     }
 
     fn set_active(&mut self, active: bool) {
@@ -403,258 +1857,4117 @@ impl Button {
     }
 }
 
+// Första synliga raden i panelens draglista med `rows` rader, när listan scrollats
+// `offset` rader bakåt från slutet. Långa partier klipps alltid till en hel sida.
+fn move_list_window_start(rows: usize, offset: usize) -> usize {
+    let max_offset = rows.saturating_sub(PANEL_MOVE_LIST_ROWS);
+    max_offset - offset.min(max_offset)
+}
+
+// Analysfönstrets första synliga drag efter att ha scrollat `rows` rader bakåt (positivt)
+// eller framåt (negativt) i en lista med `len` drag
+fn analysis_scroll_after(scroll: usize, rows: i32, len: usize) -> usize {
+    let max_scroll = len.saturating_sub(1) as i64;
+    (scroll as i64 - rows as i64).clamp(0, max_scroll) as usize
+}
+
+// Antal påbörjade helddrag för `plies` halvdrag; börjar svart räknas ett tomt vitt halvdrag in
+fn full_move_count(plies: usize, black_first: bool) -> usize {
+    (plies + black_first as usize).div_ceil(2)
+}
+
+// Tecken som skrivits sedan förra bildrutan, i den ordning de skrevs. Macroquads kö
+// är en stack, så get_char_pressed ger dem baklänges.
+fn take_typed_chars() -> Vec<char> {
+    let mut chars: Vec<char> = std::iter::from_fn(get_char_pressed).collect();
+    chars.reverse();
+    chars
+}
+
+// Dela en text i rader med högst max_chars tecken, brutna vid mellanslag
+fn wrap_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.chars().count() + 1 + word.chars().count() <= max_chars => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    lines
+}
+
 // =============================================================
-// DEL 4: SPELLOGIK & DATASTRUKTURER
+// DEL 3B: IMPORT AV FEN OCH PGN
 // =============================================================
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-struct PieceKey {
-    piece: Piece,
-    color: ChessColor,
+// Ett inläst parti: startställning, dess räknare enligt FEN och de drag som spelades
+struct ImportedGame {
+    start: Board,
+    start_halfmove: usize,
+    start_fullmove: usize,
+    moves: Vec<ChessMove>,
 }
 
-struct GameSettings {
-    player_color: ChessColor,
-    board_flipped: bool,
+impl ImportedGame {
+    // Ett nytt parti från en ställning utan egna räknare
+    fn from_position(start: Board) -> Self {
+        Self { start, start_halfmove: 0, start_fullmove: 1, moves: Vec::new() }
+    }
 }
 
-#[derive(Debug)]
-enum AiState {
-    Idle,
-    Thinking(mpsc::Receiver<ChessMove>),
+// Läs en FEN-sträng och ge ett tydligt fel om den är felaktig
+fn parse_fen(fen: &str) -> Result<Board, String> {
+    Board::from_str(fen.trim()).map_err(|_| format!("Ogiltig FEN: '{}'", fen.trim()))
 }
 
-struct ChessGame {
-    board: Board,
-    selected_square: Option<Square>,
-    highlighted_moves: Vec<ChessMove>,
-    settings: GameSettings,
-    game_over: bool,
-    ai_state: AiState,
-    textures: HashMap<PieceKey, Texture2D>,
-    move_history: Vec<String>,
-    current_analysis: Option<String>,
-    
-    // Nya fält för partianalys
-    game_analysis: Option<GameAnalysis>,
-    analysis_in_progress: bool,
-    analysis_receiver: Option<mpsc::Receiver<GameAnalysis>>,
-    
-    // Nya fält för positionsvisning
-    review_mode: bool,
-    review_board: Option<Board>,
-    review_move_index: Option<usize>,
-    original_board: Option<Board>, // För att spara ursprungligt bräde
-    
-    // UI-komponenter
-    depth_slider: Slider,
-    resign_button: Button,
-    export_button: Button,
-    flip_button: Button,
-    white_button: Button,
-    black_button: Button,
-    new_game_button: Button,
-    analyze_button: Button,
+// FEN:ens två sista fält: halvdrag sedan slag eller bondedrag och dragnumret.
+// Brädet i chess-biblioteket sparar dem inte, så de läses här (saknas de gäller 0 och 1).
+fn fen_counters(fen: &str) -> (usize, usize) {
+    let fields: Vec<&str> = fen.split_whitespace().collect();
+    let halfmove = fields.get(4).and_then(|f| f.parse().ok()).unwrap_or(0);
+    let fullmove = fields.get(5).and_then(|f| f.parse().ok()).filter(|&n| n > 0).unwrap_or(1);
+    (halfmove, fullmove)
 }
 
-impl ChessGame {
-    fn new(textures: HashMap<PieceKey, Texture2D>) -> Self {
-        const PANEL_X: f32 = 780.0;
-        
+// Brädets FEN med givna räknare i stället för bibliotekets "0 1"
+fn fen_with_counters(board: &Board, halfmove: usize, fullmove: usize) -> String {
+    let fen = board.to_string();
+    let position: Vec<&str> = fen.split_whitespace().take(4).collect();
+    format!("{} {} {}", position.join(" "), halfmove, fullmove)
+}
+
+// Taktikuppgifter läses från en textfil med en uppgift per rad: "FEN; lösning", där
+// lösningen är dragen i SAN eller UCI. Sidan vid draget i ställningen är spelaren och
+// gör vartannat drag; dragen däremellan är motståndarens svar. Ett svar skrivet som "?"
+// väljs av motorn.
+const PUZZLE_FILE: &str = "puzzles.txt";
+
+#[derive(Debug, Clone)]
+struct Puzzle {
+    start: Board,
+    // Dragen som text; None är ett svar som motorn väljer. Efter ett sådant svar är
+    // ställningen okänd, så dragen tolkas först när de ska spelas.
+    solution: Vec<Option<String>>,
+}
+
+fn parse_puzzles(text: &str) -> Result<Vec<Puzzle>, String> {
+    let mut puzzles = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let row = index + 1;
+        let (fen, moves) = line
+            .split_once(';')
+            .ok_or_else(|| format!("Rad {}: ';' saknas mellan FEN och lösning", row))?;
+        let start = parse_fen(fen).map_err(|e| format!("Rad {}: {}", row, e))?;
+
+        // Ställningen följs så länge dragen är kända, så att de kan kontrolleras direkt
+        let mut board = Some(start);
+        let mut solution = Vec::new();
+        // Dragnummer som "1." eller "12..." hoppas över
+        for token in moves.split_whitespace().filter(|t| !t.chars().all(|c| c.is_ascii_digit() || c == '.')) {
+            if token == "?" {
+                if solution.len() % 2 == 0 {
+                    return Err(format!("Rad {}: '?' kan bara stå för motståndarens svar", row));
+                }
+                board = None;
+                solution.push(None);
+                continue;
+            }
+            if let Some(ref mut known) = board {
+                let chess_move = parse_san(known, token)
+                    .ok_or_else(|| format!("Rad {}: ogiltigt drag '{}'", row, token))?;
+                *known = known.make_move_new(chess_move);
+            }
+            solution.push(Some(token.to_string()));
+        }
+        if solution.is_empty() {
+            return Err(format!("Rad {}: lösningen saknas", row));
+        }
+        puzzles.push(Puzzle { start, solution });
+    }
+    if puzzles.is_empty() {
+        return Err("Filen innehåller inga uppgifter".to_string());
+    }
+    Ok(puzzles)
+}
+
+// En serie taktikuppgifter som löses i tur och ordning
+#[derive(Debug, Clone)]
+struct PuzzleSession {
+    puzzles: Vec<Puzzle>,
+    index: usize,            // uppgiften som löses
+    color: ChessColor,       // sidan som löser uppgiften; spelarens färg lämnas orörd
+    ply: usize,              // antal spelade drag av lösningen
+    engine_reply: bool,      // motorn ska välja motståndarens svar
+    mistake: bool,           // ett fel drag har gjorts i uppgiften
+    solved: u32,             // uppgifter lösta utan fel drag
+    attempted: u32,          // avslutade uppgifter
+    pending_at: Option<f64>, // när motståndarens svar eller nästa uppgift kommer
+}
+
+impl PuzzleSession {
+    fn new(puzzles: Vec<Puzzle>) -> Self {
         Self {
-            board: Board::default(),
-            selected_square: None,
-            highlighted_moves: Vec::new(),
-            settings: GameSettings { 
-                player_color: ChessColor::White,
-                board_flipped: false,
-            },
-            game_over: false,
-            ai_state: AiState::Idle,
-            textures,
-            move_history: Vec::new(),
-            current_analysis: None,
-            game_analysis: None,
-            analysis_in_progress: false,
-            analysis_receiver: None,
-            review_mode: false,
-            review_board: None,
-            review_move_index: None,
-            original_board: None,
-            depth_slider: Slider::new(PANEL_X, 120.0, 150.0, 20.0, 1.0, 30.0, 10.0),
-            resign_button: Button::new(PANEL_X, 160.0, 70.0, 30.0, "Ge upp"),
-            export_button: Button::new(PANEL_X + 75.0, 160.0, 70.0, 30.0, "Export"),
-            flip_button: Button::new(PANEL_X, 200.0, 145.0, 30.0, "Rotera bräde"),
-            white_button: Button::new(PANEL_X, 240.0, 70.0, 30.0, "Vit"),
-            black_button: Button::new(PANEL_X + 75.0, 240.0, 70.0, 30.0, "Svart"),
-            new_game_button: Button::new(PANEL_X, 280.0, 145.0, 30.0, "Nytt spel"),
-            analyze_button: Button::new(PANEL_X, 320.0, 145.0, 30.0, "Analysera parti"),
+            puzzles,
+            index: 0,
+            color: ChessColor::White,
+            ply: 0,
+            engine_reply: false,
+            mistake: false,
+            solved: 0,
+            attempted: 0,
+            pending_at: None,
         }
     }
 
-    fn make_move(&mut self, m: ChessMove) {
-        println!("[make_move] Utför drag: {m}");
-        
-        // Lägg till i draghistorik
-        let move_str = self.format_move(m);
-        self.move_history.push(move_str);
-        
-        self.board = self.board.make_move_new(m);
-        self.selected_square = None;
-        self.highlighted_moves.clear();
-        self.update_game_state();
-        self.ai_state = AiState::Idle;
+    fn current(&self) -> &Puzzle {
+        &self.puzzles[self.index]
     }
 
-    fn format_move(&self, chess_move: ChessMove) -> String {
-        // Enkel algebraisk notation
-        let from = chess_move.get_source();
-        let to = chess_move.get_dest();
-        
-        let from_str = format!("{}{}", 
-            char::from(b'a' + from.get_file().to_index() as u8),
-            from.get_rank().to_index() + 1
-        );
-        let to_str = format!("{}{}", 
-            char::from(b'a' + to.get_file().to_index() as u8),
-            to.get_rank().to_index() + 1
-        );
-        
-        format!("{}-{}", from_str, to_str)
+    // Lösningens nästa drag i ställningen, om det är känt och går att spela där
+    fn expected(&self, board: &Board) -> Option<ChessMove> {
+        let token = self.current().solution.get(self.ply)?.as_deref()?;
+        parse_san(board, token)
     }
 
-    fn reset_game(&mut self) {
-        self.board = Board::default();
-        self.selected_square = None;
-        self.highlighted_moves.clear();
-        self.game_over = false;
-        self.ai_state = AiState::Idle;
-        self.move_history.clear();
-        self.current_analysis = None;
-        self.game_analysis = None;
-        self.analysis_in_progress = false;
-        self.analysis_receiver = None;
-        self.review_mode = false;
-        self.review_board = None;
-        self.review_move_index = None;
-        self.original_board = None;
+    fn finished(&self) -> bool {
+        self.ply >= self.current().solution.len()
     }
+}
 
-    fn resign(&mut self) {
-        self.game_over = true;
-        let winner = if self.settings.player_color == ChessColor::White { "Svart" } else { "Vit" };
-        self.move_history.push(format!("{} vann genom uppgivning", winner));
+// Ställningsredigeraren: pjäserna placeras fritt och ställningen kontrolleras först när
+// spelet ska starta, så att den får vara ofullständig under tiden
+#[derive(Debug, Clone)]
+struct BoardEditor {
+    pieces: [Option<PieceKey>; 64],
+    side_to_move: ChessColor,
+    castling: [bool; 4],    // vit O-O, vit O-O-O, svart O-O, svart O-O-O
+    tool: Option<PieceKey>, // pjäsen som placeras; None är suddgummit
+    error: Option<String>,  // varför ställningen inte kunde startas
+}
+
+impl BoardEditor {
+    // Kungens och tornets utgångsrutor för varje rockad, i samma ordning som castling
+    const CASTLING_SQUARES: [(ChessColor, Square, Square); 4] = [
+        (ChessColor::White, Square::E1, Square::H1),
+        (ChessColor::White, Square::E1, Square::A1),
+        (ChessColor::Black, Square::E8, Square::H8),
+        (ChessColor::Black, Square::E8, Square::A8),
+    ];
+
+    fn from_board(board: &Board) -> Self {
+        let mut pieces = [None; 64];
+        for square in chess::ALL_SQUARES {
+            if let (Some(piece), Some(color)) = (board.piece_on(square), board.color_on(square)) {
+                pieces[square.to_index()] = Some(PieceKey { piece, color });
+            }
+        }
+        let mut castling = [false; 4];
+        for (i, (color, _, rook)) in Self::CASTLING_SQUARES.iter().enumerate() {
+            let rights = board.castle_rights(*color);
+            castling[i] = if rook.get_file() == chess::File::H { rights.has_kingside() } else { rights.has_queenside() };
+        }
+        Self {
+            pieces,
+            side_to_move: board.side_to_move(),
+            castling,
+            tool: Some(PieceKey { piece: Piece::Pawn, color: ChessColor::White }),
+            error: None,
+        }
     }
 
-    fn export_pgn(&self) {
-        let mut pgn = String::new();
-        pgn.push_str("[Event \"Schackspel\"]\n");
-        pgn.push_str("[Site \"Lokal dator\"]\n");
-        
-        // Aktuellt datum (enkel formatering)
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        
-        // Enkel datumformatering utan external crate
-        let days_since_epoch = now / (24 * 60 * 60);
-        let year = 1970 + (days_since_epoch / 365);
-        let day_of_year = days_since_epoch % 365;
-        let month = (day_of_year / 30) + 1;
-        let day = (day_of_year % 30) + 1;
-        
-        let date = format!("{:04}.{:02}.{:02}", year, month, day);
-        pgn.push_str(&format!("[Date \"{}\"]\n", date));
-        
-        pgn.push_str("[Round \"1\"]\n");
-        pgn.push_str("[White \"Spelare\"]\n");
-        pgn.push_str("[Black \"AI\"]\n");
-        
-        // Spelresultat
-        let result = if self.game_over {
-            match self.board.status() {
-                BoardStatus::Checkmate => {
-                    if self.board.side_to_move() == ChessColor::White { "0-1" } else { "1-0" }
-                }
-                BoardStatus::Stalemate => "1/2-1/2",
-                _ => {
-                    if self.move_history.iter().any(|m| m.contains("uppgivning")) {
-                        if self.settings.player_color == ChessColor::White { "0-1" } else { "1-0" }
-                    } else {
-                        "*"
+    fn piece_on(&self, square: Square) -> Option<PieceKey> {
+        self.pieces[square.to_index()]
+    }
+
+    // Placera verktygets pjäs; samma pjäs en gång till, eller suddgummit, tömmer rutan
+    fn apply_tool(&mut self, square: Square) {
+        let slot = &mut self.pieces[square.to_index()];
+        *slot = if *slot == self.tool { None } else { self.tool };
+        self.error = None;
+    }
+
+    fn clear(&mut self) {
+        self.pieces = [None; 64];
+        self.castling = [false; 4];
+        self.error = None;
+    }
+
+    // Rockaden kan bara gälla när kung och torn står kvar på sina utgångsrutor
+    fn castling_possible(&self, index: usize) -> bool {
+        let (color, king, rook) = Self::CASTLING_SQUARES[index];
+        self.piece_on(king) == Some(PieceKey { piece: Piece::King, color })
+            && self.piece_on(rook) == Some(PieceKey { piece: Piece::Rook, color })
+    }
+
+    fn to_fen(&self) -> String {
+        let mut placement = String::new();
+        for rank in (0..8).rev() {
+            let mut empty = 0;
+            for file in 0..8 {
+                let square = Square::make_square(chess::Rank::from_index(rank), chess::File::from_index(file));
+                match self.piece_on(square) {
+                    Some(key) => {
+                        if empty > 0 {
+                            placement.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        placement.push_str(&key.piece.to_string(key.color));
                     }
+                    None => empty += 1,
                 }
             }
-        } else {
-            "*"
-        };
-        pgn.push_str(&format!("[Result \"{}\"]\n\n", result));
-        
-        // Dragsekvens
-        for (i, mv) in self.move_history.iter().enumerate() {
-            if mv.contains("uppgivning") {
-                pgn.push_str(&format!(" {} {}", mv, result));
-                break;
-            }
-            
-            if i % 2 == 0 {
-                pgn.push_str(&format!("{}. {}", i / 2 + 1, mv));
-            } else {
-                pgn.push_str(&format!(" {} ", mv));
-                if i % 4 == 3 {
-                    pgn.push('\n');
-                }
-            }
-        }
-        
-        if !pgn.ends_with(&result) {
-            pgn.push_str(&format!(" {}\n", result));
-        }
-        
-        // Spara till fil
-        match std::fs::write("schack_parti.pgn", &pgn) {
-            Ok(_) => {
-                println!("✓ PGN exporterat till 'schack_parti.pgn'");
-                println!("PGN innehåll:\n{}", pgn);
+            if empty > 0 {
+                placement.push_str(&empty.to_string());
             }
-            Err(e) => {
-                eprintln!("⚠ Kunde inte spara PGN-fil: {}", e);
-                println!("PGN innehåll:\n{}", pgn);
+            if rank > 0 {
+                placement.push('/');
             }
         }
+
+        let castling: String = ["K", "Q", "k", "q"]
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| self.castling[i] && self.castling_possible(i))
+            .map(|(_, flag)| *flag)
+            .collect();
+        let side = if self.side_to_move == ChessColor::White { "w" } else { "b" };
+        format!("{} {} {} - 0 1", placement, side, if castling.is_empty() { "-" } else { &castling })
     }
 
-    // Gå till en specifik position i partiet
-    fn show_position_at_move(&mut self, move_index: usize) {
-        if move_index >= self.move_history.len() {
+    // Kontrollera ställningen och bygg brädet som spelet startar från
+    fn validate(&self) -> Result<Board, String> {
+        let name = |color: ChessColor| if color == ChessColor::White { "Vit" } else { "Svart" };
+        for color in [ChessColor::White, ChessColor::Black] {
+            let kings = self.pieces.iter().filter(|&&key| key == Some(PieceKey { piece: Piece::King, color })).count();
+            if kings != 1 {
+                return Err(format!("{} måste ha exakt en kung (har {})", name(color), kings));
+            }
+        }
+        let back_rank_pawn = chess::ALL_SQUARES.iter().any(|&square| {
+            let rank = square.get_rank();
+            (rank == chess::Rank::First || rank == chess::Rank::Eighth)
+                && self.piece_on(square).is_some_and(|key| key.piece == Piece::Pawn)
+        });
+        if back_rank_pawn {
+            return Err("Bönder får inte stå på första eller sista raden".to_string());
+        }
+
+        let fen = self.to_fen();
+        if let Ok(board) = Board::from_str(&fen) {
+            return Ok(board);
+        }
+        // Med ombytt drag syns om det var sidan som inte är vid draget som stod i schack
+        let waiting = !self.side_to_move;
+        let swapped = Self { side_to_move: waiting, ..self.clone() };
+        match Board::from_str(&swapped.to_fen()) {
+            Ok(board) if board.checkers().popcnt() > 0 => {
+                Err(format!("{} står i schack men {} är vid draget", name(waiting), name(self.side_to_move).to_lowercase()))
+            }
+            _ => Err(format!("Ogiltig ställning: '{}'", fen)),
+        }
+    }
+}
+
+// Ingen sida kan sätta matt: bara kungar och högst en lätt pjäs, eller bara löpare
+// som alla står på samma färg (t.ex. K+L mot K+L med likfärgade löpare)
+fn insufficient_material(board: &Board) -> bool {
+    let heavy = *board.pieces(Piece::Pawn) | *board.pieces(Piece::Rook) | *board.pieces(Piece::Queen);
+    if heavy.popcnt() > 0 {
+        return false;
+    }
+
+    let knights = board.pieces(Piece::Knight).popcnt();
+    let bishops = *board.pieces(Piece::Bishop);
+    if knights + bishops.popcnt() <= 1 {
+        return true;
+    }
+    if knights > 0 {
+        return false;
+    }
+
+    let is_light = |square: Square| (square.get_file().to_index() + square.get_rank().to_index()) % 2 == 1;
+    let light = bishops.filter(|&square| is_light(square)).count();
+    light == 0 || light == bishops.popcnt() as usize
+}
+
+// Slår draget en pjäs? En passant slår en bonde som inte står på målrutan.
+fn is_capture(board: &Board, m: ChessMove) -> bool {
+    board.color_on(m.get_dest()) == Some(!board.side_to_move())
+        || (board.piece_on(m.get_source()) == Some(Piece::Pawn) && m.get_source().get_file() != m.get_dest().get_file())
+}
+
+// Det lagliga draget mellan två rutor, hämtat ur draggeneratorn så att rockad och en passant
+// blir exakt samma ChessMove som motorn och draghistoriken använder. Kung till ett eget torn
+// på samma rad betyder rockad åt det hållet, som vid klick på tornet och i Polyglot-böcker.
+fn legal_move_between(board: &Board, source: Square, dest: Square, promotion: Option<Piece>) -> Option<ChessMove> {
+    let side = board.side_to_move();
+    let dest = if board.piece_on(source) == Some(Piece::King)
+        && board.piece_on(dest) == Some(Piece::Rook)
+        && board.color_on(dest) == Some(side)
+        && source.get_rank() == dest.get_rank()
+    {
+        let file = if dest.get_file().to_index() > source.get_file().to_index() { chess::File::G } else { chess::File::C };
+        Square::make_square(dest.get_rank(), file)
+    } else {
+        dest
+    };
+    MoveGen::new_legal(board).find(|m| m.get_source() == source && m.get_dest() == dest && m.get_promotion() == promotion)
+}
+
+// Kan sidan över huvud taget sätta matt? Ensam kung eller kung och en lätt pjäs räcker inte,
+// så den som överskrider tiden mot sådant material får remi.
+fn has_mating_material(board: &Board, color: ChessColor) -> bool {
+    let own = *board.color_combined(color);
+    let heavy = *board.pieces(Piece::Pawn) | *board.pieces(Piece::Rook) | *board.pieces(Piece::Queen);
+    let minors = (*board.pieces(Piece::Knight) | *board.pieces(Piece::Bishop)) & own;
+    (heavy & own).popcnt() > 0 || minors.popcnt() >= 2
+}
+
+// Tolka ett drag i SAN ("Nf3", "exd5", "O-O", "e8=Q+") eller från-till-form ("e2-e4", "e2e4")
+fn parse_san(board: &Board, token: &str) -> Option<ChessMove> {
+    let san = token.trim_end_matches(['+', '#', '!', '?']);
+    let legal: Vec<ChessMove> = MoveGen::new_legal(board).collect();
+
+    // Rockad känns igen på att kungen flyttar två linjer
+    let castle_file = match san {
+        "O-O" | "0-0" => Some(chess::File::G),
+        "O-O-O" | "0-0-0" => Some(chess::File::C),
+        _ => None,
+    };
+    if let Some(file) = castle_file {
+        return legal.into_iter().find(|m| {
+            board.piece_on(m.get_source()) == Some(Piece::King)
+                && m.get_source().get_file() == chess::File::E
+                && m.get_dest().get_file() == file
+        });
+    }
+
+    // Egen från-till-notation och UCI. Börjar draget med versal är det SAN med pjäsbokstav
+    // ("B5c3") och får inte tolkas som från-till efter omvandling till gemener.
+    if san.starts_with(|c: char| c.is_ascii_lowercase()) {
+        let uci = san.replace('-', "").to_lowercase();
+        if let Some(m) = ChessMove::from_str(&uci).ok().and_then(|m| legal_move_between(board, m.get_source(), m.get_dest(), m.get_promotion())) {
+            return Some(m);
+        }
+    }
+
+    let (body, promotion) = match san.split_once('=') {
+        Some((body, piece)) => (body, Some(piece)),
+        None => (san, None),
+    };
+    let promotion = match promotion.and_then(|p| p.chars().next()) {
+        Some('Q') => Some(Piece::Queen),
+        Some('R') => Some(Piece::Rook),
+        Some('B') => Some(Piece::Bishop),
+        Some('N') => Some(Piece::Knight),
+        Some(_) => return None,
+        None => None,
+    };
+
+    let (piece, rest) = match body.chars().next()? {
+        'K' => (Piece::King, &body[1..]),
+        'Q' => (Piece::Queen, &body[1..]),
+        'R' => (Piece::Rook, &body[1..]),
+        'B' => (Piece::Bishop, &body[1..]),
+        'N' => (Piece::Knight, &body[1..]),
+        _ => (Piece::Pawn, body),
+    };
+    let rest = rest.replace('x', "");
+    if rest.len() < 2 || !rest.is_ascii() {
+        return None;
+    }
+    let dest = Square::from_str(&rest[rest.len() - 2..]).ok()?;
+    let disambiguation = &rest[..rest.len() - 2];
+
+    let mut candidates = legal.into_iter().filter(|m| {
+        let source = m.get_source();
+        board.piece_on(source) == Some(piece)
+            && m.get_dest() == dest
+            && m.get_promotion() == promotion
+            && disambiguation.chars().all(|c| match c {
+                'a'..='h' => source.get_file().to_index() == (c as u8 - b'a') as usize,
+                '1'..='8' => source.get_rank().to_index() == (c as u8 - b'1') as usize,
+                _ => false,
+            })
+    });
+
+    let found = candidates.next()?;
+    if candidates.next().is_some() {
+        return None; // Tvetydigt drag
+    }
+    Some(found)
+}
+
+// Skriv ett lagligt drag i SAN ("Nf3", "exd5", "O-O", "e8=Q+")
+fn move_to_san(board: &Board, m: ChessMove) -> String {
+    let source = m.get_source();
+    let dest = m.get_dest();
+    let piece = board.piece_on(source).unwrap_or(Piece::Pawn);
+
+    let mut san = if piece == Piece::King && source.get_file().to_index().abs_diff(dest.get_file().to_index()) == 2 {
+        if dest.get_file() == chess::File::G { "O-O" } else { "O-O-O" }.to_string()
+    } else {
+        let capture = is_capture(board, m);
+        let file_char = |sq: Square| char::from(b'a' + sq.get_file().to_index() as u8);
+        let mut text = String::new();
+
+        if piece == Piece::Pawn {
+            if capture {
+                text.push(file_char(source));
+            }
+        } else {
+            text.push(match piece {
+                Piece::King => 'K',
+                Piece::Queen => 'Q',
+                Piece::Rook => 'R',
+                Piece::Bishop => 'B',
+                _ => 'N',
+            });
+
+            // Ange linje eller rad om en annan likadan pjäs också kan gå till rutan
+            let rivals: Vec<Square> = MoveGen::new_legal(board)
+                .filter(|o| o.get_dest() == dest && o.get_source() != source && board.piece_on(o.get_source()) == Some(piece))
+                .map(|o| o.get_source())
+                .collect();
+            if !rivals.is_empty() {
+                if rivals.iter().all(|r| r.get_file() != source.get_file()) {
+                    text.push(file_char(source));
+                } else if rivals.iter().all(|r| r.get_rank() != source.get_rank()) {
+                    text.push(char::from(b'1' + source.get_rank().to_index() as u8));
+                } else {
+                    text.push_str(&source.to_string());
+                }
+            }
+        }
+
+        if capture {
+            text.push('x');
+        }
+        text.push_str(&dest.to_string());
+
+        if let Some(promotion) = m.get_promotion() {
+            text.push_str(match promotion {
+                Piece::Rook => "=R",
+                Piece::Bishop => "=B",
+                Piece::Knight => "=N",
+                _ => "=Q",
+            });
+        }
+        text
+    };
+
+    let after = board.make_move_new(m);
+    if after.status() == BoardStatus::Checkmate {
+        san.push('#');
+    } else if after.checkers().popcnt() > 0 {
+        san.push('+');
+    }
+    san
+}
+
+// De första `max_plies` dragen i en variant som SAN; stannar vid första olagliga drag
+fn san_line(board: &Board, moves: &[ChessMove], max_plies: usize) -> String {
+    let mut position = *board;
+    let mut parts = Vec::new();
+    for &m in moves.iter().take(max_plies) {
+        if !position.legal(m) {
+            break;
+        }
+        parts.push(move_to_san(&position, m));
+        position = position.make_move_new(m);
+    }
+    if moves.len() > parts.len() && !parts.is_empty() {
+        parts.push("…".to_string());
+    }
+    parts.join(" ")
+}
+
+// Kalenderdatum (år, månad, dag) för ett antal dagar efter 1970-01-01, med skottår.
+// Räknar i 400-årsperioder (146 097 dagar) med år som börjar 1 mars, så att skottdagen
+// hamnar sist i året (Howard Hinnants civil_from_days).
+fn civil_date(days_since_epoch: i64) -> (i64, u32, u32) {
+    let z = days_since_epoch + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153; // 0 = mars
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+// En rad i PGN-huvudet, t.ex. [White "Daniel"]. Citattecken och bakstreck i värdet skyddas.
+fn pgn_tag(name: &str, value: &str) -> String {
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("[{} \"{}\"]\n", name, escaped)
+}
+
+// PGN-dragtext: "1. e4 e5 2. Nf3 …" med resultatet sist, radbruten vid 80 tecken.
+// Numreringen börjar på startställningens dragnummer; börjar svart skrivs första
+// draget som "23... e5".
+fn pgn_movetext(moves: &[String], black_first: bool, first_move_number: usize, closing_note: Option<&str>, result: &str) -> String {
+    const LINE_WIDTH: usize = 80;
+
+    // Dragnumret hålls ihop med vits drag så att radbrytningen inte skiljer dem åt
+    let mut tokens = Vec::new();
+    for (i, mv) in moves.iter().enumerate() {
+        let ply = i + black_first as usize;
+        let number = first_move_number + ply / 2;
+        if ply.is_multiple_of(2) {
+            tokens.push(format!("{}. {}", number, mv));
+        } else if i == 0 {
+            tokens.push(format!("{}... {}", number, mv));
+        } else {
+            tokens.push(mv.clone());
+        }
+    }
+    if let Some(note) = closing_note {
+        tokens.push(format!("{{{}}}", note));
+    }
+    tokens.push(result.to_string());
+
+    let mut text = String::new();
+    let mut line_len = 0;
+    for token in tokens {
+        if line_len > 0 && line_len + 1 + token.chars().count() > LINE_WIDTH {
+            text.push('\n');
+            line_len = 0;
+        } else if line_len > 0 {
+            text.push(' ');
+            line_len += 1;
+        }
+        line_len += token.chars().count();
+        text.push_str(&token);
+    }
+    text.push('\n');
+    text
+}
+
+// Läs det första partiet i en PGN-text
+fn parse_pgn(text: &str) -> Result<ImportedGame, String> {
+    let mut start = Board::default();
+    let (mut start_halfmove, mut start_fullmove) = (0, 1);
+    let mut movetext = String::new();
+    let mut seen_moves = false;
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            // En ny rubrik efter dragen betyder att nästa parti börjar
+            if seen_moves {
+                break;
+            }
+            if let Some(fen) = line.strip_prefix("[FEN \"").and_then(|l| l.strip_suffix("\"]")) {
+                start = parse_fen(fen)?;
+                (start_halfmove, start_fullmove) = fen_counters(fen);
+            }
+        } else if !line.is_empty() {
+            seen_moves = true;
+            movetext.push_str(line);
+            movetext.push(' ');
+        }
+    }
+
+    // Ta bort kommentarer {…} och sidovarianter (…)
+    let mut cleaned = String::new();
+    let mut comment = false;
+    let mut variation_depth = 0;
+    for c in movetext.chars() {
+        match c {
+            '{' => comment = true,
+            '}' => comment = false,
+            '(' if !comment => variation_depth += 1,
+            ')' if !comment => variation_depth -= 1,
+            _ if !comment && variation_depth == 0 => cleaned.push(c),
+            _ => {}
+        }
+    }
+
+    let mut board = start;
+    let mut moves = Vec::new();
+    for token in cleaned.split_whitespace() {
+        // Hoppa över resultat, dragnummer ("12." / "12...e5") och NAG:er
+        if matches!(token, "1-0" | "0-1" | "1/2-1/2" | "*") {
+            continue;
+        }
+        let token = token.rsplit('.').next().unwrap_or(token);
+        if token.is_empty() || token.starts_with('$') {
+            continue;
+        }
+
+        let chess_move = parse_san(&board, token)
+            .ok_or_else(|| format!("Okänt drag '{}' efter {} halvdrag", token, moves.len()))?;
+        board = board.make_move_new(chess_move);
+        moves.push(chess_move);
+    }
+
+    Ok(ImportedGame { start, start_halfmove, start_fullmove, moves })
+}
+
+// Dela upp en PGN-fil i enskilda partier: en rubrikrad efter draglistan startar nästa parti
+fn split_pgn_games(text: &str) -> Vec<&str> {
+    let mut games = Vec::new();
+    let mut game_start = 0;
+    let mut seen_moves = false;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') && seen_moves {
+            games.push(&text[game_start..offset]);
+            game_start = offset;
+            seen_moves = false;
+        } else if !trimmed.is_empty() && !trimmed.starts_with('[') {
+            seen_moves = true;
+        }
+        offset += line.len();
+    }
+    if seen_moves {
+        games.push(&text[game_start..]);
+    }
+    games
+}
+
+// Meddelanden från en PGN-import som körs i bakgrunden
+enum PgnImportMessage {
+    Progress { done: usize, total: usize },
+    Done(Result<Vec<ImportedGame>, String>),
+}
+
+// Tolka alla partier i en PGN-text på en egen tråd. Importen kan avbrytas via flaggan;
+// då skickas inget resultat och inget av det redan tolkade används.
+fn import_pgn_async(text: String) -> (mpsc::Receiver<PgnImportMessage>, Arc<AtomicBool>) {
+    let (tx, rx) = mpsc::channel();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let cancelled = Arc::clone(&cancel);
+
+    thread::spawn(move || {
+        let chunks = split_pgn_games(&text);
+        let total = chunks.len();
+        let mut games = Vec::new();
+        let mut last_error = String::from("Inga partier hittades");
+
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            if cancelled.load(Ordering::Relaxed) {
+                println!("[import_pgn_async] Import avbruten efter {} av {} partier", i, total);
+                return;
+            }
+            match parse_pgn(chunk) {
+                Ok(game) => games.push(game),
+                Err(e) => last_error = e,
+            }
+            if tx.send(PgnImportMessage::Progress { done: i + 1, total }).is_err() {
+                return;
+            }
+        }
+
+        let result = if games.is_empty() { Err(last_error) } else { Ok(games) };
+        let _ = tx.send(PgnImportMessage::Done(result));
+    });
+
+    (rx, cancel)
+}
+
+// =============================================================
+// DEL 3C: DRAGLJUD
+// =============================================================
+// Ljud kräver att spelet byggs med `--features sound` (aktiverar macroquads
+// ljudstöd). Utan den är uppspelningen en tyst no-op.
+
+// Bygg en kort avtonande sinuston som WAV i minnet så att inga ljudfiler behövs
+#[cfg(feature = "sound")]
+fn synth_tone_wav(frequency: f32, millis: u32) -> Vec<u8> {
+    const SAMPLE_RATE: u32 = 44_100;
+    let samples = SAMPLE_RATE * millis / 1000;
+    let data_len = samples * 2;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+
+    for i in 0..samples {
+        let t = i as f32 / SAMPLE_RATE as f32;
+        let fade = 1.0 - i as f32 / samples as f32;
+        let value = (t * frequency * std::f32::consts::TAU).sin() * fade * 0.4;
+        wav.extend_from_slice(&((value * i16::MAX as f32) as i16).to_le_bytes());
+    }
+    wav
+}
+
+// Vad som hände, för att välja ljud
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SoundEvent {
+    Move,
+    Capture,
+    Castle,
+    Check,
+    GameOver,
+}
+
+impl SoundEvent {
+    // Ljudet för ett drag i ställningen före draget. Schack går före slag och slag före rockad.
+    fn for_move(board: &Board, m: ChessMove) -> Self {
+        let castles = board.piece_on(m.get_source()) == Some(Piece::King)
+            && (m.get_source().get_file().to_index() as i32 - m.get_dest().get_file().to_index() as i32).abs() == 2;
+
+        if board.make_move_new(m).checkers().popcnt() > 0 {
+            SoundEvent::Check
+        } else if is_capture(board, m) {
+            SoundEvent::Capture
+        } else if castles {
+            SoundEvent::Castle
+        } else {
+            SoundEvent::Move
+        }
+    }
+
+    // Egen ljudfil, om en sådan finns bland tillgångarna
+    #[cfg(feature = "sound")]
+    fn asset(self) -> &'static str {
+        match self {
+            SoundEvent::Move => "assets/sounds/move.wav",
+            SoundEvent::Capture => "assets/sounds/capture.wav",
+            SoundEvent::Castle => "assets/sounds/castle.wav",
+            SoundEvent::Check => "assets/sounds/check.wav",
+            SoundEvent::GameOver => "assets/sounds/game_over.wav",
+        }
+    }
+
+    // Syntetiserad ton (frekvens, längd i ms) när ljudfilen saknas
+    #[cfg(feature = "sound")]
+    fn tone(self) -> (f32, u32) {
+        match self {
+            SoundEvent::Move => (880.0, 70),
+            SoundEvent::Capture => (330.0, 120),
+            SoundEvent::Castle => (660.0, 140),
+            SoundEvent::Check => (1320.0, 110),
+            SoundEvent::GameOver => (220.0, 450),
+        }
+    }
+}
+
+// Ljud för drag, slag, rockad, schack och partislut, plus ett lägre dragljud för AI:n
+#[cfg_attr(not(feature = "sound"), allow(dead_code))]
+struct MoveSounds {
+    #[cfg(feature = "sound")]
+    sounds: HashMap<(SoundEvent, bool), macroquad::audio::Sound>, // (händelse, AI:ns variant)
+}
+
+impl MoveSounds {
+    // Ljudfiler läses från assets/sounds; saknade filer ersätts med en syntetiserad ton
+    #[cfg(feature = "sound")]
+    async fn load() -> Option<Self> {
+        use macroquad::audio::{load_sound, load_sound_from_bytes};
+
+        let events = [SoundEvent::Move, SoundEvent::Capture, SoundEvent::Castle, SoundEvent::Check, SoundEvent::GameOver];
+        let mut sounds = HashMap::new();
+        for event in events {
+            let sound = match load_sound(event.asset()).await {
+                Ok(sound) => {
+                    println!("✓ Laddade ljud: {}", event.asset());
+                    Ok(sound)
+                }
+                Err(_) => {
+                    let (frequency, millis) = event.tone();
+                    load_sound_from_bytes(&synth_tone_wav(frequency, millis)).await
+                }
+            };
+            match sound {
+                Ok(sound) => {
+                    sounds.insert((event, false), sound);
+                }
+                Err(e) => eprintln!("⚠ Kunde inte skapa ljud för {:?}: {}", event, e),
+            }
+        }
+        // AI:ns vanliga drag får en lägre ton så att de går att skilja från spelarens
+        if let Ok(sound) = load_sound_from_bytes(&synth_tone_wav(520.0, 90)).await {
+            sounds.insert((SoundEvent::Move, true), sound);
+        }
+
+        if sounds.is_empty() {
+            eprintln!("⚠ Kunde inte skapa dragljud");
+            return None;
+        }
+        Some(Self { sounds })
+    }
+
+    #[cfg(not(feature = "sound"))]
+    async fn load() -> Option<Self> {
+        None
+    }
+
+    // Spela ljudet för en händelse; AI:ns eget dragljud används bara om spelaren valt att skilja dem åt
+    fn play(&self, event: SoundEvent, by_ai: bool, distinct: bool) {
+        #[cfg(feature = "sound")]
+        {
+            let sound = self.sounds.get(&(event, by_ai && distinct)).or_else(|| self.sounds.get(&(event, false)));
+            if let Some(sound) = sound {
+                macroquad::audio::play_sound_once(sound);
+            }
+        }
+        #[cfg(not(feature = "sound"))]
+        let _ = (event, by_ai, distinct);
+    }
+}
+
+// =============================================================
+// DEL 3D: ÖPPNINGSBOK
+// =============================================================
+
+// Kända öppningar: ECO-kod, namn och dragföljd i samma notation som draghistoriken
+const OPENING_BOOK: &[(&str, &str, &str)] = &[
+    ("B00", "Kungsbondeöppning", "e4"),
+    ("C20", "Öppet spel", "e4 e5"),
+    ("C44", "Kungsspringarspel", "e4 e5 Nf3 Nc6"),
+    ("C60", "Spanskt parti", "e4 e5 Nf3 Nc6 Bb5"),
+    ("C65", "Spanskt, berlinerförsvar", "e4 e5 Nf3 Nc6 Bb5 Nf6"),
+    ("C68", "Spanskt, avbytesvariant", "e4 e5 Nf3 Nc6 Bb5 a6 Bxc6"),
+    ("C70", "Spanskt, morphyförsvar", "e4 e5 Nf3 Nc6 Bb5 a6 Ba4"),
+    ("C50", "Italienskt parti", "e4 e5 Nf3 Nc6 Bc4"),
+    ("C53", "Italienskt, giuoco piano", "e4 e5 Nf3 Nc6 Bc4 Bc5 c3"),
+    ("C51", "Italienskt, evansgambit", "e4 e5 Nf3 Nc6 Bc4 Bc5 b4"),
+    ("C57", "Tvåspringarspel, fried liver", "e4 e5 Nf3 Nc6 Bc4 Nf6 Ng5 d5 exd5 Nxd5 Nxf7"),
+    ("C55", "Tvåspringarspel", "e4 e5 Nf3 Nc6 Bc4 Nf6"),
+    ("C45", "Skotskt parti", "e4 e5 Nf3 Nc6 d4"),
+    ("C42", "Ryskt parti", "e4 e5 Nf3 Nf6"),
+    ("C41", "Philidors försvar", "e4 e5 Nf3 d6"),
+    ("C30", "Kungsgambit", "e4 e5 f4"),
+    ("C33", "Antagen kungsgambit", "e4 e5 f4 exf4"),
+    ("C25", "Wienerparti", "e4 e5 Nc3"),
+    ("C23", "Löparspel", "e4 e5 Bc4"),
+    ("B20", "Sicilianskt", "e4 c5"),
+    ("B22", "Sicilianskt, alapin", "e4 c5 c3"),
+    ("B23", "Sicilianskt, slutet", "e4 c5 Nc3"),
+    ("B21", "Sicilianskt, smith-morra", "e4 c5 d4 cxd4 c3"),
+    ("B50", "Sicilianskt, d6", "e4 c5 Nf3 d6"),
+    ("B51", "Sicilianskt, moskvavarianten", "e4 c5 Nf3 d6 Bb5+"),
+    ("B54", "Sicilianskt, öppet", "e4 c5 Nf3 d6 d4 cxd4 Nxd4"),
+    ("B90", "Sicilianskt, najdorf", "e4 c5 Nf3 d6 d4 cxd4 Nxd4 Nf6 Nc3 a6"),
+    ("B70", "Sicilianskt, drakvarianten", "e4 c5 Nf3 d6 d4 cxd4 Nxd4 Nf6 Nc3 g6"),
+    ("B80", "Sicilianskt, scheveningen", "e4 c5 Nf3 d6 d4 cxd4 Nxd4 Nf6 Nc3 e6"),
+    ("B56", "Sicilianskt, klassiskt", "e4 c5 Nf3 d6 d4 cxd4 Nxd4 Nf6 Nc3 Nc6"),
+    ("B30", "Sicilianskt, Nc6", "e4 c5 Nf3 Nc6"),
+    ("B33", "Sicilianskt, sveshnikov", "e4 c5 Nf3 Nc6 d4 cxd4 Nxd4 Nf6 Nc3 e5"),
+    ("B40", "Sicilianskt, e6", "e4 c5 Nf3 e6"),
+    ("B46", "Sicilianskt, taimanov", "e4 c5 Nf3 e6 d4 cxd4 Nxd4 Nc6"),
+    ("B41", "Sicilianskt, kan", "e4 c5 Nf3 e6 d4 cxd4 Nxd4 a6"),
+    ("C00", "Franskt", "e4 e6"),
+    ("C11", "Franskt, klassiskt", "e4 e6 d4 d5 Nc3 Nf6"),
+    ("C15", "Franskt, winawer", "e4 e6 d4 d5 Nc3 Bb4"),
+    ("C03", "Franskt, tarrasch", "e4 e6 d4 d5 Nd2"),
+    ("C02", "Franskt, framstötsvariant", "e4 e6 d4 d5 e5"),
+    ("C01", "Franskt, avbytesvariant", "e4 e6 d4 d5 exd5"),
+    ("B10", "Caro-Kann", "e4 c6"),
+    ("B12", "Caro-Kann, framstötsvariant", "e4 c6 d4 d5 e5"),
+    ("B13", "Caro-Kann, avbytesvariant", "e4 c6 d4 d5 exd5"),
+    ("B18", "Caro-Kann, klassiskt", "e4 c6 d4 d5 Nc3 dxe4 Nxe4 Bf5"),
+    ("B01", "Skandinaviskt", "e4 d5"),
+    ("B02", "Aljechins försvar", "e4 Nf6"),
+    ("B06", "Modernt försvar", "e4 g6"),
+    ("B07", "Pirc", "e4 d6 d4 Nf6"),
+    ("D00", "Dambondeöppning", "d4 d5"),
+    ("D02", "Londonsystemet", "d4 d5 Nf3 Nf6 Bf4"),
+    ("D06", "Damgambit", "d4 d5 c4"),
+    ("D30", "Avböjd damgambit", "d4 d5 c4 e6"),
+    ("D35", "Avböjd damgambit, avbytesvariant", "d4 d5 c4 e6 Nc3 Nf6 cxd5"),
+    ("D43", "Halvslaviskt", "d4 d5 c4 e6 Nc3 Nf6 Nf3 c6"),
+    ("D20", "Antagen damgambit", "d4 d5 c4 dxc4"),
+    ("D10", "Slaviskt", "d4 d5 c4 c6"),
+    ("A45", "Indiskt försvar", "d4 Nf6"),
+    ("E60", "Kungsindiskt", "d4 Nf6 c4 g6"),
+    ("E90", "Kungsindiskt, klassiskt", "d4 Nf6 c4 g6 Nc3 Bg7 e4 d6 Nf3"),
+    ("E80", "Kungsindiskt, sämisch", "d4 Nf6 c4 g6 Nc3 Bg7 e4 d6 f3"),
+    ("E00", "Katalanskt", "d4 Nf6 c4 e6 g3"),
+    ("A56", "Benoni", "d4 Nf6 c4 c5"),
+    ("A57", "Benkögambit", "d4 Nf6 c4 c5 d5 b5"),
+    ("A46", "Indiskt, londonsystemet", "d4 Nf6 Nf3 e6 Bf4"),
+    ("D80", "Grünfeld", "d4 Nf6 c4 g6 Nc3 d5"),
+    ("E20", "Nimzoindiskt", "d4 Nf6 c4 e6 Nc3 Bb4"),
+    ("E12", "Damindiskt", "d4 Nf6 c4 e6 Nf3 b6"),
+    ("A80", "Holländskt", "d4 f5"),
+    ("A10", "Engelskt", "c4"),
+    ("A20", "Engelskt, omvänt sicilianskt", "c4 e5"),
+    ("A30", "Engelskt, symmetrivarianten", "c4 c5"),
+    ("A01", "Larsens öppning", "b3"),
+    ("A02", "Birds öppning", "f4"),
+    ("A04", "Rétiöppning", "Nf3"),
+];
+
+// Identifierad öppning och hur länge partiet följde boken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OpeningMatch {
+    eco: &'static str,
+    name: &'static str,
+    book_plies: usize, // antal halvdrag från början som finns i boken
+}
+
+// Matcha draghistoriken mot öppningsboken. Namnet tas från den längsta helt
+// spelade boklinjen, medan bokslutet räknar även delvis följda linjer.
+fn match_opening(history: &[MoveRecord]) -> Option<OpeningMatch> {
+    let mut best: Option<OpeningMatch> = None;
+    let mut book_plies = 0;
+
+    for &(eco, name, line) in OPENING_BOOK {
+        let line_moves: Vec<&str> = line.split_whitespace().collect();
+        let common = line_moves
+            .iter()
+            .zip(history)
+            .take_while(|(book, played)| **book == played.san)
+            .count();
+        book_plies = book_plies.max(common);
+
+        let fully_played = common == line_moves.len();
+        if fully_played && best.is_none_or(|b| line_moves.len() > b.book_plies) {
+            best = Some(OpeningMatch { eco, name, book_plies: line_moves.len() });
+        }
+    }
+
+    best.map(|opening| OpeningMatch { book_plies, ..opening })
+}
+
+// =============================================================
+// DEL 3E: POLYGLOT-BOK FÖR MOTORN
+// =============================================================
+
+// En post i en Polyglot-bok (.bin): 16 byte big-endian, sorterade på nyckeln
+#[derive(Debug, Clone, Copy)]
+struct BookEntry {
+    key: u64,
+    raw_move: u16,
+    weight: u16,
+}
+
+// Boken som läses in vid start om den finns bredvid programmet
+const ENGINE_BOOK_FILE: &str = "book.bin";
+
+// Öppningsbok i Polyglot-format som motorn kan spela ur i stället för att söka
+struct PolyglotBook {
+    entries: Vec<BookEntry>,
+}
+
+impl PolyglotBook {
+    const ENTRY_SIZE: usize = 16;
+
+    fn load(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Kunde inte läsa '{}': {}", path, e))?;
+        Self::from_bytes(&bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.is_empty() || !bytes.len().is_multiple_of(Self::ENTRY_SIZE) {
+            return Err("Filen är ingen Polyglot-bok".to_string());
+        }
+        let mut entries: Vec<BookEntry> = bytes
+            .chunks_exact(Self::ENTRY_SIZE)
+            .map(|chunk| BookEntry {
+                key: u64::from_be_bytes(chunk[0..8].try_into().unwrap()),
+                raw_move: u16::from_be_bytes([chunk[8], chunk[9]]),
+                weight: u16::from_be_bytes([chunk[10], chunk[11]]),
+            })
+            .collect();
+        // Böcker ska redan vara sorterade, men binärsökningen får inte lita på det
+        entries.sort_by_key(|entry| entry.key);
+        Ok(Self { entries })
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Bokens lagliga drag i ställningen med sina vikter
+    fn moves(&self, board: &Board) -> Vec<(ChessMove, u16)> {
+        let key = polyglot_key(board);
+        let start = self.entries.partition_point(|entry| entry.key < key);
+        self.entries[start..]
+            .iter()
+            .take_while(|entry| entry.key == key)
+            .filter_map(|entry| decode_polyglot_move(board, entry.raw_move).map(|m| (m, entry.weight)))
+            .collect()
+    }
+
+    // Slumpa ett bokdrag med vikterna som sannolikheter; None när ställningen är ur bok
+    fn pick_move(&self, board: &Board) -> Option<ChessMove> {
+        let moves = self.moves(board);
+        let total: u32 = moves.iter().map(|&(_, weight)| weight as u32).sum();
+        if total == 0 {
+            // Alla vikter noll: välj jämnt bland dragen
+            return (!moves.is_empty()).then(|| moves[::rand::random_range(0..moves.len())].0);
+        }
+        let mut pick = ::rand::random_range(0..total);
+        for (m, weight) in moves {
+            if pick < weight as u32 {
+                return Some(m);
+            }
+            pick -= weight as u32;
+        }
+        None
+    }
+}
+
+// Polyglots draghärledning: bitar 0–5 målruta, 6–11 från-ruta, 12–14 förvandling.
+// Rockad skrivs som att kungen slår sitt eget torn och översätts till kungens drag.
+fn decode_polyglot_move(board: &Board, raw: u16) -> Option<ChessMove> {
+    let square = |bits: u16| chess::ALL_SQUARES[(bits & 0x3f) as usize];
+    let source = square(raw >> 6);
+    let dest = square(raw);
+    let promotion = match (raw >> 12) & 0x7 {
+        1 => Some(Piece::Knight),
+        2 => Some(Piece::Bishop),
+        3 => Some(Piece::Rook),
+        4 => Some(Piece::Queen),
+        _ => None,
+    };
+
+    // Rockad lagras som kung till eget torn, t.ex. e1h1
+    legal_move_between(board, source, dest, promotion)
+}
+
+// Polyglots Zobrist-nyckel för en ställning. En passant räknas bara när sidan vid
+// draget har en bonde som kan slå, precis som i formatet.
+fn polyglot_key(board: &Board) -> u64 {
+    let mut key = 0;
+    for square in *board.combined() {
+        let (Some(piece), Some(color)) = (board.piece_on(square), board.color_on(square)) else {
+            continue;
+        };
+        let kind = piece.to_index() * 2 + (color == ChessColor::White) as usize;
+        key ^= POLYGLOT_RANDOM[64 * kind + square.to_index()];
+    }
+
+    for (index, (color, kingside)) in [
+        (ChessColor::White, true),
+        (ChessColor::White, false),
+        (ChessColor::Black, true),
+        (ChessColor::Black, false),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let rights = board.castle_rights(color);
+        if (kingside && rights.has_kingside()) || (!kingside && rights.has_queenside()) {
+            key ^= POLYGLOT_RANDOM[768 + index];
+        }
+    }
+
+    // chess-craten anger bonden som just gått två steg; slagbara grannar står på samma rad
+    if let Some(pawn) = board.en_passant() {
+        let mover = board.side_to_move();
+        let file = pawn.get_file().to_index();
+        let can_capture = [file.wrapping_sub(1), file + 1].into_iter().filter(|&f| f < 8).any(|f| {
+            let neighbour = Square::make_square(pawn.get_rank(), chess::File::from_index(f));
+            board.piece_on(neighbour) == Some(Piece::Pawn) && board.color_on(neighbour) == Some(mover)
+        });
+        if can_capture {
+            key ^= POLYGLOT_RANDOM[772 + file];
+        }
+    }
+
+    if board.side_to_move() == ChessColor::White {
+        key ^= POLYGLOT_RANDOM[780];
+    }
+    key
+}
+
+// Polyglots slumptal: 768 för pjäs och ruta (svart bonde, vit bonde, svart springare …),
+// 4 för rockadrätter, 8 för en passant-linjer och 1 för vit vid draget
+const POLYGLOT_RANDOM: [u64; 781] = [
+    0x9D39247E33776D41, 0x2AF7398005AAA5C7, 0x44DB015024623547, 0x9C15F73E62A76AE2,
+    0x75834465489C0C89, 0x3290AC3A203001BF, 0x0FBBAD1F61042279, 0xE83A908FF2FB60CA,
+    0x0D7E765D58755C10, 0x1A083822CEAFE02D, 0x9605D5F0E25EC3B0, 0xD021FF5CD13A2ED5,
+    0x40BDF15D4A672E32, 0x011355146FD56395, 0x5DB4832046F3D9E5, 0x239F8B2D7FF719CC,
+    0x05D1A1AE85B49AA1, 0x679F848F6E8FC971, 0x7449BBFF801FED0B, 0x7D11CDB1C3B7ADF0,
+    0x82C7709E781EB7CC, 0xF3218F1C9510786C, 0x331478F3AF51BBE6, 0x4BB38DE5E7219443,
+    0xAA649C6EBCFD50FC, 0x8DBD98A352AFD40B, 0x87D2074B81D79217, 0x19F3C751D3E92AE1,
+    0xB4AB30F062B19ABF, 0x7B0500AC42047AC4, 0xC9452CA81A09D85D, 0x24AA6C514DA27500,
+    0x4C9F34427501B447, 0x14A68FD73C910841, 0xA71B9B83461CBD93, 0x03488B95B0F1850F,
+    0x637B2B34FF93C040, 0x09D1BC9A3DD90A94, 0x3575668334A1DD3B, 0x735E2B97A4C45A23,
+    0x18727070F1BD400B, 0x1FCBACD259BF02E7, 0xD310A7C2CE9B6555, 0xBF983FE0FE5D8244,
+    0x9F74D14F7454A824, 0x51EBDC4AB9BA3035, 0x5C82C505DB9AB0FA, 0xFCF7FE8A3430B241,
+    0x3253A729B9BA3DDE, 0x8C74C368081B3075, 0xB9BC6C87167C33E7, 0x7EF48F2B83024E20,
+    0x11D505D4C351BD7F, 0x6568FCA92C76A243, 0x4DE0B0F40F32A7B8, 0x96D693460CC37E5D,
+    0x42E240CB63689F2F, 0x6D2BDCDAE2919661, 0x42880B0236E4D951, 0x5F0F4A5898171BB6,
+    0x39F890F579F92F88, 0x93C5B5F47356388B, 0x63DC359D8D231B78, 0xEC16CA8AEA98AD76,
+    0x5355F900C2A82DC7, 0x07FB9F855A997142, 0x5093417AA8A7ED5E, 0x7BCBC38DA25A7F3C,
+    0x19FC8A768CF4B6D4, 0x637A7780DECFC0D9, 0x8249A47AEE0E41F7, 0x79AD695501E7D1E8,
+    0x14ACBAF4777D5776, 0xF145B6BECCDEA195, 0xDABF2AC8201752FC, 0x24C3C94DF9C8D3F6,
+    0xBB6E2924F03912EA, 0x0CE26C0B95C980D9, 0xA49CD132BFBF7CC4, 0xE99D662AF4243939,
+    0x27E6AD7891165C3F, 0x8535F040B9744FF1, 0x54B3F4FA5F40D873, 0x72B12C32127FED2B,
+    0xEE954D3C7B411F47, 0x9A85AC909A24EAA1, 0x70AC4CD9F04F21F5, 0xF9B89D3E99A075C2,
+    0x87B3E2B2B5C907B1, 0xA366E5B8C54F48B8, 0xAE4A9346CC3F7CF2, 0x1920C04D47267BBD,
+    0x87BF02C6B49E2AE9, 0x092237AC237F3859, 0xFF07F64EF8ED14D0, 0x8DE8DCA9F03CC54E,
+    0x9C1633264DB49C89, 0xB3F22C3D0B0B38ED, 0x390E5FB44D01144B, 0x5BFEA5B4712768E9,
+    0x1E1032911FA78984, 0x9A74ACB964E78CB3, 0x4F80F7A035DAFB04, 0x6304D09A0B3738C4,
+    0x2171E64683023A08, 0x5B9B63EB9CEFF80C, 0x506AACF489889342, 0x1881AFC9A3A701D6,
+    0x6503080440750644, 0xDFD395339CDBF4A7, 0xEF927DBCF00C20F2, 0x7B32F7D1E03680EC,
+    0xB9FD7620E7316243, 0x05A7E8A57DB91B77, 0xB5889C6E15630A75, 0x4A750A09CE9573F7,
+    0xCF464CEC899A2F8A, 0xF538639CE705B824, 0x3C79A0FF5580EF7F, 0xEDE6C87F8477609D,
+    0x799E81F05BC93F31, 0x86536B8CF3428A8C, 0x97D7374C60087B73, 0xA246637CFF328532,
+    0x043FCAE60CC0EBA0, 0x920E449535DD359E, 0x70EB093B15B290CC, 0x73A1921916591CBD,
+    0x56436C9FE1A1AA8D, 0xEFAC4B70633B8F81, 0xBB215798D45DF7AF, 0x45F20042F24F1768,
+    0x930F80F4E8EB7462, 0xFF6712FFCFD75EA1, 0xAE623FD67468AA70, 0xDD2C5BC84BC8D8FC,
+    0x7EED120D54CF2DD9, 0x22FE545401165F1C, 0xC91800E98FB99929, 0x808BD68E6AC10365,
+    0xDEC468145B7605F6, 0x1BEDE3A3AEF53302, 0x43539603D6C55602, 0xAA969B5C691CCB7A,
+    0xA87832D392EFEE56, 0x65942C7B3C7E11AE, 0xDED2D633CAD004F6, 0x21F08570F420E565,
+    0xB415938D7DA94E3C, 0x91B859E59ECB6350, 0x10CFF333E0ED804A, 0x28AED140BE0BB7DD,
+    0xC5CC1D89724FA456, 0x5648F680F11A2741, 0x2D255069F0B7DAB3, 0x9BC5A38EF729ABD4,
+    0xEF2F054308F6A2BC, 0xAF2042F5CC5C2858, 0x480412BAB7F5BE2A, 0xAEF3AF4A563DFE43,
+    0x19AFE59AE451497F, 0x52593803DFF1E840, 0xF4F076E65F2CE6F0, 0x11379625747D5AF3,
+    0xBCE5D2248682C115, 0x9DA4243DE836994F, 0x066F70B33FE09017, 0x4DC4DE189B671A1C,
+    0x51039AB7712457C3, 0xC07A3F80C31FB4B4, 0xB46EE9C5E64A6E7C, 0xB3819A42ABE61C87,
+    0x21A007933A522A20, 0x2DF16F761598AA4F, 0x763C4A1371B368FD, 0xF793C46702E086A0,
+    0xD7288E012AEB8D31, 0xDE336A2A4BC1C44B, 0x0BF692B38D079F23, 0x2C604A7A177326B3,
+    0x4850E73E03EB6064, 0xCFC447F1E53C8E1B, 0xB05CA3F564268D99, 0x9AE182C8BC9474E8,
+    0xA4FC4BD4FC5558CA, 0xE755178D58FC4E76, 0x69B97DB1A4C03DFE, 0xF9B5B7C4ACC67C96,
+    0xFC6A82D64B8655FB, 0x9C684CB6C4D24417, 0x8EC97D2917456ED0, 0x6703DF9D2924E97E,
+    0xC547F57E42A7444E, 0x78E37644E7CAD29E, 0xFE9A44E9362F05FA, 0x08BD35CC38336615,
+    0x9315E5EB3A129ACE, 0x94061B871E04DF75, 0xDF1D9F9D784BA010, 0x3BBA57B68871B59D,
+    0xD2B7ADEEDED1F73F, 0xF7A255D83BC373F8, 0xD7F4F2448C0CEB81, 0xD95BE88CD210FFA7,
+    0x336F52F8FF4728E7, 0xA74049DAC312AC71, 0xA2F61BB6E437FDB5, 0x4F2A5CB07F6A35B3,
+    0x87D380BDA5BF7859, 0x16B9F7E06C453A21, 0x7BA2484C8A0FD54E, 0xF3A678CAD9A2E38C,
+    0x39B0BF7DDE437BA2, 0xFCAF55C1BF8A4424, 0x18FCF680573FA594, 0x4C0563B89F495AC3,
+    0x40E087931A00930D, 0x8CFFA9412EB642C1, 0x68CA39053261169F, 0x7A1EE967D27579E2,
+    0x9D1D60E5076F5B6F, 0x3810E399B6F65BA2, 0x32095B6D4AB5F9B1, 0x35CAB62109DD038A,
+    0xA90B24499FCFAFB1, 0x77A225A07CC2C6BD, 0x513E5E634C70E331, 0x4361C0CA3F692F12,
+    0xD941ACA44B20A45B, 0x528F7C8602C5807B, 0x52AB92BEB9613989, 0x9D1DFA2EFC557F73,
+    0x722FF175F572C348, 0x1D1260A51107FE97, 0x7A249A57EC0C9BA2, 0x04208FE9E8F7F2D6,
+    0x5A110C6058B920A0, 0x0CD9A497658A5698, 0x56FD23C8F9715A4C, 0x284C847B9D887AAE,
+    0x04FEABFBBDB619CB, 0x742E1E651C60BA83, 0x9A9632E65904AD3C, 0x881B82A13B51B9E2,
+    0x506E6744CD974924, 0xB0183DB56FFC6A79, 0x0ED9B915C66ED37E, 0x5E11E86D5873D484,
+    0xF678647E3519AC6E, 0x1B85D488D0F20CC5, 0xDAB9FE6525D89021, 0x0D151D86ADB73615,
+    0xA865A54EDCC0F019, 0x93C42566AEF98FFB, 0x99E7AFEABE000731, 0x48CBFF086DDF285A,
+    0x7F9B6AF1EBF78BAF, 0x58627E1A149BBA21, 0x2CD16E2ABD791E33, 0xD363EFF5F0977996,
+    0x0CE2A38C344A6EED, 0x1A804AADB9CFA741, 0x907F30421D78C5DE, 0x501F65EDB3034D07,
+    0x37624AE5A48FA6E9, 0x957BAF61700CFF4E, 0x3A6C27934E31188A, 0xD49503536ABCA345,
+    0x088E049589C432E0, 0xF943AEE7FEBF21B8, 0x6C3B8E3E336139D3, 0x364F6FFA464EE52E,
+    0xD60F6DCEDC314222, 0x56963B0DCA418FC0, 0x16F50EDF91E513AF, 0xEF1955914B609F93,
+    0x565601C0364E3228, 0xECB53939887E8175, 0xBAC7A9A18531294B, 0xB344C470397BBA52,
+    0x65D34954DAF3CEBD, 0xB4B81B3FA97511E2, 0xB422061193D6F6A7, 0x071582401C38434D,
+    0x7A13F18BBEDC4FF5, 0xBC4097B116C524D2, 0x59B97885E2F2EA28, 0x99170A5DC3115544,
+    0x6F423357E7C6A9F9, 0x325928EE6E6F8794, 0xD0E4366228B03343, 0x565C31F7DE89EA27,
+    0x30F5611484119414, 0xD873DB391292ED4F, 0x7BD94E1D8E17DEBC, 0xC7D9F16864A76E94,
+    0x947AE053EE56E63C, 0xC8C93882F9475F5F, 0x3A9BF55BA91F81CA, 0xD9A11FBB3D9808E4,
+    0x0FD22063EDC29FCA, 0xB3F256D8ACA0B0B9, 0xB03031A8B4516E84, 0x35DD37D5871448AF,
+    0xE9F6082B05542E4E, 0xEBFAFA33D7254B59, 0x9255ABB50D532280, 0xB9AB4CE57F2D34F3,
+    0x693501D628297551, 0xC62C58F97DD949BF, 0xCD454F8F19C5126A, 0xBBE83F4ECC2BDECB,
+    0xDC842B7E2819E230, 0xBA89142E007503B8, 0xA3BC941D0A5061CB, 0xE9F6760E32CD8021,
+    0x09C7E552BC76492F, 0x852F54934DA55CC9, 0x8107FCCF064FCF56, 0x098954D51FFF6580,
+    0x23B70EDB1955C4BF, 0xC330DE426430F69D, 0x4715ED43E8A45C0A, 0xA8D7E4DAB780A08D,
+    0x0572B974F03CE0BB, 0xB57D2E985E1419C7, 0xE8D9ECBE2CF3D73F, 0x2FE4B17170E59750,
+    0x11317BA87905E790, 0x7FBF21EC8A1F45EC, 0x1725CABFCB045B00, 0x964E915CD5E2B207,
+    0x3E2B8BCBF016D66D, 0xBE7444E39328A0AC, 0xF85B2B4FBCDE44B7, 0x49353FEA39BA63B1,
+    0x1DD01AAFCD53486A, 0x1FCA8A92FD719F85, 0xFC7C95D827357AFA, 0x18A6A990C8B35EBD,
+    0xCCCB7005C6B9C28D, 0x3BDBB92C43B17F26, 0xAA70B5B4F89695A2, 0xE94C39A54A98307F,
+    0xB7A0B174CFF6F36E, 0xD4DBA84729AF48AD, 0x2E18BC1AD9704A68, 0x2DE0966DAF2F8B1C,
+    0xB9C11D5B1E43A07E, 0x64972D68DEE33360, 0x94628D38D0C20584, 0xDBC0D2B6AB90A559,
+    0xD2733C4335C6A72F, 0x7E75D99D94A70F4D, 0x6CED1983376FA72B, 0x97FCAACBF030BC24,
+    0x7B77497B32503B12, 0x8547EDDFB81CCB94, 0x79999CDFF70902CB, 0xCFFE1939438E9B24,
+    0x829626E3892D95D7, 0x92FAE24291F2B3F1, 0x63E22C147B9C3403, 0xC678B6D860284A1C,
+    0x5873888850659AE7, 0x0981DCD296A8736D, 0x9F65789A6509A440, 0x9FF38FED72E9052F,
+    0xE479EE5B9930578C, 0xE7F28ECD2D49EECD, 0x56C074A581EA17FE, 0x5544F7D774B14AEF,
+    0x7B3F0195FC6F290F, 0x12153635B2C0CF57, 0x7F5126DBBA5E0CA7, 0x7A76956C3EAFB413,
+    0x3D5774A11D31AB39, 0x8A1B083821F40CB4, 0x7B4A38E32537DF62, 0x950113646D1D6E03,
+    0x4DA8979A0041E8A9, 0x3BC36E078F7515D7, 0x5D0A12F27AD310D1, 0x7F9D1A2E1EBE1327,
+    0xDA3A361B1C5157B1, 0xDCDD7D20903D0C25, 0x36833336D068F707, 0xCE68341F79893389,
+    0xAB9090168DD05F34, 0x43954B3252DC25E5, 0xB438C2B67F98E5E9, 0x10DCD78E3851A492,
+    0xDBC27AB5447822BF, 0x9B3CDB65F82CA382, 0xB67B7896167B4C84, 0xBFCED1B0048EAC50,
+    0xA9119B60369FFEBD, 0x1FFF7AC80904BF45, 0xAC12FB171817EEE7, 0xAF08DA9177DDA93D,
+    0x1B0CAB936E65C744, 0xB559EB1D04E5E932, 0xC37B45B3F8D6F2BA, 0xC3A9DC228CAAC9E9,
+    0xF3B8B6675A6507FF, 0x9FC477DE4ED681DA, 0x67378D8ECCEF96CB, 0x6DD856D94D259236,
+    0xA319CE15B0B4DB31, 0x073973751F12DD5E, 0x8A8E849EB32781A5, 0xE1925C71285279F5,
+    0x74C04BF1790C0EFE, 0x4DDA48153C94938A, 0x9D266D6A1CC0542C, 0x7440FB816508C4FE,
+    0x13328503DF48229F, 0xD6BF7BAEE43CAC40, 0x4838D65F6EF6748F, 0x1E152328F3318DEA,
+    0x8F8419A348F296BF, 0x72C8834A5957B511, 0xD7A023A73260B45C, 0x94EBC8ABCFB56DAE,
+    0x9FC10D0F989993E0, 0xDE68A2355B93CAE6, 0xA44CFE79AE538BBE, 0x9D1D84FCCE371425,
+    0x51D2B1AB2DDFB636, 0x2FD7E4B9E72CD38C, 0x65CA5B96B7552210, 0xDD69A0D8AB3B546D,
+    0x604D51B25FBF70E2, 0x73AA8A564FB7AC9E, 0x1A8C1E992B941148, 0xAAC40A2703D9BEA0,
+    0x764DBEAE7FA4F3A6, 0x1E99B96E70A9BE8B, 0x2C5E9DEB57EF4743, 0x3A938FEE32D29981,
+    0x26E6DB8FFDF5ADFE, 0x469356C504EC9F9D, 0xC8763C5B08D1908C, 0x3F6C6AF859D80055,
+    0x7F7CC39420A3A545, 0x9BFB227EBDF4C5CE, 0x89039D79D6FC5C5C, 0x8FE88B57305E2AB6,
+    0xA09E8C8C35AB96DE, 0xFA7E393983325753, 0xD6B6D0ECC617C699, 0xDFEA21EA9E7557E3,
+    0xB67C1FA481680AF8, 0xCA1E3785A9E724E5, 0x1CFC8BED0D681639, 0xD18D8549D140CAEA,
+    0x4ED0FE7E9DC91335, 0xE4DBF0634473F5D2, 0x1761F93A44D5AEFE, 0x53898E4C3910DA55,
+    0x734DE8181F6EC39A, 0x2680B122BAA28D97, 0x298AF231C85BAFAB, 0x7983EED3740847D5,
+    0x66C1A2A1A60CD889, 0x9E17E49642A3E4C1, 0xEDB454E7BADC0805, 0x50B704CAB602C329,
+    0x4CC317FB9CDDD023, 0x66B4835D9EAFEA22, 0x219B97E26FFC81BD, 0x261E4E4C0A333A9D,
+    0x1FE2CCA76517DB90, 0xD7504DFA8816EDBB, 0xB9571FA04DC089C8, 0x1DDC0325259B27DE,
+    0xCF3F4688801EB9AA, 0xF4F5D05C10CAB243, 0x38B6525C21A42B0E, 0x36F60E2BA4FA6800,
+    0xEB3593803173E0CE, 0x9C4CD6257C5A3603, 0xAF0C317D32ADAA8A, 0x258E5A80C7204C4B,
+    0x8B889D624D44885D, 0xF4D14597E660F855, 0xD4347F66EC8941C3, 0xE699ED85B0DFB40D,
+    0x2472F6207C2D0484, 0xC2A1E7B5B459AEB5, 0xAB4F6451CC1D45EC, 0x63767572AE3D6174,
+    0xA59E0BD101731A28, 0x116D0016CB948F09, 0x2CF9C8CA052F6E9F, 0x0B090A7560A968E3,
+    0xABEEDDB2DDE06FF1, 0x58EFC10B06A2068D, 0xC6E57A78FBD986E0, 0x2EAB8CA63CE802D7,
+    0x14A195640116F336, 0x7C0828DD624EC390, 0xD74BBE77E6116AC7, 0x804456AF10F5FB53,
+    0xEBE9EA2ADF4321C7, 0x03219A39EE587A30, 0x49787FEF17AF9924, 0xA1E9300CD8520548,
+    0x5B45E522E4B1B4EF, 0xB49C3B3995091A36, 0xD4490AD526F14431, 0x12A8F216AF9418C2,
+    0x001F837CC7350524, 0x1877B51E57A764D5, 0xA2853B80F17F58EE, 0x993E1DE72D36D310,
+    0xB3598080CE64A656, 0x252F59CF0D9F04BB, 0xD23C8E176D113600, 0x1BDA0492E7E4586E,
+    0x21E0BD5026C619BF, 0x3B097ADAF088F94E, 0x8D14DEDB30BE846E, 0xF95CFFA23AF5F6F4,
+    0x3871700761B3F743, 0xCA672B91E9E4FA16, 0x64C8E531BFF53B55, 0x241260ED4AD1E87D,
+    0x106C09B972D2E822, 0x7FBA195410E5CA30, 0x7884D9BC6CB569D8, 0x0647DFEDCD894A29,
+    0x63573FF03E224774, 0x4FC8E9560F91B123, 0x1DB956E450275779, 0xB8D91274B9E9D4FB,
+    0xA2EBEE47E2FBFCE1, 0xD9F1F30CCD97FB09, 0xEFED53D75FD64E6B, 0x2E6D02C36017F67F,
+    0xA9AA4D20DB084E9B, 0xB64BE8D8B25396C1, 0x70CB6AF7C2D5BCF0, 0x98F076A4F7A2322E,
+    0xBF84470805E69B5F, 0x94C3251F06F90CF3, 0x3E003E616A6591E9, 0xB925A6CD0421AFF3,
+    0x61BDD1307C66E300, 0xBF8D5108E27E0D48, 0x240AB57A8B888B20, 0xFC87614BAF287E07,
+    0xEF02CDD06FFDB432, 0xA1082C0466DF6C0A, 0x8215E577001332C8, 0xD39BB9C3A48DB6CF,
+    0x2738259634305C14, 0x61CF4F94C97DF93D, 0x1B6BACA2AE4E125B, 0x758F450C88572E0B,
+    0x959F587D507A8359, 0xB063E962E045F54D, 0x60E8ED72C0DFF5D1, 0x7B64978555326F9F,
+    0xFD080D236DA814BA, 0x8C90FD9B083F4558, 0x106F72FE81E2C590, 0x7976033A39F7D952,
+    0xA4EC0132764CA04B, 0x733EA705FAE4FA77, 0xB4D8F77BC3E56167, 0x9E21F4F903B33FD9,
+    0x9D765E419FB69F6D, 0xD30C088BA61EA5EF, 0x5D94337FBFAF7F5B, 0x1A4E4822EB4D7A59,
+    0x6FFE73E81B637FB3, 0xDDF957BC36D8B9CA, 0x64D0E29EEA8838B3, 0x08DD9BDFD96B9F63,
+    0x087E79E5A57D1D13, 0xE328E230E3E2B3FB, 0x1C2559E30F0946BE, 0x720BF5F26F4D2EAA,
+    0xB0774D261CC609DB, 0x443F64EC5A371195, 0x4112CF68649A260E, 0xD813F2FAB7F5C5CA,
+    0x660D3257380841EE, 0x59AC2C7873F910A3, 0xE846963877671A17, 0x93B633ABFA3469F8,
+    0xC0C0F5A60EF4CDCF, 0xCAF21ECD4377B28C, 0x57277707199B8175, 0x506C11B9D90E8B1D,
+    0xD83CC2687A19255F, 0x4A29C6465A314CD1, 0xED2DF21216235097, 0xB5635C95FF7296E2,
+    0x22AF003AB672E811, 0x52E762596BF68235, 0x9AEBA33AC6ECC6B0, 0x944F6DE09134DFB6,
+    0x6C47BEC883A7DE39, 0x6AD047C430A12104, 0xA5B1CFDBA0AB4067, 0x7C45D833AFF07862,
+    0x5092EF950A16DA0B, 0x9338E69C052B8E7B, 0x455A4B4CFE30E3F5, 0x6B02E63195AD0CF8,
+    0x6B17B224BAD6BF27, 0xD1E0CCD25BB9C169, 0xDE0C89A556B9AE70, 0x50065E535A213CF6,
+    0x9C1169FA2777B874, 0x78EDEFD694AF1EED, 0x6DC93D9526A50E68, 0xEE97F453F06791ED,
+    0x32AB0EDB696703D3, 0x3A6853C7E70757A7, 0x31865CED6120F37D, 0x67FEF95D92607890,
+    0x1F2B1D1F15F6DC9C, 0xB69E38A8965C6B65, 0xAA9119FF184CCCF4, 0xF43C732873F24C13,
+    0xFB4A3D794A9A80D2, 0x3550C2321FD6109C, 0x371F77E76BB8417E, 0x6BFA9AAE5EC05779,
+    0xCD04F3FF001A4778, 0xE3273522064480CA, 0x9F91508BFFCFC14A, 0x049A7F41061A9E60,
+    0xFCB6BE43A9F2FE9B, 0x08DE8A1C7797DA9B, 0x8F9887E6078735A1, 0xB5B4071DBFC73A66,
+    0x230E343DFBA08D33, 0x43ED7F5A0FAE657D, 0x3A88A0FBBCB05C63, 0x21874B8B4D2DBC4F,
+    0x1BDEA12E35F6A8C9, 0x53C065C6C8E63528, 0xE34A1D250E7A8D6B, 0xD6B04D3B7651DD7E,
+    0x5E90277E7CB39E2D, 0x2C046F22062DC67D, 0xB10BB459132D0A26, 0x3FA9DDFB67E2F199,
+    0x0E09B88E1914F7AF, 0x10E8B35AF3EEAB37, 0x9EEDECA8E272B933, 0xD4C718BC4AE8AE5F,
+    0x81536D601170FC20, 0x91B534F885818A06, 0xEC8177F83F900978, 0x190E714FADA5156E,
+    0xB592BF39B0364963, 0x89C350C893AE7DC1, 0xAC042E70F8B383F2, 0xB49B52E587A1EE60,
+    0xFB152FE3FF26DA89, 0x3E666E6F69AE2C15, 0x3B544EBE544C19F9, 0xE805A1E290CF2456,
+    0x24B33C9D7ED25117, 0xE74733427B72F0C1, 0x0A804D18B7097475, 0x57E3306D881EDB4F,
+    0x4AE7D6A36EB5DBCB, 0x2D8D5432157064C8, 0xD1E649DE1E7F268B, 0x8A328A1CEDFE552C,
+    0x07A3AEC79624C7DA, 0x84547DDC3E203C94, 0x990A98FD5071D263, 0x1A4FF12616EEFC89,
+    0xF6F7FD1431714200, 0x30C05B1BA332F41C, 0x8D2636B81555A786, 0x46C9FEB55D120902,
+    0xCCEC0A73B49C9921, 0x4E9D2827355FC492, 0x19EBB029435DCB0F, 0x4659D2B743848A2C,
+    0x963EF2C96B33BE31, 0x74F85198B05A2E7D, 0x5A0F544DD2B1FB18, 0x03727073C2E134B1,
+    0xC7F6AA2DE59AEA61, 0x352787BAA0D7C22F, 0x9853EAB63B5E0B35, 0xABBDCDD7ED5C0860,
+    0xCF05DAF5AC8D77B0, 0x49CAD48CEBF4A71E, 0x7A4C10EC2158C4A6, 0xD9E92AA246BF719E,
+    0x13AE978D09FE5557, 0x730499AF921549FF, 0x4E4B705B92903BA4, 0xFF577222C14F0A3A,
+    0x55B6344CF97AAFAE, 0xB862225B055B6960, 0xCAC09AFBDDD2CDB4, 0xDAF8E9829FE96B5F,
+    0xB5FDFC5D3132C498, 0x310CB380DB6F7503, 0xE87FBB46217A360E, 0x2102AE466EBB1148,
+    0xF8549E1A3AA5E00D, 0x07A69AFDCC42261A, 0xC4C118BFE78FEAAE, 0xF9F4892ED96BD438,
+    0x1AF3DBE25D8F45DA, 0xF5B4B0B0D2DEEEB4, 0x962ACEEFA82E1C84, 0x046E3ECAAF453CE9,
+    0xF05D129681949A4C, 0x964781CE734B3C84, 0x9C2ED44081CE5FBD, 0x522E23F3925E319E,
+    0x177E00F9FC32F791, 0x2BC60A63A6F3B3F2, 0x222BBFAE61725606, 0x486289DDCC3D6780,
+    0x7DC7785B8EFDFC80, 0x8AF38731C02BA980, 0x1FAB64EA29A2DDF7, 0xE4D9429322CD065A,
+    0x9DA058C67844F20C, 0x24C0E332B70019B0, 0x233003B5A6CFE6AD, 0xD586BD01C5C217F6,
+    0x5E5637885F29BC2B, 0x7EBA726D8C94094B, 0x0A56A5F0BFE39272, 0xD79476A84EE20D06,
+    0x9E4C1269BAA4BF37, 0x17EFEE45B0DEE640, 0x1D95B0A5FCF90BC6, 0x93CBE0B699C2585D,
+    0x65FA4F227A2B6D79, 0xD5F9E858292504D5, 0xC2B5A03F71471A6F, 0x59300222B4561E00,
+    0xCE2F8642CA0712DC, 0x7CA9723FBB2E8988, 0x2785338347F2BA08, 0xC61BB3A141E50E8C,
+    0x150F361DAB9DEC26, 0x9F6A419D382595F4, 0x64A53DC924FE7AC9, 0x142DE49FFF7A7C3D,
+    0x0C335248857FA9E7, 0x0A9C32D5EAE45305, 0xE6C42178C4BBB92E, 0x71F1CE2490D20B07,
+    0xF1BCC3D275AFE51A, 0xE728E8C83C334074, 0x96FBF83A12884624, 0x81A1549FD6573DA5,
+    0x5FA7867CAF35E149, 0x56986E2EF3ED091B, 0x917F1DD5F8886C61, 0xD20D8C88C8FFE65F,
+    0x31D71DCE64B2C310, 0xF165B587DF898190, 0xA57E6339DD2CF3A0, 0x1EF6E6DBB1961EC9,
+    0x70CC73D90BC26E24, 0xE21A6B35DF0C3AD7, 0x003A93D8B2806962, 0x1C99DED33CB890A1,
+    0xCF3145DE0ADD4289, 0xD0E4427A5514FB72, 0x77C621CC9FB3A483, 0x67A34DAC4356550B,
+    0xF8D626AAAF278509,
+];
+
+// =============================================================
+// DEL 3F: SPAR- OCH INSTÄLLNINGSFILER
+// =============================================================
+
+// Färg i spar- och inställningsfilerna, med de svenska namn som filerna alltid haft
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SavedColor {
+    Vit,
+    Svart,
+}
+
+impl From<ChessColor> for SavedColor {
+    fn from(color: ChessColor) -> Self {
+        match color {
+            ChessColor::White => SavedColor::Vit,
+            ChessColor::Black => SavedColor::Svart,
+        }
+    }
+}
+
+impl From<SavedColor> for ChessColor {
+    fn from(color: SavedColor) -> Self {
+        match color {
+            SavedColor::Vit => ChessColor::White,
+            SavedColor::Svart => ChessColor::Black,
+        }
+    }
+}
+
+// Skiljer ett fält som saknas (None) från ett uttryckligt null (Some(None))
+fn deserialize_present<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// Inställningarna som sparas både i sparfilen och i inställningsfilen. Fält som
+// saknas i filen behåller sina nuvarande värden.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct SavedSettings {
+    board_flipped: Option<bool>,
+    eval_precision: Option<usize>,
+    player_perspective: Option<bool>,
+    analysis_depth: Option<u8>,
+    show_eval_comparison: Option<bool>,
+    sound_enabled: Option<bool>,
+    distinct_move_sounds: Option<bool>,
+    ai_min_delay_ms: Option<u32>,
+    include_analysis_in_pgn: Option<bool>,
+    move_overhead_ms: Option<u32>,
+    animations: Option<bool>,
+    coordinate_size: Option<u16>,
+    coordinates_inside: Option<bool>,
+    board_border: Option<f32>,
+    #[serde(deserialize_with = "deserialize_present")]
+    elo_limit: Option<Option<u16>>,
+    use_opening_book: Option<bool>,
+    image_size: Option<u32>,
+    #[serde(deserialize_with = "deserialize_present")]
+    time_control: Option<Option<String>>,
+    game_mode: Option<String>,
+    hot_seat_auto_flip: Option<bool>,
+    player_names: Option<Vec<String>>,
+    autoplay_elo: Option<Vec<Option<u16>>>,
+}
+
+// Inställningsfilen: inställningarna, spelarens färg, reglagen och motorns sökväg
+#[derive(Debug, Serialize, Deserialize)]
+struct SettingsFile {
+    format: String,
+    version: u32,
+    #[serde(default)]
+    player_color: Option<SavedColor>,
+    #[serde(default)]
+    search_by_time: Option<bool>,
+    #[serde(default)]
+    depth: Option<u8>,
+    #[serde(default)]
+    movetime: Option<u8>,
+    #[serde(default)]
+    skill: Option<u8>,
+    #[serde(default)]
+    engine_path: Option<String>,
+    #[serde(default)]
+    settings: SavedSettings,
+}
+
+// Formatet och versionen läses först, så att fel sorts fil får ett begripligt fel
+#[derive(Debug, Deserialize)]
+struct SaveHeader {
+    format: Option<String>,
+    version: Option<u32>,
+}
+
+// Sparfilen från "Spara spel": partiet som SAN från startställningen, plus färg,
+// inställningar, klocka och senaste analysen
+#[derive(Debug, Serialize, Deserialize)]
+struct SaveFile {
+    format: String,
+    version: u32,
+    start_fen: String,
+    moves: Vec<String>,
+    #[serde(default)]
+    resigned: Option<SavedColor>,
+    #[serde(default)]
+    player_color: Option<SavedColor>,
+    #[serde(default)]
+    settings: Option<SavedSettings>,
+    #[serde(default)]
+    clock: Option<SavedClock>,
+    #[serde(default)]
+    analysis: Option<SavedAnalysis>,
+}
+
+// Klockans tider, tiderna vid varje drag (för ångra) och en eventuell förlust på tid
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedClock {
+    white: f64,
+    black: f64,
+    #[serde(default)]
+    snapshots: Vec<[f64; 2]>,
+    #[serde(default)]
+    flagged: Option<SavedColor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedAnalysis {
+    #[serde(default)]
+    depth: u8,
+    moves: Vec<SavedMoveAnalysis>,
+}
+
+// Motorns värden för ett drag; draget, notationen och sidan tas från partiet
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct SavedMoveAnalysis {
+    eval_before: f32,
+    eval_after: f32,
+    centipawn_loss: i32,
+    blunder: bool,
+    mistake: bool,
+    inaccuracy: bool,
+    great: bool,
+    brilliant: bool,
+    best_line: Vec<String>, // UCI
+    material_after: f32,
+    skipped: bool,
+}
+
+// =============================================================
+// DEL 4: SPELLOGIK & DATASTRUKTURER
+// =============================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct PieceKey {
+    piece: Piece,
+    color: ChessColor,
+}
+
+// Blindschack-träning: vilka pjäser som döljs på brädet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlindfoldMode {
+    Off,
+    AllPieces,
+    WhitePieces,
+    BlackPieces,
+}
+
+impl BlindfoldMode {
+    fn next(self) -> Self {
+        match self {
+            BlindfoldMode::Off => BlindfoldMode::AllPieces,
+            BlindfoldMode::AllPieces => BlindfoldMode::WhitePieces,
+            BlindfoldMode::WhitePieces => BlindfoldMode::BlackPieces,
+            BlindfoldMode::BlackPieces => BlindfoldMode::Off,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            BlindfoldMode::Off => "Av",
+            BlindfoldMode::AllPieces => "Alla pjäser",
+            BlindfoldMode::WhitePieces => "Vita pjäser",
+            BlindfoldMode::BlackPieces => "Svarta pjäser",
+        }
+    }
+
+    fn hides(self, color: ChessColor) -> bool {
+        match self {
+            BlindfoldMode::Off => false,
+            BlindfoldMode::AllPieces => true,
+            BlindfoldMode::WhitePieces => color == ChessColor::White,
+            BlindfoldMode::BlackPieces => color == ChessColor::Black,
+        }
+    }
+}
+
+// Hur lagliga drag markeras på brädet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MoveHintStyle {
+    Dots,
+    Rings,
+    Tint,
+}
+
+impl MoveHintStyle {
+    fn next(self) -> Self {
+        match self {
+            MoveHintStyle::Dots => MoveHintStyle::Rings,
+            MoveHintStyle::Rings => MoveHintStyle::Tint,
+            MoveHintStyle::Tint => MoveHintStyle::Dots,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            MoveHintStyle::Dots => "Prickar",
+            MoveHintStyle::Rings => "Ringar",
+            MoveHintStyle::Tint => "Färgad ruta",
+        }
+    }
+}
+
+// Partiets resultat som det skrivs i PGN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GameResult {
+    WhiteWins,
+    BlackWins,
+    Draw,
+    Ongoing, // okänt eller ej avgjort ("*")
+}
+
+impl GameResult {
+    fn pgn_tag(self) -> &'static str {
+        match self {
+            GameResult::WhiteWins => "1-0",
+            GameResult::BlackWins => "0-1",
+            GameResult::Draw => "1/2-1/2",
+            GameResult::Ongoing => "*",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            GameResult::Draw => "½-½",
+            other => other.pgn_tag(),
+        }
+    }
+}
+
+// Betänketid: grundtid i minuter och tillägg per drag i sekunder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TimeControl {
+    minutes: u32,
+    increment: u32,
+}
+
+impl TimeControl {
+    // Valen i inställningsfönstret
+    const CHOICES: [TimeControl; 5] = [
+        TimeControl { minutes: 1, increment: 0 },
+        TimeControl { minutes: 3, increment: 2 },
+        TimeControl { minutes: 5, increment: 0 },
+        TimeControl { minutes: 10, increment: 5 },
+        TimeControl { minutes: 15, increment: 10 },
+    ];
+
+    fn label(self) -> String {
+        format!("{}+{}", self.minutes, self.increment)
+    }
+
+    // "10+5" som i label
+    fn parse(text: &str) -> Option<Self> {
+        let (minutes, increment) = text.split_once('+')?;
+        Some(Self { minutes: minutes.trim().parse().ok()?, increment: increment.trim().parse().ok()? })
+    }
+}
+
+// Schackklocka: återstående tid per sida, räknas ned för den sida som är vid draget
+#[derive(Debug, Clone)]
+struct GameClock {
+    control: TimeControl,
+    remaining: [f64; 2],      // sekunder kvar för vit och svart
+    snapshots: Vec<[f64; 2]>, // tiden när varje drag gjordes, för ångra
+}
+
+impl GameClock {
+    fn new(control: TimeControl) -> Self {
+        let start = control.minutes as f64 * 60.0;
+        Self { control, remaining: [start, start], snapshots: Vec::new() }
+    }
+
+    fn remaining(&self, color: ChessColor) -> f64 {
+        self.remaining[color.to_index()]
+    }
+
+    // Dra av tid för sidan vid draget; sant när tiden just tog slut
+    fn tick(&mut self, color: ChessColor, seconds: f64) -> bool {
+        let remaining = &mut self.remaining[color.to_index()];
+        if *remaining <= 0.0 {
+            return false;
+        }
+        *remaining = (*remaining - seconds).max(0.0);
+        *remaining == 0.0
+    }
+
+    // Anropas när sidan har gjort sitt drag
+    fn complete_move(&mut self, color: ChessColor) {
+        self.snapshots.push(self.remaining);
+        self.remaining[color.to_index()] += self.control.increment as f64;
+    }
+
+    // Återställ tiden till när drag nummer `plies` gjordes (efter ångra)
+    fn rewind_to(&mut self, plies: usize) {
+        if let Some(&snapshot) = self.snapshots.get(plies) {
+            self.remaining = snapshot;
+            self.snapshots.truncate(plies);
+        }
+    }
+}
+
+// Sökhastighet som "4.1 Mnoder/s" eller "850 knoder/s"
+fn format_nps(nps: u64) -> String {
+    if nps >= 1_000_000 {
+        format!("{:.1} Mnoder/s", nps as f64 / 1_000_000.0)
+    } else {
+        format!("{} knoder/s", nps / 1000)
+    }
+}
+
+// Klocktid som m:ss, med tiondelar under tio sekunder
+fn format_clock(seconds: f64) -> String {
+    if seconds < 10.0 {
+        format!("0:{:04.1}", seconds)
+    } else {
+        let whole = seconds.ceil() as u64;
+        format!("{}:{:02}", whole / 60, whole % 60)
+    }
+}
+
+// Varför ett parti slutade remi utan att brädet självt är patt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DrawReason {
+    Repetition,
+    FiftyMoves,
+    InsufficientMaterial,
+    Agreement,
+}
+
+impl DrawReason {
+    fn label(self) -> &'static str {
+        match self {
+            DrawReason::Repetition => "Remi genom trefaldig upprepning",
+            DrawReason::FiftyMoves => "Remi enligt 50-dragsregeln",
+            DrawReason::InsufficientMaterial => "Remi - otillräckligt material",
+            DrawReason::Agreement => "1/2-1/2 genom överenskommelse",
+        }
+    }
+}
+
+// Hur ett parti slutade när brädet självt inte avgör det (matt och patt syns på brädet)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Termination {
+    Resigned(ChessColor), // sidan som gav upp
+    Flagged(ChessColor),  // sidan vars tid tog slut
+    Draw(DrawReason),     // remi som krävts eller som parterna kommit överens om
+}
+
+// Visningsläge för streaming/demo: brädet visas men går inte att styra
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpectatorMode {
+    Off,
+    ReadOnly,   // panelen syns men all inmatning är avstängd
+    CleanBoard, // som ReadOnly men utan kontrollpanel
+}
+
+impl SpectatorMode {
+    fn next(self) -> Self {
+        match self {
+            SpectatorMode::Off => SpectatorMode::ReadOnly,
+            SpectatorMode::ReadOnly => SpectatorMode::CleanBoard,
+            SpectatorMode::CleanBoard => SpectatorMode::Off,
+        }
+    }
+
+    fn is_active(self) -> bool {
+        self != SpectatorMode::Off
+    }
+}
+
+// När motorn räknar fram evalueringen som visas i stapeln
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EngineEvalMode {
+    Live,      // motorns evaluering från varje AI-sökning
+    OnRequest, // materialevaluering under partiet; motorn bara på begäran (E) och vid partislut
+}
+
+// Vem som spelar vilken sida
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GameMode {
+    VsEngine,       // spelaren mot motorn
+    HotSeat,        // två spelare vid samma dator, motorn spelar inte
+    EngineVsEngine, // motorn spelar båda sidor, spelaren tittar på
+}
+
+impl GameMode {
+    fn next(self) -> Self {
+        match self {
+            GameMode::VsEngine => GameMode::HotSeat,
+            GameMode::HotSeat => GameMode::EngineVsEngine,
+            GameMode::EngineVsEngine => GameMode::VsEngine,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            GameMode::VsEngine => "Mot motor",
+            GameMode::HotSeat => "Två spelare",
+            GameMode::EngineVsEngine => "Motormatch",
+        }
+    }
+
+    // Nyckel i sparfilen
+    fn key(self) -> &'static str {
+        match self {
+            GameMode::VsEngine => "vs_engine",
+            GameMode::HotSeat => "hot_seat",
+            GameMode::EngineVsEngine => "engine_vs_engine",
+        }
+    }
+
+    fn parse(key: &str) -> Option<Self> {
+        [GameMode::VsEngine, GameMode::HotSeat, GameMode::EngineVsEngine]
+            .into_iter()
+            .find(|mode| mode.key() == key)
+    }
+}
+
+// Vad ett klick på en ruta leder till
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BoardClick {
+    Ignored,  // granskning, motormatch eller motståndarens drag
+    GameOver, // partiet är slut; spelaren får ett meddelande i stället
+    Play,
+}
+
+// Efter partislut går brädet aldrig att röra, oavsett spelläge. Analys, export och nytt
+// parti sköts av knapparna och påverkas inte.
+fn board_click(game_over: bool, review_mode: bool, game_mode: GameMode, side_to_move: ChessColor, human: ChessColor) -> BoardClick {
+    if review_mode {
+        BoardClick::Ignored
+    } else if game_over {
+        BoardClick::GameOver
+    } else if game_mode == GameMode::EngineVsEngine || side_to_move != human {
+        BoardClick::Ignored
+    } else {
+        BoardClick::Play
+    }
+}
+
+struct GameSettings {
+    player_color: ChessColor,
+    board_flipped: bool,
+    blindfold: BlindfoldMode,
+    eval_precision: usize, // antal decimaler när evalueringar visas
+    player_perspective: bool, // visa evalueringar ur spelarens synvinkel i stället för vits
+    analysis_depth: u8,       // sökdjup för partianalysen
+    show_eval_comparison: bool, // visa material- och motorevaluering bredvid varandra
+    move_hint_style: MoveHintStyle,
+    sound_enabled: bool,        // huvudbrytare för alla ljud
+    distinct_move_sounds: bool, // olika ljud för spelarens och AI:ns drag
+    ai_min_delay_ms: u32,       // minsta tid innan AI:ns drag visas
+    include_analysis_in_pgn: bool, // kommentera misstag med motorns förslag vid export
+    move_overhead_ms: u32,      // Stockfish "Move Overhead" för tidsbaserat spel
+    animations: bool,           // av = drag och evalueringsstapel hoppar direkt till nytt läge
+    coordinate_size: u16,       // teckenstorlek för koordinaterna, 0 = dolda
+    coordinates_inside: bool,   // koordinaterna i rutornas hörn i stället för i marginalen
+    board_border: f32,          // ramens bredd runt brädet i pixlar
+    engine_eval_mode: EngineEvalMode,
+    elo_limit: Option<u16>,     // UCI_Elo-mål, None = full styrka
+    use_opening_book: bool,     // låt motorn spela bokdrag när boken har ställningen
+    image_size: u32,            // sidlängd i pixlar för exporterad brädbild
+    time_control: Option<TimeControl>, // None = ingen klocka
+    game_mode: GameMode,
+    hot_seat_auto_flip: bool,   // vänd brädet efter varje drag i tvåspelarläget
+    autoplay_elo: [Option<u16>; 2], // UCI_Elo för vit och svart i motormatchen, None = full styrka
+    player_names: [String; 2],  // ditt namn och motspelarens i tvåspelarläget, för PGN-huvudet
+}
+
+// Ett spelat drag: draget självt, dess SAN och ställningen efteråt. Historiken behöver
+// därför aldrig tolka text igen för att spela upp partiet.
+#[derive(Debug, Clone)]
+struct MoveRecord {
+    chess_move: ChessMove,
+    san: String,
+    board_after: Board,
+}
+
+impl MoveRecord {
+    // SAN beror på ställningen före draget, så posten skapas innan brädet uppdateras
+    fn new(board: &Board, chess_move: ChessMove) -> Self {
+        MoveRecord {
+            chess_move,
+            san: move_to_san(board, chess_move),
+            board_after: board.make_move_new(chess_move),
+        }
+    }
+}
+
+// Sparad övningsställning som kan spelas om med en knapptryckning
+#[derive(Debug, Clone, Copy)]
+struct DrillPosition {
+    board: Board,
+    history_len: usize,
+}
+
+impl DrillPosition {
+    // Korta historiken till övningsställningen; ångrade drag går inte att göra om därifrån.
+    // Har partiet ångrats förbi ställningen eller spelats vidare på en annan väg finns den
+    // inte längre i historiken, och då lämnas allt orört.
+    fn rewind(&self, start: Board, history: &mut Vec<MoveRecord>, redo_stack: &mut Vec<MoveRecord>) -> bool {
+        if history.len() < self.history_len {
+            return false;
+        }
+        let board = history[..self.history_len].last().map_or(start, |record| record.board_after);
+        if board != self.board {
+            return false;
+        }
+        history.truncate(self.history_len);
+        redo_stack.clear();
+        true
+    }
+}
+
+// Pågående PGN-import i bakgrunden
+struct PgnImport {
+    name: String,
+    receiver: mpsc::Receiver<PgnImportMessage>,
+    cancel: Arc<AtomicBool>,
+    done: usize,
+    total: usize,
+}
+
+#[derive(Debug)]
+enum AiState {
+    Idle,
+    Thinking(mpsc::Receiver<Option<SearchResult>>),
+    Analyzing(mpsc::Receiver<Vec<(Vec<ChessMove>, f32)>>), // positionsanalys med flera kandidatvarianter
+}
+
+// Pågående oändlig analys av ställningen som visas
+struct LiveAnalysis {
+    board: Board,
+    cancel: Arc<AtomicBool>,
+    receiver: mpsc::Receiver<AnalysisInfo>,
+    latest: Option<AnalysisInfo>,
+}
+
+impl LiveAnalysis {
+    fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+}
+
+struct ChessGame {
+    board: Board,
+    start_board: Board, // ställningen partiet startade från (standard eller inläst FEN)
+    start_fullmove: usize, // startställningens dragnummer enligt FEN
+    start_halfmove: usize, // startställningens halvdrag sedan slag eller bondedrag enligt FEN
+    selected_square: Option<Square>,
+    highlighted_moves: Vec<ChessMove>,
+    settings: GameSettings,
+    game_over: bool,
+    result_override: Option<GameResult>, // manuellt satt resultat, t.ex. för avbrutna partier
+    ai_state: AiState,
+    ai_stop: Option<Arc<AtomicBool>>, // avbryter AI:ns pågående sökning
+    engine_restarting: bool,          // motorn startas om efter en krasch
+    ai_started_at: f64, // när AI:ns senaste sökning startade
+    ai_progress: Option<mpsc::Receiver<SearchProgress>>, // förloppet för AI:ns pågående sökning
+    latest_progress: Option<SearchProgress>,
+    search_limit: SearchLimit, // djup eller tid som den pågående sökningen körs med
+    search_by_time: bool,      // AI:n söker med fast betänketid i stället för fast djup
+    deep_search_armed: bool, // nästa sökning körs på DEEP_SEARCH_DEPTH
+    textures: HashMap<PieceKey, Texture2D>,
+    move_history: Vec<MoveRecord>,
+    current_analysis: Option<String>,
+    
+    // Nya fält för partianalys
+    game_analysis: Option<GameAnalysis>,
+    analysis_in_progress: bool,
+    analysis_receiver: Option<mpsc::Receiver<GameAnalysisMessage>>,
+    analysis_progress: (usize, usize), // (analyserade drag, totalt) för förloppsindikatorn
+    analysis_cancel: Option<(Arc<AtomicBool>, Arc<AtomicBool>)>, // (analystrådens flagga, motorns stoppflagga)
+    analysis_phase: AnalysisPhase,
+    analysis_history: Vec<GameAnalysis>, // tidigare analyser av partiet, äldst först
+    analysis_pv: Vec<ChessMove>,         // huvudvariant från senaste positionsanalysen
+    
+    // Nya fält för positionsvisning
+    review_mode: bool,
+    review_board: Option<Board>,
+    review_move_index: Option<usize>,
+    
+    // Motorinformation från UCI-handskakningen (None om Stockfish saknas)
+    engine_info: Option<EngineInfo>,
+    show_engine_info: bool,
+    show_settings: bool,
+    settings_scroll: usize, // första synliga raden i inställningsfönstret
+    show_report: bool,
+    fen_input: Option<String>, // text i FEN-rutan; None = rutan är stängd
+    window_title: String,      // fönstrets nuvarande titel
+    name_input: Option<(usize, String)>, // namnet som redigeras: plats i player_names och texten
+    engine_notice: bool,       // rutan om att ingen motor hittades visas
+    flip_overridden: bool,     // brädet har roterats för hand bort från den egna färgens vy
+    view_flipped: Option<bool>, // tillfällig vändning av vyn, t.ex. i tvåspelarläget; sparas inte
+    editor: Option<BoardEditor>, // ställningsredigeraren är öppen och ersätter panelen
+    puzzle: Option<PuzzleSession>, // taktikläget: uppgifterna och hur långt de lösts
+    puzzle_prompt: bool,           // frågan om att lämna pågående parti för taktikläget
+    settings_dirty: bool,      // inställningarna har ändrats sedan filen senast lästes eller skrevs
+    engine_path: Option<String>, // motorns sökväg till inställningsfilen; utan motor den sparade
+    redo_stack: Vec<MoveRecord>, // ångrade drag, senast ångrade sist
+    last_move: Option<ChessMove>,        // senaste draget i partiet
+    review_last_move: Option<ChessMove>, // draget som ledde till den granskade ställningen
+    fen_error: Option<String>,
+    
+    // Övningsställning för snabb omstart (R)
+    drill_position: Option<DrillPosition>,
+    
+    // Bondeförvandling som väntar på att spelaren väljer pjäs (från, till)
+    pending_promotion: Option<(Square, Square)>,
+    
+    // Motorns senaste evaluering av den levande ställningen (för evalueringsstapeln)
+    live_eval: Option<Eval>,
+    requested_eval: Option<(u64, Eval)>,               // motorevaluering på begäran och ställningens hash
+    eval_request: Option<(u64, mpsc::Receiver<Eval>)>, // pågående begäran
+    eval_request_wanted: bool,                          // spelaren tryckte E
+    infinite_analysis: bool,                            // analysera ställningen löpande medan spelet står still
+    live_analysis: Option<LiveAnalysis>,
+    last_eval_request: Option<u64>,                     // hash för senast begärda ställning
+    
+    // Släppta filer som läses in nästa bildruta, så att "Laddar…" hinner visas
+    pending_drop: Option<Vec<DroppedFile>>,
+    toasts: Toasts,
+    
+    // Autosparat parti som spelaren kan välja att fortsätta vid start
+    resume_prompt: Option<String>,
+    
+    // Hur många gånger ställningen efter varje halvdrag har förekommit (1 = första gången)
+    position_occurrences: Vec<usize>,
+    // Halvdrag sedan senaste slag eller bondedrag
+    halfmove_clock: usize,
+    // Uppgivning, förlust på tid eller remi som krävts eller gått med på
+    termination: Option<Termination>,
+    clock: Option<GameClock>,      // None när ingen betänketid är vald
+    autoplay_paused: bool,         // motormatchen är stoppad med knappen
+    // Motorns bedömning av ett remierbjudande som väntar på svar
+    draw_offer: Option<mpsc::Receiver<Eval>>,
+    
+    // PGN-import som körs i bakgrunden
+    pgn_import: Option<PgnImport>,
+    
+    // Pågående draganimation (draget och starttid) samt evalueringsstapelns visade andel vitt
+    move_animation: Option<(ChessMove, f64)>,
+    eval_bar_share: Cell<f32>,
+    
+    // Motoroptioner som ändrats i inställningarna men inte skickats än
+    engine_options_dirty: bool,
+    
+    // Åskådarläge (växlas med F2)
+    spectator: SpectatorMode,
+    
+    // Öppning enligt boken; book_plies visar var partiet lämnade teorin
+    opening: Option<OpeningMatch>,
+    
+    // Polyglot-bok som motorn spelar ur i öppningen, om en lästs in
+    engine_book: Option<PolyglotBook>,
+    
+    // Analysfönstrets draglista: första synliga drag och varje drags (index, överkant, underkant)
+    // från senaste ritningen, inklusive "Bäst:"-rad och separator
+    analysis_scroll: usize,
+    analysis_rows: RefCell<Vec<(usize, f32, f32)>>,
+    // Senaste hopp mellan fel: (dragindex, starttid) för blinkningen i draglistorna
+    error_flash: Option<(usize, f64)>,
+    
+    // Panelens draglista: antal drag bakåt från slutet och var listan ritades senast
+    move_list_offset: usize,
+    move_list_top: Cell<f32>,
+    layout: Layout,
+    
+    // Dragljud (None om ljud saknas i bygget)
+    sounds: Option<MoveSounds>,
+    
+    // UI-komponenter
+    depth_slider: Slider,
+    movetime_slider: Slider,
+    skill_slider: Slider,
+    applied_skill_level: Option<u8>, // senaste Skill Level som skickats till motorn
+    search_mode_button: Button,
+    resign_button: Button,
+    export_button: Button,
+    flip_button: Button,
+    game_mode_button: Button,
+    white_button: Button,
+    black_button: Button,
+    new_game_button: Button,
+    draw_offer_button: Button,
+    autoplay_button: Button, // på remiknappens plats under motormatchen
+    analyze_button: Button,
+    phase_button: Button,
+    engine_info_button: Button,
+    settings_button: Button,
+    drill_button: Button,
+    report_button: Button,
+    resume_yes_button: Button,
+    fen_button: Button,
+    infinite_analysis_button: Button,
+    save_game_button: Button,
+    export_image_button: Button,
+    mute_button: Button,
+    load_game_button: Button,
+    claim_draw_button: Button,
+    // Navigering i partiet under brädet: början, bakåt, framåt, slutet
+    nav_first_button: Button,
+    nav_prev_button: Button,
+    nav_next_button: Button,
+    nav_last_button: Button,
+    copy_fen_button: Button,
+    undo_button: Button,
+    redo_button: Button,
+    prev_error_button: Button,
+    next_error_button: Button,
+    paste_fen_button: Button,
+    fen_load_button: Button,
+    fen_cancel_button: Button,
+    name_save_button: Button,
+    name_cancel_button: Button,
+    engine_notice_button: Button,
+    editor_button: Button,
+    editor_eraser_button: Button,
+    editor_side_button: Button,
+    editor_castle_buttons: [Button; 4],
+    editor_clear_button: Button,
+    editor_reset_button: Button,
+    editor_start_button: Button,
+    editor_cancel_button: Button,
+    resume_no_button: Button,
+    import_cancel_button: Button,
+    puzzle_yes_button: Button,
+    puzzle_no_button: Button,
+}
+
+impl ChessGame {
+    fn new(textures: HashMap<PieceKey, Texture2D>, engine_info: Option<EngineInfo>) -> Self {
+        let layout = Layout::current();
+        let panel_x = layout.panel_x;
+        let window_title = base_window_title(engine_info.as_ref());
+        // Utan motor finns bara spel mellan två personer
+        let has_engine = engine_info.is_some();
+        let engine_path = engine_info.as_ref().map(|info| info.path.clone());
+
+        Self {
+            board: Board::default(),
+            start_board: Board::default(),
+            start_fullmove: 1,
+            start_halfmove: 0,
+            selected_square: None,
+            highlighted_moves: Vec::new(),
+            settings: GameSettings { 
+                player_color: ChessColor::White,
+                board_flipped: false,
+                blindfold: BlindfoldMode::Off,
+                eval_precision: 2,
+                player_perspective: false,
+                analysis_depth: 15, // Djupare analys för bättre precision
+                show_eval_comparison: false,
+                move_hint_style: MoveHintStyle::Dots,
+                sound_enabled: true,
+                distinct_move_sounds: true,
+                ai_min_delay_ms: 0,
+                include_analysis_in_pgn: false,
+                move_overhead_ms: 10,
+                elo_limit: None,
+                use_opening_book: true,
+                image_size: 800,
+                time_control: None,
+                game_mode: if has_engine { GameMode::VsEngine } else { GameMode::HotSeat },
+                hot_seat_auto_flip: true,
+                autoplay_elo: [Some(1500), Some(2500)],
+                player_names: ["Spelare".to_string(), "Spelare 2".to_string()],
+                animations: true,
+                coordinate_size: 24,
+                coordinates_inside: false,
+                board_border: 0.0,
+                // Utan motor finns ingen liveevaluering att visa
+                engine_eval_mode: if engine_info.is_some() { EngineEvalMode::Live } else { EngineEvalMode::OnRequest },
+            },
+            game_over: false,
+            result_override: None,
+            ai_state: AiState::Idle,
+            ai_stop: None,
+            engine_restarting: false,
+            ai_started_at: 0.0,
+            ai_progress: None,
+            latest_progress: None,
+            search_limit: SearchLimit::Depth(0),
+            search_by_time: false,
+            deep_search_armed: false,
+            textures,
+            move_history: Vec::new(),
+            current_analysis: None,
+            game_analysis: None,
+            analysis_in_progress: false,
+            analysis_receiver: None,
+            analysis_progress: (0, 0),
+            analysis_cancel: None,
+            analysis_phase: AnalysisPhase::All,
+            analysis_history: Vec::new(),
+            analysis_pv: Vec::new(),
+            review_mode: false,
+            review_board: None,
+            review_move_index: None,
+            engine_info,
+            show_engine_info: false,
+            show_settings: false,
+            settings_scroll: 0,
+            show_report: false,
+            fen_input: None,
+            window_title,
+            name_input: None,
+            engine_notice: !has_engine,
+            flip_overridden: false,
+            view_flipped: None,
+            editor: None,
+            puzzle: None,
+            puzzle_prompt: false,
+            settings_dirty: false,
+            engine_path,
+            redo_stack: Vec::new(),
+            last_move: None,
+            review_last_move: None,
+            fen_error: None,
+            drill_position: None,
+            pending_promotion: None,
+            live_eval: None,
+            requested_eval: None,
+            eval_request: None,
+            eval_request_wanted: false,
+            infinite_analysis: false,
+            live_analysis: None,
+            last_eval_request: None,
+            pending_drop: None,
+            toasts: Toasts::new(),
+            resume_prompt: None,
+            position_occurrences: Vec::new(),
+            halfmove_clock: 0,
+            termination: None,
+            clock: None,
+            autoplay_paused: false,
+            draw_offer: None,
+            pgn_import: None,
+            move_animation: None,
+            eval_bar_share: Cell::new(0.5),
+            engine_options_dirty: true,
+            spectator: SpectatorMode::Off,
+            opening: None,
+            engine_book: None,
+            analysis_scroll: 0,
+            error_flash: None,
+            analysis_rows: RefCell::new(Vec::new()),
+            move_list_offset: 0,
+            move_list_top: Cell::new(0.0),
+            layout,
+            sounds: None,
+            depth_slider: Slider::new(panel_x, 120.0, 150.0, 20.0, 1.0, 30.0, 10.0),
+            movetime_slider: Slider::new(panel_x, 120.0, 150.0, 20.0, 1.0, 30.0, 2.0),
+            skill_slider: Slider::new(panel_x, 165.0, 150.0, 20.0, 0.0, 20.0, 20.0),
+            applied_skill_level: None,
+            search_mode_button: Button::new(panel_x + 100.0, 86.0, 80.0, 22.0, "Läge: Djup"),
+            resign_button: Button::new(panel_x, 205.0, 70.0, 30.0, "Ge upp"),
+            export_button: Button::new(panel_x + 75.0, 205.0, 70.0, 30.0, "Export"),
+            flip_button: Button::new(panel_x, 245.0, 88.0, 30.0, "Rotera bräde"),
+            game_mode_button: Button::new(panel_x + 92.0, 245.0, 88.0, 30.0, "Mot motor"),
+            white_button: Button::new(panel_x, 285.0, 70.0, 30.0, "Vit"),
+            black_button: Button::new(panel_x + 75.0, 285.0, 70.0, 30.0, "Svart"),
+            new_game_button: Button::new(panel_x, 325.0, 88.0, 30.0, "Nytt spel"),
+            draw_offer_button: Button::new(panel_x + 92.0, 325.0, 88.0, 30.0, "Erbjud remi"),
+            autoplay_button: Button::new(panel_x + 92.0, 325.0, 88.0, 30.0, "Stoppa"),
+            analyze_button: Button::new(panel_x, 365.0, 95.0, 30.0, "Analysera"),
+            phase_button: Button::new(panel_x + 100.0, 365.0, 45.0, 30.0, AnalysisPhase::All.label()),
+            copy_fen_button: Button::new(panel_x, 405.0, 88.0, 30.0, "Kopiera FEN"),
+            paste_fen_button: Button::new(panel_x + 92.0, 405.0, 88.0, 30.0, "Klistra in"),
+            undo_button: Button::new(panel_x, 445.0, 88.0, 30.0, "Ångra"),
+            redo_button: Button::new(panel_x + 92.0, 445.0, 88.0, 30.0, "Gör om"),
+            prev_error_button: Button::new(0.0, 0.0, 145.0, 30.0, "⟨ Föregående fel"),
+            next_error_button: Button::new(0.0, 0.0, 145.0, 30.0, "Nästa fel ⟩"),
+            engine_info_button: Button::new(panel_x, 10.0, 145.0, 30.0, "Motorinfo"),
+            settings_button: Button::new(panel_x - 155.0, 10.0, 145.0, 30.0, "Inställningar"),
+            drill_button: Button::new(panel_x - 310.0, 10.0, 145.0, 30.0, "Spara övning"),
+            report_button: Button::new(panel_x - 465.0, 10.0, 145.0, 30.0, "Visa rapport"),
+            resume_yes_button: Button::new(330.0, 410.0, 160.0, 40.0, "Ja, fortsätt"),
+            fen_button: Button::new(panel_x - 620.0, 10.0, 145.0, 30.0, "Ange FEN"),
+            infinite_analysis_button: Button::new(panel_x - 775.0, 10.0, 145.0, 30.0, "Oändlig analys: Av"),
+            save_game_button: Button::new(panel_x - 155.0, 48.0, 145.0, 26.0, "Spara spel"),
+            export_image_button: Button::new(panel_x - 465.0, 48.0, 145.0, 26.0, "Exportera bild"),
+            mute_button: Button::new(panel_x - 620.0, 48.0, 145.0, 26.0, "Ljud: På"),
+            load_game_button: Button::new(panel_x - 310.0, 48.0, 145.0, 26.0, "Ladda spel"),
+            // Knapparna under brädet placeras av place_board_buttons
+            claim_draw_button: Button::new(0.0, 0.0, 180.0, 28.0, "Kräv remi (50 drag)"),
+            nav_first_button: Button::new(0.0, 0.0, NAV_BUTTON_WIDTH, 28.0, "|<"),
+            nav_prev_button: Button::new(0.0, 0.0, NAV_BUTTON_WIDTH, 28.0, "<"),
+            nav_next_button: Button::new(0.0, 0.0, NAV_BUTTON_WIDTH, 28.0, ">"),
+            nav_last_button: Button::new(0.0, 0.0, NAV_BUTTON_WIDTH, 28.0, ">|"),
+            fen_load_button: Button::new(480.0, 440.0, 120.0, 34.0, "Läs in"),
+            fen_cancel_button: Button::new(610.0, 440.0, 120.0, 34.0, "Avbryt"),
+            name_save_button: Button::new(430.0, 440.0, 120.0, 34.0, "Spara"),
+            name_cancel_button: Button::new(560.0, 440.0, 120.0, 34.0, "Avbryt"),
+            engine_notice_button: Button::new(430.0, 520.0, 140.0, 36.0, "Förstått"),
+            editor_button: Button::new(panel_x - 775.0, 48.0, 145.0, 26.0, "Ställ upp bräde"),
+            editor_eraser_button: Button::new(panel_x, EDITOR_PALETTE_Y + 2.0 * EDITOR_CELL + 8.0, 180.0, 28.0, "Sudda"),
+            editor_side_button: Button::new(panel_x, 220.0, 180.0, 28.0, "Vit vid draget"),
+            editor_castle_buttons: [
+                Button::new(panel_x, 285.0, 88.0, 28.0, "Vit O-O"),
+                Button::new(panel_x + 92.0, 285.0, 88.0, 28.0, "Vit O-O-O"),
+                Button::new(panel_x, 318.0, 88.0, 28.0, "Svart O-O"),
+                Button::new(panel_x + 92.0, 318.0, 88.0, 28.0, "Svart O-O-O"),
+            ],
+            editor_clear_button: Button::new(panel_x, 365.0, 180.0, 28.0, "Töm brädet"),
+            editor_reset_button: Button::new(panel_x, 398.0, 180.0, 28.0, "Utgångsställning"),
+            editor_start_button: Button::new(panel_x - 10.0, 445.0, 200.0, 34.0, "Starta från ställningen"),
+            editor_cancel_button: Button::new(panel_x, 488.0, 180.0, 28.0, "Avbryt"),
+            resume_no_button: Button::new(510.0, 410.0, 160.0, 40.0, "Nej, nytt parti"),
+            import_cancel_button: Button::new(590.0, 435.0, 100.0, 30.0, "Avbryt"),
+            puzzle_yes_button: Button::new(330.0, 410.0, 160.0, 40.0, "Ja, lämna partiet"),
+            puzzle_no_button: Button::new(510.0, 410.0, 160.0, 40.0, "Nej, spela vidare"),
+        }
+    }
+
+    fn make_move(&mut self, m: ChessMove) {
+        println!("[make_move] Utför drag: {m}");
+        
+        // Lägg till i draghistorik; ett nytt drag gör de ångrade ogiltiga
+        self.move_history.push(MoveRecord::new(&self.board, m));
+        self.redo_stack.clear();
+        // Ett obesvarat remierbjudande gäller inte längre när ett drag spelats
+        self.draw_offer = None;
+        // Ljudet bestäms av ställningen före draget
+        let sound = SoundEvent::for_move(&self.board, m);
+        let by_ai = self.settings.game_mode == GameMode::EngineVsEngine || self.board.side_to_move() != self.human_color();
+        if let Some(clock) = &mut self.clock {
+            clock.complete_move(self.board.side_to_move());
+        }
+        
+        self.board = self.board.make_move_new(m);
+        self.analysis_pv.clear();
+        self.selected_square = None;
+        self.highlighted_moves.clear();
+        self.move_animation = self.settings.animations.then(|| (m, get_time()));
+        self.update_repetitions();
+        self.update_game_state();
+        self.update_opening();
+        self.update_last_move();
+        self.ai_state = AiState::Idle;
+        self.auto_flip_hot_seat();
+        // Ett avgörande drag får partislutets ljud i stället, från end_game
+        if !self.game_over {
+            self.play_sound(sound, by_ai);
+        }
+        self.autosave();
+    }
+
+    // Senast spelade drag, för markeringen på brädet
+    fn update_last_move(&mut self) {
+        let played = self.played_move_count();
+        self.last_move = played.checked_sub(1).map(|index| self.move_history[index].chess_move);
+    }
+
+    // Räkna om hur ofta varje ställning i partiet har förekommit, via brädets hash
+    fn update_repetitions(&mut self) {
+        let mut seen: HashMap<u64, usize> = HashMap::new();
+        let mut board = self.start_board;
+        seen.insert(board.get_hash(), 1);
+
+        self.position_occurrences.clear();
+        self.halfmove_clock = self.start_halfmove;
+        for record in &self.move_history {
+            let chess_move = record.chess_move;
+            let resets_clock = board.piece_on(chess_move.get_source()) == Some(Piece::Pawn)
+                || board.piece_on(chess_move.get_dest()).is_some();
+            self.halfmove_clock = if resets_clock { 0 } else { self.halfmove_clock + 1 };
+            board = record.board_after;
+            let count = seen.entry(board.get_hash()).or_insert(0);
+            *count += 1;
+            self.position_occurrences.push(*count);
+        }
+    }
+
+    // Öppningsboken gäller bara partier från grundställningen
+    fn update_opening(&mut self) {
+        self.opening = if self.start_board == Board::default() {
+            match_opening(&self.move_history)
+        } else {
+            None
+        };
+    }
+
+    // Draget i SAN för aktuell ställning (draget är ännu inte gjort)
+    fn format_move(&self, chess_move: ChessMove) -> String {
+        move_to_san(&self.board, chess_move)
+    }
+
+    fn reset_game(&mut self) {
+        self.board = Board::default();
+        self.start_board = Board::default();
+        self.start_fullmove = 1;
+        self.start_halfmove = 0;
+        self.selected_square = None;
+        self.highlighted_moves.clear();
+        self.game_over = false;
+        self.result_override = None;
+        self.move_list_offset = 0;
+        self.analysis_scroll = 0;
+        self.move_animation = None;
+        self.show_report = false;
+        self.analysis_pv.clear();
+        self.requested_eval = None;
+        self.eval_request = None;
+        self.last_eval_request = None;
+        self.opening = None;
+        self.last_move = None;
+        self.position_occurrences.clear();
+        self.halfmove_clock = 0;
+        self.termination = None;
+        self.draw_offer = None;
+        self.clock = self.settings.time_control.map(GameClock::new);
+        self.autoplay_paused = false;
+        self.cancel_ai_search();
+        self.move_history.clear();
+        self.redo_stack.clear();
+        self.current_analysis = None;
+        self.game_analysis = None;
+        self.cancel_game_analysis();
+        self.analysis_cancel = None;
+        self.analysis_in_progress = false;
+        self.analysis_receiver = None;
+        self.analysis_history.clear();
+        self.review_mode = false;
+        self.review_board = None;
+        self.review_move_index = None;
+        self.drill_position = None;
+        self.puzzle = None;
+        self.pending_promotion = None;
+        self.live_eval = None;
+        self.auto_flip_hot_seat();
+        self.autosave();
+    }
+
+    // Läs in en ställning från FEN och börja ett nytt parti därifrån
+    fn load_fen(&mut self, fen: &str) -> Result<(), String> {
+        let board = parse_fen(fen)?;
+        let (start_halfmove, start_fullmove) = fen_counters(fen);
+        self.load_imported(ImportedGame { start_halfmove, start_fullmove, ..ImportedGame::from_position(board) });
+        Ok(())
+    }
+
+    // Läs in det första partiet i en PGN-text
+    fn load_pgn(&mut self, text: &str) -> Result<(), String> {
+        let game = parse_pgn(text)?;
+        self.load_imported(game);
+        Ok(())
+    }
+
+    fn load_imported(&mut self, game: ImportedGame) {
+        self.reset_game();
+        self.start_board = game.start;
+        self.start_fullmove = game.start_fullmove;
+        self.start_halfmove = game.start_halfmove;
+        self.board = game.start;
+        for chess_move in game.moves {
+            let record = MoveRecord::new(&self.board, chess_move);
+            self.board = record.board_after;
+            self.move_history.push(record);
+        }
+        self.update_repetitions();
+        self.update_game_state();
+        self.update_opening();
+        self.update_last_move();
+        self.auto_flip_hot_seat();
+        self.autosave();
+        println!("[load_imported] {} drag inlästa", self.move_history.len());
+    }
+
+    // Läs in en fil utifrån filändelsen: .pgn till PGN-importen, .fen till FEN-inläsningen
+    fn load_file(&mut self, name: &str, bytes: &[u8]) -> Result<(), String> {
+        if name.to_lowercase().ends_with(".bin") {
+            let book = PolyglotBook::from_bytes(bytes).map_err(|e| format!("{}: {}", name, e))?;
+            self.set_book(book);
+            return Ok(());
+        }
+        let text = std::str::from_utf8(bytes).map_err(|_| format!("'{}' är ingen textfil", name))?;
+        let lower = name.to_lowercase();
+        if lower.ends_with(".pgn") {
+            self.start_pgn_import(name, text.to_string());
+            Ok(())
+        } else if lower.ends_with(".fen") {
+            self.load_fen(text.lines().next().unwrap_or(""))
+        } else if lower.ends_with(".puz") {
+            self.load_puzzles(text)
+        } else {
+            Err(format!("Okänd filtyp: '{}'", name))
+        }
+    }
+
+    // Läs in en Polyglot-bok som motorn spelar ur så länge ställningen finns i den
+    fn load_book(&mut self, path: &str) -> Result<(), String> {
+        let book = PolyglotBook::load(path)?;
+        self.set_book(book);
+        Ok(())
+    }
+
+    fn set_book(&mut self, book: PolyglotBook) {
+        println!("[load_book] Öppningsbok inläst: {} poster", book.len());
+        self.engine_book = Some(book);
+    }
+
+    // Stora PGN-filer tolkas i bakgrunden så att fönstret inte fryser
+    fn start_pgn_import(&mut self, name: &str, text: String) {
+        self.cancel_pgn_import();
+        let (receiver, cancel) = import_pgn_async(text);
+        self.pgn_import = Some(PgnImport { name: name.to_string(), receiver, cancel, done: 0, total: 0 });
+    }
+
+    fn cancel_pgn_import(&mut self) {
+        if let Some(import) = self.pgn_import.take() {
+            import.cancel.store(true, Ordering::Relaxed);
+            self.toast(format!("Import av {} avbruten", import.name));
+        }
+    }
+
+    fn poll_pgn_import(&mut self) {
+        let Some(ref mut import) = self.pgn_import else {
+            return;
+        };
+
+        let finished = loop {
+            match import.receiver.try_recv() {
+                Ok(PgnImportMessage::Progress { done, total }) => {
+                    import.done = done;
+                    import.total = total;
+                }
+                Ok(PgnImportMessage::Done(result)) => break result,
+                Err(mpsc::TryRecvError::Empty) => return,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    break Err("Importtråden avslutades oväntat".to_string());
+                }
+            }
+        };
+
+        let name = import.name.clone();
+        self.pgn_import = None;
+        match finished {
+            Ok(mut games) => {
+                let count = games.len();
+                self.load_imported(games.swap_remove(0));
+                if count > 1 {
+                    self.toast(format!("✓ Läste in {} (parti 1 av {})", name, count));
+                } else {
+                    self.toast(format!("✓ Läste in {}", name));
+                }
+            }
+            Err(e) => self.toast(format!("⚠ {}", e)),
+        }
+    }
+
+    // Förloppsindikator med avbryt-knapp medan en PGN-import pågår
+    fn draw_pgn_import(&self) {
+        let Some(ref import) = self.pgn_import else {
+            return;
+        };
+
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.5));
+        let (width, height) = IMPORT_DIALOG_SIZE;
+        let (x, y) = overlay_origin(width, height);
+        draw_rectangle(x, y, width, height, WHITE);
+        draw_rectangle_lines(x, y, width, height, 3.0, DARKGRAY);
+        draw_text(&format!("Läser in {}", import.name), x + 20.0, y + 35.0, 20.0, BLACK);
+
+        let share = if import.total > 0 { import.done as f32 / import.total as f32 } else { 0.0 };
+        draw_rectangle(x + 20.0, y + 50.0, width - 40.0, 20.0, LIGHTGRAY);
+        draw_rectangle(x + 20.0, y + 50.0, (width - 40.0) * share, 20.0, DARKGREEN);
+        draw_text(&format!("{} / {} partier", import.done, import.total), x + 20.0, y + 90.0, 14.0, DARKGRAY);
+
+        self.import_cancel_button.draw();
+    }
+
+    // Visa ett kort meddelande för spelaren
+    fn toast(&mut self, message: impl Into<String>) {
+        self.toasts.push(message.into(), Toasts::DURATION);
+    }
+
+    // Ta emot filer som dras och släpps på fönstret; den första som går att läsa används
+    fn handle_dropped_files(&mut self) {
+        if let Some(files) = self.pending_drop.take() {
+            self.toasts.dismiss(LOADING_TOAST);
+            let mut last_error = String::new();
+            let mut loaded = None;
+
+            for file in files {
+                let name = file.path.as_ref()
+                    .and_then(|p| p.file_name())
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let bytes = match (file.bytes, &file.path) {
+                    (Some(bytes), _) => bytes,
+                    (None, Some(path)) => match std::fs::read(path) {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            last_error = format!("Kunde inte läsa '{}': {}", name, e);
+                            continue;
+                        }
+                    },
+                    (None, None) => continue,
+                };
+
+                match self.load_file(&name, &bytes) {
+                    Ok(()) => {
+                        loaded = Some(name);
+                        break;
+                    }
+                    Err(e) => last_error = e,
+                }
+            }
+
+            match loaded {
+                // PGN-importen meddelar själv när den är klar
+                Some(_) if self.pgn_import.is_some() => {}
+                Some(name) => self.toast(format!("✓ Läste in {}", name)),
+                None => self.toast(format!("⚠ {}", last_error)),
+            }
+        }
+
+        let dropped = get_dropped_files();
+        if !dropped.is_empty() && !self.spectator.is_active() {
+            self.toasts.push(LOADING_TOAST.to_string(), 30.0);
+            self.pending_drop = Some(dropped);
+        }
+    }
+
+    // Läs in taktikuppgifter och börja med den första
+    fn load_puzzles(&mut self, text: &str) -> Result<(), String> {
+        let puzzles = parse_puzzles(text)?;
+        println!("[load_puzzles] {} uppgifter", puzzles.len());
+        if self.settings.game_mode == GameMode::EngineVsEngine {
+            self.settings.game_mode = GameMode::VsEngine;
+            self.settings_dirty = true;
+        }
+        self.puzzle = Some(PuzzleSession::new(puzzles));
+        self.start_puzzle();
+        Ok(())
+    }
+
+    // P startar taktikläget från PUZZLE_FILE, eller lämnar det. Ett pågående parti
+    // ersätts först när spelaren bekräftat det.
+    fn toggle_puzzles(&mut self) {
+        if self.puzzle.is_some() {
+            self.reset_game();
+            self.toast("Taktikläget avslutat");
+        } else if !self.game_over && !self.move_history.is_empty() {
+            self.puzzle_prompt = true;
+        } else {
+            self.open_puzzles();
+        }
+    }
+
+    fn answer_puzzle_prompt(&mut self, start: bool) {
+        self.puzzle_prompt = false;
+        if start {
+            self.open_puzzles();
+        }
+    }
+
+    fn open_puzzles(&mut self) {
+        let result = std::fs::read_to_string(PUZZLE_FILE)
+            .map_err(|e| format!("Kunde inte läsa {}: {} – en uppgift per rad: FEN; lösning", PUZZLE_FILE, e))
+            .and_then(|text| self.load_puzzles(&text));
+        if let Err(e) = result {
+            self.toast(format!("⚠ {}", e));
+        }
+    }
+
+    // Ställ upp aktuell uppgift med spelaren vid draget. Sidan vid draget visas nedtill
+    // i en tillfällig vy, så spelarens färg och brädets vändning är desamma efteråt.
+    fn start_puzzle(&mut self) {
+        // Nytt parti nollställer taktikläget, så sessionen hålls utanför under tiden
+        let Some(mut session) = self.puzzle.take() else {
+            return;
+        };
+        let start = session.current().start;
+        self.load_imported(ImportedGame::from_position(start));
+        session.color = start.side_to_move();
+        self.view_flipped = Some(session.color == ChessColor::Black);
+        session.ply = 0;
+        session.engine_reply = false;
+        session.mistake = false;
+        session.pending_at = None;
+        let side = if start.side_to_move() == ChessColor::White { "Vit" } else { "Svart" };
+        self.toast(format!("Uppgift {}/{}: {} vid draget", session.index + 1, session.puzzles.len(), side));
+        self.puzzle = Some(session);
+    }
+
+    // Spelarens drag. I taktikläget måste det följa lösningen (eller sätta matt);
+    // fel drag görs inte alls.
+    fn play_human_move(&mut self, chess_move: ChessMove) {
+        let Some(ref mut session) = self.puzzle else {
+            self.make_move(chess_move);
+            return;
+        };
+        if session.pending_at.is_some() || session.engine_reply || session.finished() {
+            return;
+        }
+        let mates = self.board.make_move_new(chess_move).status() == BoardStatus::Checkmate;
+        if session.expected(&self.board) != Some(chess_move) && !mates {
+            session.mistake = true;
+            self.selected_square = None;
+            self.highlighted_moves.clear();
+            self.toast("Fel, försök igen");
+            return;
+        }
+
+        // Ett annat matt avslutar uppgiften lika väl som lösningens
+        session.ply = if mates { session.current().solution.len() } else { session.ply + 1 };
+        session.pending_at = Some(get_time() + PUZZLE_STEP_DELAY);
+        self.make_move(chess_move);
+        if !self.finish_puzzle() {
+            self.toast("Rätt!");
+        }
+    }
+
+    // Motståndarens svar är spelat. Slutar lösningen med svaret kommer nästa uppgift efter
+    // samma paus; har motorn svarat så att lösningens nästa drag inte går att spela tar
+    // uppgiften slut där.
+    fn puzzle_reply_played(&mut self) {
+        let Some(ref mut session) = self.puzzle else {
+            return;
+        };
+        session.engine_reply = false;
+        session.ply += 1;
+        if !session.finished() && session.expected(&self.board).is_none() {
+            self.toast("Motorn svarade utanför lösningen");
+            self.end_puzzle_line();
+        } else if session.finished() {
+            session.pending_at = Some(get_time() + PUZZLE_STEP_DELAY);
+            self.finish_puzzle();
+        }
+    }
+
+    // Avsluta uppgiften där den står och gå vidare efter pausen
+    fn end_puzzle_line(&mut self) {
+        let Some(ref mut session) = self.puzzle else {
+            return;
+        };
+        session.ply = session.current().solution.len();
+        session.pending_at = Some(get_time() + PUZZLE_STEP_DELAY);
+        self.finish_puzzle();
+    }
+
+    // Räkna uppgiften när lösningen är slutspelad; sann om den var det
+    fn finish_puzzle(&mut self) -> bool {
+        let Some(ref mut session) = self.puzzle else {
+            return false;
+        };
+        if !session.finished() {
+            return false;
+        }
+        session.attempted += 1;
+        if !session.mistake {
+            session.solved += 1;
+        }
+        let message = if session.mistake { "✓ Löst, men med fel på vägen" } else { "✓ Rätt! Uppgiften löst" };
+        self.toast(message);
+        true
+    }
+
+    // Motståndarens svar och bytet till nästa uppgift kommer efter en kort paus
+    fn poll_puzzle(&mut self) {
+        let has_engine = self.has_engine();
+        let Some(ref mut session) = self.puzzle else {
+            return;
+        };
+        if session.pending_at.is_none_or(|at| get_time() < at) {
+            return;
+        }
+        session.pending_at = None;
+
+        if !session.finished() {
+            match session.expected(&self.board) {
+                Some(reply) => {
+                    self.make_move(reply);
+                    self.puzzle_reply_played();
+                }
+                // Svaret väljs av motorn, som startas när det är dess tur
+                None if has_engine => session.engine_reply = true,
+                None => {
+                    self.toast("⚠ Svaret kräver en motor – uppgiften avslutas");
+                    self.end_puzzle_line();
+                }
+            }
+            return;
+        }
+
+        if session.index + 1 < session.puzzles.len() {
+            session.index += 1;
+            self.start_puzzle();
+        } else {
+            let summary = format!("Alla uppgifter klara – {} av {} lösta utan fel", session.solved, session.attempted);
+            self.toast(summary);
+        }
+    }
+
+    // Spara aktuell ställning som övningsställning
+    fn save_drill_position(&mut self) {
+        if self.review_mode {
+            return;
+        }
+        if self.game_over {
+            self.toast("Partiet är slut – ingen ställning att öva på");
+            return;
+        }
+        self.drill_position = Some(DrillPosition { board: self.board, history_len: self.move_history.len() });
+        self.toast("Övningsställning sparad – tryck R för att försöka igen");
+    }
+
+    // Återställ till övningsställningen utan att bygga om hela partiet
+    fn retry_drill(&mut self) {
+        let Some(drill) = self.drill_position else {
+            return;
+        };
+
+        if self.review_mode {
+            self.exit_review_mode();
+        }
+        if !drill.rewind(self.start_board, &mut self.move_history, &mut self.redo_stack) {
+            self.drill_position = None;
+            self.toast("Övningsställningen finns inte längre i partiet");
+            return;
+        }
+
+        self.board = drill.board;
+        self.game_over = false;
+        self.termination = None;
+        self.draw_offer = None;
+        self.cancel_ai_search();
+        self.current_analysis = None;
+        // Klockan spolas tillbaka till ställningen på samma sätt som vid ångra
+        self.after_history_change();
+        self.toast("Övningsställningen återställd");
+    }
+
+    // Hantera tangentbordskommandon
+    fn handle_keyboard(&mut self) {
+        if is_key_pressed(KeyCode::F2) {
+            self.spectator = self.spectator.next();
+            println!("[handle_keyboard] Åskådarläge: {:?}", self.spectator);
+        }
+
+        // Beskedet om saknad motor stängs med Enter eller Escape
+        if self.engine_notice {
+            if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::Escape) {
+                self.engine_notice = false;
+            }
+            return;
+        }
+
+        // Frågan om taktikläget besvaras med Enter (ja) eller Escape (nej)
+        if self.puzzle_prompt {
+            if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::KpEnter) {
+                self.answer_puzzle_prompt(true);
+            } else if is_key_pressed(KeyCode::Escape) {
+                self.answer_puzzle_prompt(false);
+            }
+            return;
+        }
+
+        // Namnrutan öppnas från inställningarna och får tangenterna före dem
+        if self.name_input.is_some() {
+            self.handle_name_keys();
+            return;
+        }
+
+        if self.spectator.is_active() || self.show_settings || self.show_engine_info {
+            return;
+        }
+
+        // Tangenter går till FEN-rutan medan den är öppen
+        if self.fen_input.is_some() {
+            self.handle_fen_keys();
+            return;
+        }
+
+        // Redigeraren stängs med Escape; partiets kortkommandon gäller inte där
+        if self.editor.is_some() {
+            if is_key_pressed(KeyCode::Escape) {
+                self.editor = None;
+            }
+            return;
+        }
+
+        // Välj förvandlingspjäs med Q/R/B/N, Enter eller Escape ger dam
+        if self.pending_promotion.is_some() {
+            let choice = if is_key_pressed(KeyCode::Q) || is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::Escape) {
+                Some(Piece::Queen)
+            } else if is_key_pressed(KeyCode::R) {
+                Some(Piece::Rook)
+            } else if is_key_pressed(KeyCode::B) {
+                Some(Piece::Bishop)
+            } else if is_key_pressed(KeyCode::N) {
+                Some(Piece::Knight)
+            } else {
+                None
+            };
+
+            if let Some(piece) = choice {
+                self.complete_promotion(piece);
+            }
+            return;
+        }
+
+        if is_key_pressed(KeyCode::M) {
+            self.toggle_sound();
+        }
+
+        let ctrl = is_key_down(KeyCode::LeftControl) || is_key_down(KeyCode::RightControl);
+        if ctrl && is_key_pressed(KeyCode::Z) {
+            self.undo();
+        }
+        if ctrl && is_key_pressed(KeyCode::Y) {
+            self.redo();
+        }
+
+        self.handle_review_keys();
+
+        if is_key_pressed(KeyCode::R) {
+            self.retry_drill();
+        }
+
+        if is_key_pressed(KeyCode::D) {
+            self.deep_search_armed = !self.deep_search_armed;
+        }
+
+        if is_key_pressed(KeyCode::E) {
+            self.eval_request_wanted = true;
+        }
+
+        if is_key_pressed(KeyCode::I) {
+            self.toggle_infinite_analysis();
+        }
+
+        if is_key_pressed(KeyCode::P) {
+            self.toggle_puzzles();
+        }
+
+        // I analysfönstret scrollar PageUp/PageDown listan och hoppar mellan fel med Shift
+        let page = PANEL_MOVE_LIST_ROWS as i32;
+        let shift = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+        if self.game_analysis.is_some() {
+            match (is_key_pressed(KeyCode::PageUp), is_key_pressed(KeyCode::PageDown), shift) {
+                (true, _, true) => self.jump_to_error(false),
+                (_, true, true) => self.jump_to_error(true),
+                (true, _, false) => self.scroll_analysis(page),
+                (_, true, false) => self.scroll_analysis(-page),
+                _ => {}
+            }
+        } else if is_key_pressed(KeyCode::PageUp) {
+            self.scroll_move_list(page);
+        } else if is_key_pressed(KeyCode::PageDown) {
+            self.scroll_move_list(-page);
+        }
+    }
+
+    // Scrolla panelens draglista; positivt värde går bakåt i partiet
+    fn scroll_move_list(&mut self, rows: i32) {
+        let max_offset = self.move_list_rows().saturating_sub(PANEL_MOVE_LIST_ROWS);
+        let offset = (self.move_list_offset as i32 + rows).clamp(0, max_offset as i32);
+        self.move_list_offset = offset as usize;
+    }
+
+    // Scrolla panelens draglista så att draget syns
+    fn scroll_move_list_to(&mut self, index: usize) {
+        let start = self.move_list_start();
+        if (start..start + PANEL_MOVE_LIST_ROWS).contains(&index) {
+            return;
+        }
+        let max_offset = self.move_list_rows().saturating_sub(PANEL_MOVE_LIST_ROWS);
+        let new_start = index.saturating_sub(PANEL_MOVE_LIST_ROWS / 2).min(max_offset);
+        self.move_list_offset = max_offset - new_start;
+    }
+
+    // Rader i panelens draglista: dragen och en eventuell uppgivning
+    fn move_list_rows(&self) -> usize {
+        self.move_history.len() + self.resigned().is_some() as usize
+    }
+
+    // Index för första synliga draget i panelens draglista
+    fn move_list_start(&self) -> usize {
+        move_list_window_start(self.move_list_rows(), self.move_list_offset)
+    }
+
+    // Vilket drag i panelens draglista som ligger under muspekaren, om något
+    fn move_list_index_at(&self, mouse_pos: (f32, f32)) -> Option<usize> {
+        let panel_x = self.layout.panel_x;
+        
+        let (mouse_x, mouse_y) = mouse_pos;
+        let top = self.move_list_top.get();
+        if !(panel_x..=panel_x + 160.0).contains(&mouse_x) || mouse_y < top + 8.0 {
+            return None;
+        }
+
+        let row = ((mouse_y - top - 8.0) / 15.0) as usize;
+        if row >= PANEL_MOVE_LIST_ROWS {
+            return None;
+        }
+        let index = self.move_list_start() + row;
+        (index < self.move_history.len()).then_some(index)
+    }
+
+    // Scrolla analysfönstrets draglista; positivt värde går bakåt i partiet
+    fn scroll_analysis(&mut self, rows: i32) {
+        let Some(ref analysis) = self.game_analysis else {
+            return;
+        };
+        self.analysis_scroll = analysis_scroll_after(self.analysis_scroll, rows, analysis.moves.len());
+    }
+
+    // Gå till föregående eller nästa blunder/misstag från det granskade draget.
+    // Sökningen fortsätter runt vid partiets ändar.
+    fn jump_to_error(&mut self, forward: bool) {
+        let Some(ref analysis) = self.game_analysis else {
+            return;
+        };
+        let errors: Vec<usize> = analysis.moves.iter().enumerate()
+            .filter(|(_, m)| !m.skipped && (m.is_blunder || m.is_mistake))
+            .map(|(index, _)| index)
+            .collect();
+        let (Some(&first), Some(&last)) = (errors.first(), errors.last()) else {
+            self.toast("Inga blunders eller misstag i partiet");
+            return;
+        };
+
+        // Utan valt drag (aktuell ställning eller startställningen) börjar sökningen i ändarna
+        let target = match (self.review_move_index, forward) {
+            (Some(current), true) => errors.iter().copied().find(|&i| i > current).unwrap_or(first),
+            (Some(current), false) => errors.iter().rev().copied().find(|&i| i < current).unwrap_or(last),
+            (None, true) => first,
+            (None, false) => last,
+        };
+
+        self.show_position_at_move(target);
+        self.error_flash = Some((target, get_time()));
+
+        // Scrolla analyslistan om draget inte syntes vid senaste ritningen
+        let visible = self.analysis_rows.borrow().iter().any(|&(index, _, _)| index == target);
+        if !visible {
+            self.analysis_scroll = target.saturating_sub(2);
+        }
+    }
+
+    // Genomskinlighet för blinkningen på ett drag man just hoppat till (0 = ingen)
+    fn error_flash_alpha(&self, move_index: usize) -> f32 {
+        const FLASH_SECONDS: f64 = 1.2;
+        match self.error_flash {
+            Some((index, started)) if index == move_index => {
+                let elapsed = get_time() - started;
+                if elapsed >= FLASH_SECONDS {
+                    return 0.0;
+                }
+                // Tre pulser som klingar av
+                let pulse = (elapsed / FLASH_SECONDS * 3.0 * std::f64::consts::TAU).cos() * 0.5 + 0.5;
+                (pulse * (1.0 - elapsed / FLASH_SECONDS)) as f32 * 0.6
+            }
+            _ => 0.0,
+        }
+    }
+
+    // Mushjulet scrollar draglistan när pekaren är över den
+    fn handle_move_list_scroll(&mut self) {
+        let panel_x = self.layout.panel_x;
+        
+        let (_, wheel_y) = mouse_wheel();
+        if wheel_y == 0.0 {
+            return;
+        }
+
+        // Inställningsfönstret ligger överst och scrollar sina egna rader
+        if self.show_settings && !self.show_engine_info {
+            self.scroll_settings(if wheel_y > 0.0 { -1 } else { 1 });
+            return;
+        }
+        if self.spectator.is_active() || self.show_settings || self.show_engine_info {
+            return;
+        }
+
+        // Analysfönstret ligger överst och tar hand om hjulet medan det är öppet
+        if self.game_analysis.is_some() {
+            self.scroll_analysis(if wheel_y > 0.0 { 3 } else { -3 });
+            return;
+        }
+
+        let (mouse_x, mouse_y) = mouse_position();
+        let top = self.move_list_top.get();
+        if (panel_x..=panel_x + 160.0).contains(&mouse_x) && (top..=top + 200.0).contains(&mouse_y) {
+            self.scroll_move_list(if wheel_y > 0.0 { 3 } else { -3 });
+        }
+    }
+
+    // Ta tillbaka drag tills det är spelarens tur igen, så att AI:ns svar och spelarens
+    // eget drag ångras tillsammans. En uppgivning ångras som ett eget steg.
+    fn undo(&mut self) {
+        if (self.move_history.is_empty() && self.resigned().is_none()) || self.pgn_import.is_some() || self.puzzle.is_some() {
+            return;
+        }
+        if self.review_mode {
+            self.exit_review_mode();
+        }
+        self.cancel_ai_search();
+
+        // En överenskommen eller krävd remi ångras som ett eget steg, precis som en uppgivning
+        self.draw_offer = None;
+        // En förlust på tid ångras tillsammans med dragen; klockan spolas tillbaka nedan
+        let termination = self.termination.take();
+        if !matches!(termination, Some(Termination::Draw(_) | Termination::Resigned(_))) {
+            while let Some(record) = self.move_history.pop() {
+                self.redo_stack.push(record);
+                let board = self.board_before_move(self.move_history.len());
+                if self.steps_single_plies() || board.side_to_move() == self.settings.player_color || self.move_history.is_empty() {
+                    break;
+                }
+            }
+        }
+
+        self.board = self.board_before_move(self.move_history.len());
+        self.game_over = false;
+        self.after_history_change();
+        println!("[undo] {} drag kvar i historiken", self.move_history.len());
+    }
+
+    // Spela upp ångrade drag igen, lika många som ångrades senast
+    fn redo(&mut self) {
+        if self.redo_stack.is_empty() || self.pgn_import.is_some() || self.game_over {
             return;
         }
+        if self.review_mode {
+            self.exit_review_mode();
+        }
+        self.cancel_ai_search();
+
+        while let Some(record) = self.redo_stack.pop() {
+            self.board = record.board_after;
+            self.move_history.push(record);
+            if self.steps_single_plies() || self.board.side_to_move() == self.settings.player_color {
+                break;
+            }
+        }
+
+        self.after_history_change();
+        println!("[redo] {} drag i historiken", self.move_history.len());
+    }
+
+    // Gemensam uppstädning när brädet byggts om från draghistoriken
+    fn after_history_change(&mut self) {
+        if let Some(clock) = &mut self.clock {
+            clock.rewind_to(self.move_history.len());
+        }
+        self.selected_square = None;
+        self.highlighted_moves.clear();
+        self.pending_promotion = None;
+        self.move_animation = None;
+        self.analysis_pv.clear();
+        self.live_eval = None;
+        self.update_repetitions();
+        self.update_game_state();
+        self.update_opening();
+        self.update_last_move();
+        self.auto_flip_hot_seat();
+        self.autosave();
+    }
+
+    fn resign(&mut self) {
+        self.cancel_ai_search();
+        self.end_game();
+        self.termination = Some(Termination::Resigned(self.human_color()));
+        self.autosave();
+    }
+
+    // Sidan som gav upp, om partiet slutade så
+    fn resigned(&self) -> Option<ChessColor> {
+        match self.termination {
+            Some(Termination::Resigned(side)) => Some(side),
+            _ => None,
+        }
+    }
+
+    // Sidan vars tid tog slut, om partiet slutade så
+    fn flagged(&self) -> Option<ChessColor> {
+        match self.termination {
+            Some(Termination::Flagged(side)) => Some(side),
+            _ => None,
+        }
+    }
+
+    // Den senaste partianalysen, även om analysfönstret har stängts
+    fn latest_analysis(&self) -> Option<&GameAnalysis> {
+        self.game_analysis.as_ref().or(self.analysis_history.last())
+    }
+
+    // NAG-tecken och motorns förslag för ett felaktigt drag, t.ex. "?? {bättre var g8-f6, +0.30}"
+    fn pgn_move_annotation(&self, m: &MoveAnalysis) -> String {
+        if m.skipped {
+            return String::new();
+        }
+        if m.is_brilliant || m.is_great {
+            return m.positive_suffix().to_string();
+        }
+        let glyph = if m.is_blunder {
+            "??"
+        } else if m.is_mistake {
+            "?"
+        } else if m.is_inaccuracy {
+            "?!"
+        } else {
+            return String::new();
+        };
+
+        match &m.best_move_notation {
+            Some(best) => format!(
+                "{} {{bättre var {}, {}}}",
+                glyph,
+                best,
+                format_eval(Eval::Pawns(m.evaluation_before), self.settings.eval_precision)
+            ),
+            None => glyph.to_string(),
+        }
+    }
+
+    // Resultatet som brädet och draglistan visar
+    fn detected_result(&self) -> GameResult {
+        if !self.game_over {
+            return GameResult::Ongoing;
+        }
+        match self.board.status() {
+            BoardStatus::Checkmate => {
+                if self.board.side_to_move() == ChessColor::White { GameResult::BlackWins } else { GameResult::WhiteWins }
+            }
+            BoardStatus::Stalemate => GameResult::Draw,
+            _ => {
+                if let Some(side) = self.flagged() {
+                    if !has_mating_material(&self.board, !side) {
+                        GameResult::Draw
+                    } else if side == ChessColor::White {
+                        GameResult::BlackWins
+                    } else {
+                        GameResult::WhiteWins
+                    }
+                } else if let Some(side) = self.resigned() {
+                    if side == ChessColor::White { GameResult::BlackWins } else { GameResult::WhiteWins }
+                } else if self.draw_reason().is_some() {
+                    GameResult::Draw
+                } else {
+                    GameResult::Ongoing
+                }
+            }
+        }
+    }
+
+    fn game_result(&self) -> GameResult {
+        self.result_override.unwrap_or_else(|| self.detected_result())
+    }
+
+    // Stega igenom Auto → 1-0 → 0-1 → ½-½ → * → Auto
+    fn cycle_result_override(&mut self) {
+        self.result_override = match self.result_override {
+            None => Some(GameResult::WhiteWins),
+            Some(GameResult::WhiteWins) => Some(GameResult::BlackWins),
+            Some(GameResult::BlackWins) => Some(GameResult::Draw),
+            Some(GameResult::Draw) => Some(GameResult::Ongoing),
+            Some(GameResult::Ongoing) => None,
+        };
+    }
+
+    fn export_pgn(&mut self) {
+        let pgn = self.build_pgn();
+        println!("PGN innehåll:\n{}", pgn);
+        
+        // Spara till fil
+        match std::fs::write("schack_parti.pgn", &pgn) {
+            Ok(_) => self.toast("✓ PGN exporterat till 'schack_parti.pgn'"),
+            Err(e) => self.toast(format!("⚠ Kunde inte spara PGN-fil: {}", e)),
+        }
+    }
+
+    // Motorns namn från UCI-handskakningen ("id name"), t.ex. "Stockfish 17"
+    fn engine_name(&self) -> &str {
+        self.engine_info.as_ref().map(|info| info.name.as_str()).filter(|name| !name.is_empty()).unwrap_or("AI")
+    }
+
+    // Namnen för vit och svart i PGN-huvudet. Ditt namn står på din färg; motspelaren
+    // är motorn eller, vid två spelare, det andra namnet.
+    fn pgn_player_names(&self) -> [String; 2] {
+        let engine = self.engine_name().to_string();
+        let names = &self.settings.player_names;
+        let (own, other) = match self.settings.game_mode {
+            GameMode::VsEngine => (names[0].clone(), engine),
+            GameMode::HotSeat => (names[0].clone(), names[1].clone()),
+            GameMode::EngineVsEngine => return [engine.clone(), engine],
+        };
+        if self.settings.player_color == ChessColor::White { [own, other] } else { [other, own] }
+    }
+
+    // Motorns Elo-gräns för en färg, om motorn spelar den färgen med begränsad styrka
+    fn engine_elo(&self, color: ChessColor) -> Option<u16> {
+        if !self.engine_supports("UCI_Elo") {
+            return None;
+        }
+        match self.settings.game_mode {
+            GameMode::VsEngine if color != self.settings.player_color => self.settings.elo_limit,
+            GameMode::EngineVsEngine => self.settings.autoplay_elo[color.to_index()],
+            _ => None,
+        }
+    }
+
+    // Sökgräns och Skill Level som motorn spelar med, t.ex. "depth 12, Skill Level 20"
+    fn engine_settings_summary(&self) -> String {
+        let search = if self.clock.is_some() {
+            "clock".to_string()
+        } else if self.search_by_time {
+            format!("movetime {}s", self.movetime_slider.get_value())
+        } else {
+            format!("depth {}", self.depth_slider.get_value())
+        };
+        format!("{}, Skill Level {}", search, self.skill_slider.get_value())
+    }
+
+    // Bygg partiets PGN-text (används av både export och autospar)
+    fn build_pgn(&self) -> String {
+        let mut pgn = String::new();
+        let event = match self.settings.game_mode {
+            GameMode::VsEngine => "Parti mot motorn",
+            GameMode::HotSeat => "Parti mellan två spelare",
+            GameMode::EngineVsEngine => "Motormatch",
+        };
+        pgn.push_str(&pgn_tag("Event", event));
+        pgn.push_str("[Site \"Lokal dator\"]\n");
+        
+        // Aktuellt datum och klockslag i UTC; lokal tidszon går inte att läsa utan extern crate
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let (year, month, day) = civil_date((now / 86_400) as i64);
+        let seconds_of_day = now % 86_400;
+        
+        let date = format!("{:04}.{:02}.{:02}", year, month, day);
+        pgn.push_str(&format!("[Date \"{}\"]\n", date));
+        
+        pgn.push_str("[Round \"1\"]\n");
+        let [white, black] = self.pgn_player_names();
+        pgn.push_str(&pgn_tag("White", &white));
+        pgn.push_str(&pgn_tag("Black", &black));
+        
+        // Spelresultat (ett manuellt satt resultat går före det upptäckta)
+        let result = self.game_result().pgn_tag();
+        pgn.push_str(&format!("[Result \"{}\"]\n", result));
+        pgn.push_str(&format!("[UTCDate \"{}\"]\n", date));
+        pgn.push_str(&format!(
+            "[UTCTime \"{:02}:{:02}:{:02}\"]\n",
+            seconds_of_day / 3600,
+            seconds_of_day / 60 % 60,
+            seconds_of_day % 60
+        ));
+        // Motorns styrka: Elo-gränsen som standardtagg, sökningen i en egen tagg
+        for (color, tag) in [(ChessColor::White, "WhiteElo"), (ChessColor::Black, "BlackElo")] {
+            if let Some(elo) = self.engine_elo(color) {
+                pgn.push_str(&pgn_tag(tag, &elo.to_string()));
+            }
+        }
+        if self.settings.game_mode != GameMode::HotSeat {
+            pgn.push_str(&pgn_tag("EngineSettings", &self.engine_settings_summary()));
+        }
         
-        // Spara ursprungligt bräde om vi inte redan är i review-läge
-        if !self.review_mode {
-            self.original_board = Some(self.board);
+        if let Some(opening) = self.opening {
+            pgn.push_str(&format!("[ECO \"{}\"]\n", opening.eco));
+            pgn.push_str(&format!("[Opening \"{}\"]\n", opening.name));
+        }
+        // PGN-standardens form: grundtid och tillägg i sekunder
+        if let Some(clock) = &self.clock {
+            pgn.push_str(&format!("[TimeControl \"{}+{}\"]\n", clock.control.minutes * 60, clock.control.increment));
+            if self.flagged().is_some() {
+                pgn.push_str("[Termination \"time forfeit\"]\n");
+            }
+        }
+        
+        // Partier som inte börjar från grundställningen behöver startställningen
+        if self.start_board != Board::default() || self.start_halfmove != 0 || self.start_fullmove != 1 {
+            pgn.push_str("[SetUp \"1\"]\n");
+            pgn.push_str(&format!("[FEN \"{}\"]\n", self.start_fen()));
+        }
+        pgn.push('\n');
+        
+        // Analys att kommentera med, om spelaren valt det
+        let analysis = if self.settings.include_analysis_in_pgn { self.latest_analysis() } else { None };
+        
+        // Dragen i SAN; en avslutande notering (t.ex. uppgivning) blir en kommentar före resultatet
+        let moves: Vec<String> = self.move_history.iter().enumerate().map(|(i, record)| {
+            match analysis.and_then(|a| a.moves.get(i)) {
+                Some(m) if m.chess_move == record.chess_move => format!("{}{}", record.san, self.pgn_move_annotation(m)),
+                _ => record.san.clone(),
+            }
+        }).collect();
+        
+        // Remi utan patt förklaras i en kommentar, som en uppgivning
+        let resignation = self.resigned().map(Self::resignation_label);
+        let closing_note = match resignation {
+            Some(ref note) => Some(note.as_str()),
+            None if self.game_over => self.draw_reason().map(DrawReason::label),
+            None => None,
+        };
+        
+        let black_first = self.start_board.side_to_move() == ChessColor::Black;
+        pgn.push_str(&pgn_movetext(&moves, black_first, self.start_fullmove, closing_note, result));
+        
+        pgn
+    }
+
+    // Spara pågående parti så att det kan återupptas vid nästa start.
+    // Avslutade eller tomma partier har inget att återuppta och tas bort.
+    fn autosave(&self) {
+        if self.game_over || self.move_history.is_empty() {
+            let _ = std::fs::remove_file(AUTOSAVE_PATH);
+            return;
+        }
+        if let Err(e) = std::fs::write(AUTOSAVE_PATH, self.build_pgn()) {
+            eprintln!("⚠ Kunde inte autospara partiet: {}", e);
+        }
+    }
+
+    // Spara partiet, spelarens färg, inställningarna och senaste analysen som JSON
+    fn save_game(&mut self) {
+        let result = serde_json::to_string_pretty(&self.build_save())
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(SAVE_PATH, text).map_err(|e| e.to_string()));
+        match result {
+            Ok(_) => self.toast(format!("✓ Spelet sparat i '{}'", SAVE_PATH)),
+            Err(e) => self.toast(format!("⚠ Kunde inte spara spelet: {}", e)),
+        }
+    }
+
+    // Inställningsfilen: inställningarna, spelarens färg, reglagen och motorns sökväg
+    fn build_settings_file(&self) -> SettingsFile {
+        SettingsFile {
+            format: SAVE_FORMAT.into(),
+            version: SAVE_VERSION,
+            player_color: Some(self.settings.player_color.into()),
+            search_by_time: Some(self.search_by_time),
+            depth: Some(self.depth_slider.get_value()),
+            movetime: Some(self.movetime_slider.get_value()),
+            skill: Some(self.skill_slider.get_value()),
+            engine_path: self.engine_path.clone(),
+            settings: self.saved_settings_block(),
+        }
+    }
+
+    fn settings_file_text(&self) -> String {
+        serde_json::to_string_pretty(&self.build_settings_file()).unwrap_or_default()
+    }
+
+    // Läs inställningsfilen vid start. Saknas den gäller standardvärdena; är den
+    // trasig sägs det till och standardvärdena gäller ändå.
+    fn load_settings(&mut self) {
+        let text = match std::fs::read_to_string(SETTINGS_PATH) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("[load_settings] Ingen inställningsfil ({}), standardvärden används", e);
+                return;
+            }
+        };
+        match serde_json::from_str::<SettingsFile>(&text) {
+            Ok(file) if file.format == SAVE_FORMAT => {
+                self.settings.player_color = file.player_color.map_or(ChessColor::White, ChessColor::from);
+                self.apply_saved_settings(&file.settings);
+                self.flip_overridden = self.settings.board_flipped != self.own_side_flipped();
+                if let Some(by_time) = file.search_by_time {
+                    self.search_by_time = by_time;
+                    self.search_mode_button.text = if by_time { "Läge: Tid" } else { "Läge: Djup" }.to_string();
+                }
+                for (value, slider) in [(file.depth, &mut self.depth_slider), (file.movetime, &mut self.movetime_slider), (file.skill, &mut self.skill_slider)] {
+                    if let Some(value) = value {
+                        slider.set_value(value as f32);
+                    }
+                }
+                // Utan motor behålls sökvägen från förra gången
+                if self.engine_path.is_none() {
+                    self.engine_path = file.engine_path;
+                }
+                self.engine_options_dirty = true;
+                println!("[load_settings] Inställningar inlästa från '{}'", SETTINGS_PATH);
+            }
+            Ok(_) => self.warn_corrupt_settings("okänt format"),
+            Err(e) => self.warn_corrupt_settings(&e.to_string()),
+        }
+        self.settings_dirty = false;
+    }
+
+    fn warn_corrupt_settings(&mut self, reason: &str) {
+        eprintln!("⚠ Kunde inte läsa '{}': {}", SETTINGS_PATH, reason);
+        self.toast("⚠ Inställningsfilen var skadad – standardvärden används");
+    }
+
+    // Skriv inställningsfilen när något ändrats; medan ett reglage dras väntar det till släppet
+    fn persist_settings(&mut self) {
+        if !self.settings_dirty || is_mouse_button_down(MouseButton::Left) {
+            return;
+        }
+        match std::fs::write(SETTINGS_PATH, self.settings_file_text()) {
+            Ok(()) => println!("[persist_settings] Inställningar sparade"),
+            Err(e) => eprintln!("⚠ Kunde inte spara inställningarna: {}", e),
+        }
+        // Även ett misslyckat försök räknas, så att det inte upprepas varje bildruta
+        self.settings_dirty = false;
+    }
+
+    fn load_game(&mut self) {
+        let result = std::fs::read_to_string(SAVE_PATH)
+            .map_err(|e| format!("Kunde inte läsa '{}': {}", SAVE_PATH, e))
+            .and_then(|text| self.restore_save(&text));
+        match result {
+            Ok(()) => self.toast(format!("✓ Spelet inläst från '{}'", SAVE_PATH)),
+            Err(e) => {
+                eprintln!("[load_game] {}", e);
+                self.toast(format!("⚠ {}", e));
+            }
+        }
+    }
+
+    // Inställningarna som sparas både i sparfilen och i inställningsfilen
+    fn saved_settings_block(&self) -> SavedSettings {
+        let s = &self.settings;
+        SavedSettings {
+            board_flipped: Some(s.board_flipped),
+            eval_precision: Some(s.eval_precision),
+            player_perspective: Some(s.player_perspective),
+            analysis_depth: Some(s.analysis_depth),
+            show_eval_comparison: Some(s.show_eval_comparison),
+            sound_enabled: Some(s.sound_enabled),
+            distinct_move_sounds: Some(s.distinct_move_sounds),
+            ai_min_delay_ms: Some(s.ai_min_delay_ms),
+            include_analysis_in_pgn: Some(s.include_analysis_in_pgn),
+            move_overhead_ms: Some(s.move_overhead_ms),
+            animations: Some(s.animations),
+            coordinate_size: Some(s.coordinate_size),
+            coordinates_inside: Some(s.coordinates_inside),
+            board_border: Some(s.board_border),
+            elo_limit: Some(s.elo_limit),
+            use_opening_book: Some(s.use_opening_book),
+            image_size: Some(s.image_size),
+            time_control: Some(s.time_control.map(TimeControl::label)),
+            game_mode: Some(s.game_mode.key().to_string()),
+            hot_seat_auto_flip: Some(s.hot_seat_auto_flip),
+            player_names: Some(s.player_names.to_vec()),
+            autoplay_elo: Some(s.autoplay_elo.to_vec()),
+        }
+    }
+
+    fn build_save(&self) -> SaveFile {
+        let analysis = self.latest_analysis().map(|analysis| SavedAnalysis {
+            depth: analysis.depth,
+            moves: analysis.moves.iter().map(|m| SavedMoveAnalysis {
+                eval_before: m.evaluation_before,
+                eval_after: m.evaluation_after,
+                centipawn_loss: m.centipawn_loss,
+                blunder: m.is_blunder,
+                mistake: m.is_mistake,
+                inaccuracy: m.is_inaccuracy,
+                great: m.is_great,
+                brilliant: m.is_brilliant,
+                best_line: m.best_line.iter().map(ChessMove::to_string).collect(),
+                material_after: m.material_after,
+                skipped: m.skipped,
+            }).collect(),
+        });
+
+        let clock = self.clock.as_ref().map(|clock| SavedClock {
+            white: clock.remaining(ChessColor::White),
+            black: clock.remaining(ChessColor::Black),
+            snapshots: clock.snapshots.clone(),
+            flagged: self.flagged().map(SavedColor::from),
+        });
+
+        SaveFile {
+            format: SAVE_FORMAT.into(),
+            version: SAVE_VERSION,
+            start_fen: self.start_fen(),
+            moves: self.move_history.iter().map(|m| m.san.clone()).collect(),
+            resigned: self.resigned().map(SavedColor::from),
+            player_color: Some(self.settings.player_color.into()),
+            settings: Some(self.saved_settings_block()),
+            clock,
+            analysis,
+        }
+    }
+
+    // Läs en sparfil. Allt tolkas och dragen spelas upp innan något i det pågående
+    // partiet ändras, så en trasig fil lämnar partiet orört.
+    fn restore_save(&mut self, text: &str) -> Result<(), String> {
+        let header: SaveHeader = serde_json::from_str(text).map_err(|e| format!("Ogiltig sparfil: {}", e))?;
+        if header.format.as_deref() != Some(SAVE_FORMAT) {
+            return Err("Filen är ingen sparfil från Daniels schack".to_string());
+        }
+        let version = header.version.ok_or("Sparfilen saknar version")?;
+        if version > SAVE_VERSION {
+            return Err(format!("Sparfilen har version {}, programmet läser bara till version {}", version, SAVE_VERSION));
+        }
+        let save: SaveFile = serde_json::from_str(text).map_err(|e| format!("Ogiltig sparfil: {}", e))?;
+
+        let start = parse_fen(&save.start_fen)?;
+        let (start_halfmove, start_fullmove) = fen_counters(&save.start_fen);
+        let mut board = start;
+        let mut moves = Vec::new();
+        let mut resigned = save.resigned.map(ChessColor::from);
+        for (i, text) in save.moves.iter().enumerate() {
+            // Äldre sparfiler har uppgivningen som sista post i draglistan, t.ex. "Vit vann genom uppgivning"
+            if i + 1 == save.moves.len() && text.ends_with("vann genom uppgivning") {
+                resigned = Some(if text.starts_with("Vit") { ChessColor::Black } else { ChessColor::White });
+                break;
+            }
+            let chess_move = parse_san(&board, text).ok_or_else(|| format!("Ogiltigt drag {} i sparfilen: {}", i + 1, text))?;
+            board = board.make_move_new(chess_move);
+            moves.push(chess_move);
+        }
+
+        let analysis = match save.analysis {
+            None => None,
+            Some(ref saved) => Some(Self::restore_analysis(saved, start, &moves)?),
+        };
+
+        self.load_imported(ImportedGame { start, start_halfmove, start_fullmove, moves });
+        if let Some(side) = resigned {
+            self.termination = Some(Termination::Resigned(side));
+            self.end_game();
+        }
+        self.settings.player_color = save.player_color.map_or(ChessColor::White, ChessColor::from);
+        self.settings_dirty = true;
+        if let Some(ref settings) = save.settings {
+            self.apply_saved_settings(settings);
+        }
+        self.flip_overridden = self.settings.board_flipped != self.own_side_flipped();
+        self.restore_clock(save.clock.as_ref());
+        // Den sparade analysen blir partiets aktuella, så granskningen visar dess värden
+        if analysis.is_some() {
+            self.game_analysis = analysis;
+            self.analysis_scroll = 0;
+        }
+        self.autosave();
+        Ok(())
+    }
+
+    // Klockan startar om med den sparade betänketiden och tar sedan över sparade tider
+    fn restore_clock(&mut self, saved: Option<&SavedClock>) {
+        self.clock = self.settings.time_control.map(GameClock::new);
+        let (Some(clock), Some(saved)) = (self.clock.as_mut(), saved) else {
+            return;
+        };
+        clock.remaining = [saved.white.max(0.0), saved.black.max(0.0)];
+        // Tiderna vid varje drag behövs för att ångra; de som inte passar partiet släpps
+        let plies = self.move_history.len();
+        clock.snapshots = saved.snapshots.iter().take(plies).map(|times| times.map(|t| t.max(0.0))).collect();
+        if let Some(side) = saved.flagged {
+            self.termination = Some(Termination::Flagged(side.into()));
+            self.end_game();
+        }
+    }
+
+    // Inställningar som saknas i filen behåller sina nuvarande värden. Värden utanför
+    // det som går att välja i inställningsfönstret justeras till närmaste giltiga.
+    fn apply_saved_settings(&mut self, saved: &SavedSettings) {
+        self.settings_dirty = true;
+        let has_engine = self.has_engine();
+        let s = &mut self.settings;
+        if let Some(v) = saved.board_flipped { s.board_flipped = v; }
+        if let Some(v) = saved.eval_precision { s.eval_precision = v.min(2); }
+        if let Some(v) = saved.player_perspective { s.player_perspective = v; }
+        if let Some(v) = saved.analysis_depth { s.analysis_depth = v.clamp(1, 30); }
+        if let Some(v) = saved.show_eval_comparison { s.show_eval_comparison = v; }
+        if let Some(v) = saved.sound_enabled { s.sound_enabled = v; }
+        if let Some(v) = saved.distinct_move_sounds { s.distinct_move_sounds = v; }
+        if let Some(v) = saved.ai_min_delay_ms { s.ai_min_delay_ms = v.min(1500); }
+        if let Some(v) = saved.include_analysis_in_pgn { s.include_analysis_in_pgn = v; }
+        if let Some(v) = saved.move_overhead_ms { s.move_overhead_ms = v.clamp(10, 500); }
+        if let Some(v) = saved.animations { s.animations = v; }
+        // Koordinaterna finns i storlekarna av (0), 16, 24 och 32
+        if let Some(v) = saved.coordinate_size { s.coordinate_size = [0, 16, 24, 32].into_iter().min_by_key(|size: &u16| size.abs_diff(v)).unwrap_or(24); }
+        if let Some(v) = saved.coordinates_inside { s.coordinates_inside = v; }
+        if let Some(v) = saved.board_border { s.board_border = v.clamp(0.0, 8.0); }
+        if let Some(v) = saved.elo_limit { s.elo_limit = v; }
+        if let Some(v) = saved.use_opening_book { s.use_opening_book = v; }
+        if let Some(v) = saved.image_size { s.image_size = v.clamp(200, 2400); }
+        if let Some(mode) = saved.game_mode.as_deref().and_then(GameMode::parse).filter(|_| has_engine) { s.game_mode = mode; }
+        if let Some(v) = saved.hot_seat_auto_flip { s.hot_seat_auto_flip = v; }
+        if let Some(ref names) = saved.player_names {
+            for (slot, name) in s.player_names.iter_mut().zip(names) {
+                if !name.trim().is_empty() {
+                    slot.clone_from(name);
+                }
+            }
+        }
+        if let Some(ref elos) = saved.autoplay_elo {
+            for (slot, elo) in s.autoplay_elo.iter_mut().zip(elos) {
+                *slot = *elo;
+            }
+        }
+        if let Some(ref time_control) = saved.time_control {
+            s.time_control = time_control.as_deref().and_then(TimeControl::parse);
+        }
+        self.auto_flip_hot_seat();
+        // Elo och Move Overhead skickas till motorn före nästa sökning
+        self.engine_options_dirty = true;
+    }
+
+    // Bygg upp en sparad analys igen. Drag, notation och sida tas från partiet,
+    // motorns värden från filen.
+    fn restore_analysis(saved: &SavedAnalysis, start: Board, moves: &[ChessMove]) -> Result<GameAnalysis, String> {
+        if saved.moves.len() > moves.len() {
+            return Err("Analysen i sparfilen passar inte partiet".to_string());
+        }
+
+        let mut board = start;
+        let mut analysis_moves = Vec::new();
+        for (entry, &chess_move) in saved.moves.iter().zip(moves) {
+            // Varianten gäller ställningen före draget; den tas med så långt den är laglig
+            let mut best_line = Vec::new();
+            let mut position = board;
+            for uci in &entry.best_line {
+                match ChessMove::from_str(uci) {
+                    Ok(m) if position.legal(m) => {
+                        best_line.push(m);
+                        position = position.make_move_new(m);
+                    }
+                    _ => break,
+                }
+            }
+            let best_move = best_line.first().copied();
+
+            analysis_moves.push(MoveAnalysis {
+                chess_move,
+                move_notation: move_to_san(&board, chess_move),
+                evaluation_before: entry.eval_before,
+                evaluation_after: entry.eval_after,
+                centipawn_loss: entry.centipawn_loss,
+                is_blunder: entry.blunder,
+                is_mistake: entry.mistake,
+                is_inaccuracy: entry.inaccuracy,
+                is_great: entry.great,
+                is_brilliant: entry.brilliant,
+                best_move,
+                best_move_notation: best_move.map(|m| move_to_san(&board, m)),
+                best_line,
+                material_after: entry.material_after,
+                skipped: entry.skipped,
+                side: board.side_to_move(),
+            });
+            board = board.make_move_new(chess_move);
+        }
+        Ok(GameAnalysis::from_moves(analysis_moves, saved.depth))
+    }
+
+    // Erbjud att fortsätta det autosparade partiet, om det finns ett
+    fn offer_resume(&mut self) {
+        if let Ok(pgn) = std::fs::read_to_string(AUTOSAVE_PATH) {
+            self.resume_prompt = Some(pgn);
+        }
+    }
+
+    fn answer_resume(&mut self, resume: bool) {
+        let Some(pgn) = self.resume_prompt.take() else {
+            return;
+        };
+        if !resume {
+            return;
+        }
+        // En trasig sparfil ignoreras och ett nytt parti startas
+        if let Err(e) = self.load_pgn(&pgn) {
+            eprintln!("[answer_resume] Kunde inte läsa autosparat parti: {}", e);
+            self.reset_game();
+            self.toast("⚠ Sparat parti var skadat – nytt parti");
+        }
+    }
+
+    // Rita frågan om att fortsätta senaste parti
+    fn draw_resume_prompt(&self) {
+        if self.resume_prompt.is_none() {
+            return;
+        }
+
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.5));
+        let (width, height) = RESUME_DIALOG_SIZE;
+        let (x, y) = overlay_origin(width, height);
+        draw_rectangle(x, y, width, height, WHITE);
+        draw_rectangle_lines(x, y, width, height, 3.0, DARKGRAY);
+        draw_text("Fortsätt senaste parti?", x + 20.0, y + 40.0, 24.0, BLACK);
+        self.resume_yes_button.draw();
+        self.resume_no_button.draw();
+    }
+
+    // Fråga innan taktikläget ersätter ett pågående parti
+    fn draw_puzzle_prompt(&self) {
+        if !self.puzzle_prompt {
+            return;
+        }
+
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.5));
+        let (width, height) = PUZZLE_DIALOG_SIZE;
+        let (x, y) = overlay_origin(width, height);
+        draw_rectangle(x, y, width, height, WHITE);
+        draw_rectangle_lines(x, y, width, height, 3.0, DARKGRAY);
+        draw_text("Lämna partiet för taktikläget?", x + 20.0, y + 40.0, 24.0, BLACK);
+        self.puzzle_yes_button.draw();
+        self.puzzle_no_button.draw();
+    }
+
+    fn has_engine(&self) -> bool {
+        self.engine_info.is_some()
+    }
+
+    // Förklara varför motorn saknas, vad som inte fungerar och hur den installeras
+    fn draw_engine_notice(&self) {
+        if !self.engine_notice {
+            return;
+        }
+
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.5));
+        let (width, height) = ENGINE_NOTICE_SIZE;
+        let (x, y) = overlay_origin(width, height);
+        draw_rectangle(x, y, width, height, WHITE);
+        draw_rectangle_lines(x, y, width, height, 3.0, RED);
+        draw_text("⚠ Ingen schackmotor hittades", x + 20.0, y + 40.0, 26.0, RED);
+        let lines = [
+            "Stockfish kunde inte startas, så spelet körs utan motor:",
+            "• AI:n spelar inte – partier spelas av två personer",
+            "• Analys, utvärdering och motormatch är avstängda",
+            "",
+            "Installera Stockfish (stockfishchess.org) och lägg stockfish.exe",
+            "bredvid programmet eller i C:\\stockfish\\, eller ange sökvägen",
+            "med --engine <sökväg> eller miljövariabeln STOCKFISH_PATH.",
+            "Starta sedan om programmet.",
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            draw_text(line, x + 20.0, y + 75.0 + i as f32 * 18.0, 17.0, BLACK);
+        }
+        self.engine_notice_button.draw();
+    }
+
+    // Kopiera aktuell (eller granskad) ställning som FEN. Urklippet kan inte bekräfta att
+    // det lyckades, så FEN skrivs alltid även till stdout.
+    fn copy_fen(&mut self) {
+        let fen = self.get_display_board().to_string();
+        miniquad::window::clipboard_set(&fen);
+        println!("[copy_fen] FEN: {}", fen);
+        self.toast("✓ FEN kopierad");
+    }
+
+    // Läs in en ställning från urklippet
+    fn paste_fen(&mut self) {
+        let Some(text) = miniquad::window::clipboard_get() else {
+            self.toast("⚠ Urklippet är inte tillgängligt");
+            return;
+        };
+        match self.load_fen(text.trim()) {
+            Ok(()) => self.toast("✓ Ställning inläst från urklipp"),
+            Err(e) => self.toast(format!("⚠ {}", e)),
+        }
+    }
+
+    // Öppna FEN-rutan, förifylld med aktuell ställning
+    fn open_fen_input(&mut self) {
+        // Teckenkön töms bara här; annars hamnar alla kortkommandon sedan start i rutan
+        clear_input_queue();
+        self.fen_input = Some(self.board.to_string());
+        self.fen_error = None;
+    }
+
+    // Läs in ställningen i FEN-rutan; vid fel ligger rutan kvar med felet
+    fn submit_fen_input(&mut self) {
+        let Some(fen) = self.fen_input.clone() else {
+            return;
+        };
+        match self.load_fen(&fen) {
+            Ok(()) => {
+                self.fen_input = None;
+                self.fen_error = None;
+                self.toast("✓ Ställning inläst");
+            }
+            Err(e) => self.fen_error = Some(e),
+        }
+    }
+
+    // Skriv i FEN-rutan: tecken läggs till, Backspace raderar, Enter läser in, Escape stänger
+    fn handle_fen_keys(&mut self) {
+        if is_key_pressed(KeyCode::Escape) {
+            self.fen_input = None;
+            return;
+        }
+        if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::KpEnter) {
+            self.submit_fen_input();
+            return;
+        }
+        let Some(text) = self.fen_input.as_mut() else {
+            return;
+        };
+        if is_key_pressed(KeyCode::Backspace) {
+            text.pop();
+        }
+        for c in take_typed_chars() {
+            if !c.is_control() && text.len() < 100 {
+                text.push(c);
+            }
+        }
+    }
+
+    // Rita FEN-rutan som overlay
+    fn draw_fen_input(&self) {
+        let Some(ref text) = self.fen_input else {
+            return;
+        };
+
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.5));
+        let (width, height) = FEN_DIALOG_SIZE;
+        let (x, y) = overlay_origin(width, height);
+        draw_rectangle(x, y, width, height, WHITE);
+        draw_rectangle_lines(x, y, width, height, 3.0, DARKGRAY);
+        draw_text("Ange ställning (FEN)", x + 20.0, y + 30.0, 22.0, BLACK);
+
+        draw_rectangle(x + 20.0, y + 45.0, width - 40.0, 30.0, Color::new(0.95, 0.95, 0.95, 1.0));
+        draw_rectangle_lines(x + 20.0, y + 45.0, width - 40.0, 30.0, 2.0, BLUE);
+        // Långa FEN-strängar visas från slutet så att markören syns
+        let skip = text.chars().count().saturating_sub(62);
+        let visible: String = text.chars().skip(skip).collect();
+        let caret = if (get_time() * 2.0) as i64 % 2 == 0 { "|" } else { "" };
+        draw_text(&format!("{visible}{caret}"), x + 26.0, y + 66.0, 14.0, BLACK);
+
+        if let Some(ref error) = self.fen_error {
+            draw_text(error, x + 20.0, y + 100.0, 14.0, RED);
+        } else {
+            draw_text("Enter = läs in, Esc = avbryt", x + 20.0, y + 100.0, 14.0, DARKGRAY);
+        }
+
+        self.fen_load_button.draw();
+        self.fen_cancel_button.draw();
+    }
+
+    // Öppna ställningsredigeraren med den ställning som visas
+    fn open_editor(&mut self) {
+        self.cancel_ai_search();
+        self.selected_square = None;
+        self.highlighted_moves.clear();
+        self.editor = Some(BoardEditor::from_board(self.get_display_board()));
+    }
+
+    // Starta ett nytt parti från redigerarens ställning om den är giltig
+    fn start_from_editor(&mut self) {
+        let Some(ref mut editor) = self.editor else {
+            return;
+        };
+        match editor.validate() {
+            Ok(board) => {
+                println!("[start_from_editor] FEN: {}", board);
+                self.editor = None;
+                self.load_imported(ImportedGame::from_position(board));
+                self.toast("✓ Spelet startar från den uppställda ställningen");
+            }
+            Err(e) => editor.error = Some(e),
+        }
+    }
+
+    // Klick i redigeraren: en ruta på brädet, en pjäs i paletten eller en av knapparna
+    fn handle_editor_click(&mut self, mouse_pos: (f32, f32)) {
+        if self.editor_start_button.is_clicked() {
+            self.start_from_editor();
+            return;
+        }
+        if self.editor_cancel_button.is_clicked() {
+            self.editor = None;
+            return;
+        }
+        let square = self.layout.square_at(mouse_pos).map(|(x, y)| self.coords_to_square(x, y));
+        let palette_tool = self.editor_palette_at(mouse_pos);
+        let Some(ref mut editor) = self.editor else {
+            return;
+        };
+
+        if let Some(square) = square {
+            editor.apply_tool(square);
+        } else if let Some(tool) = palette_tool {
+            editor.tool = Some(tool);
+        } else if self.editor_eraser_button.is_clicked() {
+            editor.tool = None;
+        } else if self.editor_side_button.is_clicked() {
+            editor.side_to_move = !editor.side_to_move;
+        } else if self.editor_clear_button.is_clicked() {
+            editor.clear();
+        } else if self.editor_reset_button.is_clicked() {
+            *editor = BoardEditor { tool: editor.tool, ..BoardEditor::from_board(&Board::default()) };
+        } else if let Some(i) = self.editor_castle_buttons.iter().position(Button::is_clicked) {
+            editor.castling[i] = !editor.castling[i];
+        }
+    }
+
+    // Pjäsen i paletten under muspekaren: vit rad överst, svart under
+    fn editor_palette_at(&self, (px, py): (f32, f32)) -> Option<PieceKey> {
+        let column = ((px - self.layout.panel_x) / EDITOR_CELL).floor();
+        let row = ((py - EDITOR_PALETTE_Y) / EDITOR_CELL).floor();
+        if !(0.0..6.0).contains(&column) || !(0.0..2.0).contains(&row) {
+            return None;
+        }
+        let color = if row == 0.0 { ChessColor::White } else { ChessColor::Black };
+        Some(PieceKey { piece: EDITOR_PIECES[column as usize], color })
+    }
+
+    // Knapptexterna följer redigerarens ställning
+    fn update_editor_buttons(&mut self) {
+        let Some(ref editor) = self.editor else {
+            return;
+        };
+        self.editor_side_button.text = if editor.side_to_move == ChessColor::White { "Vit vid draget" } else { "Svart vid draget" }.to_string();
+        let labels = ["Vit O-O", "Vit O-O-O", "Svart O-O", "Svart O-O-O"];
+        for (i, button) in self.editor_castle_buttons.iter_mut().enumerate() {
+            let possible = editor.castling_possible(i);
+            let mark = if possible && editor.castling[i] { " ✓" } else { "" };
+            button.text = format!("{}{}", labels[i], mark);
+            button.set_active(possible);
+        }
+    }
+
+    // Redigerarens pjäser på brädet och paletten i panelens ställe
+    fn draw_editor(&self) {
+        let Some(ref editor) = self.editor else {
+            return;
+        };
+        let square_size = self.layout.square_size;
+        for square in chess::ALL_SQUARES {
+            if let Some(key) = editor.piece_on(square) {
+                let (x, y) = self.square_to_coords(square);
+                let (screen_x, screen_y) = self.layout.square_origin(x, y);
+                self.draw_piece(key, screen_x, screen_y, square_size);
+            }
+        }
+
+        let panel_x = self.layout.panel_x;
+        draw_rectangle(panel_x - 10.0, 50.0, 200.0, 750.0, Color::new(0.95, 0.95, 0.95, 1.0));
+        draw_rectangle_lines(panel_x - 10.0, 50.0, 200.0, 750.0, 2.0, DARKGRAY);
+        draw_text("STÄLL UPP BRÄDE", panel_x, 80.0, 20.0, BLACK);
+
+        for (row, color) in [ChessColor::White, ChessColor::Black].into_iter().enumerate() {
+            for (column, piece) in EDITOR_PIECES.into_iter().enumerate() {
+                let key = PieceKey { piece, color };
+                let x = panel_x + column as f32 * EDITOR_CELL;
+                let y = EDITOR_PALETTE_Y + row as f32 * EDITOR_CELL;
+                draw_rectangle(x, y, EDITOR_CELL, EDITOR_CELL, if (row + column) % 2 == 0 { BEIGE } else { BROWN });
+                self.draw_piece(key, x, y, EDITOR_CELL);
+                if editor.tool == Some(key) {
+                    draw_rectangle_lines(x, y, EDITOR_CELL, EDITOR_CELL, 3.0, BLUE);
+                }
+            }
+        }
+        self.editor_eraser_button.draw();
+        if editor.tool.is_none() {
+            let b = &self.editor_eraser_button;
+            draw_rectangle_lines(b.x, b.y, b.width, b.height, 3.0, BLUE);
+        }
+
+        self.editor_side_button.draw();
+        draw_text("Rockad:", panel_x, 275.0, 16.0, BLACK);
+        for button in &self.editor_castle_buttons {
+            button.draw();
+        }
+        self.editor_clear_button.draw();
+        self.editor_reset_button.draw();
+        self.editor_start_button.draw();
+        self.editor_cancel_button.draw();
+
+        let mut y = 545.0;
+        for line in ["Klicka på en ruta för att", "placera vald pjäs; samma", "pjäs igen tar bort den.", "Esc = avbryt"] {
+            draw_text(line, panel_x, y, 14.0, DARKGRAY);
+            y += 18.0;
+        }
+        if let Some(ref error) = editor.error {
+            y += 10.0;
+            for line in wrap_text(error, 24) {
+                draw_text(&line, panel_x, y, 14.0, RED);
+                y += 18.0;
+            }
+        }
+    }
+
+    // Öppna namnrutan för en av spelarna, förifylld med nuvarande namn
+    fn open_name_input(&mut self, slot: usize) {
+        // Som i FEN-rutan: tecken som skrivits innan rutan öppnades ska inte hamna i namnet
+        clear_input_queue();
+        self.name_input = Some((slot, self.settings.player_names[slot].clone()));
+    }
+
+    // Spara namnet i rutan; ett tomt namn behåller det gamla
+    fn submit_name_input(&mut self) {
+        let Some((slot, text)) = self.name_input.take() else {
+            return;
+        };
+        let name = text.trim();
+        if !name.is_empty() {
+            self.settings.player_names[slot] = name.to_string();
+            self.settings_dirty = true;
+            self.autosave();
+        }
+    }
+
+    // Skriv i namnrutan: Backspace raderar, Enter sparar, Escape stänger
+    fn handle_name_keys(&mut self) {
+        const MAX_NAME_CHARS: usize = 24;
+
+        if is_key_pressed(KeyCode::Escape) {
+            self.name_input = None;
+            return;
+        }
+        if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::KpEnter) {
+            self.submit_name_input();
+            return;
+        }
+        let Some((_, text)) = self.name_input.as_mut() else {
+            return;
+        };
+        if is_key_pressed(KeyCode::Backspace) {
+            text.pop();
+        }
+        for c in take_typed_chars() {
+            if !c.is_control() && text.chars().count() < MAX_NAME_CHARS {
+                text.push(c);
+            }
+        }
+    }
+
+    // Rita namnrutan ovanpå inställningsfönstret
+    fn draw_name_input(&self) {
+        let Some((slot, ref text)) = self.name_input else {
+            return;
+        };
+
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.5));
+        let (width, height) = NAME_DIALOG_SIZE;
+        let (x, y) = overlay_origin(width, height);
+        draw_rectangle(x, y, width, height, WHITE);
+        draw_rectangle_lines(x, y, width, height, 3.0, DARKGRAY);
+        draw_text(if slot == 0 { "Ditt namn" } else { "Motspelarens namn" }, x + 20.0, y + 30.0, 22.0, BLACK);
+
+        draw_rectangle(x + 20.0, y + 45.0, width - 40.0, 30.0, Color::new(0.95, 0.95, 0.95, 1.0));
+        draw_rectangle_lines(x + 20.0, y + 45.0, width - 40.0, 30.0, 2.0, BLUE);
+        let caret = if (get_time() * 2.0) as i64 % 2 == 0 { "|" } else { "" };
+        draw_text(&format!("{text}{caret}"), x + 26.0, y + 66.0, 16.0, BLACK);
+        draw_text("Enter = spara, Esc = avbryt", x + 20.0, y + 100.0, 14.0, DARKGRAY);
+
+        self.name_save_button.draw();
+        self.name_cancel_button.draw();
+    }
+
+    // Gå till en specifik position i partiet
+    fn show_position_at_move(&mut self, move_index: usize) {
+        if move_index >= self.move_history.len() {
+            return;
         }
         
-        // Bygg upp positionen från början till det valda draget
-        let mut temp_board = Board::default();
-        
-        for i in 0..=move_index {
-            if let Some(move_str) = self.move_history.get(i) {
-                if move_str.contains("uppgivning") {
-                    break;
-                }
-                
-                if let Some(chess_move) = Self::find_move_from_history(&temp_board, move_str) {
-                    temp_board = temp_board.make_move_new(chess_move);
-                }
-            }
-        }
+        let record = &self.move_history[move_index];
+        self.review_last_move = Some(record.chess_move);
         
         // Sätt review-läge
         self.review_mode = true;
-        self.review_board = Some(temp_board);
+        self.review_board = Some(record.board_after);
         self.review_move_index = Some(move_index);
+        self.scroll_move_list_to(move_index);
         
         // Rensa urval
         self.selected_square = None;
@@ -662,15 +5975,106 @@ impl ChessGame {
         
         println!("[show_position_at_move] Visar position efter drag {}: {}", 
                  move_index + 1, 
-                 self.move_history.get(move_index).unwrap_or(&"?".to_string()));
+                 self.move_history[move_index].san);
     }
     
-    // Återgå till aktuell position
-    fn exit_review_mode(&mut self) {
-        if let Some(original) = self.original_board.take() {
-            self.board = original;
+    // Ställningen innan draget med givet index spelades
+    fn board_before_move(&self, move_index: usize) -> Board {
+        let played = &self.move_history[..move_index.min(self.move_history.len())];
+        played.last().map_or(self.start_board, |record| record.board_after)
+    }
+    
+    // Visa startställningen i granskningsläget (inget drag valt)
+    fn show_start_position(&mut self) {
+        self.review_mode = true;
+        self.review_board = Some(self.start_board);
+        self.review_move_index = None;
+        self.review_last_move = None;
+        self.selected_square = None;
+        self.highlighted_moves.clear();
+    }
+
+    // Dragnummer med sidmarkering för halvdrag nummer `ply` (0 = första), t.ex. "12." för
+    // vits drag och "12..." för svarts. Partier från FEN räknas från sitt eget dragnummer.
+    fn move_label(&self, ply: usize) -> String {
+        let ply = ply + (self.start_board.side_to_move() == ChessColor::Black) as usize;
+        let dots = if ply.is_multiple_of(2) { "." } else { "..." };
+        format!("{}{}", self.start_fullmove + ply / 2, dots)
+    }
+
+    // Startställningens FEN med dess räknare
+    fn start_fen(&self) -> String {
+        fen_with_counters(&self.start_board, self.start_halfmove, self.start_fullmove)
+    }
+
+    // Antal spelade drag
+    fn played_move_count(&self) -> usize {
+        self.move_history.len()
+    }
+
+    // Piltangenter stegar genom partiet: vänster/upp bakåt, höger/ned framåt,
+    // Home till startställningen och End tillbaka till aktuell ställning
+    fn handle_review_keys(&mut self) {
+        let back = is_key_pressed(KeyCode::Left) || is_key_pressed(KeyCode::Up);
+        let forward = is_key_pressed(KeyCode::Right) || is_key_pressed(KeyCode::Down);
+
+        if is_key_pressed(KeyCode::Home) {
+            self.review_first();
+        } else if is_key_pressed(KeyCode::End) {
+            self.exit_review_mode();
+        } else if back {
+            self.review_back();
+        } else if forward {
+            self.review_forward();
         }
-        
+    }
+
+    // Till startställningen; från aktuell ställning går man in i granskningsläget
+    fn review_first(&mut self) {
+        if self.played_move_count() > 0 {
+            self.show_start_position();
+        }
+    }
+
+    // Ett drag bakåt, även mitt i ett pågående parti
+    fn review_back(&mut self) {
+        let played = self.played_move_count();
+        match self.review_move_index {
+            Some(0) => self.show_start_position(),
+            Some(index) => self.show_position_at_move(index - 1),
+            // Startställningen visas redan
+            None if self.review_mode => {}
+            None if played > 1 => self.show_position_at_move(played - 2),
+            None if played == 1 => self.show_start_position(),
+            None => {}
+        }
+    }
+
+    // Ett drag framåt; från sista draget går man tillbaka till spelet
+    fn review_forward(&mut self) {
+        if !self.review_mode {
+            return;
+        }
+        match self.review_move_index {
+            Some(index) if index + 1 < self.played_move_count() => self.show_position_at_move(index + 1),
+            Some(_) => self.exit_review_mode(),
+            None if self.played_move_count() > 0 => self.show_position_at_move(0),
+            None => self.exit_review_mode(),
+        }
+    }
+
+    // Draget som motsvarar ställningen på brädet: det granskade eller det senast spelade
+    fn current_ply(&self) -> Option<usize> {
+        if self.review_mode {
+            self.review_move_index
+        } else {
+            self.played_move_count().checked_sub(1)
+        }
+    }
+
+    // Återgå till aktuell position. Granskningen ändrar aldrig self.board, så ett
+    // drag som spelats under tiden (t.ex. motorns) finns kvar.
+    fn exit_review_mode(&mut self) {
         self.review_mode = false;
         self.review_board = None;
         self.review_move_index = None;
@@ -700,13 +6104,21 @@ impl ChessGame {
             // Starta analysen i en separat tråd
             let ai_clone = ai.clone();
             let move_history_clone = self.move_history.clone();
-            let initial_board = Board::default();
+            let initial_board = self.start_board;
+            let phase = self.analysis_phase;
+            let depth = self.settings.analysis_depth;
+            let cancel = Arc::new(AtomicBool::new(false));
+            self.analysis_cancel = Some((Arc::clone(&cancel), ai.stop_handle()));
             
             let (tx, rx) = mpsc::channel();
             
+            self.analysis_progress = (0, move_history_clone.len());
             thread::spawn(move || {
-                let analysis = Self::analyze_full_game(ai_clone, move_history_clone, initial_board);
-                let _ = tx.send(analysis);
+                let progress_tx = tx.clone();
+                let analysis = Self::analyze_full_game(ai_clone, move_history_clone, initial_board, phase, depth, &cancel, |done, total| {
+                    let _ = progress_tx.send(GameAnalysisMessage::Progress { done, total });
+                });
+                let _ = tx.send(GameAnalysisMessage::Done(analysis));
             });
             
             self.analysis_receiver = Some(rx);
@@ -716,93 +6128,140 @@ impl ChessGame {
     // Analysera hela partiet från början
     fn analyze_full_game(
         ai_controller: ThreadSafeAiController, 
-        move_history: Vec<String>, 
-        board: Board
+        move_history: Vec<MoveRecord>, 
+        board: Board,
+        phase: AnalysisPhase,
+        depth: u8,
+        cancel: &AtomicBool,
+        mut on_progress: impl FnMut(usize, usize),
     ) -> GameAnalysis {
+        let total_moves = move_history.len();
         let mut analysis_moves = Vec::new();
         let mut current_board = board;
-        let depth = 15; // Djupare analys för bättre precision
         
         println!("[analyze_full_game] Analyserar {} drag...", move_history.len());
         
-        for (move_index, move_str) in move_history.iter().enumerate() {
-            if move_str.contains("uppgivning") {
+        for (move_index, record) in move_history.iter().enumerate() {
+            let played_move = record.chess_move;
+            // Avbruten analys: behåll de drag som hunnit analyseras färdigt
+            if cancel.load(Ordering::SeqCst) {
+                println!("[analyze_full_game] Avbruten efter {} drag", move_index);
                 break;
             }
+            on_progress(move_index, total_moves);
             
-            println!("[analyze_full_game] Analyserar drag {}: {}", move_index + 1, move_str);
+            // Drag utanför vald fas får en markerad tom post så att indexen stämmer med draghistoriken
+            if !phase.contains(move_index, &current_board) {
+                let side = current_board.side_to_move();
+                current_board = record.board_after;
+                analysis_moves.push(MoveAnalysis {
+                    chess_move: played_move,
+                    move_notation: record.san.clone(),
+                    evaluation_before: 0.0,
+                    evaluation_after: 0.0,
+                    centipawn_loss: 0,
+                    is_blunder: false,
+                    is_mistake: false,
+                    is_inaccuracy: false,
+                    is_great: false,
+                    is_brilliant: false,
+                    best_move: None,
+                    best_move_notation: None,
+                    best_line: Vec::new(),
+                    material_after: Self::simple_material_evaluation(&current_board),
+                    skipped: true,
+                    side,
+                });
+                continue;
+            }
             
-            // Hämta aktuell position före draget
-            let evaluation_before = Self::get_position_evaluation(&ai_controller, &current_board, depth);
+            println!("[analyze_full_game] Analyserar drag {}: {}", move_index + 1, record.san);
             
-            // Hitta det faktiska draget som spelades
-            if let Some(played_move) = Self::find_move_from_history(&current_board, move_str) {
-                // Hämta bästa draget enligt motorn
-                let best_move_result = Self::get_best_move_sync(&ai_controller, &current_board, depth);
-                
-                // Gör draget
-                current_board = current_board.make_move_new(played_move);
-                
-                // Utvärdera positionen efter draget
-                let evaluation_after = Self::get_position_evaluation(&ai_controller, &current_board, depth);
-                
-                // Beräkna centipawn-förlust  
-                let side_that_moved = if move_index % 2 == 0 { ChessColor::White } else { ChessColor::Black };
-                let centipawn_loss = Self::calculate_centipawn_loss(
-                    evaluation_before, 
-                    evaluation_after, 
-                    side_that_moved
-                );
-                
-                // Klassificera draget
-                let (is_blunder, is_mistake, is_inaccuracy) = Self::classify_move(centipawn_loss);
-                
-                let analysis = MoveAnalysis {
-                    chess_move: played_move,
-                    move_notation: move_str.clone(),
-                    evaluation_before,
-                    evaluation_after,
-                    centipawn_loss,
-                    is_blunder,
-                    is_mistake,
-                    is_inaccuracy,
-                    best_move: best_move_result.0,
-                    best_move_notation: best_move_result.1,
-                };
-                
-                analysis_moves.push(analysis);
+            // En sökning ger både evalueringen före draget och motorns bästa drag
+            let before = Self::analyze_position(&ai_controller, &current_board, depth, cancel);
+            let evaluation_before = before.evaluation;
+            let best_move = before.best_line.first().copied();
+            let best_move_notation = best_move.map(|m| move_to_san(&current_board, m));
+            let material_before = Self::simple_material_evaluation(&current_board);
+            
+            // Gör draget (sidan vid draget tas från brädet så att partier från FEN blir rätt)
+            let side_that_moved = current_board.side_to_move();
+            current_board = record.board_after;
+            
+            // Utvärdera positionen efter draget
+            let after = Self::analyze_position(&ai_controller, &current_board, depth, cancel);
+            let evaluation_after = after.evaluation;
+
+            // Ett drag vars sökning avbröts har bara materialvärden och tas inte med
+            if cancel.load(Ordering::SeqCst) {
+                println!("[analyze_full_game] Avbruten efter {} drag", move_index);
+                break;
             }
+            
+            // Beräkna centipawn-förlust  
+            let centipawn_loss = Self::calculate_centipawn_loss(
+                evaluation_before, 
+                evaluation_after, 
+                side_that_moved
+            );
+            
+            // Klassificera draget efter hur mycket vinstchansen sjönk
+            let (win_before, win_after) = mover_win_percents(evaluation_before, evaluation_after, side_that_moved);
+            let (is_blunder, is_mistake, is_inaccuracy) = Self::classify_move(win_before, win_after);
+            let is_great = Some(played_move) == best_move
+                && is_only_good_move(evaluation_before, before.second_evaluation, side_that_moved);
+            let is_brilliant = win_before - win_after <= BRILLIANT_MAX_DROP
+                && win_after >= 50.0
+                && sacrificed_material(material_before, &current_board, &after.best_line, side_that_moved) >= SACRIFICE_PAWNS;
+            
+            let analysis = MoveAnalysis {
+                chess_move: played_move,
+                move_notation: record.san.clone(),
+                evaluation_before,
+                evaluation_after,
+                centipawn_loss,
+                is_blunder,
+                is_mistake,
+                is_inaccuracy,
+                is_great: is_great && !is_brilliant,
+                is_brilliant,
+                best_move,
+                best_move_notation,
+                best_line: before.best_line,
+                material_after: Self::simple_material_evaluation(&current_board),
+                skipped: false,
+                side: side_that_moved,
+            };
+            
+            analysis_moves.push(analysis);
         }
         
-        // Beräkna övergripande statistik
-        let (white_accuracy, black_accuracy) = Self::calculate_accuracy(&analysis_moves);
-        let total_blunders = analysis_moves.iter().filter(|m| m.is_blunder).count();
-        let total_mistakes = analysis_moves.iter().filter(|m| m.is_mistake).count();
-        let total_inaccuracies = analysis_moves.iter().filter(|m| m.is_inaccuracy).count();
+        on_progress(analysis_moves.len(), total_moves);
         
+        let analysis = GameAnalysis::from_moves(analysis_moves, depth);
         println!("[analyze_full_game] Analys klar! Blunders: {}, Misstag: {}, Inexaktheter: {}", 
-                 total_blunders, total_mistakes, total_inaccuracies);
-        
-        GameAnalysis {
-            moves: analysis_moves,
-            white_accuracy,
-            black_accuracy,
-            total_blunders,
-            total_mistakes,
-            total_inaccuracies,
+                 analysis.total_blunders, analysis.total_mistakes, analysis.total_inaccuracies);
+        analysis
+    }
+
+    // Evaluering och bästa drag ur en enda sökning, via motorns cache. Vid avbrott eller
+    // motorfel används materialvärdet, som inte cachas så att ställningen provas igen.
+    fn analyze_position(ai_controller: &ThreadSafeAiController, board: &Board, depth: u8, cancel: &AtomicBool) -> PositionResult {
+        // Efter avbrott startas inga nya sökningar
+        if cancel.load(Ordering::SeqCst) {
+            return PositionResult::material_only(board);
         }
+        ai_controller.analyze_position(board, depth, cancel).unwrap_or_else(|_| PositionResult::material_only(board))
     }
 
-    // Hjälpfunktion för att få positionsutvärdering
-    fn get_position_evaluation(ai_controller: &ThreadSafeAiController, board: &Board, depth: u8) -> f32 {
-        match ai_controller.inner.lock() {
-            Ok(mut sf) => {
-                match sf.get_evaluation(board, depth) {
-                    Ok(eval) => eval,
-                    Err(_) => Self::simple_material_evaluation(board)
-                }
-            }
-            Err(_) => Self::simple_material_evaluation(board)
+    // Pjäsvärden i bönder för den enkla materialräkningen
+    fn piece_value(piece: Piece) -> f32 {
+        match piece {
+            Piece::Pawn => 1.0,
+            Piece::Knight | Piece::Bishop => 3.0,
+            Piece::Rook => 5.0,
+            Piece::Queen => 9.0,
+            Piece::King => 0.0,
         }
     }
 
@@ -811,21 +6270,9 @@ impl ChessGame {
         let mut white_material = 0.0;
         let mut black_material = 0.0;
         
-        let piece_values = [
-            (Piece::Pawn, 1.0),
-            (Piece::Knight, 3.0),
-            (Piece::Bishop, 3.0),
-            (Piece::Rook, 5.0),
-            (Piece::Queen, 9.0),
-            (Piece::King, 0.0),
-        ];
-        
         for square in chess::ALL_SQUARES {
             if let Some(piece) = board.piece_on(square) {
-                let value = piece_values.iter()
-                    .find(|(p, _)| *p == piece)
-                    .map(|(_, v)| *v)
-                    .unwrap_or(0.0);
+                let value = Self::piece_value(piece);
                 
                 match board.color_on(square).unwrap() {
                     ChessColor::White => white_material += value,
@@ -837,224 +6284,674 @@ impl ChessGame {
         white_material - black_material
     }
 
-    // Hitta drag från draghistorik
-    fn find_move_from_history(board: &Board, move_str: &str) -> Option<ChessMove> {
-        // Enkel parsing av algebraisk notation
-        if let Some(dash_pos) = move_str.find('-') {
-            let from_str = &move_str[..dash_pos];
-            let to_str = &move_str[dash_pos + 1..];
-            
-            if let (Ok(from_square), Ok(to_square)) = (
-                Square::from_str(from_str),
-                Square::from_str(to_str)
-            ) {
-                let chess_move = ChessMove::new(from_square, to_square, None);
-                
-                // Kontrollera om draget är lagligt
-                let movegen = MoveGen::new_legal(board);
-                if movegen.into_iter().any(|m| m == chess_move) {
-                    return Some(chess_move);
+    // Motståndarens pjäser som en sida har slagit, per pjässlag: skillnaden mot startställningen.
+    // Förvandlade bönder räknas som den nya pjäsen, så ett antal kan inte bli negativt.
+    fn captured_pieces(start: &Board, board: &Board, capturer: ChessColor) -> Vec<(Piece, u32)> {
+        let victim = board.color_combined(!capturer);
+        let start_victim = start.color_combined(!capturer);
+        [Piece::Pawn, Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen]
+            .into_iter()
+            .map(|piece| {
+                let before = (start.pieces(piece) & start_victim).popcnt();
+                let now = (board.pieces(piece) & victim).popcnt();
+                (piece, before.saturating_sub(now))
+            })
+            .filter(|&(_, count)| count > 0)
+            .collect()
+    }
+
+    // Beräkna centipawn-förlust
+    fn calculate_centipawn_loss(eval_before: f32, eval_after: f32, side_that_moved: ChessColor) -> i32 {
+        // För vit: förlust = minskning i utvärdering (eval_before > eval_after)
+        // För svart: förlust = ökning i utvärdering (eval_after > eval_before, från vits perspektiv)
+        let loss = if side_that_moved == ChessColor::White {
+            eval_before - eval_after
+        } else {
+            eval_after - eval_before
+        };
+        
+        // Konvertera till centipawns och se till att vi bara har positiva förluster
+        (loss * 100.0).max(0.0) as i32
+    }
+
+    // Klassificera drag efter fallet i vinstchans (procentenheter) för den som drog.
+    // Samma förlust i bönder väger tungt nära jämvikt men nästan inget i en redan
+    // avgjord ställning, där vinstchansen knappt rör sig.
+    fn classify_move(win_before: f32, win_after: f32) -> (bool, bool, bool) {
+        let drop = win_before - win_after;
+        let is_blunder = drop >= 15.0;      // Blunder: ≥15 procentenheter (~1.7 bönder vid jämvikt)
+        let is_mistake = drop >= 10.0;      // Misstag: ≥10 procentenheter (~1.1 bönder)
+        let is_inaccuracy = drop >= 5.0;    // Inexakthet: ≥5 procentenheter (~0.55 bönder)
+        
+        (is_blunder, is_mistake && !is_blunder, is_inaccuracy && !is_mistake && !is_blunder)
+    }
+
+    // Beräkna noggrannhet för båda spelarna
+    fn calculate_accuracy(moves: &[MoveAnalysis]) -> (f32, f32) {
+        let mut white_moves = Vec::new();
+        let mut black_moves = Vec::new();
+        
+        for m in moves {
+            if m.skipped {
+                continue;
+            }
+            if m.side == ChessColor::White {
+                white_moves.push(m);
+            } else {
+                black_moves.push(m);
+            }
+        }
+        
+        let white_accuracy = Self::calculate_player_accuracy(&white_moves);
+        let black_accuracy = Self::calculate_player_accuracy(&black_moves);
+        
+        (white_accuracy, black_accuracy)
+    }
+
+    // Beräkna noggrannhet för en spelare: medelvärdet av varje drags noggrannhet,
+    // som bygger på hur mycket vinstchansen sjönk (samma modell som Lichess)
+    fn calculate_player_accuracy(moves: &[&MoveAnalysis]) -> f32 {
+        if moves.is_empty() {
+            return 100.0;
+        }
+        
+        let total: f32 = moves.iter().map(|m| m.accuracy()).sum();
+        total / moves.len() as f32
+    }
+
+    fn start_analysis(&mut self, ai: &ThreadSafeAiController) {
+        if matches!(self.ai_state, AiState::Idle) {
+            let depth = self.take_search_depth();
+            println!("[start_analysis] Startar positionsanalys med djup {depth} …");
+            let rx = ai.get_top_lines_async(self.board, depth, POSITION_ANALYSIS_LINES);
+            self.ai_state = AiState::Analyzing(rx);
+            self.current_analysis = Some("Analyserar position...".to_string());
+        }
+    }
+
+    // Spelarens perspektiv används bara när inställningen är på och spelaren har svart;
+    // lagrade evalueringar är alltid från vits perspektiv
+    fn shows_black_perspective(&self) -> bool {
+        self.settings.player_perspective && self.settings.player_color == ChessColor::Black
+    }
+
+    fn perspective_eval(&self, eval: Eval) -> Eval {
+        if self.shows_black_perspective() { eval.flipped() } else { eval }
+    }
+
+    fn finish_analysis(&mut self, top_lines: &[(Vec<ChessMove>, f32)]) {
+        let Some((best_line, best_score)) = top_lines.first() else {
+            self.current_analysis = Some("Ingen analys tillgänglig".to_string());
+            return;
+        };
+        let (best_move, best_score) = (best_line[0], *best_score);
+        self.analysis_pv = best_line.clone();
+
+        // Skapa analystext
+        let move_str = self.format_move(best_move);
+        let evaluation = self.perspective_eval(Eval::Pawns(best_score));
+        let evaluation_text = format_eval(evaluation, self.settings.eval_precision);
+        
+        let (better, worse) = if self.settings.player_perspective {
+            ("Du står bättre", "Motorn står bättre")
+        } else {
+            ("Vit står bättre", "Svart står bättre")
+        };
+        
+        self.current_analysis = Some(format!(
+            "Bästa drag: {}\nEvaluering: {}\nRekommendation: {}",
+            move_str,
+            evaluation_text,
+            if evaluation.as_pawns() > 0.0 { better } 
+            else if evaluation.as_pawns() < 0.0 { worse } 
+            else { "Jämn ställning" }
+        ));
+
+        // Huvudvarianten i SAN, följt av rangordnade kandidatdrag när motorn gav fler än ett
+        let mut text = format!("\nBästa linje:\n{}", san_line(&self.board, best_line, PV_DISPLAY_PLIES));
+        if top_lines.len() > 1 {
+            text.push_str("\nKandidatdrag:");
+            for (rank, (line, score)) in top_lines.iter().enumerate() {
+                let score_text = format_eval(self.perspective_eval(Eval::Pawns(*score)), self.settings.eval_precision);
+                text.push_str(&format!("\n{}. {} ({})", rank + 1, self.format_move(line[0]), score_text));
+            }
+        }
+        if let Some(analysis) = self.current_analysis.as_mut() {
+            analysis.push_str(&text);
+        }
+        
+        println!("[Analys] Bästa drag: {} | {}", move_str, evaluation_text);
+    }
+
+    fn evaluate_position(&self) -> Eval {
+        // Enkel materialevaluering
+        Eval::Pawns(Self::simple_material_evaluation(&self.board))
+    }
+
+    fn update_game_state(&mut self) {
+        if self.board.status() != BoardStatus::Ongoing {
+            self.end_game();
+            println!("[update_game_state] Partiet slut: {:?}", self.board.status());
+        } else if let Some(reason) = self.draw_reason() {
+            self.end_game();
+            self.toast(reason.label());
+            println!("[update_game_state] Partiet slut: {}", reason.label());
+        }
+    }
+
+    // Remi som brädet inte själv känner till. Förekomsterna räknas från draghistoriken
+    // och inte från granskningsbrädet, så granskning påverkar inte räkningen.
+    fn draw_reason(&self) -> Option<DrawReason> {
+        if let Some(Termination::Draw(reason)) = self.termination {
+            return Some(reason);
+        }
+        if insufficient_material(&self.board) {
+            return Some(DrawReason::InsufficientMaterial);
+        }
+        if self.position_occurrences.iter().any(|&count| count >= 3) {
+            return Some(DrawReason::Repetition);
+        }
+        None
+    }
+
+    // Efter 50 drag utan slag eller bondedrag får spelaren kräva remi; partiet slutar inte av sig självt
+    fn can_claim_fifty_moves(&self) -> bool {
+        !self.game_over && self.pgn_import.is_none() && self.halfmove_clock >= 100
+    }
+
+    fn claim_fifty_move_draw(&mut self) {
+        if !self.can_claim_fifty_moves() {
+            return;
+        }
+        self.cancel_ai_search();
+        self.termination = Some(Termination::Draw(DrawReason::FiftyMoves));
+        self.update_game_state();
+        self.autosave();
+    }
+
+    // Erbjud motorn remi. Den tackar ja om ställningen är jämn enligt sin egen evaluering.
+    fn offer_draw(&mut self, ai_controller: &Option<ThreadSafeAiController>) {
+        if !self.can_offer_draw() {
+            return;
+        }
+        // Två spelare vid samma dator kommer överens direkt
+        if self.settings.game_mode == GameMode::HotSeat {
+            self.termination = Some(Termination::Draw(DrawReason::Agreement));
+            self.update_game_state();
+            self.autosave();
+            return;
+        }
+        let Some(ai) = ai_controller else {
+            self.current_analysis = Some("Ingen motor att erbjuda remi".to_string());
+            return;
+        };
+        println!("[offer_draw] Remi erbjuden");
+        self.draw_offer = Some(ai.get_evaluation_async(self.board, self.settings.analysis_depth));
+    }
+
+    fn can_offer_draw(&self) -> bool {
+        !self.game_over
+            && !self.review_mode
+            && self.draw_offer.is_none()
+            && self.pgn_import.is_none()
+            && matches!(self.ai_state, AiState::Idle)
+            && self.settings.game_mode != GameMode::EngineVsEngine
+            && self.board.side_to_move() == self.human_color()
+    }
+
+    // Ta emot motorns svar på ett remierbjudande
+    fn poll_draw_offer(&mut self) {
+        const ACCEPT_MARGIN: f32 = 0.3;
+
+        let Some(ref rx) = self.draw_offer else {
+            return;
+        };
+        let eval = match rx.try_recv() {
+            Ok(eval) => eval,
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                self.draw_offer = None;
+                self.current_analysis = Some("Motorn svarade inte på remierbjudandet".to_string());
+                return;
+            }
+        };
+        self.draw_offer = None;
+
+        let accepted = matches!(eval, Eval::Pawns(p) if p.abs() <= ACCEPT_MARGIN);
+        let eval_text = format_eval(self.perspective_eval(eval), self.settings.eval_precision);
+        println!("[poll_draw_offer] Motorn bedömer {eval_text}, accepterar: {accepted}");
+        if accepted {
+            self.termination = Some(Termination::Draw(DrawReason::Agreement));
+            self.update_game_state();
+            self.autosave();
+        } else {
+            self.current_analysis = Some(format!("Motorn avböjer remi ({eval_text})"));
+        }
+    }
+
+    // Räkna ned klockan för sidan vid draget. Klockan står still före första draget,
+    // under granskning och när partiet är slut.
+    fn tick_clock(&mut self) {
+        let running = !self.game_over
+            && !self.review_mode
+            && self.resume_prompt.is_none()
+            && !self.puzzle_prompt
+            && self.pgn_import.is_none()
+            && self.played_move_count() > 0;
+        let side = self.board.side_to_move();
+        let Some(clock) = self.clock.as_mut().filter(|_| running) else {
+            return;
+        };
+        if clock.tick(side, get_frame_time() as f64) {
+            self.termination = Some(Termination::Flagged(side));
+            self.cancel_ai_search();
+            self.end_game();
+            let label = self.flag_label(side);
+            println!("[tick_clock] {}", label);
+            self.toast(label);
+            self.autosave();
+        }
+    }
+
+    fn flag_label(&self, side: ChessColor) -> String {
+        let loser = if side == ChessColor::White { "Vit" } else { "Svart" };
+        if has_mating_material(&self.board, !side) {
+            format!("{} förlorade på tid", loser)
+        } else {
+            format!("{} överskred tiden – remi (otillräckligt material)", loser)
+        }
+    }
+
+    // Noteringen efter sista draget när en sida gett upp
+    fn resignation_label(side: ChessColor) -> String {
+        let winner = if side == ChessColor::White { "Svart" } else { "Vit" };
+        format!("{} vann genom uppgivning", winner)
+    }
+
+    // Markera partiet som slut och släpp allt halvfärdigt brädval
+    fn end_game(&mut self) {
+        if !self.game_over {
+            self.play_sound(SoundEvent::GameOver, false);
+        }
+        self.game_over = true;
+        self.selected_square = None;
+        self.highlighted_moves.clear();
+        self.pending_promotion = None;
+    }
+
+    // Djup för nästa sökning. En engångsdjupsökning (D eller Shift) gäller bara en gång,
+    // sedan används reglagets djup igen.
+    fn take_search_depth(&mut self) -> u8 {
+        let shift = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+        let depth = if self.deep_search_armed || shift {
+            DEEP_SEARCH_DEPTH.max(self.depth_slider.get_value())
+        } else {
+            self.depth_slider.get_value()
+        };
+        self.deep_search_armed = false;
+        self.search_limit = SearchLimit::Depth(depth);
+        depth
+    }
+
+    // Gräns för AI:ns nästa drag. En armerad djupsökning går före klockan och tidsläget;
+    // med klocka i partiet hushållar motorn själv med sin tid.
+    fn take_search_limit(&mut self) -> SearchLimit {
+        let shift = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+        if let Some(clock) = self.clock.as_ref().filter(|_| !self.deep_search_armed && !shift) {
+            let millis = |seconds: f64| (seconds * 1000.0) as u32;
+            let increment = clock.control.increment * 1000;
+            self.search_limit = SearchLimit::Clock {
+                wtime: millis(clock.remaining(ChessColor::White)),
+                btime: millis(clock.remaining(ChessColor::Black)),
+                winc: increment,
+                binc: increment,
+            };
+            return self.search_limit;
+        }
+        if self.search_by_time && !self.deep_search_armed && !shift {
+            self.search_limit = SearchLimit::MoveTime(self.movetime_slider.get_value() as u32 * 1000);
+            return self.search_limit;
+        }
+        SearchLimit::Depth(self.take_search_depth())
+    }
+
+    // Släpp AI:ns pågående sökning direkt. Motorn ombeds sluta och dess
+    // "bestmove" kastas, eftersom mottagaren försvinner med AiState::Thinking.
+    fn cancel_ai_search(&mut self) {
+        if let (AiState::Thinking(_), Some(stop)) = (&self.ai_state, &self.ai_stop) {
+            println!("[cancel_ai_search] Avbryter AI:ns sökning");
+            stop.store(true, Ordering::SeqCst);
+        }
+        self.ai_state = AiState::Idle;
+        self.ai_stop = None;
+    }
+
+    fn start_ai(&mut self, ai: &ThreadSafeAiController) {
+        if let AiState::Idle = self.ai_state {
+            // Bokdrag skickas genom samma kanal som motorns svar, så minsta betänketid gäller
+            if let Some(book_move) = self.book_move() {
+                println!("[start_ai] Bokdrag: {book_move}");
+                let (tx, rx) = mpsc::channel();
+                let _ = tx.send(Some(SearchResult { best_move: book_move, eval: None, pv: vec![book_move] }));
+                self.ai_state = AiState::Thinking(rx);
+                self.ai_stop = None;
+                self.ai_started_at = get_time();
+                return;
+            }
+            let limit = self.take_search_limit();
+            println!("[start_ai] Startar AI‑beräkning med {limit:?} …");
+            let (rx, progress) = if self.settings.game_mode == GameMode::EngineVsEngine {
+                let elo = self.settings.autoplay_elo[self.board.side_to_move().to_index()];
+                ai.get_best_move_at_elo_async(self.board, limit, elo)
+            } else {
+                ai.get_best_move_async(self.board, limit)
+            };
+            self.ai_state = AiState::Thinking(rx);
+            self.ai_progress = Some(progress);
+            self.latest_progress = None;
+            self.ai_stop = Some(ai.stop_handle());
+            self.ai_started_at = get_time();
+        }
+    }
+
+    // Ett viktat slumpdrag ur motorns bok, om boken används och har ställningen
+    fn book_move(&self) -> Option<ChessMove> {
+        if !self.settings.use_opening_book {
+            return None;
+        }
+        self.engine_book.as_ref()?.pick_move(&self.board)
+    }
+
+    fn poll_ai(&mut self) {
+        // Bara den senaste förloppsrapporten behövs
+        if let Some(ref progress) = self.ai_progress {
+            while let Ok(latest) = progress.try_recv() {
+                self.latest_progress = Some(latest);
+            }
+        }
+
+        // Positionsanalysen är inget drag och ska inte vänta in minsta betänketid
+        if let AiState::Analyzing(ref rx) = self.ai_state {
+            if let Ok(top_lines) = rx.try_recv() {
+                self.finish_analysis(&top_lines);
+                self.ai_state = AiState::Idle;
+            }
+            return;
+        }
+        
+        // Låt AI:ns drag ligga kvar i kanalen tills minsta betänketid gått. Sökningens
+        // egen tid räknas in, så djupa sökningar fördröjs inte i onödan. Motormatchen
+        // väntar alltid en stund mellan dragen så att den går att följa.
+        let mut min_delay = self.settings.ai_min_delay_ms as f64 / 1000.0;
+        if self.settings.game_mode == GameMode::EngineVsEngine {
+            min_delay = min_delay.max(AUTOPLAY_MOVE_DELAY);
+        }
+        if get_time() - self.ai_started_at < min_delay {
+            return;
+        }
+        
+        if let AiState::Thinking(ref rx) = self.ai_state {
+            match rx.try_recv() {
+                Ok(Some(result)) => {
+                    let ai_move = result.best_move;
+                    if let Some(eval) = result.eval {
+                        self.live_eval = Some(eval);
+                    }
+
+                    println!("[poll_ai] AI‑drag mottaget: {ai_move}");
+                    self.make_move(ai_move);
+                    // I taktikläget är motorns drag alltid motståndarens svar
+                    self.puzzle_reply_played();
+                }
+                // Motorn har inget drag: ställningen är redan matt eller patt
+                Ok(None) => {
+                    println!("[poll_ai] Motorn har inga lagliga drag");
+                    self.ai_state = AiState::Idle;
+                    self.update_game_state();
+                }
+                Err(mpsc::TryRecvError::Empty) => {}
+                // Söktråden avslutades utan svar (motorfel); annars väntar AI:n för evigt
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    eprintln!("[poll_ai] AI-tråden avslutades utan drag");
+                    self.ai_state = AiState::Idle;
+                    self.ai_stop = None;
+                    self.toast("⚠ Motorn svarade inte – AI:ns sökning avbröts");
                 }
             }
         }
-        None
     }
 
-    // Hämta bästa drag synkront
-    fn get_best_move_sync(ai_controller: &ThreadSafeAiController, board: &Board, depth: u8) -> (Option<ChessMove>, Option<String>) {
-        match ai_controller.inner.lock() {
-            Ok(mut sf) => {
-                match sf.get_best_move(board, depth) {
-                    Ok(best_move) => {
-                        let notation = format!("{}-{}", 
-                            best_move.get_source(), 
-                            best_move.get_dest()
-                        );
-                        (Some(best_move), Some(notation))
-                    }
-                    Err(_) => (None, None)
+    // Ny funktion för att hantera partianalys
+    fn poll_analysis(&mut self) {
+        let Some(ref rx) = self.analysis_receiver else {
+            return;
+        };
+
+        let analysis = loop {
+            match rx.try_recv() {
+                Ok(GameAnalysisMessage::Progress { done, total }) => self.analysis_progress = (done, total),
+                Ok(GameAnalysisMessage::Done(analysis)) => break analysis,
+                Err(mpsc::TryRecvError::Empty) => return,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.analysis_in_progress = false;
+                    self.analysis_receiver = None;
+                    self.analysis_cancel = None;
+                    self.current_analysis = Some("Partianalysen avbröts oväntat".to_string());
+                    return;
                 }
             }
-            Err(_) => (None, None)
+        };
+
+        let cancelled = self.analysis_cancel.take().is_some_and(|(cancel, _)| cancel.load(Ordering::SeqCst));
+        self.analysis_in_progress = false;
+        self.analysis_receiver = None;
+        if analysis.moves.is_empty() {
+            self.current_analysis = Some("Partianalysen avbröts innan något drag analyserats".to_string());
+            return;
         }
-    }
 
-    // Beräkna centipawn-förlust
-    fn calculate_centipawn_loss(eval_before: f32, eval_after: f32, side_that_moved: ChessColor) -> i32 {
-        // För vit: förlust = minskning i utvärdering (eval_before > eval_after)
-        // För svart: förlust = ökning i utvärdering (eval_after > eval_before, från vits perspektiv)
-        let loss = if side_that_moved == ChessColor::White {
-            eval_before - eval_after
+        self.current_analysis = Some(if cancelled {
+            format!("Partianalys avbruten efter {} drag", analysis.moves.len())
         } else {
-            eval_after - eval_before
-        };
-        
-        // Konvertera till centipawns och se till att vi bara har positiva förluster
-        (loss * 100.0).max(0.0) as i32
+            "Partianalys klar! Se resultatet nedan.".to_string()
+        });
+        self.archive_analysis();
+        self.game_analysis = Some(analysis);
+        self.analysis_scroll = 0;
+        self.show_report = true;
+        println!("[poll_analysis] Partianalys mottagen och sparad!");
     }
 
-    // Klassificera drag baserat på centipawn-förlust
-    fn classify_move(centipawn_loss: i32) -> (bool, bool, bool) {
-        let is_blunder = centipawn_loss >= 300;      // Blunder: ≥3.00 pawn
-        let is_mistake = centipawn_loss >= 100;      // Misstag: ≥1.00 pawn
-        let is_inaccuracy = centipawn_loss >= 50;    // Inexakthet: ≥0.50 pawn
-        
-        (is_blunder, is_mistake && !is_blunder, is_inaccuracy && !is_mistake && !is_blunder)
+    // Be analystråden sluta efter aktuellt drag och motorn avbryta sökningen som pågår.
+    // Tråden skickar sedan en delvis analys som tas emot i poll_analysis.
+    fn cancel_game_analysis(&mut self) {
+        if let Some((ref cancel, ref engine_stop)) = self.analysis_cancel {
+            println!("[cancel_game_analysis] Avbryter partianalysen");
+            cancel.store(true, Ordering::SeqCst);
+            engine_stop.store(true, Ordering::SeqCst);
+        }
     }
 
-    // Beräkna noggrannhet för båda spelarna
-    fn calculate_accuracy(moves: &[MoveAnalysis]) -> (f32, f32) {
-        let mut white_moves = Vec::new();
-        let mut black_moves = Vec::new();
-        
-        for (i, m) in moves.iter().enumerate() {
-            if i % 2 == 0 {
-                white_moves.push(m);
-            } else {
-                black_moves.push(m);
+    // Flytta den aktuella analysen till historiken så att nästa körning kan jämföras med den
+    fn archive_analysis(&mut self) {
+        if let Some(previous) = self.game_analysis.take() {
+            self.analysis_history.push(previous);
+            if self.analysis_history.len() > ANALYSIS_HISTORY_LEN {
+                self.analysis_history.remove(0);
             }
         }
-        
-        let white_accuracy = Self::calculate_player_accuracy(&white_moves);
-        let black_accuracy = Self::calculate_player_accuracy(&black_moves);
-        
-        (white_accuracy, black_accuracy)
     }
 
-    // Beräkna noggrannhet för en spelare
-    fn calculate_player_accuracy(moves: &[&MoveAnalysis]) -> f32 {
-        if moves.is_empty() {
-            return 100.0;
+    // Jämförelserader mellan den aktuella och den senast sparade analysen
+    fn current_analysis_diff(&self) -> Vec<String> {
+        match (self.analysis_history.last(), &self.game_analysis) {
+            (Some(old), Some(new)) => analysis_diff_lines(old, new),
+            _ => Vec::new(),
         }
-        
-        let total_centipawn_loss: i32 = moves.iter()
-            .map(|m| m.centipawn_loss.max(0))
-            .sum();
-        
-        let average_loss = total_centipawn_loss as f32 / moves.len() as f32;
-        
-        // Konvertera till procent (förenklad formel)
-        (100.0 - (average_loss / 10.0)).max(0.0).min(100.0)
     }
 
-    fn start_analysis(&mut self, ai: &ThreadSafeAiController) {
-        if matches!(self.ai_state, AiState::Idle) {
-            println!("[start_analysis] Startar positionsanalys med djup {} …", self.depth_slider.get_value());
-            let rx = ai.get_best_move_async(self.board, self.depth_slider.get_value());
-            self.ai_state = AiState::Thinking(rx);
-            self.current_analysis = Some("Analyserar position...".to_string());
+    // Sidan som spelas från brädet: i tvåspelarläget alltid sidan vid draget
+    fn human_color(&self) -> ChessColor {
+        if let Some(ref session) = self.puzzle {
+            session.color
+        } else if self.settings.game_mode == GameMode::HotSeat {
+            self.board.side_to_move()
+        } else {
+            self.settings.player_color
         }
     }
 
-    fn finish_analysis(&mut self, best_move: ChessMove) {
-        // Skapa analystext
-        let move_str = self.format_move(best_move);
-        let evaluation = self.evaluate_position();
-        
-        self.current_analysis = Some(format!(
-            "Bästa drag: {}\nEvaluering: {}\nRekommendation: {}",
-            move_str,
-            evaluation,
-            if evaluation.contains("+") { "Vit står bättre" } 
-            else if evaluation.contains("-") { "Svart står bättre" } 
-            else { "Jämn ställning" }
-        ));
-        
-        println!("[Analys] Bästa drag: {} | {}", move_str, evaluation);
+    // Ångra och gör om stegar ett halvdrag i taget när ingen sida spelas mot motorn
+    fn steps_single_plies(&self) -> bool {
+        self.settings.game_mode != GameMode::VsEngine
     }
 
-    fn evaluate_position(&self) -> String {
-        // Enkel materialevaluering
-        let mut white_material = 0;
-        let mut black_material = 0;
-        
-        for square in chess::ALL_SQUARES {
-            if let Some(piece) = self.board.piece_on(square) {
-                let value = match piece {
-                    Piece::Pawn => 1,
-                    Piece::Knight | Piece::Bishop => 3,
-                    Piece::Rook => 5,
-                    Piece::Queen => 9,
-                    Piece::King => 0,
-                };
-                
-                match self.board.color_on(square).unwrap() {
-                    ChessColor::White => white_material += value,
-                    ChessColor::Black => black_material += value,
-                }
-            }
+    // Stega till nästa spelläge: mot motorn, två spelare, motormatch
+    fn cycle_game_mode(&mut self) {
+        if !self.has_engine() {
+            self.toast("⚠ Ingen motor – endast två spelare");
+            return;
         }
-        
-        let diff = white_material - black_material;
-        if diff > 0 {
-            format!("+{}", diff)
-        } else if diff < 0 {
-            format!("{}", diff)
-        } else {
-            "0".to_string()
+        self.cancel_ai_search();
+        let previous = self.settings.game_mode;
+        self.settings.game_mode = previous.next();
+        self.settings_dirty = true;
+        self.autoplay_paused = false;
+        // Motormatchen ställer om motorns Elo för varje drag; återställ den vanliga inställningen
+        if previous == GameMode::EngineVsEngine {
+            self.engine_options_dirty = true;
         }
+        self.auto_flip_hot_seat();
+        println!("[cycle_game_mode] Spelläge: {:?}", self.settings.game_mode);
+        self.toast(match self.settings.game_mode {
+            GameMode::VsEngine => "Spel mot motorn",
+            GameMode::HotSeat => "Två spelare – motorn spelar inte",
+            GameMode::EngineVsEngine => "Motormatch – motorn spelar båda sidor",
+        });
     }
 
-    fn update_game_state(&mut self) {
-        if self.board.status() != BoardStatus::Ongoing {
-            self.game_over = true;
-            println!("[update_game_state] Partiet slut: {:?}", self.board.status());
+    // Stoppa eller återuppta motormatchen
+    fn toggle_autoplay(&mut self) {
+        self.autoplay_paused = !self.autoplay_paused;
+        if self.autoplay_paused {
+            self.cancel_ai_search();
         }
+        println!("[toggle_autoplay] Pausad: {}", self.autoplay_paused);
     }
 
-    fn start_ai(&mut self, ai: &ThreadSafeAiController) {
-        if let AiState::Idle = self.ai_state {
-            println!("[start_ai] Startar AI‑beräkning med djup {} …", self.depth_slider.get_value());
-            let rx = ai.get_best_move_async(self.board, self.depth_slider.get_value());
-            self.ai_state = AiState::Thinking(rx);
+    // Brädets vändning när den egna färgen står nedtill
+    fn own_side_flipped(&self) -> bool {
+        self.settings.player_color == ChessColor::Black
+    }
+
+    // Byt färg och vänd brädet så att de egna pjäserna står nedtill,
+    // om inte brädet har roterats för hand
+    fn choose_player_color(&mut self, color: ChessColor) {
+        self.settings.player_color = color;
+        self.settings_dirty = true;
+        if !self.flip_overridden {
+            self.settings.board_flipped = self.own_side_flipped();
         }
+        println!("[choose_player_color] Spelare: {:?}, bräde roterat: {}", color, self.settings.board_flipped);
     }
 
-    fn poll_ai(&mut self) {
-        if let AiState::Thinking(ref rx) = self.ai_state {
-            if let Ok(ai_move) = rx.try_recv() {
-                if self.current_analysis.is_some() && self.current_analysis.as_ref().unwrap().contains("Analyserar position") {
-                    // Detta var en positionsanalys, inte ett drag
-                    self.finish_analysis(ai_move);
-                    self.ai_state = AiState::Idle;
-                } else {
-                    // Detta var ett riktigt AI-drag
-                    println!("[poll_ai] AI‑drag mottaget: {ai_move}");
-                    self.make_move(ai_move);
-                }
-            }
+    // Brädets vändning som den visas: den tillfälliga vyn om en sådan gäller, annars inställningen
+    fn board_flipped(&self) -> bool {
+        self.view_flipped.unwrap_or(self.settings.board_flipped)
+    }
+
+    // Rotera brädet för hand; tillbaka i den egna färgens vy gäller den automatiska vändningen igen.
+    // Under en tillfällig vy vänds bara vyn.
+    fn flip_board(&mut self) {
+        if let Some(ref mut flipped) = self.view_flipped {
+            *flipped = !*flipped;
+            return;
         }
+        self.settings.board_flipped = !self.settings.board_flipped;
+        self.settings_dirty = true;
+        self.flip_overridden = self.settings.board_flipped != self.own_side_flipped();
     }
 
-    // Ny funktion för att hantera partianalys
-    fn poll_analysis(&mut self) {
-        if let Some(ref rx) = self.analysis_receiver {
-            if let Ok(analysis) = rx.try_recv() {
-                self.game_analysis = Some(analysis);
-                self.analysis_in_progress = false;
-                self.analysis_receiver = None;
-                self.current_analysis = Some("Partianalys klar! Se resultatet nedan.".to_string());
-                println!("[poll_analysis] Partianalys mottagen och sparad!");
-            }
+    // Sidan vid draget nedtill, om tvåspelarläget ska vända brädet. Det vänder bara vyn,
+    // så den sparade vändningen gäller igen när läget eller inställningen slås av.
+    fn auto_flip_hot_seat(&mut self) {
+        // Taktikläget har sin egen vy, som gäller tills läget lämnas
+        if self.puzzle.is_some() {
+            return;
         }
+        self.view_flipped = (self.settings.game_mode == GameMode::HotSeat && self.settings.hot_seat_auto_flip)
+            .then(|| self.board.side_to_move() == ChessColor::Black);
     }
 
     fn is_ai_turn(&self) -> bool {
+        let engine_to_move = match self.puzzle {
+            // I taktikläget spelar motorn bara svar som lösningen lämnar åt den
+            Some(ref session) => session.engine_reply,
+            None => match self.settings.game_mode {
+                GameMode::VsEngine => self.board.side_to_move() != self.settings.player_color,
+                GameMode::HotSeat => false,
+                GameMode::EngineVsEngine => !self.autoplay_paused,
+            },
+        };
+        engine_to_move &&
+        self.resume_prompt.is_none() &&
+        !self.puzzle_prompt &&
+        self.editor.is_none() &&
+        self.pgn_import.is_none() &&
         !self.game_over && 
-        self.board.side_to_move() != self.settings.player_color && 
         matches!(self.ai_state, AiState::Idle)
     }
 
     fn ai_status(&self) -> String {
+        if !self.has_engine() {
+            return "Ingen motor – AI och analys avstängda".to_string();
+        }
+        if self.engine_restarting {
+            return "Motorn startas om…".to_string();
+        }
         match self.ai_state {
             AiState::Idle => {
                 if self.analysis_in_progress {
                     "Analyserar parti...".to_string()
+                } else if self.draw_offer.is_some() {
+                    "Motorn överväger remi …".to_string()
+                } else if self.settings.game_mode == GameMode::EngineVsEngine && self.autoplay_paused && !self.game_over {
+                    "Motormatchen är stoppad".to_string()
                 } else {
                     String::new()
                 }
             },
-            AiState::Thinking(_) => format!("AI tänker (djup {}) …", self.depth_slider.get_value()),
+            AiState::Analyzing(_) => "Analyserar position …".to_string(),
+            AiState::Thinking(_) => {
+                let status = match self.search_limit {
+                    SearchLimit::Depth(depth) => format!("AI tänker (djup {depth}) …"),
+                    SearchLimit::MoveTime(millis) => format!("AI tänker (max {} s) …", millis / 1000),
+                    SearchLimit::Clock { .. } => "AI tänker (på klockan) …".to_string(),
+                };
+                match self.latest_progress {
+                    Some(progress) => format!("{status}\ndjup {}, {}", progress.depth, format_nps(progress.nps)),
+                    None => status,
+                }
+            }
+        }
+    }
+
+    // Hur stor del av AI:ns sökning som är klar (0..1): nått djup eller förbrukad tid.
+    // Med klocka bestämmer motorn själv tiden, så där finns ingen andel att visa.
+    fn ai_search_share(&self) -> Option<f32> {
+        if !matches!(self.ai_state, AiState::Thinking(_)) {
+            return None;
+        }
+        match self.search_limit {
+            SearchLimit::Depth(depth) => {
+                let reached = self.latest_progress.map_or(0, |p| p.depth);
+                Some((reached as f32 / depth.max(1) as f32).min(1.0))
+            }
+            SearchLimit::MoveTime(millis) => {
+                let elapsed = (get_time() - self.ai_started_at) * 1000.0;
+                Some((elapsed / millis.max(1) as f64).min(1.0) as f32)
+            }
+            SearchLimit::Clock { .. } => None,
         }
     }
 
@@ -1064,212 +6961,737 @@ impl ChessGame {
             // Fönsterinställningar
             const WINDOW_WIDTH: f32 = 600.0;
             const WINDOW_HEIGHT: f32 = 700.0;
-            const WINDOW_X: f32 = 300.0;
-            const WINDOW_Y: f32 = 75.0;
+            let (window_x, window_y) = overlay_origin(WINDOW_WIDTH, WINDOW_HEIGHT);
             
             // Rita bakgrund med genomskinlighet
             draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.5));
             
             // Rita analysfönster
-            draw_rectangle(WINDOW_X, WINDOW_Y, WINDOW_WIDTH, WINDOW_HEIGHT, WHITE);
-            draw_rectangle_lines(WINDOW_X, WINDOW_Y, WINDOW_WIDTH, WINDOW_HEIGHT, 3.0, DARKGRAY);
+            draw_rectangle(window_x, window_y, WINDOW_WIDTH, WINDOW_HEIGHT, WHITE);
+            draw_rectangle_lines(window_x, window_y, WINDOW_WIDTH, WINDOW_HEIGHT, 3.0, DARKGRAY);
             
             // Titel
-            draw_text("PARTIANALYS", WINDOW_X + 20.0, WINDOW_Y + 30.0, 24.0, BLACK);
+            draw_text("PARTIANALYS", window_x + 20.0, window_y + 30.0, 24.0, BLACK);
             
             // Stäng-knapp (X)
-            let close_x = WINDOW_X + WINDOW_WIDTH - 40.0;
-            let close_y = WINDOW_Y + 10.0;
+            let close_x = window_x + WINDOW_WIDTH - 40.0;
+            let close_y = window_y + 10.0;
             draw_rectangle(close_x, close_y, 30.0, 30.0, RED);
             draw_text("X", close_x + 10.0, close_y + 20.0, 20.0, WHITE);
             
             // Tillbaka-knapp (om vi är i review-läge)
             if self.review_mode {
-                let back_x = WINDOW_X + WINDOW_WIDTH - 80.0;
-                let back_y = WINDOW_Y + 10.0;
+                let back_x = window_x + WINDOW_WIDTH - 80.0;
+                let back_y = window_y + 10.0;
                 draw_rectangle(back_x, back_y, 35.0, 30.0, BLUE);
                 draw_text("↺", back_x + 12.0, back_y + 20.0, 20.0, WHITE);
             }
             
+            // Hopp mellan blunders och misstag (även Shift+PageUp/PageDown)
+            self.prev_error_button.draw();
+            self.next_error_button.draw();
+            
             // Scrollbar area
-            const CONTENT_X: f32 = WINDOW_X + 20.0;
-            const CONTENT_Y: f32 = WINDOW_Y + 50.0;
+            let content_x = window_x + 20.0;
+            let content_y = window_y + 50.0;
             const CONTENT_WIDTH: f32 = WINDOW_WIDTH - 60.0;
             const CONTENT_HEIGHT: f32 = WINDOW_HEIGHT - 70.0;
             
             // Klipp innehållet till fönsterområdet
-            draw_rectangle(CONTENT_X, CONTENT_Y, CONTENT_WIDTH, CONTENT_HEIGHT, Color::new(0.98, 0.98, 0.98, 1.0));
-            draw_rectangle_lines(CONTENT_X, CONTENT_Y, CONTENT_WIDTH, CONTENT_HEIGHT, 1.0, LIGHTGRAY);
+            draw_rectangle(content_x, content_y, CONTENT_WIDTH, CONTENT_HEIGHT, Color::new(0.98, 0.98, 0.98, 1.0));
+            draw_rectangle_lines(content_x, content_y, CONTENT_WIDTH, CONTENT_HEIGHT, 1.0, LIGHTGRAY);
             
-            let mut y_pos = CONTENT_Y + 20.0;
+            let mut y_pos = content_y + 20.0;
             let line_height = 18.0;
             
             // Sammanfattning
-            draw_text("SAMMANFATTNING", CONTENT_X + 10.0, y_pos, 18.0, DARKBLUE);
+            draw_text("SAMMANFATTNING", content_x + 10.0, y_pos, 18.0, DARKBLUE);
             y_pos += 25.0;
             
-            draw_text(&format!("Vit noggrannhet: {:.1}%", analysis.white_accuracy), CONTENT_X + 10.0, y_pos, 16.0, BLACK);
-            y_pos += line_height;
+            if self.settings.player_perspective {
+                let (own, engine) = if self.settings.player_color == ChessColor::White {
+                    (analysis.white_accuracy, analysis.black_accuracy)
+                } else {
+                    (analysis.black_accuracy, analysis.white_accuracy)
+                };
+                draw_text(&format!("Din noggrannhet: {:.1}%", own), content_x + 10.0, y_pos, 16.0, BLACK);
+                y_pos += line_height;
+                
+                draw_text(&format!("Motorns noggrannhet: {:.1}%", engine), content_x + 10.0, y_pos, 16.0, BLACK);
+                y_pos += line_height;
+            } else {
+                draw_text(&format!("Vit noggrannhet: {:.1}%", analysis.white_accuracy), content_x + 10.0, y_pos, 16.0, BLACK);
+                y_pos += line_height;
+                
+                draw_text(&format!("Svart noggrannhet: {:.1}%", analysis.black_accuracy), content_x + 10.0, y_pos, 16.0, BLACK);
+                y_pos += line_height;
+            }
             
-            draw_text(&format!("Svart noggrannhet: {:.1}%", analysis.black_accuracy), CONTENT_X + 10.0, y_pos, 16.0, BLACK);
+            draw_text(&format!("Blunders: {}", analysis.total_blunders), content_x + 10.0, y_pos, 16.0, RED);
             y_pos += line_height;
             
-            draw_text(&format!("Blunders: {}", analysis.total_blunders), CONTENT_X + 10.0, y_pos, 16.0, RED);
+            draw_text(&format!("Misstag: {}", analysis.total_mistakes), content_x + 10.0, y_pos, 16.0, ORANGE);
             y_pos += line_height;
             
-            draw_text(&format!("Misstag: {}", analysis.total_mistakes), CONTENT_X + 10.0, y_pos, 16.0, ORANGE);
+            draw_text(&format!("Inexaktheter: {}", analysis.total_inaccuracies), content_x + 10.0, y_pos, 16.0, Color::new(0.8, 0.8, 0.0, 1.0));
             y_pos += line_height;
             
-            draw_text(&format!("Inexaktheter: {}", analysis.total_inaccuracies), CONTENT_X + 10.0, y_pos, 16.0, Color::new(0.8, 0.8, 0.0, 1.0));
-            y_pos += 30.0;
+            // Sträckor där materialövertaget inte gick att omsätta
+            for &(first, last) in &analysis.fortresses {
+                draw_text(
+                    &format!(
+                        "Möjlig fästning drag {}–{}: materialövertag men evaluering nära 0",
+                        self.move_label(first),
+                        self.move_label(last)
+                    ),
+                    content_x + 10.0, y_pos, 14.0, DARKPURPLE
+                );
+                y_pos += line_height;
+            }
+            
+            // Skillnad mot föregående analys, t.ex. efter ändrat analysdjup
+            for line in self.current_analysis_diff() {
+                draw_text(&line, content_x + 10.0, y_pos, 14.0, DARKBLUE);
+                y_pos += line_height;
+            }
+            y_pos += 30.0 - line_height;
             
             // Detaljerad draglista
-            draw_text("DETALJERAD DRAGLISTA", CONTENT_X + 10.0, y_pos, 18.0, DARKBLUE);
+            draw_text("DETALJERAD DRAGLISTA", content_x + 10.0, y_pos, 18.0, DARKBLUE);
             y_pos += 25.0;
             
             // Förklaring av färgkoder och interaktion
-            draw_text("Färgkoder:", CONTENT_X + 10.0, y_pos, 14.0, BLACK);
+            draw_text("Färgkoder:", content_x + 10.0, y_pos, 14.0, BLACK);
             y_pos += line_height;
-            draw_text("● Röd = Blunder (≥3.00 bönder)", CONTENT_X + 20.0, y_pos, 12.0, RED);
+            draw_text("● Röd = Blunder (vinstchans -15 %)", content_x + 20.0, y_pos, 12.0, RED);
             y_pos += 15.0;
-            draw_text("● Orange = Misstag (≥1.00 bönder)", CONTENT_X + 20.0, y_pos, 12.0, ORANGE);
+            draw_text("● Orange = Misstag (vinstchans -10 %)", content_x + 20.0, y_pos, 12.0, ORANGE);
             y_pos += 15.0;
-            draw_text("● Gul = Inexakthet (≥0.50 bönder)", CONTENT_X + 20.0, y_pos, 12.0, Color::new(0.8, 0.8, 0.0, 1.0));
+            draw_text("● Gul = Inexakthet (vinstchans -5 %)", content_x + 20.0, y_pos, 12.0, Color::new(0.8, 0.8, 0.0, 1.0));
             y_pos += 15.0;
-            draw_text("● Grön = Bra drag", CONTENT_X + 20.0, y_pos, 12.0, DARKGREEN);
+            draw_text("● Grön = Bra drag", content_x + 20.0, y_pos, 12.0, DARKGREEN);
             y_pos += 20.0;
             
-            draw_text("💡 Klicka på ett drag för att se positionen!", CONTENT_X + 10.0, y_pos, 12.0, DARKBLUE);
+            draw_text("💡 Klicka på ett drag för att se positionen! Shift+PageUp/PageDown hoppar mellan fel.", content_x + 10.0, y_pos, 12.0, DARKBLUE);
             y_pos += 25.0;
             
             // Rita separator
-            draw_line(CONTENT_X + 10.0, y_pos, CONTENT_X + CONTENT_WIDTH - 20.0, y_pos, 1.0, LIGHTGRAY);
+            draw_line(content_x + 10.0, y_pos, content_x + CONTENT_WIDTH - 20.0, y_pos, 1.0, LIGHTGRAY);
             y_pos += 15.0;
             
-            // Visa alla analyserade drag
-            for (move_num, move_analysis) in analysis.moves.iter().enumerate() {
+            // Visa dragen från scrollpositionen och kom ihåg var varje rad hamnade för klickhanteringen
+            let mut rows = self.analysis_rows.borrow_mut();
+            rows.clear();
+            let first_move = self.analysis_scroll.min(analysis.moves.len().saturating_sub(1));
+            if first_move > 0 {
+                draw_text(&format!("... {} tidigare drag (scrolla uppåt)", first_move), content_x + 10.0, y_pos, 12.0, GRAY);
+                y_pos += 15.0;
+            }
+            for (move_num, move_analysis) in analysis.moves.iter().enumerate().skip(first_move) {
                 // Kontrollera om vi fortfarande är inom synligt område
-                if y_pos > CONTENT_Y + CONTENT_HEIGHT - 80.0 {
+                if y_pos > content_y + CONTENT_HEIGHT - 80.0 {
                     // Visa scrollindikation
-                    draw_text("... (scrolla för att se fler drag)", CONTENT_X + 10.0, y_pos, 12.0, GRAY);
+                    let remaining = analysis.moves.len() - move_num;
+                    draw_text(&format!("... {} drag till (scrolla nedåt)", remaining), content_x + 10.0, y_pos, 12.0, GRAY);
                     break;
                 }
-                
-                let drag_color = if move_analysis.is_blunder {
-                    RED
-                } else if move_analysis.is_mistake {
-                    ORANGE
-                } else if move_analysis.is_inaccuracy {
-                    Color::new(0.8, 0.8, 0.0, 1.0)
-                } else {
-                    DARKGREEN
+                let row_top = y_pos - 12.0;
+                
+                let drag_color = if move_analysis.skipped {
+                    GRAY
+                } else {
+                    move_analysis.classification().1
+                };
+                
+                // Markera aktuellt drag i review-läge
+                let is_current_move = self.review_move_index == Some(move_num);
+                if is_current_move {
+                    draw_rectangle(content_x + 5.0, y_pos - 12.0, CONTENT_WIDTH - 30.0, 20.0, Color::new(0.8, 0.8, 1.0, 0.3));
+                    draw_rectangle_lines(content_x + 5.0, y_pos - 12.0, CONTENT_WIDTH - 30.0, 20.0, 2.0, BLUE);
+                }
+                let flash = self.error_flash_alpha(move_num);
+                if flash > 0.0 {
+                    draw_rectangle(content_x + 5.0, y_pos - 12.0, CONTENT_WIDTH - 30.0, 20.0, Color::new(1.0, 0.6, 0.0, flash));
+                }
+                
+                // Visa dragnummer och notation
+                let drag_text = format!("{}. {}{} ", move_num + 1, move_analysis.move_notation, move_analysis.positive_suffix());
+                draw_text(&drag_text, content_x + 10.0, y_pos, 14.0, drag_color);
+                
+                // Visa centipawn-förlust om det finns
+                if move_analysis.skipped {
+                    let drag_text_width = measure_text(&drag_text, None, 14, 1.0).width;
+                    draw_text("(ej analyserat)", content_x + 10.0 + drag_text_width, y_pos, 12.0, GRAY);
+                } else if move_analysis.centipawn_loss > 0 {
+                    let loss = Eval::Pawns(-(move_analysis.centipawn_loss as f32) / 100.0);
+                    let loss_text = format!("({})", format_eval(loss, self.settings.eval_precision));
+                    let drag_text_width = measure_text(&drag_text, None, 14, 1.0).width;
+                    draw_text(&loss_text, content_x + 10.0 + drag_text_width, y_pos, 14.0, drag_color);
+                }
+                
+                y_pos += line_height;
+                
+                // Visa bästa draget om det skiljer sig
+                if let Some(ref best_notation) = move_analysis.best_move_notation {
+                    if best_notation != &move_analysis.move_notation {
+                        draw_text(&format!("   Bäst: {}", best_notation), content_x + 20.0, y_pos, 12.0, GREEN);
+                        y_pos += 15.0;
+                    }
+                }
+                
+                // Rita tunn separator mellan drag
+                if move_analysis.is_blunder || move_analysis.is_mistake || move_analysis.is_inaccuracy {
+                    draw_line(content_x + 10.0, y_pos + 2.0, content_x + CONTENT_WIDTH - 20.0, y_pos + 2.0, 0.5, LIGHTGRAY);
+                    y_pos += 8.0;
+                }
+                rows.push((move_num, row_top, y_pos - 12.0));
+            }
+            
+            // Scrollbar som följer scrollpositionen i draglistan
+            let scrollbar_x = content_x + CONTENT_WIDTH - 15.0;
+            draw_rectangle(scrollbar_x, content_y, 10.0, CONTENT_HEIGHT, LIGHTGRAY);
+            let progress = first_move as f32 / analysis.moves.len().max(1) as f32;
+            let thumb_y = content_y + 20.0 + progress * (CONTENT_HEIGHT - 100.0);
+            draw_rectangle(scrollbar_x + 1.0, thumb_y, 8.0, 60.0, DARKGRAY);
+        }
+    }
+
+    // Kontrollera om man klickar på stäng-knappen eller drag i analysfönstret
+    fn handle_analysis_window_click(&mut self, mouse_pos: (f32, f32)) -> bool {
+        if self.game_analysis.is_some() {
+            let (mouse_x, mouse_y) = mouse_pos;
+            const WINDOW_WIDTH: f32 = 600.0;
+            const WINDOW_HEIGHT: f32 = 700.0;
+            let (window_x, window_y) = overlay_origin(WINDOW_WIDTH, WINDOW_HEIGHT);
+            
+            // Stäng-knapp
+            let close_x = window_x + WINDOW_WIDTH - 40.0;
+            let close_y = window_y + 10.0;
+            
+            if mouse_x >= close_x && mouse_x <= close_x + 30.0 &&
+               mouse_y >= close_y && mouse_y <= close_y + 30.0 {
+                return true; // Stäng fönstret
+            }
+            
+            // Tillbaka-knapp (om vi är i review-läge)
+            if self.review_mode {
+                let back_x = window_x + WINDOW_WIDTH - 80.0;
+                let back_y = window_y + 10.0;
+                
+                if mouse_x >= back_x && mouse_x <= back_x + 35.0 &&
+                   mouse_y >= back_y && mouse_y <= back_y + 30.0 {
+                    self.exit_review_mode();
+                    return false; // Håll fönstret öppet
+                }
+            }
+            
+            if self.prev_error_button.is_clicked() {
+                self.jump_to_error(false);
+                return false;
+            }
+            if self.next_error_button.is_clicked() {
+                self.jump_to_error(true);
+                return false;
+            }
+            
+            // Kontrollera klick på drag i listan
+            let content_x = window_x + 20.0;
+            let content_y = window_y + 50.0;
+            const CONTENT_WIDTH: f32 = WINDOW_WIDTH - 60.0;
+            const CONTENT_HEIGHT: f32 = WINDOW_HEIGHT - 70.0;
+            
+            // Kontrollera om klicket är inom innehållsområdet
+            if (content_x..=content_x + CONTENT_WIDTH).contains(&mouse_x) &&
+               (content_y..=content_y + CONTENT_HEIGHT).contains(&mouse_y) {
+                
+                // Slå upp draget i radtabellen från senaste ritningen; varje drag täcker
+                // hela sin höjd, även tilläggsrader, så träffen blir exakt
+                let clicked = self.analysis_rows.borrow().iter()
+                    .find(|(_, top, bottom)| (*top..*bottom).contains(&mouse_y))
+                    .map(|&(index, _, _)| index);
+                
+                if let Some(move_index) = clicked {
+                    self.show_position_at_move(move_index);
+                }
+            }
+        }
+        false
+    }
+
+    // Rita rapportkortet: en sammanfattning av senaste partianalysen på en skärm
+    fn draw_report(&self) {
+        if !self.show_report {
+            return;
+        }
+        let Some(analysis) = self.latest_analysis() else {
+            return;
+        };
+
+        const WINDOW_WIDTH: f32 = 500.0;
+        const WINDOW_HEIGHT: f32 = 520.0;
+        let (window_x, window_y) = overlay_origin(WINDOW_WIDTH, WINDOW_HEIGHT);
+
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.5));
+        draw_rectangle(window_x, window_y, WINDOW_WIDTH, WINDOW_HEIGHT, WHITE);
+        draw_rectangle_lines(window_x, window_y, WINDOW_WIDTH, WINDOW_HEIGHT, 3.0, DARKGRAY);
+
+        draw_text("PARTIRAPPORT", window_x + 20.0, window_y + 30.0, 24.0, BLACK);
+
+        // Stäng-knapp (X)
+        let close_x = window_x + WINDOW_WIDTH - 40.0;
+        let close_y = window_y + 10.0;
+        draw_rectangle(close_x, close_y, 30.0, 30.0, RED);
+        draw_text("X", close_x + 10.0, close_y + 20.0, 20.0, WHITE);
+
+        let mut y_pos = window_y + 65.0;
+        let opening = match self.opening {
+            Some(opening) => format!("{} {}", opening.eco, opening.name),
+            None => "Okänd".to_string(),
+        };
+        draw_text(&format!("Öppning: {}", opening), window_x + 20.0, y_pos, 16.0, DARKGREEN);
+        y_pos += 22.0;
+        draw_text(&format!("Resultat: {}   (analysdjup {})", self.game_result().label(), analysis.depth), window_x + 20.0, y_pos, 16.0, BLACK);
+        y_pos += 35.0;
+
+        // Jämförelsetabell, vit och svart sida vid sida
+        let white_col = window_x + 260.0;
+        let black_col = window_x + 380.0;
+        draw_text("Vit", white_col, y_pos, 18.0, BLACK);
+        draw_text("Svart", black_col, y_pos, 18.0, BLACK);
+        y_pos += 8.0;
+        draw_line(window_x + 20.0, y_pos, window_x + WINDOW_WIDTH - 20.0, y_pos, 1.0, LIGHTGRAY);
+        y_pos += 22.0;
+
+        let (white_errors, black_errors) = (analysis.error_counts(ChessColor::White), analysis.error_counts(ChessColor::Black));
+        let rows = [
+            ("Precision", format!("{:.1}%", analysis.white_accuracy), format!("{:.1}%", analysis.black_accuracy), BLACK),
+            (
+                "Snittförlust (ACPL)",
+                format!("{:.0}", analysis.average_centipawn_loss(ChessColor::White)),
+                format!("{:.0}", analysis.average_centipawn_loss(ChessColor::Black)),
+                BLACK,
+            ),
+            ("Blunders", white_errors.0.to_string(), black_errors.0.to_string(), RED),
+            ("Misstag", white_errors.1.to_string(), black_errors.1.to_string(), ORANGE),
+            ("Inexaktheter", white_errors.2.to_string(), black_errors.2.to_string(), Color::new(0.8, 0.8, 0.0, 1.0)),
+        ];
+        for (label, white, black, color) in rows {
+            draw_text(label, window_x + 20.0, y_pos, 16.0, color);
+            draw_text(&white, white_col, y_pos, 16.0, BLACK);
+            draw_text(&black, black_col, y_pos, 16.0, BLACK);
+            y_pos += 24.0;
+        }
+
+        y_pos += 20.0;
+        draw_text("NYCKELMOMENT", window_x + 20.0, y_pos, 18.0, DARKBLUE);
+        y_pos += 25.0;
+
+        let moments = analysis.key_moments(5);
+        if moments.is_empty() {
+            draw_text("Inga misstag hittades – starkt parti!", window_x + 20.0, y_pos, 14.0, DARKGREEN);
+        }
+        for (index, m) in moments {
+            let (label, color) = m.classification();
+            let side = if m.side == ChessColor::White { "vit" } else { "svart" };
+            let loss = format_eval(Eval::Pawns(-(m.centipawn_loss as f32) / 100.0), self.settings.eval_precision);
+            draw_text(
+                &format!("{} {} ({}) – {} {}", self.move_label(index), m.move_notation, side, label, loss),
+                window_x + 20.0,
+                y_pos,
+                14.0,
+                color,
+            );
+            y_pos += 18.0;
+            if let Some(ref best) = m.best_move_notation {
+                draw_text(&format!("   Bäst var: {}", best), window_x + 20.0, y_pos, 13.0, DARKGREEN);
+                y_pos += 20.0;
+            }
+        }
+    }
+
+    // Stäng rapportkortet med X
+    fn handle_report_click(&mut self, mouse_pos: (f32, f32)) {
+        const WINDOW_WIDTH: f32 = 500.0;
+        const WINDOW_HEIGHT: f32 = 520.0;
+        let (window_x, window_y) = overlay_origin(WINDOW_WIDTH, WINDOW_HEIGHT);
+
+        let (mouse_x, mouse_y) = mouse_pos;
+        let close_x = window_x + WINDOW_WIDTH - 40.0;
+        let close_y = window_y + 10.0;
+
+        if (close_x..=close_x + 30.0).contains(&mouse_x) && (close_y..=close_y + 30.0).contains(&mouse_y) {
+            self.show_report = false;
+        }
+    }
+
+    // Rita motorinformation som overlay
+    fn draw_engine_info_window(&self, ai_controller: &Option<ThreadSafeAiController>) {
+        if !self.show_engine_info {
+            return;
+        }
+
+        const WINDOW_WIDTH: f32 = 420.0;
+        const WINDOW_HEIGHT: f32 = 320.0;
+        let (window_x, window_y) = overlay_origin(WINDOW_WIDTH, WINDOW_HEIGHT);
+
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.5));
+        draw_rectangle(window_x, window_y, WINDOW_WIDTH, WINDOW_HEIGHT, WHITE);
+        draw_rectangle_lines(window_x, window_y, WINDOW_WIDTH, WINDOW_HEIGHT, 3.0, DARKGRAY);
+
+        draw_text("MOTORINFO", window_x + 20.0, window_y + 30.0, 24.0, BLACK);
+
+        // Stäng-knapp (X)
+        let close_x = window_x + WINDOW_WIDTH - 40.0;
+        let close_y = window_y + 10.0;
+        draw_rectangle(close_x, close_y, 30.0, 30.0, RED);
+        draw_text("X", close_x + 10.0, close_y + 20.0, 20.0, WHITE);
+
+        let mut y_pos = window_y + 70.0;
+        let line_height = 20.0;
+
+        let Some(ref info) = self.engine_info else {
+            draw_text("Ingen motor är igång.", window_x + 20.0, y_pos, 16.0, RED);
+            return;
+        };
+
+        draw_text(&format!("Namn: {}", info.name), window_x + 20.0, y_pos, 16.0, BLACK);
+        y_pos += line_height;
+        draw_text(&format!("Författare: {}", info.author), window_x + 20.0, y_pos, 16.0, BLACK);
+        y_pos += 30.0;
+
+        draw_text(&format!("INSTÄLLNINGAR ({} st)", info.options.len()), window_x + 20.0, y_pos, 18.0, DARKBLUE);
+        y_pos += 25.0;
+
+        // Visa bara de inställningar som är intressanta för spelaren
+        const KEY_OPTIONS: [&str; 7] = ["Threads", "Hash", "MultiPV", "Skill Level", "UCI_LimitStrength", "UCI_Elo", "Move Overhead"];
+        for key in KEY_OPTIONS {
+            let Some(option) = info.options.iter().find(|o| o.name == key) else {
+                continue;
+            };
+
+            let mut text = format!("{}: {}", option.name, option.default.as_deref().unwrap_or("-"));
+            if let (Some(min), Some(max)) = (&option.min, &option.max) {
+                text.push_str(&format!(" ({}–{})", min, max));
+            }
+            draw_text(&text, window_x + 30.0, y_pos, 14.0, DARKGRAY);
+            y_pos += line_height;
+        }
+
+        if let Some(ai) = ai_controller {
+            let (entries, capacity) = ai.cache_stats();
+            y_pos += 10.0;
+            draw_text(&format!("Evalcache: {} / {} ställningar", entries, capacity), window_x + 20.0, y_pos, 14.0, DARKGRAY);
+        }
+    }
+
+    // Stäng motorinfo-fönstret om man klickar på X
+    fn handle_engine_info_click(&mut self, mouse_pos: (f32, f32)) {
+        const WINDOW_WIDTH: f32 = 420.0;
+        const WINDOW_HEIGHT: f32 = 320.0;
+        let (window_x, window_y) = overlay_origin(WINDOW_WIDTH, WINDOW_HEIGHT);
+
+        let (mouse_x, mouse_y) = mouse_pos;
+        let close_x = window_x + WINDOW_WIDTH - 40.0;
+        let close_y = window_y + 10.0;
+
+        if (close_x..=close_x + 30.0).contains(&mouse_x) && (close_y..=close_y + 30.0).contains(&mouse_y) {
+            self.show_engine_info = false;
+        }
+    }
+
+    // Har motorn UCI-optionen med det namnet
+    fn engine_supports(&self, option: &str) -> bool {
+        self.engine_info.as_ref().is_some_and(|info| info.supports_option(option))
+    }
+
+    // Skicka ändrade motorinställningar; körs första gången direkt efter start
+    fn apply_engine_options(&mut self, ai_controller: &Option<ThreadSafeAiController>) {
+        let Some(ai) = ai_controller else {
+            return;
+        };
+
+        // Motorstyrkan skickas när reglaget släppts på ett nytt värde, inte för varje steg under dragningen
+        let skill_level = self.skill_slider.get_value();
+        if !self.skill_slider.dragging && self.applied_skill_level != Some(skill_level) {
+            self.applied_skill_level = Some(skill_level);
+            if self.engine_supports("Skill Level") {
+                ai.set_skill_level_async(skill_level);
+            }
+        }
+
+        if !self.engine_options_dirty {
+            return;
+        }
+        self.engine_options_dirty = false;
+
+        if self.engine_supports("Move Overhead") {
+            ai.set_option_async("Move Overhead", self.settings.move_overhead_ms.to_string());
+        }
+        ai.set_elo_async(self.settings.elo_limit);
+    }
+
+    // Rader i inställningsfönstret: (etikett, aktuellt värde)
+    fn settings_rows(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("Blindschack", self.settings.blindfold.label().to_string()),
+            ("Decimaler i evaluering", self.settings.eval_precision.to_string()),
+            ("Visa ur min synvinkel", if self.settings.player_perspective { "På" } else { "Av" }.to_string()),
+            ("Analysdjup", self.settings.analysis_depth.to_string()),
+            ("Material + motor", if self.settings.show_eval_comparison { "På" } else { "Av" }.to_string()),
+            ("Dragmarkering", self.settings.move_hint_style.label().to_string()),
+            ("Ljud", self.sound_label(self.settings.sound_enabled)),
+            ("Eget ljud för AI:n", self.sound_label(self.settings.distinct_move_sounds)),
+            ("AI:ns minsta betänketid", if self.settings.ai_min_delay_ms == 0 { "Av".to_string() } else { format!("{} ms", self.settings.ai_min_delay_ms) }),
+            ("Partiresultat (PGN)", match self.result_override {
+                Some(result) => result.label().to_string(),
+                None => format!("Auto ({})", self.detected_result().label()),
+            }),
+            ("Inkludera analys i PGN", if self.settings.include_analysis_in_pgn { "På" } else { "Av" }.to_string()),
+            ("Move Overhead", if self.engine_supports("Move Overhead") {
+                format!("{} ms", self.settings.move_overhead_ms)
+            } else {
+                "Stöds ej".to_string()
+            }),
+            ("Animationer", if self.settings.animations { "På" } else { "Av" }.to_string()),
+            ("Koordinater", match self.settings.coordinate_size {
+                0 => "Av".to_string(),
+                16 => "Små".to_string(),
+                24 => "Normala".to_string(),
+                _ => "Stora".to_string(),
+            }),
+            ("Brädram", if self.settings.board_border == 0.0 { "Av".to_string() } else { format!("{} px", self.settings.board_border) }),
+            ("Motorevaluering", match self.settings.engine_eval_mode {
+                EngineEvalMode::Live => "Live",
+                EngineEvalMode::OnRequest => "Vid begäran (E)",
+            }.to_string()),
+            ("Motorns Elo", if !self.engine_supports("UCI_Elo") {
+                "Stöds ej".to_string()
+            } else {
+                self.settings.elo_limit.map_or("Full styrka".to_string(), |elo| elo.to_string())
+            }),
+            ("Bildstorlek (export)", format!("{} px", self.settings.image_size)),
+            ("Använd öppningsbok", match (&self.engine_book, self.settings.use_opening_book) {
+                (None, _) => "Ingen bok".to_string(),
+                (Some(_), true) => "På".to_string(),
+                (Some(_), false) => "Av".to_string(),
+            }),
+            ("Betänketid", self.settings.time_control.map_or("Ingen klocka".to_string(), TimeControl::label)),
+            ("Vänd brädet (två spelare)", if self.settings.hot_seat_auto_flip { "På" } else { "Av" }.to_string()),
+            ("Vits Elo (motormatch)", self.autoplay_elo_label(ChessColor::White)),
+            ("Svarts Elo (motormatch)", self.autoplay_elo_label(ChessColor::Black)),
+            ("Ditt namn", self.settings.player_names[0].clone()),
+            ("Motspelare (två spelare)", self.settings.player_names[1].clone()),
+            ("Koordinatplacering", if self.settings.coordinates_inside { "I rutorna" } else { "Marginalen" }.to_string()),
+        ]
+    }
+
+    // Ljudraderna går inte att ändra när bygget saknas ljudstöd eller ljuden inte kunde laddas
+    fn sound_label(&self, enabled: bool) -> String {
+        match (&self.sounds, enabled) {
+            (None, _) => "Ej i bygget",
+            (Some(_), true) => "På",
+            (Some(_), false) => "Av",
+        }
+        .to_string()
+    }
+
+    fn autoplay_elo_label(&self, color: ChessColor) -> String {
+        if !self.engine_supports("UCI_Elo") {
+            return "Stöds ej".to_string();
+        }
+        self.settings.autoplay_elo[color.to_index()].map_or("Full styrka".to_string(), |elo| elo.to_string())
+    }
+
+    fn cycle_autoplay_elo(&mut self, color: ChessColor) {
+        let elo = &mut self.settings.autoplay_elo[color.to_index()];
+        *elo = match *elo {
+            None => Some(1320),
+            Some(1320) => Some(1500),
+            Some(1500) => Some(2000),
+            Some(2000) => Some(2500),
+            Some(2500) => Some(3000),
+            _ => None,
+        };
+    }
+
+    // Stega fram inställningen på en given rad
+    fn cycle_setting(&mut self, row: usize) {
+        self.settings_dirty = true;
+        match row {
+            0 => self.settings.blindfold = self.settings.blindfold.next(),
+            1 => self.settings.eval_precision = (self.settings.eval_precision + 1) % 3,
+            2 => self.settings.player_perspective = !self.settings.player_perspective,
+            3 => self.settings.analysis_depth = if self.settings.analysis_depth >= 25 { 10 } else { self.settings.analysis_depth + 5 },
+            4 => self.settings.show_eval_comparison = !self.settings.show_eval_comparison,
+            5 => self.settings.move_hint_style = self.settings.move_hint_style.next(),
+            6 if self.sounds.is_some() => self.settings.sound_enabled = !self.settings.sound_enabled,
+            7 if self.sounds.is_some() => self.settings.distinct_move_sounds = !self.settings.distinct_move_sounds,
+            8 => {
+                self.settings.ai_min_delay_ms = match self.settings.ai_min_delay_ms {
+                    0 => 300,
+                    300 => 700,
+                    700 => 1500,
+                    _ => 0,
+                }
+            }
+            9 => self.cycle_result_override(),
+            10 => self.settings.include_analysis_in_pgn = !self.settings.include_analysis_in_pgn,
+            11 if self.engine_supports("Move Overhead") => {
+                self.settings.move_overhead_ms = match self.settings.move_overhead_ms {
+                    10 => 50,
+                    50 => 100,
+                    100 => 250,
+                    250 => 500,
+                    _ => 10,
                 };
-                
-                // Markera aktuellt drag i review-läge
-                let is_current_move = self.review_move_index == Some(move_num);
-                if is_current_move {
-                    draw_rectangle(CONTENT_X + 5.0, y_pos - 12.0, CONTENT_WIDTH - 30.0, 20.0, Color::new(0.8, 0.8, 1.0, 0.3));
-                    draw_rectangle_lines(CONTENT_X + 5.0, y_pos - 12.0, CONTENT_WIDTH - 30.0, 20.0, 2.0, BLUE);
-                }
-                
-                // Visa dragnummer och notation
-                let drag_text = format!("{}. {} ", move_num + 1, move_analysis.move_notation);
-                draw_text(&drag_text, CONTENT_X + 10.0, y_pos, 14.0, drag_color);
-                
-                // Visa centipawn-förlust om det finns
-                if move_analysis.centipawn_loss > 0 {
-                    let loss_text = format!("(-{})", move_analysis.centipawn_loss);
-                    let drag_text_width = measure_text(&drag_text, None, 14, 1.0).width;
-                    draw_text(&loss_text, CONTENT_X + 10.0 + drag_text_width, y_pos, 14.0, drag_color);
+                self.engine_options_dirty = true;
+            }
+            12 => self.settings.animations = !self.settings.animations,
+            13 => {
+                self.settings.coordinate_size = match self.settings.coordinate_size {
+                    0 => 16,
+                    16 => 24,
+                    24 => 32,
+                    _ => 0,
                 }
-                
-                y_pos += line_height;
-                
-                // Visa bästa draget om det skiljer sig
-                if let Some(ref best_notation) = move_analysis.best_move_notation {
-                    if best_notation != &move_analysis.move_notation {
-                        draw_text(&format!("   Bäst: {}", best_notation), CONTENT_X + 20.0, y_pos, 12.0, GREEN);
-                        y_pos += 15.0;
-                    }
+            }
+            // Ramen får inte bli bredare än att koordinaterna ryms i marginalen
+            14 => self.settings.board_border = if self.settings.board_border >= 8.0 { 0.0 } else { self.settings.board_border + 4.0 },
+            15 => {
+                self.settings.engine_eval_mode = match self.settings.engine_eval_mode {
+                    EngineEvalMode::Live => EngineEvalMode::OnRequest,
+                    EngineEvalMode::OnRequest => EngineEvalMode::Live,
                 }
-                
-                // Rita tunn separator mellan drag
-                if move_analysis.is_blunder || move_analysis.is_mistake || move_analysis.is_inaccuracy {
-                    draw_line(CONTENT_X + 10.0, y_pos + 2.0, CONTENT_X + CONTENT_WIDTH - 20.0, y_pos + 2.0, 0.5, LIGHTGRAY);
-                    y_pos += 8.0;
+            }
+            16 if self.engine_supports("UCI_Elo") => {
+                self.settings.elo_limit = match self.settings.elo_limit {
+                    None => Some(1320),
+                    Some(1320) => Some(1600),
+                    Some(1600) => Some(2000),
+                    Some(2000) => Some(2400),
+                    Some(2400) => Some(2800),
+                    _ => None,
+                };
+                self.engine_options_dirty = true;
+            }
+            17 => {
+                self.settings.image_size = match self.settings.image_size {
+                    400 => 600,
+                    600 => 800,
+                    800 => 1200,
+                    _ => 400,
                 }
             }
-            
-            // Scrollbar (enkel indikation)
-            let scrollbar_x = CONTENT_X + CONTENT_WIDTH - 15.0;
-            draw_rectangle(scrollbar_x, CONTENT_Y, 10.0, CONTENT_HEIGHT, LIGHTGRAY);
-            draw_rectangle(scrollbar_x + 1.0, CONTENT_Y + 20.0, 8.0, 60.0, DARKGRAY);
+            18 if self.engine_book.is_some() => self.settings.use_opening_book = !self.settings.use_opening_book,
+            // En ny betänketid gäller direkt, med full tid för båda sidor
+            19 => {
+                let choices = TimeControl::CHOICES;
+                self.settings.time_control = match self.settings.time_control {
+                    None => Some(choices[0]),
+                    Some(current) => choices.iter().position(|&c| c == current).and_then(|i| choices.get(i + 1)).copied(),
+                };
+                self.clock = self.settings.time_control.map(GameClock::new);
+            }
+            20 => {
+                self.settings.hot_seat_auto_flip = !self.settings.hot_seat_auto_flip;
+                self.auto_flip_hot_seat();
+            }
+            // Ny styrka gäller från sidans nästa drag
+            21 if self.engine_supports("UCI_Elo") => self.cycle_autoplay_elo(ChessColor::White),
+            22 if self.engine_supports("UCI_Elo") => self.cycle_autoplay_elo(ChessColor::Black),
+            23 => self.open_name_input(0),
+            24 => self.open_name_input(1),
+            25 => self.settings.coordinates_inside = !self.settings.coordinates_inside,
+            _ => {}
         }
     }
 
-    // Kontrollera om man klickar på stäng-knappen eller drag i analysfönstret
-    fn handle_analysis_window_click(&mut self, mouse_pos: (f32, f32)) -> bool {
-        if self.game_analysis.is_some() {
-            let (mouse_x, mouse_y) = mouse_pos;
-            const WINDOW_WIDTH: f32 = 600.0;
-            const WINDOW_X: f32 = 300.0;
-            const WINDOW_Y: f32 = 75.0;
-            const WINDOW_HEIGHT: f32 = 700.0;
-            
-            // Stäng-knapp
-            let close_x = WINDOW_X + WINDOW_WIDTH - 40.0;
-            let close_y = WINDOW_Y + 10.0;
-            
-            if mouse_x >= close_x && mouse_x <= close_x + 30.0 &&
-               mouse_y >= close_y && mouse_y <= close_y + 30.0 {
-                return true; // Stäng fönstret
-            }
-            
-            // Tillbaka-knapp (om vi är i review-läge)
-            if self.review_mode {
-                let back_x = WINDOW_X + WINDOW_WIDTH - 80.0;
-                let back_y = WINDOW_Y + 10.0;
-                
-                if mouse_x >= back_x && mouse_x <= back_x + 35.0 &&
-                   mouse_y >= back_y && mouse_y <= back_y + 30.0 {
-                    self.exit_review_mode();
-                    return false; // Håll fönstret öppet
-                }
-            }
-            
-            // Kontrollera klick på drag i listan
-            const CONTENT_X: f32 = WINDOW_X + 20.0;
-            const CONTENT_Y: f32 = WINDOW_Y + 50.0;
-            const CONTENT_WIDTH: f32 = WINDOW_WIDTH - 60.0;
-            const CONTENT_HEIGHT: f32 = WINDOW_HEIGHT - 70.0;
-            
-            // Kontrollera om klicket är inom innehållsområdet
-            if mouse_x >= CONTENT_X && mouse_x <= CONTENT_X + CONTENT_WIDTH &&
-               mouse_y >= CONTENT_Y && mouse_y <= CONTENT_Y + CONTENT_HEIGHT {
-                
-                // Beräkna ungefär var draglistan börjar (efter sammanfattning och färgförklaring)
-                let drag_list_start_y = CONTENT_Y + 20.0 + // Sammanfattning titel
-                    25.0 + 18.0 * 5.0 + 30.0 + // Sammanfattning innehåll
-                    25.0 + 18.0 + 15.0 * 4.0 + 25.0 + 15.0; // Färgförklaring
-                
-                if mouse_y >= drag_list_start_y {
-                    // Beräkna vilket drag som klickades
-                    let relative_y = mouse_y - drag_list_start_y;
-                    let line_height = 18.0;
-                    
-                    // Ungefärligt dragindex (tar hänsyn till extra rader för bästa drag)
-                    let estimated_move_index = (relative_y / line_height) as usize;
-                    
-                    // Begränsa till faktiska drag
-                    if let Some(ref analysis) = self.game_analysis {
-                        if estimated_move_index < analysis.moves.len() {
-                            self.show_position_at_move(estimated_move_index);
-                        }
-                    }
-                }
+    // Inställningsfönstret: (x, y, höjd, första synliga raden, antal synliga rader).
+    // Fönstret krymps till skärmen och raderna som inte ryms nås med mushjulet.
+    fn settings_window_geometry(&self) -> (f32, f32, f32, usize, usize) {
+        let rows = self.settings_rows().len();
+        let full_height = SETTINGS_HEADER + rows as f32 * SETTINGS_ROW_HEIGHT + SETTINGS_FOOTER;
+        let height = full_height.min(screen_height() - 20.0);
+        let (first, visible) = settings_page(rows, height - SETTINGS_HEADER - SETTINGS_FOOTER, self.settings_scroll);
+        let (x, y) = overlay_origin(SETTINGS_WINDOW_WIDTH, height);
+        (x, y, height, first, visible)
+    }
+
+    // Scrolla inställningsfönstret; positivt värde går nedåt i listan
+    fn scroll_settings(&mut self, rows: i32) {
+        let (_, _, _, first, visible) = self.settings_window_geometry();
+        let max_scroll = self.settings_rows().len().saturating_sub(visible);
+        self.settings_scroll = (first as i32 + rows).clamp(0, max_scroll as i32) as usize;
+    }
+
+    // Rita inställningsfönstret som overlay
+    fn draw_settings_window(&self) {
+        if !self.show_settings {
+            return;
+        }
+
+        let (window_x, window_y, window_height, first, visible) = self.settings_window_geometry();
+
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.5));
+        draw_rectangle(window_x, window_y, SETTINGS_WINDOW_WIDTH, window_height, WHITE);
+        draw_rectangle_lines(window_x, window_y, SETTINGS_WINDOW_WIDTH, window_height, 3.0, DARKGRAY);
+
+        draw_text("INSTÄLLNINGAR", window_x + 20.0, window_y + 30.0, 24.0, BLACK);
+
+        // Stäng-knapp (X)
+        let close_x = window_x + SETTINGS_WINDOW_WIDTH - 40.0;
+        let close_y = window_y + 10.0;
+        draw_rectangle(close_x, close_y, 30.0, 30.0, RED);
+        draw_text("X", close_x + 10.0, close_y + 20.0, 20.0, WHITE);
+
+        let rows = self.settings_rows();
+        let mut y_pos = window_y + SETTINGS_HEADER;
+        for (label, value) in rows.iter().skip(first).take(visible) {
+            draw_text(label, window_x + 20.0, y_pos + 18.0, 16.0, BLACK);
+
+            // Värdet fungerar som en knapp som stegar till nästa alternativ
+            Button::new(window_x + 240.0, y_pos, 160.0, 26.0, value).draw();
+            y_pos += SETTINGS_ROW_HEIGHT;
+        }
+
+        // Rullningslist när alla rader inte ryms
+        let footer_y = window_y + window_height - 15.0;
+        if visible < rows.len() {
+            let track_y = window_y + SETTINGS_HEADER;
+            let track_height = visible as f32 * SETTINGS_ROW_HEIGHT;
+            let thumb_height = track_height * visible as f32 / rows.len() as f32;
+            let thumb_y = track_y + track_height * first as f32 / rows.len() as f32;
+            draw_rectangle(window_x + 406.0, track_y, 6.0, track_height, LIGHTGRAY);
+            draw_rectangle(window_x + 406.0, thumb_y, 6.0, thumb_height, DARKGRAY);
+            draw_text(&format!("Rad {}–{} av {}, scrolla med mushjulet", first + 1, first + visible, rows.len()), window_x + 20.0, footer_y - 14.0, 12.0, DARKGRAY);
+        }
+        draw_text("Tips: håll in mellanslag för att visa dolda pjäser", window_x + 20.0, footer_y, 12.0, DARKGRAY);
+    }
+
+    // Hantera klick i inställningsfönstret
+    fn handle_settings_click(&mut self, mouse_pos: (f32, f32)) {
+        let (window_x, window_y, _, first, visible) = self.settings_window_geometry();
+
+        let (mouse_x, mouse_y) = mouse_pos;
+        let close_x = window_x + SETTINGS_WINDOW_WIDTH - 40.0;
+        let close_y = window_y + 10.0;
+
+        if (close_x..=close_x + 30.0).contains(&mouse_x) && (close_y..=close_y + 30.0).contains(&mouse_y) {
+            self.show_settings = false;
+            return;
+        }
+
+        if !(window_x + 240.0..=window_x + 400.0).contains(&mouse_x) {
+            return;
+        }
+
+        for (i, row) in (first..first + visible).enumerate() {
+            let row_y = window_y + SETTINGS_HEADER + i as f32 * SETTINGS_ROW_HEIGHT;
+            if (row_y..=row_y + 26.0).contains(&mouse_y) {
+                self.cycle_setting(row);
+                return;
             }
         }
-        false
     }
 
     // Konvertera Square till (x, y) koordinater med hänsyn till rotation
@@ -1277,7 +7699,7 @@ impl ChessGame {
         let file = square.get_file().to_index() as i32;
         let rank = square.get_rank().to_index() as i32;
         
-        if self.settings.board_flipped {
+        if self.board_flipped() {
             (7 - file, rank)
         } else {
             (file, 7 - rank)
@@ -1286,7 +7708,7 @@ impl ChessGame {
 
     // Konvertera koordinater tillbaka till Square
     fn coords_to_square(&self, x: i32, y: i32) -> Square {
-        let (file_idx, rank_idx) = if self.settings.board_flipped {
+        let (file_idx, rank_idx) = if self.board_flipped() {
             (7 - x, y)
         } else {
             (x, 7 - y)
@@ -1303,110 +7725,683 @@ impl ChessGame {
         movegen.into_iter().any(|m| m == chess_move)
     }
 
+    // Evalueringen för den ställning som visas: den oändliga analysen om den är igång,
+    // partianalysen i granskningsläge, annars motorns senaste bedömning av partiet
+    fn displayed_eval(&self) -> Option<Eval> {
+        if let Some(info) = self.infinite_analysis_info() {
+            return Some(info.eval);
+        }
+        if self.review_mode {
+            let index = self.review_move_index?;
+            let move_analysis = self.game_analysis.as_ref()?.moves.get(index)?;
+            (!move_analysis.skipped).then_some(Eval::Pawns(move_analysis.evaluation_after))
+        } else if self.settings.engine_eval_mode == EngineEvalMode::OnRequest {
+            Some(self.requested_eval_for_board().unwrap_or_else(|| self.evaluate_position()))
+        } else {
+            self.live_eval
+        }
+    }
+
+    // Motorevalueringen på begäran, om den gäller ställningen på brädet just nu
+    fn requested_eval_for_board(&self) -> Option<Eval> {
+        self.requested_eval
+            .filter(|(hash, _)| *hash == self.board.get_hash())
+            .map(|(_, eval)| eval)
+    }
+
+    // Starta och ta emot motorevalueringar i läget "vid begäran": när spelaren
+    // trycker E och automatiskt en gång när partiet är slut
+    fn update_requested_eval(&mut self, ai_controller: &Option<ThreadSafeAiController>) {
+        if let Some((hash, ref rx)) = self.eval_request {
+            match rx.try_recv() {
+                Ok(eval) => {
+                    self.requested_eval = Some((hash, eval));
+                    self.eval_request = None;
+                }
+                Err(mpsc::TryRecvError::Disconnected) => self.eval_request = None,
+                Err(mpsc::TryRecvError::Empty) => {}
+            }
+            return;
+        }
+
+        let wanted = std::mem::take(&mut self.eval_request_wanted);
+        // Den oändliga analysen håller motorn och ger redan en evaluering
+        if self.settings.engine_eval_mode != EngineEvalMode::OnRequest || self.review_mode || self.live_analysis.is_some() {
+            return;
+        }
+        let Some(ai) = ai_controller else {
+            return;
+        };
+
+        // Slutställningen begärs bara en gång, även om motorn inte svarar
+        let hash = self.board.get_hash();
+        let at_game_end = self.game_over && self.last_eval_request != Some(hash);
+        if wanted || at_game_end {
+            self.last_eval_request = Some(hash);
+            self.eval_request = Some((hash, ai.get_evaluation_async(self.board, self.settings.analysis_depth)));
+        }
+    }
+
+    fn toggle_infinite_analysis(&mut self) {
+        if !self.has_engine() {
+            return;
+        }
+        self.infinite_analysis = !self.infinite_analysis;
+        self.infinite_analysis_button.text =
+            if self.infinite_analysis { "Oändlig analys: På" } else { "Oändlig analys: Av" }.to_string();
+        println!("[toggle_infinite_analysis] Oändlig analys: {}", self.infinite_analysis);
+    }
+
+    // Oändlig analys: analysera ställningen som visas så länge inget annat behöver
+    // motorn. Byts ställningen avbryts sökningen och en ny startas.
+    fn update_infinite_analysis(&mut self, ai_controller: &Option<ThreadSafeAiController>) {
+        let board = *self.get_display_board();
+        let wanted = self.infinite_analysis
+            && matches!(self.ai_state, AiState::Idle)
+            && !self.is_ai_turn()
+            && !self.analysis_in_progress
+            && self.pgn_import.is_none()
+            && self.draw_offer.is_none()
+            && self.editor.is_none()
+            // Utan lagliga drag har motorn inget att söka på
+            && MoveGen::new_legal(&board).len() > 0;
+
+        if let Some(ref live) = self.live_analysis {
+            if !wanted || live.board != board {
+                live.cancel();
+                self.live_analysis = None;
+            }
+        }
+
+        if let Some(ref mut live) = self.live_analysis {
+            while let Ok(info) = live.receiver.try_recv() {
+                live.latest = Some(info);
+            }
+            return;
+        }
+
+        let (true, Some(ai)) = (wanted, ai_controller) else {
+            return;
+        };
+        println!("[update_infinite_analysis] Analyserar {board}");
+        let (cancel, receiver) = ai.analyze_async(board, INFINITE_ANALYSIS_DEPTH);
+        self.live_analysis = Some(LiveAnalysis { board, cancel, receiver, latest: None });
+    }
+
+    // Senaste resultatet från den oändliga analysen, om det gäller ställningen som visas
+    fn infinite_analysis_info(&self) -> Option<&AnalysisInfo> {
+        let live = self.live_analysis.as_ref()?;
+        (live.board == *self.get_display_board()).then_some(live.latest.as_ref()?)
+    }
+
+    // Rita evalueringsstapeln till vänster om brädet
+    fn draw_eval_bar(&self) {
+        const BAR_WIDTH: f32 = 20.0;
+        // Stapeln följer brädets vänsterkant och höjd
+        let bar_x = self.layout.board_x - 55.0;
+        let bar_y = self.layout.board_y;
+        let bar_height = self.layout.board_size();
+
+        let Some(eval) = self.displayed_eval() else {
+            return;
+        };
+
+        // Andel av stapeln som är vit; ändliga värden når aldrig ända ut
+        // så att en forcerad matt syns tydligt som ett eget läge
+        let (white_share, mate_color) = match eval {
+            Eval::Pawns(p) => (0.5 + 0.45 * (p * 0.35).tanh(), None),
+            Eval::Mate { winner: ChessColor::White, .. } => (1.0, Some(Color::new(1.0, 0.85, 0.3, 1.0))),
+            Eval::Mate { winner: ChessColor::Black, .. } => (0.0, Some(Color::new(0.6, 0.1, 0.1, 1.0))),
+        };
+
+        // Glid mot det nya värdet om animationer är på, annars hoppa direkt
+        let white_share = if self.settings.animations {
+            let shown = self.eval_bar_share.get();
+            shown + (white_share - shown) * (get_frame_time() * 8.0).min(1.0)
+        } else {
+            white_share
+        };
+        self.eval_bar_share.set(white_share);
+
+        let white_height = bar_height * white_share;
+        let black_height = bar_height - white_height;
+
+        // Vit del nedtill, om inte brädet är roterat
+        if self.board_flipped() {
+            draw_rectangle(bar_x, bar_y, BAR_WIDTH, white_height, WHITE);
+            draw_rectangle(bar_x, bar_y + white_height, BAR_WIDTH, black_height, Color::new(0.2, 0.2, 0.2, 1.0));
+        } else {
+            draw_rectangle(bar_x, bar_y, BAR_WIDTH, black_height, Color::new(0.2, 0.2, 0.2, 1.0));
+            draw_rectangle(bar_x, bar_y + black_height, BAR_WIDTH, white_height, WHITE);
+        }
+
+        let border_color = mate_color.unwrap_or(DARKGRAY);
+        let border_width = if mate_color.is_some() { 4.0 } else { 2.0 };
+        draw_rectangle_lines(bar_x, bar_y, BAR_WIDTH, bar_height, border_width, border_color);
+
+        // Stapeln ritas alltid från vits sida, men siffran följer spelarens synvinkel
+        let text = format_eval(self.perspective_eval(eval), 1);
+        let text_width = measure_text(&text, None, 14, 1.0).width;
+        let text_color = mate_color.unwrap_or(BLACK);
+        draw_text(&text, bar_x + BAR_WIDTH / 2.0 - text_width / 2.0, bar_y + bar_height + 20.0, 14.0, text_color);
+
+        // Visa när stapeln bara bygger på material
+        let material_only = !self.review_mode
+            && self.settings.engine_eval_mode == EngineEvalMode::OnRequest
+            && self.requested_eval_for_board().is_none()
+            && self.infinite_analysis_info().is_none();
+        if material_only {
+            let label_width = measure_text("mat.", None, 12, 1.0).width;
+            draw_text("mat.", bar_x + BAR_WIDTH / 2.0 - label_width / 2.0, bar_y + bar_height + 34.0, 12.0, DARKGRAY);
+        }
+    }
+
     // Rita koordinater runt brädet
     fn draw_coordinates(&self) {
-        const BOARD_OFFSET: f32 = 100.0;
-        const SQUARE_SIZE: f32 = 80.0;
+        // Avstånd mellan ram och koordinat
+        const GAP: f32 = 6.0;
+
+        if self.settings.coordinate_size == 0 {
+            return;
+        }
+        if self.settings.coordinates_inside {
+            self.draw_inner_coordinates();
+            return;
+        }
+        let font_size = self.settings.coordinate_size;
+        let border = self.settings.board_border;
+        let layout = self.layout;
+        let square = layout.square_size;
         
-        // Rita filbeteckningar (a-h)
+        // Rita filbeteckningar (a-h), centrerade under och över varje linje
         for i in 0..8 {
-            let file_char = if self.settings.board_flipped {
+            let file_char = if self.board_flipped() {
                 char::from(b'h' - i as u8)
             } else {
                 char::from(b'a' + i as u8)
             };
+            let label = file_char.to_string();
+            let dims = measure_text(&label, None, font_size, 1.0);
             
-            let x = BOARD_OFFSET + i as f32 * SQUARE_SIZE + SQUARE_SIZE / 2.0 - 5.0;
+            let x = layout.board_x + i as f32 * square + (square - dims.width) / 2.0;
             
             // Under brädet
-            let y_bottom = BOARD_OFFSET + 8.0 * SQUARE_SIZE + 25.0;
-            draw_text(&file_char.to_string(), x, y_bottom, 24.0, BLACK);
+            let y_bottom = layout.board_y + layout.board_size() + border + GAP + dims.offset_y;
+            draw_text(&label, x, y_bottom, font_size as f32, BLACK);
             
             // Över brädet
-            let y_top = BOARD_OFFSET - 10.0;
-            draw_text(&file_char.to_string(), x, y_top, 24.0, BLACK);
+            let y_top = layout.board_y - border - GAP - (dims.height - dims.offset_y);
+            draw_text(&label, x, y_top, font_size as f32, BLACK);
+        }
+        
+        // Rita radbeteckningar (1-8), mitt för varje rad
+        for i in 0..8 {
+            let rank = if self.board_flipped() {
+                (i + 1).to_string()
+            } else {
+                (8 - i).to_string()
+            };
+            let dims = measure_text(&rank, None, font_size, 1.0);
+            
+            let y = layout.board_y + i as f32 * square + (square + dims.offset_y) / 2.0;
+            
+            // Till vänster om brädet
+            let x_left = layout.board_x - border - GAP - dims.width;
+            draw_text(&rank, x_left, y, font_size as f32, BLACK);
+            
+            // Till höger om brädet
+            let x_right = layout.board_x + layout.board_size() + border + GAP;
+            draw_text(&rank, x_right, y, font_size as f32, BLACK);
+        }
+    }
+
+    // Koordinater i rutornas hörn: linjens bokstav nere till vänster i brädets nedersta rad
+    // och radens siffra uppe till höger i den vänstra linjen, i den andra rutfärgen
+    fn draw_inner_coordinates(&self) {
+        const PADDING: f32 = 3.0;
+
+        let layout = self.layout;
+        let square = layout.square_size;
+        let font_size = self.settings.coordinate_size.min((square * 0.3) as u16);
+        let label_color = |x: i32, y: i32| if (x + y) % 2 == 0 { BROWN } else { BEIGE };
+
+        for x in 0..8 {
+            let file = self.coords_to_square(x, 7).get_file().to_index();
+            let label = char::from(b'a' + file as u8).to_string();
+            let dims = measure_text(&label, None, font_size, 1.0);
+            let (left, top) = layout.square_origin(x, 7);
+            let baseline = top + square - PADDING - (dims.height - dims.offset_y);
+            draw_text(&label, left + PADDING, baseline, font_size as f32, label_color(x, 7));
+        }
+
+        for y in 0..8 {
+            let label = (self.coords_to_square(0, y).get_rank().to_index() + 1).to_string();
+            let dims = measure_text(&label, None, font_size, 1.0);
+            let (left, top) = layout.square_origin(0, y);
+            draw_text(&label, left + square - PADDING - dims.width, top + PADDING + dims.offset_y, font_size as f32, label_color(0, y));
+        }
+    }
+
+    // Koordinaterna tar plats utanför brädet bara när de ritas i marginalen
+    fn coordinates_in_margin(&self) -> bool {
+        self.settings.coordinate_size > 0 && !self.settings.coordinates_inside
+    }
+
+    fn draw_squares(&self) {
+        let size = self.layout.square_size;
+        for y in 0..8 {
+            for x in 0..8 {
+                let c = if (x + y) % 2 == 0 { BEIGE } else { BROWN };
+                let (left, top) = self.layout.square_origin(x, y);
+                draw_rectangle(left, top, size, size, c);
+            }
+        }
+    }
+
+    // Rita den visade ställningen till en PNG-fil. Brädets vanliga ritfunktioner
+    // används med en layout för bilden och en kamera mot ett bildmål i minnet.
+    fn export_board_image(&mut self) {
+        // export_png avbryter programmet om filen inte kan skrivas, så det provas först
+        if let Err(e) = std::fs::File::create(BOARD_IMAGE_PATH) {
+            self.toast(format!("⚠ Kunde inte spara bild: {}", e));
+            return;
+        }
+
+        let size = self.settings.image_size;
+        let margin = if self.coordinates_in_margin() {
+            self.settings.board_border + self.settings.coordinate_size as f32 + 10.0
+        } else {
+            self.settings.board_border
+        };
+
+        // Urval, dragmarkeringar och animation hör till skärmen och tas bort under ritningen
+        let screen_layout = std::mem::replace(&mut self.layout, Layout::for_image(size as f32, margin));
+        let selected = self.selected_square.take();
+        let highlighted = std::mem::take(&mut self.highlighted_moves);
+        let animation = self.move_animation.take();
+
+        let target = render_target(size, size);
+        let mut camera = Camera2D::from_display_rect(Rect::new(0.0, 0.0, size as f32, size as f32));
+        camera.render_target = Some(target.clone());
+        set_camera(&camera);
+        clear_background(Color::new(0.9, 0.9, 0.9, 1.0));
+        self.draw_board_border();
+        self.draw_squares();
+        self.draw_coordinates();
+        self.draw_highlights();
+        self.draw_pieces();
+        set_default_camera();
+
+        self.layout = screen_layout;
+        self.selected_square = selected;
+        self.highlighted_moves = highlighted;
+        self.move_animation = animation;
+
+        target.texture.get_texture_data().export_png(BOARD_IMAGE_PATH);
+        println!("[export_board_image] {}×{} px till {}", size, size, BOARD_IMAGE_PATH);
+        self.toast(format!("✓ Bild sparad i '{}' ({} px)", BOARD_IMAGE_PATH, size));
+    }
+
+    // Slagna pjäser och materialövervikt för varje sida, i raden ovanför respektive under brädet.
+    // Räknas om varje bildruta från ställningen som visas.
+    fn draw_material_tally(&self) {
+        const ICON_SIZE: f32 = 22.0;
+        const SAME_PIECE_STEP: f32 = 9.0; // pjäser av samma slag ritas delvis över varandra
+        const GROUP_GAP: f32 = 6.0;
+
+        let board = self.get_display_board();
+        let balance = Self::simple_material_evaluation(board);
+        let bottom_color = if self.board_flipped() { ChessColor::Black } else { ChessColor::White };
+
+        for capturer in [ChessColor::White, ChessColor::Black] {
+            let bar_y = self.player_bar_y(capturer == bottom_color);
+            let icon_y = bar_y + (PLAYER_BAR_HEIGHT - ICON_SIZE) / 2.0;
+            let mut x = self.layout.board_x;
+
+            for (piece, count) in Self::captured_pieces(&self.start_board, board, capturer) {
+                for _ in 0..count {
+                    let key = PieceKey { piece, color: !capturer };
+                    if let Some(texture) = self.textures.get(&key) {
+                        draw_texture_ex(texture, x, icon_y, WHITE, DrawTextureParams {
+                            dest_size: Some(Vec2::new(ICON_SIZE, ICON_SIZE)),
+                            ..Default::default()
+                        });
+                    } else {
+                        let fill = if key.color == ChessColor::White { WHITE } else { BLACK };
+                        draw_circle(x + ICON_SIZE / 2.0, icon_y + ICON_SIZE / 2.0, ICON_SIZE * 0.3, fill);
+                        draw_circle_lines(x + ICON_SIZE / 2.0, icon_y + ICON_SIZE / 2.0, ICON_SIZE * 0.3, 1.0, DARKGRAY);
+                    }
+                    x += SAME_PIECE_STEP;
+                }
+                x += ICON_SIZE - SAME_PIECE_STEP + GROUP_GAP;
+            }
+
+            // Övervikten skrivs bara ut hos den sida som leder
+            let lead = if capturer == ChessColor::White { balance } else { -balance };
+            if lead > 0.0 {
+                draw_text(&format!("+{}", lead), x + 2.0, bar_y + PLAYER_BAR_HEIGHT / 2.0 + 6.0, 20.0, DARKGRAY);
+            }
+        }
+    }
+
+    // Klockorna längst till höger i raderna ovanför och under brädet. Sidan vid draget
+    // markeras och den sista tiden visas i rött.
+    fn draw_clocks(&self) {
+        const CLOCK_WIDTH: f32 = 90.0;
+        const LOW_TIME_SECONDS: f64 = 20.0;
+
+        let Some(clock) = &self.clock else {
+            return;
+        };
+        let bottom_color = if self.board_flipped() { ChessColor::Black } else { ChessColor::White };
+        let x = self.layout.board_x + self.layout.board_size() - CLOCK_WIDTH;
+
+        for color in [ChessColor::White, ChessColor::Black] {
+            let y = self.player_bar_y(color == bottom_color) + 1.0;
+            let height = PLAYER_BAR_HEIGHT - 2.0;
+            let remaining = clock.remaining(color);
+            let to_move = !self.game_over && self.board.side_to_move() == color;
+
+            let background = if to_move { WHITE } else { Color::new(0.8, 0.8, 0.8, 1.0) };
+            let text_color = if remaining < LOW_TIME_SECONDS { RED } else if to_move { BLACK } else { DARKGRAY };
+            draw_rectangle(x, y, CLOCK_WIDTH, height, background);
+            draw_rectangle_lines(x, y, CLOCK_WIDTH, height, if to_move { 2.0 } else { 1.0 }, DARKGRAY);
+
+            let text = format_clock(remaining);
+            let width = measure_text(&text, None, 22, 1.0).width;
+            draw_text(&text, x + (CLOCK_WIDTH - width) / 2.0, y + height / 2.0 + 7.0, 22.0, text_color);
         }
-        
-        // Rita radbeteckningar (1-8)
-        for i in 0..8 {
-            let rank = if self.settings.board_flipped {
-                (i + 1).to_string()
-            } else {
-                (8 - i).to_string()
-            };
-            
-            let y = BOARD_OFFSET + i as f32 * SQUARE_SIZE + SQUARE_SIZE / 2.0 + 8.0;
-            
-            // Till vänster om brädet
-            let x_left = BOARD_OFFSET - 25.0;
-            draw_text(&rank, x_left, y, 24.0, BLACK);
-            
-            // Till höger om brädet
-            let x_right = BOARD_OFFSET + 8.0 * SQUARE_SIZE + 15.0;
-            draw_text(&rank, x_right, y, 24.0, BLACK);
+    }
+
+    // Ram runt brädet; ritas före rutorna så att den hamnar i marginalen
+    fn draw_board_border(&self) {
+        let border = self.settings.board_border;
+        if border > 0.0 {
+            draw_rectangle(
+                self.layout.board_x - border,
+                self.layout.board_y - border,
+                self.layout.board_size() + 2.0 * border,
+                self.layout.board_size() + 2.0 * border,
+                Color::new(0.3, 0.2, 0.1, 1.0)
+            );
+        }
+    }
+
+    // Hur långt draganimationen kommit (0..1) för pjäsen på given ruta, om den animeras
+    fn move_animation_progress(&self, square: Square) -> Option<f32> {
+        const MOVE_ANIMATION_SECS: f64 = 0.15;
+
+        let (chess_move, started) = self.move_animation?;
+        if !self.settings.animations || self.review_mode || chess_move.get_dest() != square {
+            return None;
         }
+        let elapsed = get_time() - started;
+        (elapsed < MOVE_ANIMATION_SECS).then(|| (elapsed / MOVE_ANIMATION_SECS) as f32)
     }
 
     // Rita schackpjäserna
     fn draw_pieces(&self) {
-        const PIECE_SIZE: f32 = 75.0;
-        const SQUARE_SIZE: f32 = 80.0;
-        const BOARD_OFFSET: f32 = 100.0;
+        // Pjäserna fyller rutan med en liten marginal, oavsett rutstorlek
+        let square_size = self.layout.square_size;
         
         // Använd display_board istället för self.board
         let display_board = self.get_display_board();
         
+        // Blindschack: håll in mellanslag för att tillfälligt visa pjäserna
+        let reveal = is_key_down(KeyCode::Space);
+        
         for square in chess::ALL_SQUARES {
             if let Some(piece) = display_board.piece_on(square) {
                 let color = display_board.color_on(square).unwrap();
+                if self.settings.blindfold.hides(color) && !reveal {
+                    continue;
+                }
                 let (x, y) = self.square_to_coords(square);
                 
-                let screen_x = x as f32 * SQUARE_SIZE + BOARD_OFFSET;
-                let screen_y = y as f32 * SQUARE_SIZE + BOARD_OFFSET;
-                
-                let piece_key = PieceKey { piece, color };
+                let (mut screen_x, mut screen_y) = self.layout.square_origin(x, y);
                 
-                // Om vi har en textur för denna pjäs, använd den
-                if let Some(texture) = self.textures.get(&piece_key) {
-                    let offset = (SQUARE_SIZE - PIECE_SIZE) / 2.0;
-                    draw_texture_ex(
-                        texture, 
-                        screen_x + offset, 
-                        screen_y + offset, 
-                        WHITE,
-                        DrawTextureParams {
-                            dest_size: Some(Vec2::new(PIECE_SIZE, PIECE_SIZE)),
-                            ..Default::default()
-                        }
-                    );
-                } else {
-                    // Fallback till symboler
-                    let piece_color = if color == ChessColor::White { WHITE } else { BLACK };
-                    
-                    draw_circle(screen_x + 40.0, screen_y + 40.0, 25.0, piece_color);
-                    draw_circle_lines(screen_x + 40.0, screen_y + 40.0, 25.0, 2.0, DARKGRAY);
-                    
-                    let symbol = match piece {
-                        Piece::Pawn => "♟",
-                        Piece::Rook => "♜",
-                        Piece::Knight => "♞",
-                        Piece::Bishop => "♝",
-                        Piece::Queen => "♛",
-                        Piece::King => "♚",
-                    };
-                    
-                    let text_color = if color == ChessColor::White { BLACK } else { WHITE };
-                    draw_text(symbol, screen_x + 30.0, screen_y + 45.0, 30.0, text_color);
+                // Låt den senast flyttade pjäsen glida från sin ursprungsruta
+                if let Some(progress) = self.move_animation_progress(square) {
+                    let (from_x, from_y) = self.square_to_coords(self.move_animation.unwrap().0.get_source());
+                    let (from_screen_x, from_screen_y) = self.layout.square_origin(from_x, from_y);
+                    screen_x = from_screen_x + (screen_x - from_screen_x) * progress;
+                    screen_y = from_screen_y + (screen_y - from_screen_y) * progress;
                 }
+                
+                self.draw_piece(PieceKey { piece, color }, screen_x, screen_y, square_size);
             }
         }
     }
 
+    // Rita en pjäs i en ruta med övre vänstra hörnet (x, y)
+    fn draw_piece(&self, piece_key: PieceKey, screen_x: f32, screen_y: f32, square_size: f32) {
+        let piece_size = square_size * 0.94;
+        let PieceKey { piece, color } = piece_key;
+
+        // Om vi har en textur för denna pjäs, använd den
+        if let Some(texture) = self.textures.get(&piece_key) {
+            let offset = (square_size - piece_size) / 2.0;
+            draw_texture_ex(
+                texture, 
+                screen_x + offset, 
+                screen_y + offset, 
+                WHITE,
+                DrawTextureParams {
+                    dest_size: Some(Vec2::new(piece_size, piece_size)),
+                    ..Default::default()
+                }
+            );
+        } else {
+            // Fallback till symboler
+            let piece_color = if color == ChessColor::White { WHITE } else { BLACK };
+            
+            let half = square_size / 2.0;
+            draw_circle(screen_x + half, screen_y + half, square_size * 0.31, piece_color);
+            draw_circle_lines(screen_x + half, screen_y + half, square_size * 0.31, 2.0, DARKGRAY);
+            
+            let symbol = match piece {
+                Piece::Pawn => "♟",
+                Piece::Rook => "♜",
+                Piece::Knight => "♞",
+                Piece::Bishop => "♝",
+                Piece::Queen => "♛",
+                Piece::King => "♚",
+            };
+            
+            let text_color = if color == ChessColor::White { BLACK } else { WHITE };
+            draw_text(symbol, screen_x + square_size * 0.375, screen_y + square_size * 0.56, square_size * 0.375, text_color);
+        }
+    }
+
     // Hantera musklick
     fn handle_mouse_click(&mut self, mouse_pos: (f32, f32), ai_controller: &Option<ThreadSafeAiController>) {
+        // Beskedet om saknad motor ligger överst och stängs först
+        if self.engine_notice {
+            if self.engine_notice_button.is_clicked() {
+                self.engine_notice = false;
+            }
+            return;
+        }
+
+        // Frågan om att fortsätta senaste parti måste besvaras först
+        if self.resume_prompt.is_some() {
+            if self.resume_yes_button.is_clicked() {
+                self.answer_resume(true);
+            } else if self.resume_no_button.is_clicked() {
+                self.answer_resume(false);
+            }
+            return;
+        }
+
+        // Taktikläget ersätter inte ett pågående parti utan svar
+        if self.puzzle_prompt {
+            if self.puzzle_yes_button.is_clicked() {
+                self.answer_puzzle_prompt(true);
+            } else if self.puzzle_no_button.is_clicked() {
+                self.answer_puzzle_prompt(false);
+            }
+            return;
+        }
+
+        // En pågående import kan bara avbrytas
+        if self.pgn_import.is_some() {
+            if self.import_cancel_button.is_clicked() {
+                self.cancel_pgn_import();
+            }
+            return;
+        }
+
+        // Namnrutan ligger ovanpå inställningarna och blockerar dem
+        if self.name_input.is_some() {
+            if self.name_save_button.is_clicked() {
+                self.submit_name_input();
+            } else if self.name_cancel_button.is_clicked() {
+                self.name_input = None;
+            }
+            return;
+        }
+
+        // FEN-rutan blockerar allt annat medan den är öppen
+        if self.fen_input.is_some() {
+            if self.fen_load_button.is_clicked() {
+                self.submit_fen_input();
+            } else if self.fen_cancel_button.is_clicked() {
+                self.fen_input = None;
+            }
+            return;
+        }
+
+        if self.editor.is_some() {
+            self.handle_editor_click(mouse_pos);
+            return;
+        }
+
+        // Åskådarläget visar bara partiet
+        if self.spectator.is_active() {
+            return;
+        }
+
+        // Motorinfo-fönstret ligger överst och blockerar allt annat
+        if self.show_engine_info {
+            self.handle_engine_info_click(mouse_pos);
+            return;
+        }
+
+        if self.show_settings {
+            self.handle_settings_click(mouse_pos);
+            return;
+        }
+
+        if self.show_report {
+            self.handle_report_click(mouse_pos);
+            return;
+        }
+
+        if self.pending_promotion.is_some() {
+            self.handle_promotion_click(mouse_pos);
+            return;
+        }
+
+        if self.report_button.is_clicked() {
+            self.show_report = true;
+            return;
+        }
+
+        if self.fen_button.is_clicked() {
+            self.open_fen_input();
+            return;
+        }
+
+        if self.editor_button.is_clicked() {
+            self.open_editor();
+            return;
+        }
+
+        if self.save_game_button.is_clicked() {
+            self.save_game();
+            return;
+        }
+
+        if self.load_game_button.is_clicked() {
+            self.load_game();
+            return;
+        }
+
+        if self.export_image_button.is_clicked() {
+            self.export_board_image();
+            return;
+        }
+
+        if self.mute_button.is_clicked() {
+            self.toggle_sound();
+            return;
+        }
+
+        if self.infinite_analysis_button.is_clicked() {
+            self.toggle_infinite_analysis();
+            return;
+        }
+
+        if self.can_claim_fifty_moves() && self.claim_draw_button.is_clicked() {
+            self.claim_fifty_move_draw();
+            return;
+        }
+
+        if self.nav_first_button.is_clicked() {
+            self.review_first();
+            return;
+        }
+        if self.nav_prev_button.is_clicked() {
+            self.review_back();
+            return;
+        }
+        if self.nav_next_button.is_clicked() {
+            self.review_forward();
+            return;
+        }
+        if self.nav_last_button.is_clicked() {
+            self.exit_review_mode();
+            return;
+        }
+
+        if self.undo_button.is_clicked() {
+            self.undo();
+            return;
+        }
+
+        if self.redo_button.is_clicked() {
+            self.redo();
+            return;
+        }
+
+        if self.copy_fen_button.is_clicked() {
+            self.copy_fen();
+            return;
+        }
+
+        if self.paste_fen_button.is_clicked() {
+            self.paste_fen();
+            return;
+        }
+
+        if self.engine_info_button.is_clicked() {
+            self.show_engine_info = true;
+            return;
+        }
+
+        if self.settings_button.is_clicked() {
+            self.show_settings = true;
+            return;
+        }
+
+        if self.drill_button.is_clicked() {
+            self.save_drill_position();
+            return;
+        }
+
         // Kontrollera först om analysfönstret är öppet och om man klickar på stäng-knappen
         if self.handle_analysis_window_click(mouse_pos) {
-            self.game_analysis = None; // Stäng analysfönstret
+            self.archive_analysis(); // Stäng analysfönstret men spara analysen för jämförelse
             return;
         }
         
@@ -1420,6 +8415,16 @@ impl ChessGame {
             self.resign();
             return;
         }
+
+        if self.settings.game_mode == GameMode::EngineVsEngine {
+            if self.autoplay_button.is_clicked() {
+                self.toggle_autoplay();
+                return;
+            }
+        } else if self.draw_offer_button.is_clicked() {
+            self.offer_draw(ai_controller);
+            return;
+        }
         
         if self.export_button.is_clicked() {
             self.export_pgn();
@@ -1427,17 +8432,22 @@ impl ChessGame {
         }
         
         if self.flip_button.is_clicked() {
-            self.settings.board_flipped = !self.settings.board_flipped;
+            self.flip_board();
+            return;
+        }
+
+        if self.game_mode_button.is_clicked() {
+            self.cycle_game_mode();
             return;
         }
         
         if self.white_button.is_clicked() && !matches!(self.ai_state, AiState::Thinking(_)) {
-            self.settings.player_color = ChessColor::White;
+            self.choose_player_color(ChessColor::White);
             return;
         }
         
         if self.black_button.is_clicked() && !matches!(self.ai_state, AiState::Thinking(_)) {
-            self.settings.player_color = ChessColor::Black;
+            self.choose_player_color(ChessColor::Black);
             return;
         }
         
@@ -1446,8 +8456,23 @@ impl ChessGame {
             return;
         }
         
+        if self.search_mode_button.is_clicked() {
+            self.search_by_time = !self.search_by_time;
+            self.settings_dirty = true;
+            self.search_mode_button.text = if self.search_by_time { "Läge: Tid" } else { "Läge: Djup" }.to_string();
+            return;
+        }
+        
+        if self.phase_button.is_clicked() {
+            self.analysis_phase = self.analysis_phase.next();
+            self.phase_button.text = self.analysis_phase.label().to_string();
+            return;
+        }
+        
         if self.analyze_button.is_clicked() {
-            if let Some(ai) = ai_controller {
+            if self.analysis_in_progress {
+                self.cancel_game_analysis();
+            } else if let Some(ai) = ai_controller {
                 if !self.move_history.is_empty() {
                     self.start_full_game_analysis(ai);
                 } else {
@@ -1457,41 +8482,41 @@ impl ChessGame {
             return;
         }
 
-        // Hantera drag på brädet (endast om vi inte är i review-läge)
-        if self.review_mode {
-            return; // Blockera dragning när vi tittar på historiska positioner
-        }
-        
-        if self.game_over || self.board.side_to_move() != self.settings.player_color {
+        // Klick på ett drag i panelens draglista visar ställningen efter det
+        if let Some(index) = self.move_list_index_at(mouse_pos) {
+            self.show_position_at_move(index);
             return;
         }
 
-        let (mouse_x, mouse_y) = mouse_pos;
-        const BOARD_OFFSET: f32 = 100.0;
-        const BOARD_SIZE: f32 = 640.0;
-        
-        if mouse_x < BOARD_OFFSET || mouse_x > BOARD_OFFSET + BOARD_SIZE || 
-           mouse_y < BOARD_OFFSET || mouse_y > BOARD_OFFSET + BOARD_SIZE {
+        let Some((board_x, board_y)) = self.layout.square_at(mouse_pos) else {
             return;
-        }
+        };
 
-        let board_x = ((mouse_x - BOARD_OFFSET) / 80.0) as i32;
-        let board_y = ((mouse_y - BOARD_OFFSET) / 80.0) as i32;
-        
-        if board_x < 0 || board_x >= 8 || board_y < 0 || board_y >= 8 {
-            return;
+        // Granskade ställningar och motormatchen är bara till för att titta på
+        match board_click(self.game_over, self.review_mode, self.settings.game_mode, self.board.side_to_move(), self.human_color()) {
+            BoardClick::Ignored => return,
+            BoardClick::GameOver => {
+                self.toast("Partiet är slut");
+                return;
+            }
+            BoardClick::Play => {}
         }
 
         let clicked_square = self.coords_to_square(board_x, board_y);
 
         if let Some(selected) = self.selected_square {
-            let chess_move = ChessMove::new(selected, clicked_square, None);
+            // Bonde till sista raden: låt spelaren välja pjäs innan draget görs
+            if legal_move_between(&self.board, selected, clicked_square, Some(Piece::Queen)).is_some() {
+                self.pending_promotion = Some((selected, clicked_square));
+                return;
+            }
             
-            if self.is_legal_move(chess_move) {
-                self.make_move(chess_move);
+            // Rockad går att göra både med kungen två steg och genom att klicka på tornet
+            if let Some(chess_move) = legal_move_between(&self.board, selected, clicked_square, None) {
+                self.play_human_move(chess_move);
             } else {
                 if self.board.piece_on(clicked_square).is_some() && 
-                   self.board.color_on(clicked_square) == Some(self.settings.player_color) {
+                   self.board.color_on(clicked_square) == Some(self.human_color()) {
                     self.selected_square = Some(clicked_square);
                     self.update_highlighted_moves();
                 } else {
@@ -1501,172 +8526,657 @@ impl ChessGame {
             }
         } else {
             if self.board.piece_on(clicked_square).is_some() && 
-               self.board.color_on(clicked_square) == Some(self.settings.player_color) {
+               self.board.color_on(clicked_square) == Some(self.human_color()) {
                 self.selected_square = Some(clicked_square);
                 self.update_highlighted_moves();
             }
         }
     }
 
-    fn update_highlighted_moves(&mut self) {
-        self.highlighted_moves.clear();
-        if let Some(selected) = self.selected_square {
-            let movegen = MoveGen::new_legal(&self.board);
-            
-            for m in movegen {
-                if m.get_source() == selected {
-                    self.highlighted_moves.push(m);
-                }
-            }
+    // Pjäser att välja mellan vid bondeförvandling, i den ordning de ritas
+    const PROMOTION_PIECES: [Piece; 4] = [Piece::Queen, Piece::Rook, Piece::Bishop, Piece::Knight];
+
+    fn complete_promotion(&mut self, piece: Piece) {
+        if let Some((from, to)) = self.pending_promotion.take() {
+            let chess_move = ChessMove::new(from, to, Some(piece));
+            if self.is_legal_move(chess_move) {
+                self.play_human_move(chess_move);
+            }
+        }
+    }
+
+    // Stäng av eller slå på alla ljud (knappen eller M)
+    fn toggle_sound(&mut self) {
+        if self.sounds.is_none() {
+            return;
+        }
+        self.settings.sound_enabled = !self.settings.sound_enabled;
+        self.settings_dirty = true;
+        println!("[toggle_sound] Ljud: {}", self.settings.sound_enabled);
+        self.toast(if self.settings.sound_enabled { "Ljud på" } else { "Ljud av" });
+    }
+
+    fn play_sound(&self, event: SoundEvent, by_ai: bool) {
+        if !self.settings.sound_enabled {
+            return;
+        }
+        if let Some(sounds) = &self.sounds {
+            sounds.play(event, by_ai, self.settings.distinct_move_sounds);
+        }
+    }
+
+    // Rita valrutan för bondeförvandling ovanpå brädet
+    // Dialogen (400×140) centreras över brädet
+    fn promotion_dialog_origin(&self) -> (f32, f32) {
+        let (center_x, center_y) = self.layout.board_center();
+        (center_x - 200.0, center_y - 70.0)
+    }
+
+    fn draw_promotion_dialog(&self) {
+        const SLOT_SIZE: f32 = 80.0;
+        let (dialog_x, dialog_y) = self.promotion_dialog_origin();
+
+        if self.pending_promotion.is_none() {
+            return;
+        }
+
+        draw_rectangle(dialog_x, dialog_y, 400.0, 140.0, WHITE);
+        draw_rectangle_lines(dialog_x, dialog_y, 400.0, 140.0, 3.0, DARKGRAY);
+        draw_text("Välj pjäs (Q/R/B/N, Enter/Esc = dam)", dialog_x + 15.0, dialog_y + 22.0, 14.0, BLACK);
+
+        let color = self.human_color();
+        for (i, piece) in Self::PROMOTION_PIECES.iter().enumerate() {
+            let slot_x = dialog_x + 20.0 + i as f32 * (SLOT_SIZE + 14.0);
+            let slot_y = dialog_y + 40.0;
+            draw_rectangle(slot_x, slot_y, SLOT_SIZE, SLOT_SIZE, BEIGE);
+            draw_rectangle_lines(slot_x, slot_y, SLOT_SIZE, SLOT_SIZE, 2.0, DARKGRAY);
+
+            if let Some(texture) = self.textures.get(&PieceKey { piece: *piece, color }) {
+                draw_texture_ex(
+                    texture,
+                    slot_x + 2.5,
+                    slot_y + 2.5,
+                    WHITE,
+                    DrawTextureParams {
+                        dest_size: Some(Vec2::new(75.0, 75.0)),
+                        ..Default::default()
+                    }
+                );
+            } else {
+                let letter = match piece {
+                    Piece::Queen => "D",
+                    Piece::Rook => "T",
+                    Piece::Bishop => "L",
+                    _ => "S",
+                };
+                draw_text(letter, slot_x + 30.0, slot_y + 50.0, 36.0, BLACK);
+            }
+        }
+    }
+
+    // Klick i förvandlingsrutan; klick utanför avbryter
+    fn handle_promotion_click(&mut self, mouse_pos: (f32, f32)) {
+        const SLOT_SIZE: f32 = 80.0;
+        let (dialog_x, dialog_y) = self.promotion_dialog_origin();
+
+        let (mouse_x, mouse_y) = mouse_pos;
+        let slot_y = dialog_y + 40.0;
+
+        for (i, piece) in Self::PROMOTION_PIECES.iter().enumerate() {
+            let slot_x = dialog_x + 20.0 + i as f32 * (SLOT_SIZE + 14.0);
+            if (slot_x..=slot_x + SLOT_SIZE).contains(&mouse_x) && (slot_y..=slot_y + SLOT_SIZE).contains(&mouse_y) {
+                self.complete_promotion(*piece);
+                return;
+            }
+        }
+
+        self.pending_promotion = None;
+    }
+
+    fn update_highlighted_moves(&mut self) {
+        self.highlighted_moves.clear();
+        if let Some(selected) = self.selected_square {
+            let movegen = MoveGen::new_legal(&self.board);
+            
+            for m in movegen {
+                if m.get_source() == selected {
+                    self.highlighted_moves.push(m);
+                }
+            }
+        }
+    }
+
+    // Huvudvarianten som svaga pilar: senaste positionsanalysen, eller i granskningsläget
+    // det motorn föredrog framför det granskade draget
+    fn draw_pv_arrows(&self) {
+        let line = if self.review_mode {
+            self.review_move_index
+                .and_then(|i| self.game_analysis.as_ref()?.moves.get(i))
+                .filter(|m| m.best_move.is_some_and(|best| best != m.chess_move))
+                .map_or(&[][..], |m| &m.best_line[..])
+        } else {
+            &self.analysis_pv[..]
+        };
+
+        let center = |square: Square| {
+            let (x, y) = self.square_to_coords(square);
+            let (left, top) = self.layout.square_origin(x, y);
+            let half = self.layout.square_size / 2.0;
+            Vec2::new(left + half, top + half)
+        };
+
+        for (ply, m) in line.iter().take(PV_DISPLAY_PLIES).enumerate() {
+            let from = center(m.get_source());
+            let to = center(m.get_dest());
+            let direction = (to - from).normalize_or_zero();
+            let normal = Vec2::new(-direction.y, direction.x);
+            let head_base = to - direction * 18.0;
+            let color = Color::new(0.1, 0.4, 0.9, 0.55 - ply as f32 * 0.07);
+
+            draw_line(from.x, from.y, head_base.x, head_base.y, 6.0, color);
+            draw_triangle(to, head_base + normal * 12.0, head_base - normal * 12.0, color);
+        }
+    }
+
+    // Röd, pulserande ruta under kungen när sidan vid draget står i schack
+    fn draw_check_indicator(&self) {
+        let board = self.get_display_board();
+        if board.checkers().popcnt() == 0 {
+            return;
+        }
+
+        let (x, y) = self.square_to_coords(board.king_square(board.side_to_move()));
+        let (left, top) = self.layout.square_origin(x, y);
+        let size = self.layout.square_size;
+        // Utan animationer lyser rutan med jämn styrka
+        let pulse = if self.settings.animations { (get_time() * 5.0).sin() as f32 * 0.15 } else { 0.0 };
+
+        draw_rectangle(left, top, size, size, Color::new(0.9, 0.1, 0.1, 0.45 + pulse));
+        draw_rectangle_lines(left, top, size, size, 4.0, RED);
+    }
+
+    // Rita markerad ruta och möjliga drag
+    fn draw_highlights(&self) {
+        let size = self.layout.square_size;
+
+        // Senaste draget (eller i granskningsläget draget som ledde hit) i ljusblått,
+        // så att det skiljer sig från gult urval och gröna dragmarkeringar
+        let last_move = if self.review_mode { self.review_last_move } else { self.last_move };
+        if let Some(m) = last_move {
+            for square in [m.get_source(), m.get_dest()] {
+                let (x, y) = self.square_to_coords(square);
+                let (left, top) = self.layout.square_origin(x, y);
+                draw_rectangle(left, top, size, size, Color::new(0.3, 0.6, 1.0, 0.35));
+            }
+        }
+        
+        if let Some(selected) = self.selected_square {
+            let (x, y) = self.square_to_coords(selected);
+            let (left, top) = self.layout.square_origin(x, y);
+            draw_rectangle_lines(left, top, size, size, 4.0, YELLOW);
+        }
+
+        for m in &self.highlighted_moves {
+            let (x, y) = self.square_to_coords(m.get_dest());
+            let (left, top) = self.layout.square_origin(x, y);
+            let center_x = left + size / 2.0;
+            let center_y = top + size / 2.0;
+
+            // Slag (även en passant) markeras med en ring runt hela rutan, vanliga drag
+            // med en liten markering i mitten; storlekarna följer rutan
+            match (self.settings.move_hint_style, is_capture(&self.board, *m)) {
+                (MoveHintStyle::Dots, false) => draw_circle(center_x, center_y, size * 0.13, GREEN),
+                (MoveHintStyle::Dots, true) => {
+                    draw_circle_lines(center_x, center_y, size / 2.0 - 4.0, 5.0, GREEN)
+                }
+                (MoveHintStyle::Rings, false) => draw_circle_lines(center_x, center_y, size * 0.18, 3.0, GREEN),
+                (MoveHintStyle::Rings, true) => {
+                    draw_circle_lines(center_x, center_y, size / 2.0 - 4.0, 4.0, RED)
+                }
+                (MoveHintStyle::Tint, false) => {
+                    draw_rectangle(left, top, size, size, Color::new(0.0, 0.8, 0.0, 0.35))
+                }
+                (MoveHintStyle::Tint, true) => {
+                    draw_rectangle(left, top, size, size, Color::new(0.9, 0.1, 0.1, 0.4))
+                }
+            }
+        }
+    }
+
+    // Räkna om layouten från fönsterstorleken och flytta panelens komponenter med
+    fn update_layout(&mut self) {
+        let layout = Layout::current();
+        self.place_dialog_buttons();
+        if layout == self.layout {
+            self.place_board_buttons();
+            return;
+        }
+        let dx = layout.panel_x - self.layout.panel_x;
+        self.layout = layout;
+        self.place_board_buttons();
+        if dx == 0.0 {
+            return;
+        }
+
+        for slider in [&mut self.depth_slider, &mut self.movetime_slider, &mut self.skill_slider] {
+            slider.shift_x(dx);
+        }
+        for button in [
+            &mut self.search_mode_button,
+            &mut self.resign_button,
+            &mut self.export_button,
+            &mut self.flip_button,
+            &mut self.game_mode_button,
+            &mut self.white_button,
+            &mut self.black_button,
+            &mut self.new_game_button,
+            &mut self.draw_offer_button,
+            &mut self.autoplay_button,
+            &mut self.analyze_button,
+            &mut self.phase_button,
+            &mut self.copy_fen_button,
+            &mut self.paste_fen_button,
+            &mut self.undo_button,
+            &mut self.redo_button,
+            &mut self.engine_info_button,
+            &mut self.settings_button,
+            &mut self.drill_button,
+            &mut self.report_button,
+            &mut self.fen_button,
+            &mut self.infinite_analysis_button,
+            &mut self.save_game_button,
+            &mut self.load_game_button,
+            &mut self.export_image_button,
+            &mut self.mute_button,
+            &mut self.editor_button,
+            &mut self.editor_eraser_button,
+            &mut self.editor_side_button,
+            &mut self.editor_clear_button,
+            &mut self.editor_reset_button,
+            &mut self.editor_start_button,
+            &mut self.editor_cancel_button,
+        ] {
+            button.shift_x(dx);
+        }
+        for button in &mut self.editor_castle_buttons {
+            button.shift_x(dx);
         }
     }
 
-    // Rita markerad ruta och möjliga drag
-    fn draw_highlights(&self) {
-        const BOARD_OFFSET: f32 = 100.0;
-        const SQUARE_SIZE: f32 = 80.0;
-        
-        if let Some(selected) = self.selected_square {
-            let (x, y) = self.square_to_coords(selected);
-            draw_rectangle_lines(
-                x as f32 * SQUARE_SIZE + BOARD_OFFSET,
-                y as f32 * SQUARE_SIZE + BOARD_OFFSET,
-                SQUARE_SIZE,
-                SQUARE_SIZE,
-                4.0,
-                YELLOW
-            );
+    // Övre kanten på raden med slagna pjäser ovanför eller under brädet, utanför ram och koordinater
+    fn player_bar_y(&self, bottom: bool) -> f32 {
+        let mut offset = self.settings.board_border;
+        if self.coordinates_in_margin() {
+            offset += 6.0 + self.settings.coordinate_size as f32;
+        }
+        if bottom {
+            self.layout.board_y + self.layout.board_size() + offset
+        } else {
+            self.layout.board_y - offset - PLAYER_BAR_HEIGHT
         }
+    }
 
-        for m in &self.highlighted_moves {
-            let (x, y) = self.square_to_coords(m.get_dest());
-            draw_circle(
-                x as f32 * SQUARE_SIZE + BOARD_OFFSET + SQUARE_SIZE / 2.0,
-                y as f32 * SQUARE_SIZE + BOARD_OFFSET + SQUARE_SIZE / 2.0,
-                10.0,
-                GREEN
-            );
+    // Raden under brädet: navigeringen till vänster, remikravet till höger. Den beror även på
+    // ram och koordinater och placeras därför om varje bildruta.
+    fn place_board_buttons(&mut self) {
+        let y = self.player_bar_y(true) + PLAYER_BAR_HEIGHT + 4.0;
+        let mut x = self.layout.board_x;
+        for button in [
+            &mut self.nav_first_button,
+            &mut self.nav_prev_button,
+            &mut self.nav_next_button,
+            &mut self.nav_last_button,
+        ] {
+            button.x = x;
+            button.y = y;
+            x += NAV_BUTTON_WIDTH + 6.0;
+        }
+        self.claim_draw_button.x = self.layout.board_x + self.layout.board_size() - self.claim_draw_button.width;
+        self.claim_draw_button.y = y;
+    }
+
+    // Knapparna i dialogrutorna och analysfönstret följer rutorna, som centreras i fönstret
+    fn place_dialog_buttons(&mut self) {
+        let place = |button: &mut Button, (x, y): (f32, f32), dx: f32, dy: f32| {
+            button.x = x + dx;
+            button.y = y + dy;
+        };
+        let fen = overlay_origin(FEN_DIALOG_SIZE.0, FEN_DIALOG_SIZE.1);
+        place(&mut self.fen_load_button, fen, 240.0, 120.0);
+        place(&mut self.fen_cancel_button, fen, 370.0, 120.0);
+        let name = overlay_origin(NAME_DIALOG_SIZE.0, NAME_DIALOG_SIZE.1);
+        place(&mut self.name_save_button, name, 110.0, 120.0);
+        place(&mut self.name_cancel_button, name, 240.0, 120.0);
+        let resume = overlay_origin(RESUME_DIALOG_SIZE.0, RESUME_DIALOG_SIZE.1);
+        place(&mut self.resume_yes_button, resume, 40.0, 80.0);
+        place(&mut self.resume_no_button, resume, 220.0, 80.0);
+        let puzzle = overlay_origin(PUZZLE_DIALOG_SIZE.0, PUZZLE_DIALOG_SIZE.1);
+        place(&mut self.puzzle_yes_button, puzzle, 40.0, 80.0);
+        place(&mut self.puzzle_no_button, puzzle, 220.0, 80.0);
+        let notice = overlay_origin(ENGINE_NOTICE_SIZE.0, ENGINE_NOTICE_SIZE.1);
+        place(&mut self.engine_notice_button, notice, 230.0, 230.0);
+        let import = overlay_origin(IMPORT_DIALOG_SIZE.0, IMPORT_DIALOG_SIZE.1);
+        place(&mut self.import_cancel_button, import, 300.0, 105.0);
+        let analysis = overlay_origin(ANALYSIS_WINDOW_SIZE.0, ANALYSIS_WINDOW_SIZE.1);
+        place(&mut self.prev_error_button, analysis, 220.0, 10.0);
+        place(&mut self.next_error_button, analysis, 370.0, 10.0);
+    }
+
+    // Fönstertiteln visar läget i partiet, så det syns i aktivitetsfältet
+    fn update_window_title(&mut self) {
+        let status = if self.game_over {
+            Some("Spel över")
+        } else if matches!(self.ai_state, AiState::Thinking(_)) {
+            Some("AI tänker")
+        } else if self.analysis_in_progress {
+            Some("Analyserar")
+        } else if self.settings.game_mode == GameMode::EngineVsEngine && self.autoplay_paused {
+            Some("Motormatch stoppad")
+        } else if self.settings.game_mode == GameMode::VsEngine && self.board.side_to_move() == self.settings.player_color {
+            Some("Din tur")
+        } else {
+            None
+        };
+        let base = base_window_title(self.engine_info.as_ref());
+        let title = match status {
+            Some(status) => format!("{base} – {status}"),
+            None => base,
+        };
+        if title != self.window_title && set_window_title(&self.window_title, &title) {
+            self.window_title = title;
         }
     }
 
     fn update(&mut self) {
-        self.depth_slider.update();
+        self.update_layout();
+        self.update_window_title();
+        self.update_editor_buttons();
+        self.tick_clock();
+        if self.search_by_time {
+            self.movetime_slider.update();
+        } else {
+            self.depth_slider.update();
+        }
+        self.skill_slider.update();
+        // Ett reglage som dras ändrar sitt värde; filen skrivs när musknappen släpps
+        if self.movetime_slider.dragging || self.depth_slider.dragging || self.skill_slider.dragging {
+            self.settings_dirty = true;
+        }
+        self.toasts.update();
         
         // Uppdatera knappstatus
-        self.resign_button.set_active(!self.game_over);
+        // I motormatchen finns ingen spelare som kan ge upp eller erbjuda remi
+        self.resign_button.set_active(!self.game_over && self.settings.game_mode != GameMode::EngineVsEngine);
+        self.autoplay_button.set_active(!self.game_over);
+        self.draw_offer_button.set_active(self.can_offer_draw());
+        self.drill_button.set_active(!self.game_over && !self.review_mode);
+        self.report_button.set_active(self.latest_analysis().is_some());
+        self.undo_button.set_active(!self.move_history.is_empty() && self.pgn_import.is_none() && self.puzzle.is_none());
+        self.redo_button.set_active(!self.redo_stack.is_empty() && self.pgn_import.is_none() && self.puzzle.is_none());
         self.white_button.set_active(!matches!(self.ai_state, AiState::Thinking(_)) && self.settings.player_color != ChessColor::White);
         self.black_button.set_active(!matches!(self.ai_state, AiState::Thinking(_)) && self.settings.player_color != ChessColor::Black);
-        self.analyze_button.set_active(matches!(self.ai_state, AiState::Idle) && !self.analysis_in_progress);
+        self.analyze_button.set_active(matches!(self.ai_state, AiState::Idle) || self.analysis_in_progress);
+        self.analyze_button.text = if self.analysis_in_progress { "Avbryt" } else { "Analysera" }.to_string();
+        // Bakåt går alltid när det finns drag kvar att visa; framåt bara under granskning.
+        // På sista draget leder framåt tillbaka till spelet.
+        let can_go_back = self.played_move_count() > 0 && !(self.review_mode && self.review_move_index.is_none());
+        let at_last_ply = self.review_mode && self.review_move_index.is_some_and(|i| i + 1 >= self.played_move_count());
+        self.nav_first_button.set_active(can_go_back);
+        self.nav_prev_button.set_active(can_go_back);
+        self.nav_next_button.set_active(self.review_mode);
+        self.nav_last_button.set_active(self.review_mode);
+        self.nav_next_button.text = if at_last_ply { "Spel" } else { ">" }.to_string();
+        self.mute_button.text = format!("Ljud: {}", self.sound_label(self.settings.sound_enabled));
+        self.mute_button.set_active(self.sounds.is_some());
+        self.game_mode_button.text = self.settings.game_mode.label().to_string();
+        self.autoplay_button.text = if self.autoplay_paused { "Fortsätt" } else { "Stoppa" }.to_string();
+        self.phase_button.set_active(!self.analysis_in_progress);
+
+        // Utan motor går inga knappar som behöver den att trycka på
+        if !self.has_engine() {
+            for button in [
+                &mut self.analyze_button,
+                &mut self.phase_button,
+                &mut self.infinite_analysis_button,
+                &mut self.game_mode_button,
+                &mut self.search_mode_button,
+                &mut self.engine_info_button,
+            ] {
+                button.set_active(false);
+            }
+        }
     }
 
     fn draw_control_panel(&self) {
-        const PANEL_X: f32 = 780.0;
+        let panel_x = self.layout.panel_x;
         const PANEL_WIDTH: f32 = 200.0;
         
         // Rita panelbakgrund
-        draw_rectangle(PANEL_X - 10.0, 50.0, PANEL_WIDTH, 750.0, Color::new(0.95, 0.95, 0.95, 1.0));
-        draw_rectangle_lines(PANEL_X - 10.0, 50.0, PANEL_WIDTH, 750.0, 2.0, DARKGRAY);
+        draw_rectangle(panel_x - 10.0, 50.0, PANEL_WIDTH, 750.0, Color::new(0.95, 0.95, 0.95, 1.0));
+        draw_rectangle_lines(panel_x - 10.0, 50.0, PANEL_WIDTH, 750.0, 2.0, DARKGRAY);
         
         // Titel
-        draw_text("KONTROLLPANEL", PANEL_X, 80.0, 20.0, BLACK);
+        draw_text("KONTROLLPANEL", panel_x, 80.0, 20.0, BLACK);
         
-        // AI-sökdjup slider
-        self.depth_slider.draw("AI Sökdjup:");
+        // AI-sökdjup eller betänketid, beroende på sökläge
+        if self.search_by_time {
+            self.movetime_slider.draw("AI Tid (s):");
+        } else {
+            self.depth_slider.draw("AI Sökdjup:");
+        }
+        self.search_mode_button.draw();
+        self.skill_slider.draw("Motorstyrka (0-20):");
         
         // Knappar
         self.resign_button.draw();
         self.export_button.draw();
         self.flip_button.draw();
+        self.game_mode_button.draw();
         self.white_button.draw();
         self.black_button.draw();
         self.new_game_button.draw();
+        if self.settings.game_mode == GameMode::EngineVsEngine {
+            self.autoplay_button.draw();
+        } else {
+            self.draw_offer_button.draw();
+        }
         self.analyze_button.draw();
+        self.phase_button.draw();
+        self.engine_info_button.draw();
+        self.settings_button.draw();
+        self.drill_button.draw();
+        self.report_button.draw();
+        self.fen_button.draw();
+        self.editor_button.draw();
+        self.infinite_analysis_button.draw();
+        self.save_game_button.draw();
+        self.load_game_button.draw();
+        self.export_image_button.draw();
+        self.mute_button.draw();
+        if self.can_claim_fifty_moves() {
+            self.claim_draw_button.draw();
+        }
+        self.nav_first_button.draw();
+        self.nav_prev_button.draw();
+        self.nav_next_button.draw();
+        self.nav_last_button.draw();
+        self.copy_fen_button.draw();
+        self.paste_fen_button.draw();
+        self.undo_button.draw();
+        self.redo_button.draw();
         
         // Spelstatus
-        let mut y_pos = 370.0;
-        draw_text("STATUS:", PANEL_X, y_pos, 16.0, BLACK);
+        let mut y_pos = 495.0;
+        draw_text("STATUS:", panel_x, y_pos, 16.0, BLACK);
         y_pos += 25.0;
         
         // Visa olika status beroende på läge
         if self.review_mode {
-            draw_text("GRANSKNINGSLÄGE", PANEL_X, y_pos, 14.0, BLUE);
+            draw_text("GRANSKNINGSLÄGE", panel_x, y_pos, 14.0, BLUE);
             y_pos += 20.0;
             
             if let Some(move_index) = self.review_move_index {
-                draw_text(&format!("Visar drag: {}", move_index + 1), PANEL_X, y_pos, 14.0, DARKGRAY);
+                draw_text(&format!("Visar drag: {}", move_index + 1), panel_x, y_pos, 14.0, DARKGRAY);
                 y_pos += 20.0;
                 
-                if let Some(move_str) = self.move_history.get(move_index) {
-                    draw_text(&format!("Drag: {}", move_str), PANEL_X, y_pos, 14.0, DARKGRAY);
+                if let Some(record) = self.move_history.get(move_index) {
+                    draw_text(&format!("Drag: {}", record.san), panel_x, y_pos, 14.0, DARKGRAY);
+                    y_pos += 20.0;
+                }
+
+                // Vad draget förändrade enligt partianalysen (om partiet är analyserat)
+                if let Some(move_analysis) = self.game_analysis.as_ref().and_then(|a| a.moves.get(move_index)).filter(|m| !m.skipped) {
+                    let side_that_moved = move_analysis.side;
+                    let (label, color) = move_analysis.classification();
+                    let delta = format_eval(Eval::Pawns(move_analysis.eval_delta(side_that_moved)), self.settings.eval_precision);
+                    draw_text(&format!("{} ({})", delta, label), panel_x, y_pos, 14.0, color);
                     y_pos += 20.0;
+
+                    if let (Some(best), Some(best_notation)) = (move_analysis.best_move, &move_analysis.best_move_notation) {
+                        if best != move_analysis.chess_move {
+                            draw_text(&format!("Bäst var: {}", best_notation), panel_x, y_pos, 14.0, DARKGREEN);
+                            y_pos += 20.0;
+
+                            if move_analysis.best_line.len() > 1 {
+                                let line = san_line(&self.board_before_move(move_index), &move_analysis.best_line, PV_DISPLAY_PLIES);
+                                draw_text("Bästa linje:", panel_x, y_pos, 12.0, DARKGREEN);
+                                draw_text(&line, panel_x, y_pos + 14.0, 12.0, DARKGREEN);
+                                y_pos += 34.0;
+                            }
+                        }
+                    }
                 }
             }
             
             let display_board = self.get_display_board();
-            draw_text(&format!("Position: {:?} att dra", display_board.side_to_move()), PANEL_X, y_pos, 14.0, DARKGRAY);
+            draw_text(&format!("Position: {:?} att dra", display_board.side_to_move()), panel_x, y_pos, 14.0, DARKGRAY);
             y_pos += 20.0;
             
         } else {
-            draw_text(&format!("Tur: {:?}", self.board.side_to_move()), PANEL_X, y_pos, 14.0, DARKGRAY);
+            draw_text(&format!("Tur: {:?}", self.board.side_to_move()), panel_x, y_pos, 14.0, DARKGRAY);
+            y_pos += 20.0;
+            
+            match self.settings.game_mode {
+                GameMode::VsEngine => draw_text(&format!("Du spelar: {:?}", self.settings.player_color), panel_x, y_pos, 14.0, DARKGRAY),
+                GameMode::HotSeat => draw_text("Två spelare vid datorn", panel_x, y_pos, 14.0, DARKGRAY),
+                GameMode::EngineVsEngine => draw_text("Motorn spelar båda sidor", panel_x, y_pos, 14.0, DARKGRAY),
+            };
             y_pos += 20.0;
             
-            draw_text(&format!("Du spelar: {:?}", self.settings.player_color), PANEL_X, y_pos, 14.0, DARKGRAY);
+            if self.drill_position.is_some() {
+                draw_text("Övning sparad (R = igen)", panel_x, y_pos, 14.0, DARKGREEN);
+                y_pos += 20.0;
+            }
+
+            if let Some(ref session) = self.puzzle {
+                let text = format!("Taktik {}/{}: löst {} av {}", session.index + 1, session.puzzles.len(), session.solved, session.attempted);
+                draw_text(&text, panel_x, y_pos, 14.0, DARKGREEN);
+                draw_text("P = avsluta taktik", panel_x, y_pos + 16.0, 12.0, DARKGRAY);
+                y_pos += 36.0;
+            }
+        }
+        
+        // Material och motorns bedömning sida vid sida, så att det syns när
+        // ett materialövertag vägs upp av ställningen
+        if self.settings.show_eval_comparison {
+            let material = Eval::Pawns(Self::simple_material_evaluation(self.get_display_board()));
+            let material_text = format_eval(self.perspective_eval(material), 0);
+            let engine_text = self.displayed_eval()
+                .map(|eval| format_eval(self.perspective_eval(eval), self.settings.eval_precision))
+                .unwrap_or_else(|| "–".to_string());
+            draw_text(&format!("Material: {} | Motor: {}", material_text, engine_text), panel_x, y_pos, 14.0, DARKGRAY);
+            y_pos += 20.0;
+        }
+        
+        let ai_status = self.ai_status();
+        for line in ai_status.lines() {
+            draw_text(line, panel_x, y_pos, 14.0, BLUE);
             y_pos += 20.0;
         }
+        if let Some(share) = self.ai_search_share() {
+            draw_rectangle(panel_x, y_pos - 12.0, 160.0, 6.0, LIGHTGRAY);
+            draw_rectangle(panel_x, y_pos - 12.0, 160.0 * share, 6.0, BLUE);
+            y_pos += 4.0;
+        }
+
+        // Förloppsindikator för partianalysen, flyttas fram för varje analyserat drag
+        if self.analysis_in_progress {
+            let (done, total) = self.analysis_progress;
+            let share = if total > 0 { done as f32 / total as f32 } else { 0.0 };
+            draw_rectangle(panel_x, y_pos - 10.0, 160.0, 12.0, LIGHTGRAY);
+            draw_rectangle(panel_x, y_pos - 10.0, 160.0 * share, 12.0, DARKGREEN);
+            draw_rectangle_lines(panel_x, y_pos - 10.0, 160.0, 12.0, 1.0, DARKGRAY);
+            draw_text(&format!("Drag {} / {}", done, total), panel_x, y_pos + 16.0, 12.0, DARKGRAY);
+            y_pos += 36.0;
+        }
         
-        if !self.ai_status().is_empty() {
-            draw_text(&self.ai_status(), PANEL_X, y_pos, 14.0, BLUE);
+        if self.deep_search_armed {
+            draw_text(&format!("Nästa sökning: djup {} (D)", DEEP_SEARCH_DEPTH), panel_x, y_pos, 14.0, PURPLE);
             y_pos += 20.0;
         }
+
+        // Oändlig analys: djup, evaluering och huvudvariant för ställningen som visas
+        if self.infinite_analysis {
+            if let Some(info) = self.infinite_analysis_info() {
+                let eval_text = format_eval(self.perspective_eval(info.eval), self.settings.eval_precision);
+                draw_text(&format!("Analys djup {}: {}", info.depth, eval_text), panel_x, y_pos, 14.0, DARKBLUE);
+                let line = san_line(self.get_display_board(), &info.pv, PV_DISPLAY_PLIES);
+                draw_text(&line, panel_x, y_pos + 14.0, 12.0, DARKBLUE);
+                y_pos += 34.0;
+            } else if self.live_analysis.is_some() {
+                draw_text("Analyserar …", panel_x, y_pos, 14.0, DARKBLUE);
+                y_pos += 20.0;
+            }
+        }
         
         // Analysresultat för enskild position
         if let Some(ref analysis) = self.current_analysis {
             if analysis.contains("Bästa drag:") {
-                draw_text("POSITIONSANALYS:", PANEL_X, y_pos, 16.0, BLACK);
+                draw_text("POSITIONSANALYS:", panel_x, y_pos, 16.0, BLACK);
                 y_pos += 25.0;
                 
                 // Rita analysen i en ruta
                 let analysis_lines: Vec<&str> = analysis.split('\n').collect();
                 let analysis_height = analysis_lines.len() as f32 * 15.0 + 10.0;
                 
-                draw_rectangle(PANEL_X, y_pos, 160.0, analysis_height, Color::new(0.9, 0.9, 1.0, 1.0));
-                draw_rectangle_lines(PANEL_X, y_pos, 160.0, analysis_height, 1.0, BLUE);
+                draw_rectangle(panel_x, y_pos, 160.0, analysis_height, Color::new(0.9, 0.9, 1.0, 1.0));
+                draw_rectangle_lines(panel_x, y_pos, 160.0, analysis_height, 1.0, BLUE);
                 
                 let mut line_y = y_pos + 15.0;
                 for line in analysis_lines {
-                    draw_text(line, PANEL_X + 5.0, line_y, 12.0, DARKBLUE);
+                    draw_text(line, panel_x + 5.0, line_y, 12.0, DARKBLUE);
                     line_y += 15.0;
                 }
                 
                 y_pos += analysis_height + 20.0;
             } else {
                 // Visa andra typer av analysmeddelanden
-                draw_text("ANALYS:", PANEL_X, y_pos, 16.0, BLACK);
+                draw_text("ANALYS:", panel_x, y_pos, 16.0, BLACK);
                 y_pos += 25.0;
-                draw_text(analysis, PANEL_X, y_pos, 12.0, DARKBLUE);
+                draw_text(analysis, panel_x, y_pos, 12.0, DARKBLUE);
                 y_pos += 30.0;
             }
         }
         
         if self.game_over {
-            draw_text("SPEL ÖVER", PANEL_X, y_pos, 16.0, RED);
+            draw_text("SPEL ÖVER", panel_x, y_pos, 16.0, RED);
             y_pos += 25.0;
             
             match self.board.status() {
                 BoardStatus::Checkmate => {
                     let winner = if self.board.side_to_move() == ChessColor::White { "Svart" } else { "Vit" };
-                    draw_text(&format!("{} vann!", winner), PANEL_X, y_pos, 14.0, RED);
+                    draw_text(&format!("{} vann!", winner), panel_x, y_pos, 14.0, RED);
                 }
                 BoardStatus::Stalemate => {
-                    draw_text("Patt - Oavgjort", PANEL_X, y_pos, 14.0, ORANGE);
+                    draw_text("Patt - Oavgjort", panel_x, y_pos, 14.0, ORANGE);
                 }
                 _ => {
-                    if !self.move_history.is_empty() {
-                        if let Some(last_move) = self.move_history.last() {
-                            if last_move.contains("uppgivning") {
-                                draw_text("Uppgivning", PANEL_X, y_pos, 14.0, RED);
-                            }
-                        }
+                    if let Some(side) = self.flagged() {
+                        draw_text(&self.flag_label(side), panel_x, y_pos, 14.0, RED);
+                    } else if self.resigned().is_some() {
+                        draw_text("Uppgivning", panel_x, y_pos, 14.0, RED);
+                    } else if let Some(reason) = self.draw_reason() {
+                        draw_text(reason.label(), panel_x, y_pos, 14.0, ORANGE);
                     }
                 }
             }
@@ -1675,39 +9185,37 @@ impl ChessGame {
         
         // Draglista med färgkodning för analyserade drag
         y_pos += 10.0;
-        draw_text("DRAGLISTA:", PANEL_X, y_pos, 16.0, BLACK);
+        draw_text("DRAGLISTA:", panel_x, y_pos, 16.0, BLACK);
         y_pos += 25.0;
         
         // Rita ruta för draglistan
         let list_height = 200.0; // Mindre höjd för att få plats med analysen
-        draw_rectangle(PANEL_X, y_pos, 160.0, list_height, WHITE);
-        draw_rectangle_lines(PANEL_X, y_pos, 160.0, list_height, 1.0, DARKGRAY);
+        draw_rectangle(panel_x, y_pos, 160.0, list_height, WHITE);
+        draw_rectangle_lines(panel_x, y_pos, 160.0, list_height, 1.0, DARKGRAY);
+        self.move_list_top.set(y_pos);
         
-        // Visa de senaste dragen med färgkodning
-        let visible_moves = 12;
-        let start_index = if self.move_history.len() > visible_moves {
-            self.move_history.len() - visible_moves
-        } else {
-            0
-        };
+        // Visa dragen från scrollpositionen (följer slutet av partiet när inget scrollats)
+        let start_index = self.move_list_start();
+        let current_ply = self.current_ply();
         
         let mut list_y = y_pos + 20.0;
-        for (i, move_str) in self.move_history.iter().enumerate().skip(start_index) {
+        for (i, record) in self.move_history.iter().enumerate().skip(start_index) {
             if list_y > y_pos + list_height - 20.0 {
                 break;
             }
             
             let move_number = i + 1;
-            let display_text = if move_str.contains("uppgivning") {
-                move_str.clone()
-            } else {
-                format!("{}. {}", move_number, move_str)
-            };
+            let move_analysis = self.game_analysis.as_ref().and_then(|analysis| analysis.moves.get(i));
+            let display_text = format!("{}. {}{}", move_number, record.san, move_analysis.map_or("", MoveAnalysis::positive_suffix));
             
             // Bestäm färg baserat på analys
             let text_color = if let Some(ref analysis) = self.game_analysis {
                 if let Some(move_analysis) = analysis.moves.get(i) {
-                    if move_analysis.is_blunder {
+                    if move_analysis.is_brilliant {
+                        BRILLIANT_COLOR
+                    } else if move_analysis.is_great {
+                        GREAT_COLOR
+                    } else if move_analysis.is_blunder {
                         RED
                     } else if move_analysis.is_mistake {
                         ORANGE
@@ -1723,18 +9231,61 @@ impl ChessGame {
                 BLACK
             };
             
-            draw_text(&display_text, PANEL_X + 5.0, list_y, 12.0, text_color);
+            if current_ply == Some(i) {
+                draw_rectangle(panel_x + 1.0, list_y - 12.0, 158.0, 15.0, Color::new(0.7, 0.85, 1.0, 1.0));
+            }
+            let flash = self.error_flash_alpha(i);
+            if flash > 0.0 {
+                draw_rectangle(panel_x + 1.0, list_y - 12.0, 158.0, 15.0, Color::new(1.0, 0.6, 0.0, flash));
+            }
+            draw_text(&display_text, panel_x + 5.0, list_y, 12.0, text_color);
+            
+            // Ställningar som förekommit tidigare får en liten ikon med antal förekomster
+            if let Some(&count) = self.position_occurrences.get(i) {
+                if count > 1 {
+                    draw_circle(panel_x + 102.0, list_y - 4.0, 6.0, ORANGE);
+                    draw_text(&count.to_string(), panel_x + 99.0, list_y, 11.0, WHITE);
+                }
+            }
+            
+            // Markera sista bokdraget när partiet sedan gått vidare på egen hand
+            if let Some(opening) = self.opening {
+                if i + 1 == opening.book_plies && self.move_history.len() > opening.book_plies {
+                    draw_text("ur bok", panel_x + 115.0, list_y, 12.0, DARKGREEN);
+                }
+            }
             list_y += 15.0;
         }
+        // Uppgivningen står som en egen rad efter sista draget
+        if let Some(side) = self.resigned().filter(|_| list_y <= y_pos + list_height - 20.0) {
+            draw_text(&Self::resignation_label(side), panel_x + 5.0, list_y, 12.0, BLACK);
+        }
+        
+        if self.move_list_offset > 0 {
+            draw_text("(mer ovan)", panel_x + 100.0, y_pos + 12.0, 12.0, DARKGRAY);
+        }
         
         // Visa totalt antal drag
+        let plies = self.move_history.len();
+        let black_first = self.start_board.side_to_move() == ChessColor::Black;
+        let full_moves = full_move_count(plies, black_first);
         draw_text(
-            &format!("Totalt: {} drag", self.move_history.len()),
-            PANEL_X,
+            &format!("Totalt: {} drag ({} halvdrag)", full_moves, plies),
+            panel_x,
             y_pos + list_height + 20.0,
             12.0,
             DARKGRAY
         );
+        
+        if let Some(opening) = self.opening {
+            draw_text(
+                &format!("{} {}", opening.eco, opening.name),
+                panel_x,
+                y_pos + list_height + 35.0,
+                12.0,
+                DARKGREEN
+            );
+        }
     }
 }
 
@@ -1742,11 +9293,49 @@ impl ChessGame {
 // DEL 5: FÖNSTERKONFIGURATION & HUVUDFUNKTION
 // =============================================================
 
-fn window_conf() -> Conf {
+// Fönstertiteln byggs från motorns "id name" så att versionen alltid stämmer.
+// Macroquad kan inte byta titel i efterhand, därför startas motorn före fönstret.
+fn base_window_title(engine_info: Option<&EngineInfo>) -> String {
+    match engine_info {
+        Some(info) if !info.name.is_empty() => format!("Daniels schack - powered by {} i Rust - 2025 - v1.0", info.name),
+        Some(_) => "Daniels schack - powered by Stockfish i Rust - 2025 - v1.0".to_string(),
+        None => "Daniels schack - ingen motor - 2025 - v1.0".to_string(),
+    }
+}
+
+// Byt titel på det öppna fönstret. Miniquad saknar stöd för det, så på Windows sätts
+// titeln direkt via user32, som miniquad redan länkar. Fönstret hittas på miniquads
+// fönsterklass och den nuvarande titeln. Returnerar false om titeln inte kunde bytas.
+// Grenen byggs inte på Linux; kontrollera den med `cargo check --target x86_64-pc-windows-gnu`.
+#[cfg(target_os = "windows")]
+fn set_window_title(current: &str, title: &str) -> bool {
+    use std::ffi::c_void;
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn FindWindowW(class_name: *const u16, window_name: *const u16) -> *mut c_void;
+        fn SetWindowTextW(hwnd: *mut c_void, text: *const u16) -> i32;
+    }
+
+    let wide = |text: &str| text.encode_utf16().chain(std::iter::once(0)).collect::<Vec<u16>>();
+    let (class_name, current, title) = (wide("MINIQUADAPP"), wide(current), wide(title));
+    // SAFETY: alla strängar är nollterminerade och lever under anropen
+    unsafe {
+        let hwnd = FindWindowW(class_name.as_ptr(), current.as_ptr());
+        !hwnd.is_null() && SetWindowTextW(hwnd, title.as_ptr()) != 0
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn set_window_title(_current: &str, _title: &str) -> bool {
+    false
+}
+
+fn window_conf(engine_info: Option<&EngineInfo>) -> Conf {
     Conf {
-        window_title:  "Daniels schack - powered by Stockfish 17.1 i Rust - 2025 - v1.0".into(),
+        window_title: base_window_title(engine_info),
         window_width:  1000, // Återställ till ursprunglig bredd
-        window_height: 850,
+        window_height: 910,
         ..Default::default()
     }
 }
@@ -1787,8 +9376,7 @@ async fn load_piece_textures() -> HashMap<PieceKey, Texture2D> {
     textures
 }
 
-#[macroquad::main(window_conf)]
-async fn main() {
+fn main() {
     // ===== En‑instans‑lås ====================================
     let instance = SingleInstance::new("chess_macroquad_instance").expect("kunde inte skapa låsfil");
     if !instance.is_single() {
@@ -1813,7 +9401,50 @@ async fn main() {
         }
     };
 
-    let mut game = ChessGame::new(load_piece_textures().await);
+    // "--bench" kör motorns benchmark och avslutar utan att öppna fönstret
+    if std::env::args().any(|arg| arg == "--bench") {
+        run_bench(ai_controller.as_ref());
+        return;
+    }
+
+    let engine_info = ai_controller.as_ref().map(|ai| ai.engine_info());
+    let conf = window_conf(engine_info.as_ref());
+    macroquad::Window::from_config(conf, run(ai_controller, engine_info));
+
+    // Låset ska hållas tills fönstret stängts
+    drop(instance);
+}
+
+fn run_bench(ai_controller: Option<&ThreadSafeAiController>) {
+    const BENCH_DEPTH: u8 = 13;
+
+    let Some(ai) = ai_controller else {
+        eprintln!("⚠ Ingen motor – kan inte köra benchmark");
+        return;
+    };
+
+    println!("[run_bench] Kör Stockfish bench på djup {BENCH_DEPTH}...");
+    match ai.bench(BENCH_DEPTH) {
+        Ok(result) => {
+            println!("✓ Benchmark klar");
+            println!("  Total tid:  {} ms", result.total_time_ms);
+            println!("  Noder:      {}", result.nodes);
+            println!("  Noder/sek:  {}", result.nps);
+        }
+        Err(e) => eprintln!("⚠ Benchmark misslyckades: {e}"),
+    }
+}
+
+async fn run(ai_controller: Option<ThreadSafeAiController>, engine_info: Option<EngineInfo>) {
+    let mut game = ChessGame::new(load_piece_textures().await, engine_info);
+    game.sounds = MoveSounds::load().await;
+    game.load_settings();
+    game.offer_resume();
+    if std::path::Path::new(ENGINE_BOOK_FILE).exists() {
+        if let Err(e) = game.load_book(ENGINE_BOOK_FILE) {
+            game.toast(format!("⚠ {}", e));
+        }
+    }
     println!("✓ Schackspel initierat!");
 
     // =========================================================
@@ -1822,71 +9453,620 @@ async fn main() {
     loop {
         clear_background(Color::new(0.9, 0.9, 0.9, 1.0));
 
-        // 1) Uppdatera UI-komponenter
+        // 1) Uppdatera UI-komponenter och spara ändrade inställningar
         game.update();
+        game.persist_settings();
 
         // 2) Hantera musklick
         if is_mouse_button_pressed(MouseButton::Left) {
             game.handle_mouse_click(mouse_position(), &ai_controller);
         }
+        game.handle_keyboard();
+        game.handle_dropped_files();
+        game.handle_move_list_scroll();
 
-        // 3) Poll AI för drag
+        // 3) Skicka ändrade motoroptioner och polla AI för drag
+        game.engine_restarting = ai_controller.as_ref().is_some_and(ThreadSafeAiController::is_restarting);
+        game.apply_engine_options(&ai_controller);
+        game.update_requested_eval(&ai_controller);
+        game.poll_draw_offer();
+        game.poll_pgn_import();
         game.poll_ai();
+        game.poll_puzzle();
 
         // 4) Poll partianalys
         game.poll_analysis();
 
-        // 5) Start AI om det är dess tur
+        // 5) Oändlig analys när spelet står still, annars start AI om det är dess tur
+        game.update_infinite_analysis(&ai_controller);
         if game.is_ai_turn() {
             if let Some(ref ai) = ai_controller {
                 game.start_ai(ai);
             }
         }
 
-        // 6) Rita brädet 8×8
-        const BOARD_OFFSET: f32 = 100.0;
-        const SQUARE_SIZE: f32 = 80.0;
-        for y in 0..8 {
-            for x in 0..8 {
-                let c = if (x + y) % 2 == 0 { BEIGE } else { BROWN };
-                draw_rectangle(
-                    x as f32 * SQUARE_SIZE + BOARD_OFFSET, 
-                    y as f32 * SQUARE_SIZE + BOARD_OFFSET, 
-                    SQUARE_SIZE, 
-                    SQUARE_SIZE, 
-                    c
-                );
-            }
-        }
+        // 6) Rita ram och brädet 8×8
+        game.draw_board_border();
+        game.draw_squares();
 
-        // 7) Rita koordinater
+        // 7) Rita koordinater; ställningsredigeraren ritar sina pjäser och sin panel själv
         game.draw_coordinates();
+        if game.editor.is_some() {
+            game.draw_editor();
+        } else {
+            // Evalueringsstapel, slagna pjäser och klockor
+            game.draw_eval_bar();
+            game.draw_material_tally();
+            game.draw_clocks();
 
-        // 8) Rita markeringar
-        game.draw_highlights();
+            // 8) Rita markeringar
+            game.draw_highlights();
+            game.draw_check_indicator();
 
-        // 9) Rita pjäserna
-        game.draw_pieces();
+            // 9) Rita pjäserna
+            game.draw_pieces();
+            game.draw_pv_arrows();
+            game.draw_promotion_dialog();
 
-        // 10) Rita kontrollpanel
-        game.draw_control_panel();
+            // 10) Rita kontrollpanel (döljs i åskådarläget med rent bräde)
+            if game.spectator != SpectatorMode::CleanBoard {
+                game.draw_control_panel();
+            }
+        }
+        if game.spectator.is_active() {
+            draw_text("ÅSKÅDARLÄGE (F2)", 600.0, 90.0, 16.0, DARKGRAY);
+        }
 
         // 11) Rita analysfönster som overlay (om det finns)
         game.draw_analysis_window();
+        game.draw_report();
+        game.draw_engine_info_window(&ai_controller);
+        game.draw_settings_window();
+        game.draw_name_input();
+        game.draw_resume_prompt();
+        game.draw_puzzle_prompt();
+        game.draw_engine_notice();
+        game.draw_fen_input();
+        game.draw_pgn_import();
+        game.toasts.draw(game.layout.board_center());
 
         // 12) Rita huvudtitel
         draw_text("SCHACKSPEL", 10.0, 30.0, 24.0, BLACK);
 
         // Debug-information längst ner
         let debug_text = format!(
-            "Stockfish: {} | Bräde roterat: {} | Spelare: {:?} | Analys: {}",
-            if ai_controller.is_some() { "Aktiv" } else { "Ej tillgänglig" },
-            game.settings.board_flipped,
+            "Motor: {} | Bräde roterat: {} | Spelare: {:?} | Analys: {}",
+            if ai_controller.is_some() { game.engine_name() } else { "Ej tillgänglig" },
+            game.board_flipped(),
             game.settings.player_color,
             if game.analysis_in_progress { "Pågår" } else if game.game_analysis.is_some() { "Klar" } else { "Ingen" }
         );
-        draw_text(&debug_text, 10.0, 820.0, 12.0, DARKGRAY);
+        draw_text(&debug_text, 10.0, screen_height() - 30.0, 12.0, DARKGRAY);
 
         next_frame().await;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_eval_signs_and_precision() {
+        assert_eq!(format_eval(Eval::Pawns(1.234), 2), "+1.23");
+        assert_eq!(format_eval(Eval::Pawns(-0.5), 2), "-0.50");
+        assert_eq!(format_eval(Eval::Pawns(0.26), 1), "+0.3");
+        assert_eq!(format_eval(Eval::Pawns(3.0), 0), "+3");
+    }
+
+    #[test]
+    fn format_eval_rounds_tiny_values_to_unsigned_zero() {
+        assert_eq!(format_eval(Eval::Pawns(0.0), 2), "0.00");
+        assert_eq!(format_eval(Eval::Pawns(-0.004), 2), "0.00");
+        assert_eq!(format_eval(Eval::Pawns(0.04), 1), "0.0");
+    }
+
+    #[test]
+    fn format_eval_mates() {
+        assert_eq!(format_eval(Eval::Mate { moves: 4, winner: ChessColor::White }, 2), "M4");
+        assert_eq!(format_eval(Eval::Mate { moves: 3, winner: ChessColor::Black }, 2), "-M3");
+        assert_eq!(format_eval(Eval::Mate { moves: 0, winner: ChessColor::White }, 2), "#");
+        assert_eq!(format_eval(Eval::Mate { moves: 0, winner: ChessColor::Black }, 2), "-#");
+    }
+
+    #[test]
+    fn board_is_locked_after_game_over_in_every_mode() {
+        for mode in [GameMode::VsEngine, GameMode::HotSeat, GameMode::EngineVsEngine] {
+            for side in [ChessColor::White, ChessColor::Black] {
+                assert_eq!(board_click(true, false, mode, side, side), BoardClick::GameOver);
+            }
+        }
+    }
+
+    #[test]
+    fn review_mode_ignores_board_clicks_even_after_game_over() {
+        assert_eq!(board_click(true, true, GameMode::VsEngine, ChessColor::White, ChessColor::White), BoardClick::Ignored);
+        assert_eq!(board_click(false, true, GameMode::VsEngine, ChessColor::White, ChessColor::White), BoardClick::Ignored);
+    }
+
+    #[test]
+    fn board_click_during_play() {
+        let (white, black) = (ChessColor::White, ChessColor::Black);
+        assert_eq!(board_click(false, false, GameMode::VsEngine, white, white), BoardClick::Play);
+        assert_eq!(board_click(false, false, GameMode::VsEngine, black, white), BoardClick::Ignored);
+        assert_eq!(board_click(false, false, GameMode::EngineVsEngine, white, white), BoardClick::Ignored);
+    }
+
+    #[test]
+    fn eval_cache_evicts_least_recently_used() {
+        let mut cache = EvalCache::new(2);
+        cache.insert("a", 10, Eval::Pawns(0.1));
+        cache.insert("b", 10, Eval::Pawns(0.2));
+        // "a" används igen, så "b" är äldst när "c" läggs till
+        assert_eq!(cache.get("a", 10), Some(Eval::Pawns(0.1)));
+        cache.insert("c", 10, Eval::Pawns(0.3));
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("b", 10), None);
+        assert_eq!(cache.get("a", 10), Some(Eval::Pawns(0.1)));
+        assert_eq!(cache.get("c", 10), Some(Eval::Pawns(0.3)));
+    }
+
+    #[test]
+    fn eval_cache_separates_depths_and_updates_in_place() {
+        let mut cache = EvalCache::new(3);
+        cache.insert("a", 10, Eval::Pawns(0.1));
+        cache.insert("a", 20, Eval::Pawns(0.5));
+        cache.insert("a", 10, Eval::Pawns(0.2));
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("a", 10), Some(Eval::Pawns(0.2)));
+        assert_eq!(cache.get("a", 20), Some(Eval::Pawns(0.5)));
+    }
+
+    #[test]
+    fn eval_cache_never_exceeds_capacity() {
+        let mut cache = EvalCache::new(4);
+        for i in 0..100 {
+            cache.insert(&i.to_string(), 12, Eval::Pawns(i as f32));
+        }
+        assert_eq!(cache.len(), cache.capacity());
+        assert_eq!(cache.get("99", 12), Some(Eval::Pawns(99.0)));
+        assert_eq!(cache.get("95", 12), None);
+    }
+
+    // Ett parti med `plies` halvdrag där springarna går fram och tillbaka
+    fn long_game(plies: usize) -> (Board, Vec<MoveRecord>) {
+        let shuffle = ["g1f3", "g8f6", "f3g1", "f6g8"];
+        let mut board = Board::default();
+        let mut records = Vec::new();
+        for uci in shuffle.iter().cycle().take(plies) {
+            let record = MoveRecord::new(&board, ChessMove::from_str(uci).unwrap());
+            board = record.board_after;
+            records.push(record);
+        }
+        (board, records)
+    }
+
+    #[test]
+    fn very_long_game_move_list_and_totals() {
+        let (board, records) = long_game(300);
+        assert_eq!(board, Board::default());
+        assert_eq!(full_move_count(records.len(), false), 150);
+        assert_eq!(full_move_count(records.len(), true), 151);
+
+        // Utan scroll syns de sista dragen; scroll förbi början stannar vid första draget
+        assert_eq!(move_list_window_start(300, 0), 300 - PANEL_MOVE_LIST_ROWS);
+        assert_eq!(move_list_window_start(300, 100), 200 - PANEL_MOVE_LIST_ROWS);
+        assert_eq!(move_list_window_start(300, 10_000), 0);
+        assert_eq!(move_list_window_start(5, 3), 0);
+    }
+
+    #[test]
+    fn very_long_game_analysis_scroll_is_clamped() {
+        assert_eq!(analysis_scroll_after(0, -12, 300), 12);
+        assert_eq!(analysis_scroll_after(290, -50, 300), 299);
+        assert_eq!(analysis_scroll_after(5, 50, 300), 0);
+        assert_eq!(analysis_scroll_after(0, -3, 0), 0);
+    }
+
+    #[test]
+    fn very_long_game_pgn_numbers_every_move() {
+        let (_, records) = long_game(300);
+        let sans: Vec<String> = records.iter().map(|r| r.san.clone()).collect();
+        let text = pgn_movetext(&sans, false, 1, None, "*");
+        assert!(text.starts_with("1. Nf3 Nf6 2. Ng1 Ng8"));
+        assert!(text.contains("150. Ng1 Ng8 *"));
+        assert!(text.lines().all(|line| line.chars().count() <= 80));
+    }
+
+    // Spela dragen i UCI-form som spelaren skulle göra dem och returnera posterna
+    fn play_uci(moves: &[&str]) -> (Board, Vec<MoveRecord>) {
+        let mut board = Board::default();
+        let mut records = Vec::new();
+        for uci in moves {
+            let chess_move = ChessMove::from_str(uci).unwrap();
+            assert!(board.legal(chess_move), "olagligt drag {uci}");
+            let record = MoveRecord::new(&board, chess_move);
+            board = record.board_after;
+            records.push(record);
+        }
+        (board, records)
+    }
+
+    // SAN-historiken spelas upp igen via PGN-importen och ska ge samma ställning
+    fn assert_castling_replays(moves: &[&str], expected_castles: [&str; 2]) {
+        let (board, records) = play_uci(moves);
+        let sans: Vec<String> = records.iter().map(|r| r.san.clone()).collect();
+        let castles: Vec<&str> = sans.iter().map(String::as_str).filter(|san| san.starts_with("O-O")).collect();
+        assert_eq!(castles, expected_castles);
+
+        let game = parse_pgn(&pgn_movetext(&sans, false, 1, None, "*")).unwrap();
+        assert_eq!(game.moves, records.iter().map(|r| r.chess_move).collect::<Vec<_>>());
+        let replayed = game.moves.iter().fold(game.start, |b, &m| b.make_move_new(m));
+        assert_eq!(replayed, board);
+    }
+
+    #[test]
+    fn castling_replays_white_kingside_black_queenside() {
+        let moves = [
+            "e2e4", "e7e5", "g1f3", "b8c6", "f1c4", "f8c5", "e1g1", "d7d6",
+            "d2d3", "c8e6", "b1c3", "d8d7", "c1e3", "e8c8",
+        ];
+        assert_castling_replays(&moves, ["O-O", "O-O-O"]);
+        let (board, _) = play_uci(&moves);
+        assert_eq!(board.piece_on(Square::G1), Some(Piece::King));
+        assert_eq!(board.piece_on(Square::F1), Some(Piece::Rook));
+        assert_eq!(board.piece_on(Square::C8), Some(Piece::King));
+        assert_eq!(board.piece_on(Square::D8), Some(Piece::Rook));
+    }
+
+    #[test]
+    fn castling_replays_white_queenside_black_kingside() {
+        let moves = [
+            "d2d4", "d7d5", "b1c3", "b8c6", "c1f4", "c8f5", "d1d2", "e7e6",
+            "e1c1", "g8f6", "e2e3", "f8e7", "f1d3", "e8g8",
+        ];
+        assert_castling_replays(&moves, ["O-O-O", "O-O"]);
+        let (board, _) = play_uci(&moves);
+        assert_eq!(board.piece_on(Square::C1), Some(Piece::King));
+        assert_eq!(board.piece_on(Square::D1), Some(Piece::Rook));
+        assert_eq!(board.piece_on(Square::G8), Some(Piece::King));
+        assert_eq!(board.piece_on(Square::F8), Some(Piece::Rook));
+    }
+
+    #[test]
+    fn parse_score_reads_centipawns_from_white() {
+        let line = "info depth 20 seldepth 28 multipv 1 score cp -37 nodes 1234567 nps 987654 pv e7e5 g1f3";
+        assert_eq!(StockfishController::parse_score(line, ChessColor::White), Some(Eval::Pawns(-0.37)));
+        assert_eq!(StockfishController::parse_score(line, ChessColor::Black), Some(Eval::Pawns(0.37)));
+    }
+
+    #[test]
+    fn parse_score_reads_mates_for_either_side() {
+        let winning = "info depth 30 score mate 3 nodes 5000 pv d1h5 g7g6 h5e5";
+        let losing = "info depth 30 score mate -2 nodes 5000 pv e8f7";
+        let mated = "info depth 0 score mate 0";
+        assert_eq!(
+            StockfishController::parse_score(winning, ChessColor::Black),
+            Some(Eval::Mate { moves: 3, winner: ChessColor::Black })
+        );
+        assert_eq!(
+            StockfishController::parse_score(losing, ChessColor::Black),
+            Some(Eval::Mate { moves: 2, winner: ChessColor::White })
+        );
+        assert_eq!(
+            StockfishController::parse_score(mated, ChessColor::White),
+            Some(Eval::Mate { moves: 0, winner: ChessColor::Black })
+        );
+    }
+
+    #[test]
+    fn parse_score_ignores_lines_without_score() {
+        assert_eq!(StockfishController::parse_score("info string NNUE evaluation using nn.nnue", ChessColor::White), None);
+        assert_eq!(StockfishController::parse_score("info depth 5 currmove e2e4 currmovenumber 1", ChessColor::White), None);
+    }
+
+    #[test]
+    fn shorter_mates_sort_above_longer_mates_and_material() {
+        let mate_in = |moves, winner| Eval::Mate { moves, winner }.as_pawns();
+        assert!(mate_in(2, ChessColor::White) > mate_in(5, ChessColor::White));
+        assert!(mate_in(40, ChessColor::White) > 50.0);
+        assert!(mate_in(2, ChessColor::Black) < mate_in(5, ChessColor::Black));
+        assert_eq!(mate_in(3, ChessColor::White), -mate_in(3, ChessColor::Black));
+    }
+
+    #[test]
+    fn parse_info_fields() {
+        let line = "info depth 18 seldepth 25 multipv 1 score cp 31 nodes 2500000 nps 1250000 hashfull 300 tbhits 0 time 2000 pv e2e4 e7e5 g1f3";
+        assert_eq!(StockfishController::parse_depth(line), Some(18));
+        assert_eq!(StockfishController::parse_info_number(line, "nodes"), Some(2_500_000));
+        assert_eq!(StockfishController::parse_info_number(line, "nps"), Some(1_250_000));
+        let pv = StockfishController::parse_pv(line).unwrap();
+        assert_eq!(pv.iter().map(ToString::to_string).collect::<Vec<_>>(), ["e2e4", "e7e5", "g1f3"]);
+        assert_eq!(StockfishController::parse_pv("info depth 1 score cp 0"), None);
+    }
+
+    #[test]
+    fn pgn_round_trip_from_standard_start() {
+        let (board, records) = play_uci(&["e2e4", "c7c5", "g1f3", "d7d6", "d2d4", "c5d4", "f3d4", "g8f6", "b1c3", "a7a6"]);
+        let sans: Vec<String> = records.iter().map(|r| r.san.clone()).collect();
+        let text = pgn_movetext(&sans, false, 1, None, "*");
+        assert_eq!(text, "1. e4 c5 2. Nf3 d6 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 a6 *\n");
+
+        let game = parse_pgn(&text).unwrap();
+        assert_eq!(game.start, Board::default());
+        assert_eq!(game.start_fullmove, 1);
+        assert_eq!(game.moves.iter().fold(game.start, |b, &m| b.make_move_new(m)), board);
+    }
+
+    #[test]
+    fn pgn_round_trip_keeps_fen_move_number() {
+        let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 3 23";
+        let start = parse_fen(fen).unwrap();
+        assert_eq!(fen_counters(fen), (3, 23));
+        assert_eq!(fen_with_counters(&start, 3, 23), fen);
+
+        let mut board = start;
+        let mut sans = Vec::new();
+        for uci in ["g8f6", "b1c3", "f8c5"] {
+            let m = ChessMove::from_str(uci).unwrap();
+            sans.push(move_to_san(&board, m));
+            board = board.make_move_new(m);
+        }
+        let movetext = pgn_movetext(&sans, true, 23, None, "*");
+        assert_eq!(movetext, "23... Nf6 24. Nc3 Bc5 *\n");
+
+        let pgn = format!("[SetUp \"1\"]\n[FEN \"{}\"]\n\n{}", fen_with_counters(&start, 3, 23), movetext);
+        let game = parse_pgn(&pgn).unwrap();
+        assert_eq!(game.start, start);
+        assert_eq!((game.start_halfmove, game.start_fullmove), (3, 23));
+        assert_eq!(game.moves.iter().fold(game.start, |b, &m| b.make_move_new(m)), board);
+        assert_eq!(pgn_movetext(&sans, true, game.start_fullmove, None, "*"), movetext);
+    }
+
+    #[test]
+    fn insufficient_material_detects_dead_positions() {
+        let dead = |fen: &str| insufficient_material(&parse_fen(fen).unwrap());
+        assert!(dead("8/8/4k3/8/8/3K4/8/8 w - - 0 1"));
+        assert!(dead("8/8/4k3/8/8/3K4/5B2/8 w - - 0 1"));
+        assert!(dead("8/8/4k3/8/8/3K4/8/6n1 b - - 0 1"));
+        // Löpare på samma färg kan aldrig ge matt, oavsett vem som äger dem
+        assert!(dead("5b2/8/4k3/8/8/3K4/8/2B5 w - - 0 1"));
+        assert!(dead("8/8/4k3/8/8/3K4/8/2B1B3 w - - 0 1"));
+
+        assert!(!dead("4b3/8/4k3/8/8/3K4/8/2B5 w - - 0 1"));
+        assert!(!dead("8/8/4k3/8/8/3K4/8/1NN5 w - - 0 1"));
+        assert!(!dead("8/8/4k3/8/8/3K4/8/2B3n1 w - - 0 1"));
+        assert!(!dead("8/8/4k3/8/8/3K4/4P3/8 w - - 0 1"));
+        assert!(!dead("8/8/4k3/8/8/3K4/8/7R w - - 0 1"));
+    }
+
+    #[test]
+    fn sloppy_move_in_won_position_is_not_an_error() {
+        let classify = |before: f32, after: f32, side: ChessColor| {
+            let (win_before, win_after) = mover_win_percents(before, after, side);
+            ChessGame::classify_move(win_before, win_after)
+        };
+        // Samma förlust på 1,5 bönder: ingen anmärkning vid +8, ett misstag vid jämvikt
+        assert_eq!(classify(8.0, 6.5, ChessColor::White), (false, false, false));
+        assert_eq!(classify(-8.0, -6.5, ChessColor::Black), (false, false, false));
+        assert_eq!(classify(0.0, -1.5, ChessColor::White), (false, true, false));
+        assert_eq!(classify(0.0, 1.5, ChessColor::Black), (false, true, false));
+        // Att kasta bort hela vinsten är fortfarande en blunder
+        assert_eq!(classify(8.0, 0.0, ChessColor::White), (true, false, false));
+    }
+
+    #[test]
+    fn opening_book_lines_are_legal_and_distinct() {
+        let mut names = std::collections::HashSet::new();
+        for &(eco, name, line) in OPENING_BOOK {
+            assert!(names.insert(name), "{eco} {name} förekommer två gånger");
+            line.split_whitespace().fold(Board::default(), |board, san| {
+                let m = parse_san(&board, san).unwrap_or_else(|| panic!("{eco} {name}: ogiltigt drag {san}"));
+                board.make_move_new(m)
+            });
+        }
+    }
+
+    #[test]
+    fn save_file_reads_older_files_and_keeps_null_apart_from_missing() {
+        let text = r#"{
+            "format": "daniels-schack", "version": 1,
+            "start_fen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "moves": ["e4", "e5"], "player_color": "svart",
+            "settings": {"elo_limit": null, "coordinate_size": 16},
+            "clock": {"white": 290.5, "black": 300, "flagged": null}
+        }"#;
+        let save: SaveFile = serde_json::from_str(text).unwrap();
+        assert_eq!(save.player_color, Some(SavedColor::Svart));
+        let settings = save.settings.unwrap();
+        assert_eq!(settings.elo_limit, Some(None));
+        assert_eq!(settings.time_control, None);
+        assert_eq!(settings.coordinate_size, Some(16));
+        let clock = save.clock.unwrap();
+        assert_eq!((clock.white, clock.black), (290.5, 300.0));
+        assert!(clock.snapshots.is_empty());
+
+        let written = serde_json::to_string(&SaveFile { settings: Some(settings), clock: Some(clock), ..save }).unwrap();
+        let again: SaveFile = serde_json::from_str(&written).unwrap();
+        assert_eq!(again.moves, ["e4", "e5"]);
+        assert_eq!(again.settings.unwrap().elo_limit, Some(None));
+    }
+
+    #[test]
+    fn deeply_nested_save_file_is_an_error() {
+        let text = format!("{{\"format\": \"daniels-schack\", \"extra\": {}", "[".repeat(100_000));
+        assert!(serde_json::from_str::<SaveHeader>(&text).is_err());
+        assert!(serde_json::from_str::<SaveFile>(&text).is_err());
+    }
+
+    #[test]
+    fn settings_page_fits_rows_to_the_window() {
+        // Allt ryms: ingen scrollning, oavsett sparat läge
+        assert_eq!(settings_page(26, 26.0 * SETTINGS_ROW_HEIGHT, 5), (0, 26));
+        // Tio rader ryms; scrollningen stannar vid sista sidan
+        assert_eq!(settings_page(26, 10.5 * SETTINGS_ROW_HEIGHT, 3), (3, 10));
+        assert_eq!(settings_page(26, 10.5 * SETTINGS_ROW_HEIGHT, 40), (16, 10));
+        // Ett mycket lågt fönster visar ändå en rad
+        assert_eq!(settings_page(26, 5.0, 25), (25, 1));
+    }
+
+    // Klickade från-till-par: dubbelsteg, en passant och båda rockaderna, den korta som
+    // kung till eget torn
+    const CLICKED_GAME: [(&str, &str); 15] = [
+        ("e2", "e4"), ("a7", "a6"), ("e4", "e5"), ("d7", "d5"), ("e5", "d6"), ("c7", "d6"),
+        ("g1", "f3"), ("b8", "c6"), ("f1", "e2"), ("c8", "e6"), ("e1", "h1"), ("d8", "d7"),
+        ("b1", "c3"), ("e8", "c8"), ("d2", "d3"),
+    ];
+    const CLICKED_GAME_FEN: &str = "2kr1bnr/1p1qpppp/p1npb3/8/8/2NP1N2/PPP1BPPP/R1BQ1RK1 b - - 0 1";
+
+    fn play_clicks(clicks: &[(&str, &str)]) -> (Board, Vec<MoveRecord>) {
+        let mut board = Board::default();
+        let mut records = Vec::new();
+        for &(from, to) in clicks {
+            let (source, dest) = (Square::from_str(from).unwrap(), Square::from_str(to).unwrap());
+            let m = legal_move_between(&board, source, dest, None).unwrap_or_else(|| panic!("{from}-{to} är inget lagligt drag"));
+            let record = MoveRecord::new(&board, m);
+            board = record.board_after;
+            records.push(record);
+        }
+        (board, records)
+    }
+
+    #[test]
+    fn clicked_moves_cover_en_passant_and_castling() {
+        let (board, records) = play_clicks(&CLICKED_GAME);
+        assert_eq!(board, parse_fen(CLICKED_GAME_FEN).unwrap());
+
+        let sans: Vec<&str> = records.iter().map(|r| r.san.as_str()).collect();
+        assert_eq!(sans[4], "exd6");
+        assert_eq!((sans[10], sans[13]), ("O-O", "O-O-O"));
+        // Kung till eget torn blir samma drag som kungens två steg
+        assert_eq!(records[10].chess_move, ChessMove::from_str("e1g1").unwrap());
+        assert_eq!(records[13].chess_move, ChessMove::from_str("e8c8").unwrap());
+        assert_eq!(records[4].board_after.piece_on(Square::from_str("d5").unwrap()), None);
+    }
+
+    #[test]
+    fn san_and_from_to_histories_replay_to_the_same_board() {
+        let (board, records) = play_clicks(&CLICKED_GAME);
+        let expected: Vec<ChessMove> = records.iter().map(|r| r.chess_move).collect();
+
+        let from_to: Vec<String> = CLICKED_GAME.iter().map(|(from, to)| format!("{from}-{to}")).collect();
+        let sans: Vec<String> = records.iter().map(|r| r.san.clone()).collect();
+        for history in [sans, from_to] {
+            let mut replayed = Board::default();
+            let mut moves = Vec::new();
+            for token in &history {
+                let m = parse_san(&replayed, token).unwrap_or_else(|| panic!("kunde inte tolka {token}"));
+                replayed = replayed.make_move_new(m);
+                moves.push(m);
+            }
+            assert_eq!(moves, expected);
+            assert_eq!(replayed, board);
+        }
+    }
+
+    #[test]
+    fn puzzle_replies_marked_with_question_mark_are_left_to_the_engine() {
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        let puzzles = parse_puzzles(&format!("{start}; 1. e4 ? 2. Qh5")).unwrap();
+        assert_eq!(puzzles[0].solution, vec![Some("e4".to_string()), None, Some("Qh5".to_string())]);
+
+        // Lösningens drag efter motorns svar tolkas i ställningen som svaret gav
+        let mut session = PuzzleSession::new(puzzles);
+        session.ply = 2;
+        let board = parse_fen("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2").unwrap();
+        assert_eq!(session.expected(&board), Some(ChessMove::new(Square::D1, Square::H5, None)));
+        session.ply = 1;
+        assert_eq!(session.expected(&board), None);
+
+        assert!(parse_puzzles(&format!("{start}; ? e5")).is_err());
+        assert!(parse_puzzles(&format!("{start}; e4 e5 Ke3")).is_err());
+    }
+
+    #[test]
+    fn drill_retry_after_undo_drops_the_redo_line() {
+        let mut board = Board::default();
+        let mut history = Vec::new();
+        let mut drill = None;
+        for (i, san) in ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6"].into_iter().enumerate() {
+            if i == 2 {
+                drill = Some(DrillPosition { board, history_len: history.len() });
+            }
+            let record = MoveRecord::new(&board, parse_san(&board, san).unwrap());
+            board = record.board_after;
+            history.push(record);
+        }
+        let drill = drill.unwrap();
+
+        // Två drag ångrade, men fortfarande efter övningsställningen
+        let mut redo_stack: Vec<MoveRecord> = history.drain(4..).rev().collect();
+        assert!(drill.rewind(Board::default(), &mut history, &mut redo_stack));
+        assert_eq!(history.len(), 2);
+        assert!(redo_stack.is_empty());
+        assert_eq!(history.last().unwrap().board_after, drill.board);
+
+        // Ångrat förbi ställningen: inget ändras
+        redo_stack.extend(history.drain(1..));
+        assert!(!drill.rewind(Board::default(), &mut history, &mut redo_stack));
+        assert_eq!((history.len(), redo_stack.len()), (1, 1));
+
+        // Samma antal drag men en annan väg når inte ställningen
+        let other = MoveRecord::new(&history[0].board_after, parse_san(&history[0].board_after, "c5").unwrap());
+        history.push(other);
+        assert!(!drill.rewind(Board::default(), &mut history, &mut redo_stack));
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn clock_rewinds_to_the_drill_ply_and_forgets_the_abandoned_line() {
+        let mut clock = GameClock::new(TimeControl { minutes: 3, increment: 2 });
+        let mut side = ChessColor::White;
+        for seconds in [5.0, 7.0, 11.0, 13.0] {
+            clock.tick(side, seconds);
+            clock.complete_move(side);
+            side = !side;
+        }
+        // Övningsställningen efter två halvdrag: tiden som den var när det tredje gjordes
+        clock.rewind_to(2);
+        assert_eq!(clock.remaining, [166.0, 175.0]);
+        assert_eq!(clock.snapshots.len(), 2);
+
+        // En ny väg därifrån ångras till sina egna tider, inte den övergivnas
+        clock.tick(ChessColor::White, 20.0);
+        clock.complete_move(ChessColor::White);
+        clock.rewind_to(2);
+        assert_eq!(clock.remaining, [146.0, 175.0]);
+    }
+
+    #[test]
+    fn bound_scores_are_skipped() {
+        let stream = [
+            "info depth 18 seldepth 24 multipv 1 score cp 35 nodes 100 nps 1000 pv e2e4",
+            "info depth 19 seldepth 25 multipv 1 score cp 80 lowerbound nodes 200 nps 1000 pv e2e4",
+            "info depth 19 seldepth 25 multipv 1 score cp -10 upperbound nodes 300 nps 1000 pv d2d4",
+            "bestmove e2e4 ponder e7e5",
+        ];
+        assert_eq!(StockfishController::final_score(stream, ChessColor::White), Some(Eval::Pawns(0.35)));
+    }
+
+    #[test]
+    fn final_score_keeps_last_exact_score_before_bestmove() {
+        let stream = [
+            "info depth 10 score cp 20 pv e7e5",
+            "info depth 11 score cp 50 upperbound pv e7e5",
+            "info depth 11 score cp 42 pv c7c5",
+            "bestmove c7c5",
+            "info depth 1 score cp 999 pv a7a6",
+        ];
+        // Svart vid draget: motorns +0.42 blir -0.42 från vits perspektiv
+        assert_eq!(StockfishController::final_score(stream, ChessColor::Black), Some(Eval::Pawns(-0.42)));
+    }
+
+    #[test]
+    fn final_score_without_exact_score_is_none() {
+        let stream = ["info depth 5 score cp 100 lowerbound pv e2e4", "bestmove e2e4"];
+        assert_eq!(StockfishController::final_score(stream, ChessColor::White), None);
+    }
+}